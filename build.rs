@@ -1,81 +1,91 @@
-use anyhow::*;
-use glob::glob;
-use std::fs::{read_to_string, write};
-use std::path::PathBuf;
+//Only the renderer needs the shaders
+#[cfg(feature = "render")]
+mod shaders {
+    use anyhow::*;
+    use glob::glob;
+    use std::fs::{read_to_string, write};
+    use std::path::PathBuf;
 
-struct ShaderData {
-    src: String,
-    src_path: PathBuf,
-    spv_path: PathBuf,
-    kind: shaderc::ShaderKind,
-}
+    struct ShaderData {
+        src: String,
+        src_path: PathBuf,
+        spv_path: PathBuf,
+        kind: shaderc::ShaderKind,
+    }
 
-impl ShaderData {
-    pub fn load(src_path: PathBuf) -> Result<Self> {
-        let extension = src_path
-            .extension()
-            .context("File has no extension")?
-            .to_str()
-            .context("Extension cannot be converted to &str")?;
-        let kind = match extension {
-            "vert" => shaderc::ShaderKind::Vertex,
-            "frag" => shaderc::ShaderKind::Fragment,
-            "comp" => shaderc::ShaderKind::Compute,
-            _ => bail!("Unsupported shader: {}", src_path.display()),
-        };
+    impl ShaderData {
+        pub fn load(src_path: PathBuf) -> Result<Self> {
+            let extension = src_path
+                .extension()
+                .context("File has no extension")?
+                .to_str()
+                .context("Extension cannot be converted to &str")?;
+            let kind = match extension {
+                "vert" => shaderc::ShaderKind::Vertex,
+                "frag" => shaderc::ShaderKind::Fragment,
+                "comp" => shaderc::ShaderKind::Compute,
+                _ => bail!("Unsupported shader: {}", src_path.display()),
+            };
 
-        let src = read_to_string(src_path.clone())?;
-        let spv_path = src_path.with_extension(format!("{}.spv", extension));
+            let src = read_to_string(src_path.clone())?;
+            let spv_path = src_path.with_extension(format!("{}.spv", extension));
 
-        Ok(Self {
-            src,
-            src_path,
-            spv_path,
-            kind,
-        })
+            Ok(Self {
+                src,
+                src_path,
+                spv_path,
+                kind,
+            })
+        }
     }
-}
 
-fn main() -> Result<()> {
-    // Collect all shaders recursively within /src/
-    let mut shader_paths = [
-        glob("./src/**/*.vert")?,
-        glob("./src/**/*.frag")?,
-        glob("./src/**/*.comp")?,
-    ];
+    pub fn compile() -> Result<()> {
+        // Collect all shaders recursively within /src/
+        let mut shader_paths = [
+            glob("./src/**/*.vert")?,
+            glob("./src/**/*.frag")?,
+            glob("./src/**/*.comp")?,
+        ];
 
-    // This could be parallelized
-    let shaders = shader_paths
-        .iter_mut()
-        .flatten()
-        .map(|glob_result| ShaderData::load(glob_result?))
-        .collect::<Vec<Result<_>>>()
-        .into_iter()
-        .collect::<Result<Vec<_>>>()?;
+        // This could be parallelized
+        let shaders = shader_paths
+            .iter_mut()
+            .flatten()
+            .map(|glob_result| ShaderData::load(glob_result?))
+            .collect::<Vec<Result<_>>>()
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
 
-    let mut compiler = shaderc::Compiler::new().context("Unable to create shader compiler")?;
+        let mut compiler = shaderc::Compiler::new().context("Unable to create shader compiler")?;
 
-    // This can't be parallelized. The [shaderc::Compiler] is not
-    // thread safe. Also, it creates a lot of resources. You could
-    // spawn multiple processes to handle this, but it would probably
-    // be better just to only compile shaders that have been changed
-    // recently.
-    for shader in shaders {
-        // This tells cargo to rerun this script if something in /src/ changes.
-        println!(
-            "cargo:rerun-if-changed={}",
-            shader.src_path.as_os_str().to_str().unwrap()
-        );
+        // This can't be parallelized. The [shaderc::Compiler] is not
+        // thread safe. Also, it creates a lot of resources. You could
+        // spawn multiple processes to handle this, but it would probably
+        // be better just to only compile shaders that have been changed
+        // recently.
+        for shader in shaders {
+            // This tells cargo to rerun this script if something in /src/ changes.
+            println!(
+                "cargo:rerun-if-changed={}",
+                shader.src_path.as_os_str().to_str().unwrap()
+            );
 
-        let compiled = compiler.compile_into_spirv(
-            &shader.src,
-            shader.kind,
-            &shader.src_path.to_str().unwrap(),
-            "main",
-            None,
-        )?;
-        write(shader.spv_path, compiled.as_binary_u8())?;
+            let compiled = compiler.compile_into_spirv(
+                &shader.src,
+                shader.kind,
+                &shader.src_path.to_str().unwrap(),
+                "main",
+                None,
+            )?;
+            write(shader.spv_path, compiled.as_binary_u8())?;
+        }
+
+        Ok(())
     }
+}
 
+fn main() -> anyhow::Result<()> {
+    #[cfg(feature = "render")]
+    shaders::compile()?;
     Ok(())
 }
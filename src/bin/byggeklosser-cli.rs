@@ -0,0 +1,19 @@
+//! The headless subcommands on their own, for building without the editor and its
+//! GPU and window dependencies.
+
+use byggeklosser::cli;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    match cli::run(&args) {
+        Some(Ok(())) => {}
+        Some(Err(e)) => {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        }
+        None => {
+            eprintln!("{}", cli::USAGE);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::brick::BrickShape;
+use crate::palette::ColorId;
+use crate::shape;
+use crate::world::{BlockType, World};
+
+//Brick pitch and height in millimetres
+pub const CELL_WIDTH_MM: f32 = 8.0;
+pub const CELL_HEIGHT_MM: f32 = 9.6;
+
+//A 2x4 brick weighs about 2.3 g, so roughly 0.29 g per solid cell of ABS
+const GRAMS_PER_CELL: f32 = 0.29;
+
+/// How many of one part in one colour the world needs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BomLine {
+    #[serde(skip)]
+    pub shape: BrickShape,
+    pub part: String,
+    pub blocktype: String,
+    //Smallest side first, so rotated copies of a part count together
+    pub width: u8,
+    pub depth: u8,
+    pub color_id: ColorId,
+    pub color: String,
+    pub ldraw_color: Option<u32>,
+    pub quantity: u32,
+}
+
+/// Parts list for a world.
+#[derive(Debug, Clone, Serialize)]
+pub struct BillOfMaterials {
+    pub lines: Vec<BomLine>,
+    pub total: u32,
+    //Bounding box in cells along x, y and z, and in millimetres
+    pub dimensions: [i64; 3],
+    pub dimensions_mm: [f32; 3],
+    pub mass_grams: f32,
+}
+
+impl BillOfMaterials {
+    pub fn from_world(world: &World) -> Self {
+        let mut counts: BTreeMap<(u8, u8, u8, ColorId), u32> = BTreeMap::new();
+        let mut volumes = [0.0; BlockType::ALL.len()];
+        for blocktype in BlockType::ALL.iter() {
+            volumes[blocktype.index() as usize] = shape::volume(*blocktype);
+        }
+
+        let mut mass_grams = 0.0;
+        for (_, object) in world.objects() {
+            let shape = object.shape;
+            let (a, b) = if shape.width <= shape.depth { (shape.width, shape.depth) } else { (shape.depth, shape.width) };
+            *counts.entry((shape.blocktype.index(), a, b, object.color)).or_insert(0) += 1;
+            mass_grams += GRAMS_PER_CELL
+                * volumes[shape.blocktype.index() as usize]
+                * (shape.width as f32 * shape.depth as f32);
+        }
+
+        let mut lines = Vec::new();
+        for ((blocktype, width, depth, color_id), quantity) in counts {
+            let shape = BrickShape::new(BlockType::from_index(blocktype).unwrap(), width, depth);
+            let color = world.palette.get(color_id);
+            lines.push(BomLine {
+                shape,
+                part: shape.name(),
+                blocktype: format!("{:?}", shape.blocktype),
+                width,
+                depth,
+                color_id,
+                color: color.map_or_else(|| format!("Colour {}", color_id), |color| color.name.clone()),
+                ldraw_color: color.and_then(|color| color.ldraw_id),
+                quantity,
+            });
+        }
+
+        let dimensions = match bounds(world) {
+            Some((min, max)) => [max[0] - min[0] + 1, max[1] - min[1] + 1, max[2] - min[2] + 1],
+            None => [0; 3],
+        };
+        BillOfMaterials {
+            total: lines.iter().map(|line| line.quantity).sum(),
+            lines,
+            dimensions,
+            dimensions_mm: [
+                dimensions[0] as f32 * CELL_WIDTH_MM,
+                dimensions[1] as f32 * CELL_HEIGHT_MM,
+                dimensions[2] as f32 * CELL_WIDTH_MM,
+            ],
+            mass_grams,
+        }
+    }
+
+    /// One row per line, with the totals as trailing comment rows.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("part,blocktype,width,depth,color_id,color,ldraw_color,quantity\n");
+        for line in &self.lines {
+            csv += &format!(
+                "{},{},{},{},{},{},{},{}\n",
+                csv_field(&line.part),
+                line.blocktype,
+                line.width,
+                line.depth,
+                line.color_id,
+                csv_field(&line.color),
+                line.ldraw_color.map_or(String::new(), |id| id.to_string()),
+                line.quantity,
+            );
+        }
+        csv += &format!("# total,{}\n", self.total);
+        csv += &format!(
+            "# dimensions,{}x{}x{} cells,{:.1}x{:.1}x{:.1} mm\n",
+            self.dimensions[0], self.dimensions[1], self.dimensions[2],
+            self.dimensions_mm[0], self.dimensions_mm[1], self.dimensions_mm[2],
+        );
+        csv += &format!("# mass,{:.1} g\n", self.mass_grams);
+        csv
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn save_csv<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_csv()).with_context(|| format!("Cannot write {}", path.display()))
+    }
+
+    pub fn save_json<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_json()?).with_context(|| format!("Cannot write {}", path.display()))
+    }
+}
+
+/// Lowest and highest occupied cell, if the world has any blocks.
+pub fn bounds(world: &World) -> Option<([i64; 3], [i64; 3])> {
+    let mut bounds: Option<([i64; 3], [i64; 3])> = None;
+    for (position, _) in world.iter_blocks() {
+        let (min, max) = bounds.get_or_insert((position, position));
+        for axis in 0..3 {
+            min[axis] = min[axis].min(position[axis]);
+            max[axis] = max[axis].max(position[axis]);
+        }
+    }
+    bounds
+}
+
+/// Quote a CSV field if it needs it.
+pub fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Split one CSV line into fields, undoing `csv_field` quoting.
+pub fn parse_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
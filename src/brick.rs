@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+
+use crate::orientation::Orientation;
+use crate::palette::ColorId;
+use crate::world::BlockType;
+
+/// Identifies a placed brick in `World::bricks`.
+pub type BrickId = u32;
+
+/// The footprint and kind of a part. Width runs along x and depth along z.
+///
+/// The grid is brick pitched, so plates and tiles still claim a whole cell in height;
+/// they are only drawn at plate height.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
+pub struct BrickShape {
+    pub blocktype: BlockType,
+    pub width: u8,
+    pub depth: u8,
+}
+
+impl BrickShape {
+    pub const fn new(blocktype: BlockType, width: u8, depth: u8) -> Self {
+        Self { blocktype, width, depth }
+    }
+
+    pub fn name(&self) -> String {
+        let kind = match self.blocktype {
+            BlockType::NORMAL => return "Block".to_string(),
+            BlockType::BRICK => "Brick",
+            BlockType::PLATE => "Plate",
+            BlockType::TILE => "Tile",
+            BlockType::SLOPE => "Slope 45",
+            BlockType::SLOPE_INVERTED => "Slope Inverted 45",
+            BlockType::SLOPE_CORNER => "Slope 45 Corner",
+            BlockType::WEDGE => "Wedge",
+            BlockType::ARCH => "Arch",
+        };
+        //Parts are named smallest side first, like the real catalogues do
+        let (a, b) = if self.width <= self.depth { (self.width, self.depth) } else { (self.depth, self.width) };
+        format!("{} {}x{}", kind, a, b)
+    }
+
+    /// Cells covered by the shape, relative to its origin.
+    pub fn cells(&self) -> impl Iterator<Item = [i64; 3]> {
+        let depth = self.depth as i64;
+        (0..self.width as i64).flat_map(move |x| (0..depth).map(move |z| [x, 0, z]))
+    }
+
+    pub fn size(&self) -> [f32; 3] {
+        [self.width as f32, self.blocktype.height(), self.depth as f32]
+    }
+}
+
+/// Shapes the editor can place. The unit block comes first.
+pub const CATALOGUE: &[BrickShape] = &[
+    BrickShape::new(BlockType::NORMAL, 1, 1),
+    BrickShape::new(BlockType::BRICK, 1, 1),
+    BrickShape::new(BlockType::BRICK, 1, 2),
+    BrickShape::new(BlockType::BRICK, 1, 3),
+    BrickShape::new(BlockType::BRICK, 1, 4),
+    BrickShape::new(BlockType::BRICK, 1, 6),
+    BrickShape::new(BlockType::BRICK, 1, 8),
+    BrickShape::new(BlockType::BRICK, 2, 2),
+    BrickShape::new(BlockType::BRICK, 2, 3),
+    BrickShape::new(BlockType::BRICK, 2, 4),
+    BrickShape::new(BlockType::BRICK, 2, 6),
+    BrickShape::new(BlockType::BRICK, 2, 8),
+    BrickShape::new(BlockType::PLATE, 1, 1),
+    BrickShape::new(BlockType::PLATE, 1, 2),
+    BrickShape::new(BlockType::PLATE, 1, 3),
+    BrickShape::new(BlockType::PLATE, 1, 4),
+    BrickShape::new(BlockType::PLATE, 1, 6),
+    BrickShape::new(BlockType::PLATE, 1, 8),
+    BrickShape::new(BlockType::PLATE, 2, 2),
+    BrickShape::new(BlockType::PLATE, 2, 3),
+    BrickShape::new(BlockType::PLATE, 2, 4),
+    BrickShape::new(BlockType::PLATE, 2, 6),
+    BrickShape::new(BlockType::PLATE, 2, 8),
+    BrickShape::new(BlockType::TILE, 1, 1),
+    BrickShape::new(BlockType::TILE, 1, 2),
+    BrickShape::new(BlockType::TILE, 1, 4),
+    BrickShape::new(BlockType::TILE, 2, 2),
+    BrickShape::new(BlockType::TILE, 2, 4),
+    BrickShape::new(BlockType::SLOPE, 1, 1),
+    BrickShape::new(BlockType::SLOPE, 1, 2),
+    BrickShape::new(BlockType::SLOPE, 2, 2),
+    BrickShape::new(BlockType::SLOPE_INVERTED, 1, 1),
+    BrickShape::new(BlockType::SLOPE_INVERTED, 1, 2),
+    BrickShape::new(BlockType::SLOPE_CORNER, 1, 1),
+    BrickShape::new(BlockType::SLOPE_CORNER, 2, 2),
+    BrickShape::new(BlockType::WEDGE, 2, 2),
+    BrickShape::new(BlockType::ARCH, 3, 1),
+    BrickShape::new(BlockType::ARCH, 4, 1),
+    BrickShape::new(BlockType::ARCH, 6, 1),
+];
+
+/// A part placed in the world. Every cell it covers holds a block pointing back at it.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
+pub struct Brick {
+    pub shape: BrickShape,
+    //Lowest corner of the cells covered after rotation
+    pub origin: [i64; 3],
+    pub orientation: Orientation,
+    pub color: ColorId,
+}
+
+impl Brick {
+    /// Global addresses of the covered cells.
+    pub fn cells(&self) -> impl Iterator<Item = [i64; 3]> {
+        let origin = self.origin;
+        let orientation = self.orientation;
+        let min = orientation.min_corner([self.shape.width as f32, 1.0, self.shape.depth as f32]);
+        let min = [min[0] as i64, min[1] as i64, min[2] as i64];
+        self.shape.cells().map(move |cell| {
+            //Rotate the cell centre (doubled to stay on integers) and find the cell it lands in
+            let centre = orientation.rotate([2 * cell[0] + 1, 2 * cell[1] + 1, 2 * cell[2] + 1]);
+            let mut address = [0; 3];
+            for axis in 0..3 {
+                address[axis] = origin[axis] + (centre[axis] - 1).div_euclid(2) - min[axis];
+            }
+            address
+        })
+    }
+
+    /// Translation that goes with `orientation` to put the rotated shape at `origin`.
+    pub fn position(&self) -> [f32; 3] {
+        let min = self.orientation.min_corner(self.shape.size());
+        [
+            self.origin[0] as f32 - min[0],
+            self.origin[1] as f32 - min[1],
+            self.origin[2] as f32 - min[2],
+        ]
+    }
+}
@@ -1,8 +1,10 @@
 use cgmath::*;
+use serde::{Deserialize, Serialize};
 use std::f32::consts::FRAC_PI_2;
+#[cfg(feature = "render")]
 use std::time::Duration;
-use winit::dpi::PhysicalPosition;
-use winit::event::*;
+#[cfg(feature = "render")]
+use winit::{dpi::PhysicalPosition, event::*};
 
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
@@ -12,7 +14,8 @@ pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     0.0, 0.0, 0.5, 1.0,
 );
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "CameraView", into = "CameraView")]
 pub struct Camera {
     pub position: Point3<f32>,
     yaw: Rad<f32>,
@@ -32,6 +35,19 @@ impl Camera {
         }
     }
 
+    /// A camera at `position` turned towards `target`.
+    pub fn looking_at(position: Point3<f32>, target: Point3<f32>) -> Self {
+        let direction = target - position;
+        let horizontal = (direction.x * direction.x + direction.z * direction.z).sqrt();
+        //calc_matrix keeps the horizontal part of the view direction at unit length
+        let pitch = if horizontal > 0.0 {
+            (direction.y / horizontal).clamp(-1.0, 1.0).asin()
+        } else {
+            -FRAC_PI_2 * direction.y.signum()
+        };
+        Self::new(position, Rad(direction.z.atan2(direction.x)), Rad(pitch))
+    }
+
     pub fn calc_matrix(&self) -> Matrix4<f32> {
         Matrix4::look_at_dir(
             self.position,
@@ -41,6 +57,30 @@ impl Camera {
     }
 }
 
+/// A camera as text formats write it, angles in degrees.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraView {
+    pub position: [f32; 3],
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+impl From<Camera> for CameraView {
+    fn from(camera: Camera) -> Self {
+        CameraView {
+            position: camera.position.into(),
+            yaw: Deg::from(camera.yaw).0,
+            pitch: Deg::from(camera.pitch).0,
+        }
+    }
+}
+
+impl From<CameraView> for Camera {
+    fn from(view: CameraView) -> Self {
+        Camera::new(view.position, Deg(view.yaw), Deg(view.pitch))
+    }
+}
+
 pub struct Projection {
     aspect: f32,
     fovy: Rad<f32>,
@@ -67,6 +107,8 @@ impl Projection {
     }
 }
 
+/// Flies the camera from keyboard and mouse input in the editor.
+#[cfg(feature = "render")]
 #[derive(Debug)]
 pub struct CameraController {
     amount_left: f32,
@@ -82,6 +124,7 @@ pub struct CameraController {
     sensitivity: f32,
 }
 
+#[cfg(feature = "render")]
 impl CameraController {
     pub fn new(speed: f32, sensitivity: f32) -> Self {
         Self {
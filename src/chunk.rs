@@ -0,0 +1,301 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+use crate::world::Block;
+
+/// Fixed width integers packed into u64 words. Values never straddle two words,
+/// so a word holds `64 / bits` values and the rest of its bits stay unused.
+#[derive(Debug, Clone)]
+struct PackedArray {
+    bits: u8,
+    len: usize,
+    words: Vec<u64>,
+}
+
+impl PackedArray {
+    fn new(bits: u8, len: usize) -> Self {
+        let per_word = 64 / bits as usize;
+        Self {
+            bits,
+            len,
+            words: vec![0; len.div_ceil(per_word)],
+        }
+    }
+
+    fn per_word(&self) -> usize {
+        64 / self.bits as usize
+    }
+
+    fn mask(&self) -> u64 {
+        (1u64 << self.bits) - 1
+    }
+
+    fn get(&self, index: usize) -> usize {
+        let per_word = self.per_word();
+        let shift = (index % per_word) * self.bits as usize;
+        ((self.words[index / per_word] >> shift) & self.mask()) as usize
+    }
+
+    fn set(&mut self, index: usize, value: usize) {
+        let per_word = self.per_word();
+        let shift = (index % per_word) * self.bits as usize;
+        let mask = self.mask();
+        let word = &mut self.words[index / per_word];
+        *word = (*word & !(mask << shift)) | ((value as u64 & mask) << shift);
+    }
+
+    //Copy every value into a wider array
+    fn widened(&self, bits: u8) -> Self {
+        let mut wider = PackedArray::new(bits, self.len);
+        for i in 0..self.len {
+            wider.set(i, self.get(i));
+        }
+        wider
+    }
+}
+
+/// A cube of `size`^3 cells stored as indexes into a palette of the unique blocks
+/// in the chunk. Palette slot 0 is always air. The index width starts at one bit
+/// and grows whenever the palette outgrows it.
+///
+/// Cells are laid out y-major, then z, then x, which is also the iteration order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "ChunkCells", into = "ChunkCells")]
+pub struct Chunk {
+    size: u8,
+    palette: Vec<Option<Block>>,
+    //Number of cells using each palette slot. Slots dropping to zero are reused.
+    refcounts: Vec<u32>,
+    indexes: PackedArray,
+}
+
+impl Chunk {
+    pub fn new(size: u8) -> Self {
+        assert!(size > 0, "chunk size must be at least 1");
+        let volume = size as usize * size as usize * size as usize;
+        Self {
+            size,
+            palette: vec![None],
+            refcounts: vec![volume as u32],
+            indexes: PackedArray::new(1, volume),
+        }
+    }
+
+    pub fn size(&self) -> u8 {
+        self.size
+    }
+
+    pub fn bits_per_block(&self) -> u8 {
+        self.indexes.bits
+    }
+
+    fn index(&self, pos: [u8; 3]) -> usize {
+        let size = self.size as usize;
+        assert!(
+            pos.iter().all(|&p| (p as usize) < size),
+            "block position {:?} outside chunk of size {}",
+            pos,
+            size
+        );
+        (pos[1] as usize * size + pos[2] as usize) * size + pos[0] as usize
+    }
+
+    fn position(&self, index: usize) -> [u8; 3] {
+        let size = self.size as usize;
+        [
+            (index % size) as u8,
+            (index / (size * size)) as u8,
+            ((index / size) % size) as u8,
+        ]
+    }
+
+    pub fn get(&self, pos: [u8; 3]) -> Option<&Block> {
+        self.palette[self.indexes.get(self.index(pos))].as_ref()
+    }
+
+    /// Store `block` at `pos` (or air for `None`) and return what was there.
+    pub fn set(&mut self, pos: [u8; 3], block: Option<Block>) -> Option<Block> {
+        let index = self.index(pos);
+        let old_slot = self.indexes.get(index);
+        let old = self.palette[old_slot];
+        if old == block {
+            return old;
+        }
+
+        let new_slot = self.palette_slot(block);
+        self.refcounts[old_slot] -= 1;
+        self.refcounts[new_slot] += 1;
+        self.indexes.set(index, new_slot);
+        old
+    }
+
+    pub fn insert(&mut self, pos: [u8; 3], block: Block) -> Option<Block> {
+        self.set(pos, Some(block))
+    }
+
+    pub fn remove(&mut self, pos: [u8; 3]) -> Option<Block> {
+        self.set(pos, None)
+    }
+
+    //Find the palette slot for block, adding it (and widening the indexes) if needed.
+    fn palette_slot(&mut self, block: Option<Block>) -> usize {
+        if block.is_none() {
+            return 0;
+        }
+        if let Some(slot) = (1..self.palette.len())
+            .find(|&slot| self.refcounts[slot] > 0 && self.palette[slot] == block)
+        {
+            return slot;
+        }
+        if let Some(slot) = (1..self.palette.len()).find(|&slot| self.refcounts[slot] == 0) {
+            self.palette[slot] = block;
+            return slot;
+        }
+
+        self.palette.push(block);
+        self.refcounts.push(0);
+        if self.palette.len() > 1 << self.indexes.bits {
+            self.indexes = self.indexes.widened(self.indexes.bits + 1);
+        }
+        self.palette.len() - 1
+    }
+
+    /// Number of non-air cells.
+    pub fn len(&self) -> usize {
+        self.indexes.len - self.refcounts[0] as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The distinct blocks currently stored in the chunk.
+    pub fn palette(&self) -> impl Iterator<Item = &Block> + '_ {
+        self.palette
+            .iter()
+            .zip(self.refcounts.iter())
+            .filter_map(|(block, &count)| if count > 0 { block.as_ref() } else { None })
+    }
+
+    /// All non-air blocks, in y, z, x order.
+    pub fn iter(&self) -> impl Iterator<Item = ([u8; 3], &Block)> + '_ {
+        (0..self.indexes.len).filter_map(move |index| {
+            self.palette[self.indexes.get(index)]
+                .as_ref()
+                .map(|block| (self.position(index), block))
+        })
+    }
+}
+
+/// A chunk as text formats write it: only the occupied cells, in iteration order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkCells {
+    pub size: u8,
+    pub cells: Vec<([u8; 3], Block)>,
+}
+
+impl From<Chunk> for ChunkCells {
+    fn from(chunk: Chunk) -> Self {
+        ChunkCells {
+            size: chunk.size,
+            cells: chunk.iter().map(|(pos, block)| (pos, *block)).collect(),
+        }
+    }
+}
+
+impl TryFrom<ChunkCells> for Chunk {
+    type Error = String;
+
+    fn try_from(cells: ChunkCells) -> Result<Self, String> {
+        let size = cells.size;
+        if size == 0 {
+            return Err("chunk size must be at least 1".to_string());
+        }
+        let mut chunk = Chunk::new(size);
+        for (pos, block) in cells.cells {
+            if pos.iter().any(|&coordinate| coordinate >= size) {
+                return Err(format!("cell {:?} is outside a chunk of size {}", pos, size));
+            }
+            chunk.insert(pos, block);
+        }
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::BlockType;
+
+    fn block(color: u16) -> Block {
+        Block { blocktype: BlockType::NORMAL, color, orientation: Default::default(), brick: None }
+    }
+
+    #[test]
+    fn packed_values_stay_in_their_word() {
+        //Three bits leave one bit of every word unused
+        let mut packed = PackedArray::new(3, 50);
+        assert_eq!(packed.per_word(), 21);
+        assert_eq!(packed.words.len(), 3);
+        for i in 0..50 {
+            packed.set(i, i % 8);
+        }
+        for i in 0..50 {
+            assert_eq!(packed.get(i), i % 8);
+        }
+        assert_eq!(packed.words[0] >> 63, 0);
+
+        packed.set(20, 5);
+        assert_eq!((packed.get(19), packed.get(20), packed.get(21)), (3, 5, 5));
+        let wider = packed.widened(5);
+        assert_eq!((0..50).map(|i| wider.get(i)).collect::<Vec<_>>(), (0..50).map(|i| packed.get(i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn indexes_widen_as_the_palette_grows() {
+        let mut chunk = Chunk::new(4);
+        assert_eq!(chunk.bits_per_block(), 1);
+        for x in 0..4 {
+            for z in 0..4 {
+                chunk.insert([x, 0, z], block((x * 4 + z) as u16));
+            }
+        }
+        //Air and 16 colours
+        assert_eq!(chunk.bits_per_block(), 5);
+        assert_eq!(chunk.len(), 16);
+        for x in 0..4 {
+            for z in 0..4 {
+                assert_eq!(chunk.get([x, 0, z]), Some(&block((x * 4 + z) as u16)));
+            }
+        }
+        assert_eq!(chunk.get([1, 1, 1]), None);
+    }
+
+    #[test]
+    fn freed_palette_slots_are_reused() {
+        let mut chunk = Chunk::new(2);
+        chunk.insert([0, 0, 0], block(1));
+        chunk.insert([1, 0, 0], block(2));
+        assert_eq!(chunk.remove([0, 0, 0]), Some(block(1)));
+        chunk.insert([0, 1, 0], block(3));
+        assert_eq!(chunk.palette.len(), 3);
+        assert_eq!(chunk.palette().count(), 2);
+        for x in 0..2 {
+            chunk.remove([x, 0, 0]);
+            chunk.remove([x, 1, 0]);
+        }
+        assert!(chunk.is_empty());
+    }
+
+    #[test]
+    fn cells_round_trip() {
+        let mut chunk = Chunk::new(3);
+        chunk.insert([2, 1, 0], block(4));
+        chunk.insert([0, 2, 2], block(6));
+        let cells = ChunkCells::from(chunk.clone());
+        assert_eq!(cells.cells.len(), 2);
+        let read = Chunk::try_from(cells).unwrap();
+        assert_eq!(read.iter().collect::<Vec<_>>(), chunk.iter().collect::<Vec<_>>());
+        assert!(Chunk::try_from(ChunkCells { size: 2, cells: vec![([2, 0, 0], block(1))] }).is_err());
+    }
+}
@@ -0,0 +1,606 @@
+use anyhow::*;
+
+use crate::bom::BillOfMaterials;
+use crate::diff::{self, WorldDiff};
+use crate::brick::BrickShape;
+use crate::gltf::{self, GltfMode};
+use crate::heightmap::Heightmap;
+use crate::history::History;
+#[cfg(feature = "render")]
+use crate::instructions::{self, StepOrder};
+use crate::inventory::Inventory;
+use crate::layer_plan::{self, Axis};
+use crate::ldraw;
+use crate::lego;
+use crate::mesh;
+use crate::mesh_export;
+use crate::mosaic::{Layout, Mosaic};
+use crate::palette::Palette;
+use crate::quantize::DeltaE;
+use crate::recovery::{self, Recovery};
+use crate::optimizer::Optimizer;
+#[cfg(feature = "render")]
+use crate::render;
+use crate::scene::{self, SceneFormat};
+use crate::schematic::{self, BlockMapping};
+#[cfg(feature = "render")]
+use crate::timelapse;
+use crate::voxel_grid;
+use crate::voxelize::{Fill, Voxelizer};
+use crate::world::{BlockType, World};
+use crate::wanted_list::WantedList;
+use crate::world_file;
+
+pub const USAGE: &str = "usage:
+  byggeklosser [world.bkw] [--inventory inventory.csv] [--diff before.bkw]
+    F9 switches between the world and its changes since before.bkw
+    F10 takes a snapshot, F11 and Shift+F11 step back and forward through them
+    unsaved changes are autosaved and journalled; after a crash F12 restores them
+  byggeklosser-cli <subcommand> ...
+    the subcommands below without the editor; instructions and timelapse need the render feature
+  byggeklosser bom <world.bkw> [--csv out.csv] [--json out.json] [--optimize]
+  byggeklosser optimize <world.bkw> <out.bkw> [--parts 2x4,2x2,1x2,1x1]
+  byggeklosser ldraw <world.bkw> <out.ldr> [--optimize]
+  byggeklosser wanted <world.bkw> <out> [--optimize]
+    writes out.bricklink.xml, out.rebrickable.csv and out.unmapped.csv
+  byggeklosser missing <world.bkw> <inventory.csv> [--out name] [--optimize]
+    lists the parts the inventory lacks, optionally as wanted lists like above
+  byggeklosser instructions <world.bkw> <folder> [--per-step n] [--size 800x600] [--optimize]
+    one step per layer, or n parts per step in the order they were placed
+  byggeklosser plan <world.bkw> <folder> [--axis x|y|z] [--optimize]
+    an svg and a png per slice, layers seen from above unless another axis is given
+  byggeklosser mosaic <picture> <out.bkw> [--width 48] [--wall] [--palette basic|lego] [--no-dither] [--cie76]
+    a baseplate of 1x1 tiles, or a wall of 1x1 bricks, in real brick colours
+  byggeklosser terrain <heightmap> <out.bkw> [--scale 16] [--base 0] [--colors picture]
+    columns as high as the heightmap is bright, coloured by height or after the picture
+  byggeklosser voxelize <mesh.obj|mesh.stl> <out.bkw> [--size 32] [--surface] [--z-up] [--palette basic|lego] [--optimize]
+    solid unless --surface, coloured from materials or vertex colours
+  byggeklosser mesh <world.bkw> <out.obj|out.ply|out.stl> [--scale lego|factor] [--optimize]
+    the visible surface, one unit per cell unless scaled; lego gives millimetres
+  byggeklosser gltf <world.bkw> <out.glb> [--instanced] [--scale lego|metres] [--optimize]
+    merged meshes per chunk, or instanced parts with EXT_mesh_gpu_instancing; real brick size unless scaled
+  byggeklosser schematic <in> <out> [--mapping table.csv] [--optimize]
+    converts between .bkw and Sponge .schem or Litematica .litematic, reporting unmapped blocks
+  byggeklosser mapping <out.csv>
+    writes the built in block mapping table to edit and pass to --mapping
+  byggeklosser voxels <in> <out> [--optimize]
+    converts between .bkw and .binvox occupancy or a .raw byte grid with a .json sidecar
+  byggeklosser scene <in> <out> [--optimize]
+    converts between .bkw and a readable .json or .ron scene
+  byggeklosser diff <before.bkw> <after.bkw>
+    lists added (+), removed (-), recoloured (~) and replaced (*) cells
+  byggeklosser merge <base.bkw> <ours.bkw> <theirs.bkw> <out.bkw>
+    applies their edits to ours where they do not conflict and lists the conflicting cells
+  byggeklosser history <world.bkw>
+    lists the snapshots kept in the world file as a tree, * marks the current one
+  byggeklosser snapshot <world.bkw> <name>
+    keeps the world as it is now as a snapshot after the current one
+  byggeklosser restore <world.bkw> <snapshot>
+    goes back to a snapshot, by number or name, after keeping any newer changes as a snapshot
+  byggeklosser branch <world.bkw> <snapshot> <out.bkw>
+    writes a new world file starting at a snapshot, with the history up to it
+  byggeklosser timelapse <world.bkw> <folder> [--snapshot name] [--size 800x600]
+    renders a frame per snapshot leading to the current one, or to the one given
+  byggeklosser recover <world.bkw> [out.bkw]
+    saves the unsaved changes the editor left after a crash, over the world file unless out is given";
+
+//Value following `flag`, if the flag is given
+fn option<'a>(args: &'a [String], flag: &str, what: &str) -> Result<Option<&'a str>> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) => match args.get(i + 1) {
+            Some(value) => Ok(Some(value)),
+            None => bail!("{} needs {}\n{}", flag, what, USAGE),
+        },
+        None => Ok(None),
+    }
+}
+
+//Flags that take no value
+const SWITCHES: &[&str] = &["--optimize", "--wall", "--no-dither", "--cie76", "--surface", "--z-up", "--instanced"];
+
+//The n-th argument that is not a flag or a flag value
+fn positional<'a>(args: &'a [String], n: usize, what: &str) -> Result<&'a str> {
+    let mut skip = false;
+    let mut found = 0;
+    for arg in args {
+        if skip {
+            skip = false;
+            continue;
+        }
+        if SWITCHES.contains(&arg.as_str()) {
+            continue;
+        }
+        if arg.starts_with("--") {
+            skip = true;
+            continue;
+        }
+        if found == n {
+            return Ok(arg);
+        }
+        found += 1;
+    }
+    bail!("missing {}\n{}", what, USAGE)
+}
+
+//Brick sizes like "2x4,1x2"
+fn parse_parts(parts: &str) -> Result<Vec<BrickShape>> {
+    let mut shapes = Vec::new();
+    for part in parts.split(',') {
+        let sides: Vec<u8> = part.trim().split('x').filter_map(|side| side.parse().ok()).collect();
+        match sides[..] {
+            [width, depth] if width > 0 && depth > 0 => shapes.push(BrickShape::new(BlockType::BRICK, width, depth)),
+            _ => bail!("Invalid part size {:?}, expected e.g. 2x4", part),
+        }
+    }
+    Ok(shapes)
+}
+
+//The world to work on, optimized first if asked to
+fn load_input(args: &[String]) -> Result<World> {
+    let world = world_file::load(positional(args, 0, "world file")?)?;
+    if args.iter().any(|arg| arg == "--optimize") {
+        Optimizer::default().optimize(&world)
+    } else {
+        Ok(world)
+    }
+}
+
+fn bom(args: &[String]) -> Result<()> {
+    let world = load_input(args)?;
+    let bom = BillOfMaterials::from_world(&world);
+    let csv = option(args, "--csv", "a csv file name")?;
+    let json = option(args, "--json", "a json file name")?;
+    if let Some(csv) = csv {
+        bom.save_csv(csv)?;
+    }
+    if let Some(json) = json {
+        bom.save_json(json)?;
+    }
+    if csv.is_none() && json.is_none() {
+        print!("{}", bom.to_csv());
+    }
+    Ok(())
+}
+
+fn optimize(args: &[String]) -> Result<()> {
+    let world = world_file::load(positional(args, 0, "world file")?)?;
+    let optimizer = match option(args, "--parts", "brick sizes like 2x4,1x2")? {
+        Some(parts) => Optimizer::new(parse_parts(parts)?),
+        None => Optimizer::default(),
+    };
+    let optimized = optimizer.optimize(&world)?;
+    println!("{} objects merged into {}", world.objects().count(), optimized.objects().count());
+    world_file::save(&optimized, positional(args, 1, "output file")?)
+}
+
+fn export_ldraw(args: &[String]) -> Result<()> {
+    let world = load_input(args)?;
+    ldraw::save(&world, positional(args, 1, "output file")?)
+}
+
+fn wanted(args: &[String]) -> Result<()> {
+    let world = load_input(args)?;
+    let wanted = WantedList::from_bom(&BillOfMaterials::from_world(&world));
+    wanted.save(positional(args, 1, "output name")?)?;
+    if !wanted.is_complete() {
+        eprint!("Some parts could not be mapped:\n{}", wanted.unmapped_report());
+    }
+    Ok(())
+}
+
+fn missing(args: &[String]) -> Result<()> {
+    let world = load_input(args)?;
+    let inventory = Inventory::load(positional(args, 1, "inventory file")?)?;
+    let missing = inventory.missing(&world);
+    if let Some(out) = option(args, "--out", "an output file name")? {
+        missing.save(out)?;
+    }
+    if missing.items.is_empty() {
+        println!("The inventory has every part");
+    } else {
+        print!("{}", missing.to_rebrickable_csv());
+    }
+    if !missing.unmapped.is_empty() {
+        eprint!("Some parts could not be mapped:\n{}", missing.unmapped_report());
+    }
+    Ok(())
+}
+
+//Picture size like "800x600"
+#[cfg(feature = "render")]
+fn size_option(args: &[String]) -> Result<(u32, u32)> {
+    Ok(match option(args, "--size", "a picture size like 800x600")? {
+        Some(size) => {
+            let sides: Vec<u32> = size.split('x').filter_map(|side| side.parse().ok()).collect();
+            //Larger pictures don't fit in a texture
+            let fits = |side: u32| (1..=render::MAX_TEXTURE_SIZE).contains(&side);
+            match sides[..] {
+                [width, height] if fits(width) && fits(height) => (width, height),
+                _ => bail!(
+                    "Invalid image size {:?}, expected e.g. 800x600 with sides up to {}",
+                    size,
+                    render::MAX_TEXTURE_SIZE
+                ),
+            }
+        }
+        None => (instructions::DEFAULT_SIZE, instructions::DEFAULT_SIZE),
+    })
+}
+
+#[cfg(feature = "render")]
+fn export_instructions(args: &[String]) -> Result<()> {
+    let world = load_input(args)?;
+    let order = match option(args, "--per-step", "a number of parts")? {
+        Some(n) => StepOrder::Placement(n.parse().with_context(|| format!("Invalid step size {}", n))?),
+        None => StepOrder::Layers,
+    };
+    let size = size_option(args)?;
+    let steps = instructions::steps(&world, order);
+    let (device, queue) = render::headless_device()?;
+    let dir = positional(args, 1, "output folder")?;
+    instructions::write_instructions(&device, &queue, &world, &steps, dir.as_ref(), size)?;
+    println!("{} steps written to {}", steps.len(), dir);
+    Ok(())
+}
+
+fn export_plans(args: &[String]) -> Result<()> {
+    let world = load_input(args)?;
+    let axis = match option(args, "--axis", "an axis, x, y or z")? {
+        Some(axis) => Axis::parse(axis)?,
+        None => Axis::Y,
+    };
+    let dir = positional(args, 1, "output folder")?;
+    let count = layer_plan::save(&world, axis, dir.as_ref())?;
+    println!("{} plans written to {}", count, dir);
+    Ok(())
+}
+
+fn palette_option(args: &[String]) -> Result<Option<Palette>> {
+    match option(args, "--palette", "a palette, basic or lego")? {
+        Some("basic") => Ok(Some(lego::lego_basic_palette())),
+        Some("lego") => Ok(Some(lego::lego_palette())),
+        Some(palette) => bail!("Unknown palette {:?}, expected basic or lego", palette),
+        None => Ok(None),
+    }
+}
+
+fn make_mosaic(args: &[String]) -> Result<()> {
+    let mut mosaic = Mosaic::default();
+    if let Some(width) = option(args, "--width", "a width in studs")? {
+        mosaic.width = width.parse().with_context(|| format!("Invalid width {}", width))?;
+    }
+    if let Some(palette) = palette_option(args)? {
+        mosaic.palette = palette;
+    }
+    for arg in args {
+        match arg.as_str() {
+            "--wall" => mosaic.layout = Layout::Wall,
+            "--no-dither" => mosaic.dither = false,
+            "--cie76" => mosaic.metric = DeltaE::Cie76,
+            _ => {}
+        }
+    }
+    let world = mosaic.load(positional(args, 0, "picture")?)?;
+    let out = positional(args, 1, "output world file")?;
+    world_file::save(&world, out)?;
+    println!("{} parts written to {}", world.objects().count(), out);
+    Ok(())
+}
+
+fn make_terrain(args: &[String]) -> Result<()> {
+    let mut heightmap = Heightmap::default();
+    if let Some(scale) = option(args, "--scale", "the height of white in blocks")? {
+        heightmap.scale = scale.parse().with_context(|| format!("Invalid scale {}", scale))?;
+    }
+    if let Some(base) = option(args, "--base", "a base level")? {
+        heightmap.base = base.parse().with_context(|| format!("Invalid base level {}", base))?;
+    }
+    if let Some(colors) = option(args, "--colors", "a picture to take colours from")? {
+        heightmap.load_colors(colors)?;
+    }
+    let world = heightmap.load(positional(args, 0, "heightmap")?)?;
+    let out = positional(args, 1, "output world file")?;
+    world_file::save(&world, out)?;
+    println!("{} blocks in {} chunks written to {}", world.iter_blocks().count(), world.chunks.len(), out);
+    Ok(())
+}
+
+fn voxelize(args: &[String]) -> Result<()> {
+    let mut voxelizer = Voxelizer::default();
+    if let Some(size) = option(args, "--size", "a size in cells")? {
+        voxelizer.size = size.parse().with_context(|| format!("Invalid size {}", size))?;
+    }
+    if let Some(palette) = palette_option(args)? {
+        voxelizer.default_color = palette.find_ldraw(71).unwrap_or(0);
+        voxelizer.palette = palette;
+    }
+    if args.iter().any(|arg| arg == "--surface") {
+        voxelizer.fill = Fill::Surface;
+    }
+    let mut mesh = mesh::load(positional(args, 0, "mesh")?)?;
+    if args.iter().any(|arg| arg == "--z-up") {
+        mesh.z_up_to_y_up();
+    }
+    let mut world = voxelizer.voxelize(&mesh)?;
+    if args.iter().any(|arg| arg == "--optimize") {
+        world = Optimizer::default().optimize(&world)?;
+    }
+    let out = positional(args, 1, "output world file")?;
+    world_file::save(&world, out)?;
+    println!("{} triangles became {} objects, written to {}", mesh.triangles.len(), world.objects().count(), out);
+    Ok(())
+}
+
+fn export_mesh(args: &[String]) -> Result<()> {
+    let world = load_input(args)?;
+    let scale = match option(args, "--scale", "lego or a scale factor")? {
+        Some("lego") => mesh_export::LEGO_MM,
+        Some(factor) => {
+            let factor: f32 = factor.parse().with_context(|| format!("Invalid scale {}, expected lego or a number", factor))?;
+            [factor; 3]
+        }
+        None => mesh_export::CELLS,
+    };
+    let out = positional(args, 1, "output mesh file")?;
+    mesh_export::save(&world, out, scale)?;
+    println!("Exported {}", out);
+    Ok(())
+}
+
+fn export_gltf(args: &[String]) -> Result<()> {
+    let world = load_input(args)?;
+    let scale = match option(args, "--scale", "lego or metres per cell")? {
+        Some("lego") | None => gltf::LEGO_METRES,
+        Some(metres) => {
+            let metres: f32 = metres.parse().with_context(|| format!("Invalid scale {}, expected lego or metres per cell", metres))?;
+            [metres; 3]
+        }
+    };
+    let mode = if args.iter().any(|arg| arg == "--instanced") { GltfMode::Instanced } else { GltfMode::Merged };
+    let out = positional(args, 1, "output glb file")?;
+    gltf::save(&world, out, mode, scale)?;
+    println!("Exported {}", out);
+    Ok(())
+}
+
+fn mapping_option(args: &[String]) -> Result<BlockMapping> {
+    match option(args, "--mapping", "a mapping csv file name")? {
+        Some(path) => BlockMapping::load(path),
+        None => Ok(BlockMapping::default()),
+    }
+}
+
+fn is_world_file(path: &str) -> bool {
+    path.to_lowercase().ends_with(".bkw")
+}
+
+fn convert_schematic(args: &[String]) -> Result<()> {
+    let mapping = mapping_option(args)?;
+    let input = positional(args, 0, "input file")?;
+    let out = positional(args, 1, "output file")?;
+    if is_world_file(input) {
+        let world = load_input(args)?;
+        let approximated = schematic::save(&world, out, &mapping)?;
+        for (what, count) in approximated {
+            println!("{} x{}", what, count);
+        }
+        println!("Exported {}", out);
+        return Ok(());
+    }
+    ensure!(is_world_file(out), "Either the input or the output must be a .bkw world file");
+    let imported = schematic::load(input, &mapping)?;
+    if !imported.unknown.is_empty() {
+        println!("{} block states are not in the mapping and were left out:", imported.unknown.len());
+        print!("{}", imported.unknown_report());
+    }
+    let mut world = imported.world;
+    if args.iter().any(|arg| arg == "--optimize") {
+        world = Optimizer::default().optimize(&world)?;
+    }
+    world_file::save(&world, out)?;
+    println!("Imported {} objects to {}", world.objects().count(), out);
+    Ok(())
+}
+
+fn convert_voxels(args: &[String]) -> Result<()> {
+    let input = positional(args, 0, "input file")?;
+    let out = positional(args, 1, "output file")?;
+    if is_world_file(input) {
+        voxel_grid::save(&load_input(args)?, out)?;
+        println!("Exported {}", out);
+        return Ok(());
+    }
+    ensure!(is_world_file(out), "Either the input or the output must be a .bkw world file");
+    let mut world = voxel_grid::load(input)?;
+    if args.iter().any(|arg| arg == "--optimize") {
+        world = Optimizer::default().optimize(&world)?;
+    }
+    world_file::save(&world, out)?;
+    println!("Imported {} objects to {}", world.objects().count(), out);
+    Ok(())
+}
+
+fn convert_scene(args: &[String]) -> Result<()> {
+    let input = positional(args, 0, "input file")?;
+    let out = positional(args, 1, "output file")?;
+    if is_world_file(input) {
+        scene::save(&load_input(args)?, None, out)?;
+        println!("Exported {}", out);
+        return Ok(());
+    }
+    ensure!(is_world_file(out), "Either the input or the output must be a .bkw world file");
+    ensure!(SceneFormat::of(input).is_some(), "Unknown scene format {:?}, expected .json or .ron", input);
+    let mut world = scene::load(input)?.world;
+    if args.iter().any(|arg| arg == "--optimize") {
+        world = Optimizer::default().optimize(&world)?;
+    }
+    world_file::save(&world, out)?;
+    println!("Imported {} objects to {}", world.objects().count(), out);
+    Ok(())
+}
+
+fn show_diff(args: &[String]) -> Result<()> {
+    let before = world_file::load(positional(args, 0, "world file before")?)?;
+    let after = world_file::load(positional(args, 1, "world file after")?)?;
+    let diff = WorldDiff::between(&before, &after);
+    print!("{}", diff.to_text(&before, &after));
+    println!("{}", diff.summary());
+    Ok(())
+}
+
+fn merge_worlds(args: &[String]) -> Result<()> {
+    let base = world_file::load(positional(args, 0, "base world file")?)?;
+    let ours = world_file::load(positional(args, 1, "our world file")?)?;
+    let theirs = world_file::load(positional(args, 2, "their world file")?)?;
+    let out = positional(args, 3, "output world file")?;
+    let merge = diff::merge(&base, &ours, &theirs)?;
+    world_file::save(&merge.world, out)?;
+    println!("Applied {} of their changes, written to {}", merge.applied, out);
+    if !merge.conflicts.is_empty() {
+        println!("{} conflicting cells kept our version:", merge.conflicts.len());
+        for [x, y, z] in &merge.conflicts {
+            println!("{} {} {}", x, y, z);
+        }
+    }
+    Ok(())
+}
+
+fn find_snapshot(history: &History, name: &str) -> Result<usize> {
+    history.find(name).with_context(|| format!("No snapshot {:?}, see the history command", name))
+}
+
+fn show_history(args: &[String]) -> Result<()> {
+    let (_, history) = world_file::load_with_history(positional(args, 0, "world file")?)?;
+    if history.is_empty() {
+        println!("No snapshots yet");
+    }
+    print!("{}", history.to_text());
+    Ok(())
+}
+
+fn take_snapshot(args: &[String]) -> Result<()> {
+    let path = positional(args, 0, "world file")?;
+    let name = positional(args, 1, "snapshot name")?;
+    let (world, mut history) = world_file::load_with_history(path)?;
+    let index = history.snapshot(&world, name)?;
+    world_file::save_with_history(&world, &history, path)?;
+    println!("Snapshot {} {}", index, name);
+    Ok(())
+}
+
+fn restore_snapshot(args: &[String]) -> Result<()> {
+    let path = positional(args, 0, "world file")?;
+    let (world, mut history) = world_file::load_with_history(path)?;
+    let index = find_snapshot(&history, positional(args, 1, "snapshot")?)?;
+    if let Some(kept) = history.snapshot_if_changed(&world, "before restoring")? {
+        println!("Kept the changes since the last snapshot as snapshot {}", kept);
+    }
+    let restored = history.restore(index, world.chunk_size())?;
+    world_file::save_with_history(&restored, &history, path)?;
+    println!("Restored snapshot {} {}", index, history.snapshots[index].name);
+    Ok(())
+}
+
+fn branch_snapshot(args: &[String]) -> Result<()> {
+    let (world, history) = world_file::load_with_history(positional(args, 0, "world file")?)?;
+    let index = find_snapshot(&history, positional(args, 1, "snapshot")?)?;
+    let out = positional(args, 2, "output world file")?;
+    //Only the snapshots leading to this one come along, renumbered
+    let lineage = history.lineage(index);
+    let mut branch = History::default();
+    for (number, &step) in lineage.iter().enumerate() {
+        let mut snapshot = history.snapshots[step].clone();
+        snapshot.parent = number.checked_sub(1);
+        branch.snapshots.push(snapshot);
+    }
+    branch.current = Some(lineage.len() - 1);
+    let restored = history.world(index, world.chunk_size())?;
+    world_file::save_with_history(&restored, &branch, out)?;
+    println!("Branched from snapshot {} {} into {}", index, history.snapshots[index].name, out);
+    Ok(())
+}
+
+#[cfg(feature = "render")]
+fn write_timelapse(args: &[String]) -> Result<()> {
+    let (world, history) = world_file::load_with_history(positional(args, 0, "world file")?)?;
+    let last = match option(args, "--snapshot", "a snapshot number or name")? {
+        Some(name) => find_snapshot(&history, name)?,
+        None => history.current.context("The world has no snapshots to replay")?,
+    };
+    let dir = positional(args, 1, "output folder")?;
+    let size = size_option(args)?;
+    let (device, queue) = render::headless_device()?;
+    let frames = timelapse::write_timelapse(&device, &queue, &history, last, world.chunk_size(), dir.as_ref(), size)?;
+    println!("{} frames written to {}", frames, dir);
+    Ok(())
+}
+
+fn recover(args: &[String]) -> Result<()> {
+    let path = positional(args, 0, "world file")?;
+    if recovery::pending(path).is_none() {
+        bail!("No unsaved changes to {} were left behind", path);
+    }
+    let (world, history, replayed) = recovery::recover(path)?;
+    let out = positional(args, 1, "output world file").unwrap_or(path);
+    world_file::save_with_history(&world, &history, out)?;
+    if out == path {
+        Recovery::new(path).clear()?;
+    }
+    println!("Recovered {} objects, {} edits replayed from the journal, written to {}", world.objects().count(), replayed, out);
+    Ok(())
+}
+
+fn write_mapping(args: &[String]) -> Result<()> {
+    let out = positional(args, 0, "output csv file")?;
+    std::fs::write(out, BlockMapping::default().to_csv()).with_context(|| format!("Cannot write {}", out))?;
+    println!("Exported {}", out);
+    Ok(())
+}
+
+/// World file, inventory and world to show a diff against to start the editor with, all optional.
+pub fn editor_args(args: &[String]) -> Result<(Option<&str>, Option<&str>, Option<&str>)> {
+    let rest = &args[1..];
+    Ok((positional(rest, 0, "world file").ok(), option(rest, "--inventory", "an inventory file name")?, option(rest, "--diff", "a world file name")?))
+}
+
+/// Runs a headless subcommand if `args` names one. `None` means start the editor.
+/// Without the `render` feature the commands that render pictures fail.
+pub fn run(args: &[String]) -> Option<Result<()>> {
+    let command = args.get(1)?;
+    let rest = &args[2..];
+    match command.as_str() {
+        "bom" => Some(bom(rest)),
+        "optimize" => Some(optimize(rest)),
+        "ldraw" => Some(export_ldraw(rest)),
+        "wanted" => Some(wanted(rest)),
+        "missing" => Some(missing(rest)),
+        #[cfg(feature = "render")]
+        "instructions" => Some(export_instructions(rest)),
+        "plan" => Some(export_plans(rest)),
+        "mosaic" => Some(make_mosaic(rest)),
+        "terrain" => Some(make_terrain(rest)),
+        "voxelize" => Some(voxelize(rest)),
+        "mesh" => Some(export_mesh(rest)),
+        "gltf" => Some(export_gltf(rest)),
+        "schematic" => Some(convert_schematic(rest)),
+        "mapping" => Some(write_mapping(rest)),
+        "voxels" => Some(convert_voxels(rest)),
+        "scene" => Some(convert_scene(rest)),
+        "diff" => Some(show_diff(rest)),
+        "merge" => Some(merge_worlds(rest)),
+        "history" => Some(show_history(rest)),
+        "snapshot" => Some(take_snapshot(rest)),
+        "restore" => Some(restore_snapshot(rest)),
+        "branch" => Some(branch_snapshot(rest)),
+        #[cfg(feature = "render")]
+        "timelapse" => Some(write_timelapse(rest)),
+        #[cfg(not(feature = "render"))]
+        "instructions" | "timelapse" => Some(Err(anyhow!("{} renders pictures, which needs the render feature", command))),
+        "recover" => Some(recover(rest)),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Some(Ok(()))
+        }
+        _ => None,
+    }
+}
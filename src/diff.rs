@@ -0,0 +1,377 @@
+use anyhow::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::brick::{Brick, BrickShape};
+use crate::palette::{ColorId, Palette};
+use crate::world::{Block, World};
+
+/// What happened to a cell between two worlds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    //Same shape and orientation in another colour
+    Recolored,
+    //Another shape or orientation
+    Replaced,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellChange {
+    pub position: [i64; 3],
+    //Colours index the palette of the world each block comes from
+    pub before: Option<Block>,
+    pub after: Option<Block>,
+}
+
+impl CellChange {
+    pub fn kind(&self) -> ChangeKind {
+        match (self.before, self.after) {
+            (None, _) => ChangeKind::Added,
+            (_, None) => ChangeKind::Removed,
+            (Some(before), Some(after)) if before.blocktype == after.blocktype && before.orientation == after.orientation => {
+                ChangeKind::Recolored
+            }
+            _ => ChangeKind::Replaced,
+        }
+    }
+}
+
+//Whether two blocks from different worlds look the same. Colours are compared by
+//palette entry, since the same colour can have another id in each world, and which
+//brick covers a cell does not matter.
+fn same_block(a: &Block, a_palette: &Palette, b: &Block, b_palette: &Palette) -> bool {
+    a.blocktype == b.blocktype && a.orientation == b.orientation && a_palette.get(a.color) == b_palette.get(b.color)
+}
+
+//Every cell that differs, by position
+fn cell_changes(before: &World, after: &World) -> BTreeMap<[i64; 3], CellChange> {
+    let mut changes = BTreeMap::new();
+    for (position, block) in before.iter_blocks() {
+        let other = after.get_block(position[0], position[1], position[2]);
+        if other.is_none_or(|other| !same_block(block, &before.palette, other, &after.palette)) {
+            changes.insert(position, CellChange { position, before: Some(*block), after: other.copied() });
+        }
+    }
+    for (position, block) in after.iter_blocks() {
+        if before.get_block(position[0], position[1], position[2]).is_none() {
+            changes.insert(position, CellChange { position, before: None, after: Some(*block) });
+        }
+    }
+    changes
+}
+
+/// The cells that differ between two worlds, in position order.
+#[derive(Debug, Clone, Default)]
+pub struct WorldDiff {
+    pub changes: Vec<CellChange>,
+}
+
+impl WorldDiff {
+    pub fn between(before: &World, after: &World) -> WorldDiff {
+        WorldDiff {
+            changes: cell_changes(before, after).into_values().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn count(&self, kind: ChangeKind) -> usize {
+        self.changes.iter().filter(|change| change.kind() == kind).count()
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} added, {} removed, {} recoloured, {} replaced",
+            self.count(ChangeKind::Added),
+            self.count(ChangeKind::Removed),
+            self.count(ChangeKind::Recolored),
+            self.count(ChangeKind::Replaced),
+        )
+    }
+
+    /// One line per cell: `+` added, `-` removed, `~` recoloured and `*` replaced, with the
+    /// block before and after.
+    pub fn to_text(&self, before: &World, after: &World) -> String {
+        let describe = |block: &Option<Block>, palette: &Palette| match block {
+            Some(block) => format!(
+                "{:?} {} {}",
+                block.blocktype,
+                block.orientation.index(),
+                palette.get(block.color).map_or("?", |color| color.name.as_str())
+            ),
+            None => "empty".to_string(),
+        };
+        let mut text = String::new();
+        for change in &self.changes {
+            let sign = match change.kind() {
+                ChangeKind::Added => '+',
+                ChangeKind::Removed => '-',
+                ChangeKind::Recolored => '~',
+                ChangeKind::Replaced => '*',
+            };
+            let [x, y, z] = change.position;
+            text += &format!(
+                "{} {} {} {}: {} -> {}\n",
+                sign,
+                x,
+                y,
+                z,
+                describe(&change.before, &before.palette),
+                describe(&change.after, &after.palette)
+            );
+        }
+        text
+    }
+
+    /// A world to look at the diff in: `after` with the removed blocks put back as ghosts,
+    /// and how to shade each object, by its origin as `Model::shades` wants it. Objects
+    /// that were only partly changed count as changed.
+    pub fn overlay(&self, before: &World, after: &World) -> Result<(World, HashMap<[i64; 3], ChangeKind>)> {
+        let by_position: HashMap<[i64; 3], ChangeKind> =
+            self.changes.iter().map(|change| (change.position, change.kind())).collect();
+        let mut world = World::from_objects(after.chunk_size(), after.palette.clone(), after.objects().map(|(_, object)| object))?;
+        let mut shades = HashMap::new();
+        for (_, object) in after.objects() {
+            let kinds: Vec<ChangeKind> = object.cells().filter_map(|cell| by_position.get(&cell).copied()).collect();
+            if kinds.is_empty() {
+                continue;
+            }
+            let kind = if kinds.len() == object.cells().count() && kinds.iter().all(|&kind| kind == ChangeKind::Added) {
+                ChangeKind::Added
+            } else {
+                ChangeKind::Replaced
+            };
+            shades.insert(object.origin, kind);
+        }
+
+        //Removed objects come back whole where they can, otherwise cell by cell. Ghosts
+        //are drawn in a colour of their own, so the palette id does not matter.
+        let removed: BTreeSet<[i64; 3]> =
+            self.changes.iter().filter(|change| change.kind() == ChangeKind::Removed).map(|change| change.position).collect();
+        let mut placed = BTreeSet::new();
+        for (_, object) in before.objects() {
+            if !object.cells().all(|cell| removed.contains(&cell)) {
+                continue;
+            }
+            let ghost = Brick { color: 0, ..object };
+            if world.place_brick(ghost).is_ok() {
+                shades.insert(ghost.origin, ChangeKind::Removed);
+                placed.extend(object.cells());
+            }
+        }
+        for &cell in removed.difference(&placed) {
+            let block = match before.get_block(cell[0], cell[1], cell[2]) {
+                Some(block) => block,
+                None => continue,
+            };
+            let ghost = Brick {
+                shape: BrickShape::new(block.blocktype, 1, 1),
+                origin: cell,
+                orientation: block.orientation,
+                color: 0,
+            };
+            if world.place_brick(ghost).is_ok() {
+                shades.insert(cell, ChangeKind::Removed);
+            }
+        }
+        Ok((world, shades))
+    }
+}
+
+/// Where a colour of `from` is in `to`, adding it if `to` lacks it.
+fn color_into(from: &Palette, color: ColorId, to: &mut Palette) -> Result<ColorId> {
+    let entry = from.get(color).with_context(|| format!("Colour {} is not in the palette", color))?;
+    if let Some((id, _)) = to.iter().find(|(_, other)| *other == entry) {
+        return Ok(id);
+    }
+    match entry.ldraw_id {
+        Some(ldraw_id) => to.add_ldraw(&entry.name, entry.rgb, ldraw_id),
+        None => to.add(&entry.name, entry.rgb),
+    }
+}
+
+/// The outcome of a three-way merge.
+pub struct Merge {
+    pub world: World,
+    //Cells both sides changed differently, or that a change of theirs could not be
+    //applied to. These keep our version.
+    pub conflicts: Vec<[i64; 3]>,
+    //Objects of theirs that were removed or placed
+    pub applied: usize,
+}
+
+fn object_key(object: &Brick) -> (u8, u8, u8, [i64; 3], u8, ColorId) {
+    (
+        object.shape.blocktype.index(),
+        object.shape.width,
+        object.shape.depth,
+        object.origin,
+        object.orientation.index(),
+        object.color,
+    )
+}
+
+/// Merges the edits `theirs` made to `base` into `ours`. Edits are applied a whole object
+/// at a time, so a brick is never cut apart; any that touch a conflicting cell are left out.
+pub fn merge(base: &World, ours: &World, theirs: &World) -> Result<Merge> {
+    let ours_changes = cell_changes(base, ours);
+    let theirs_changes = cell_changes(base, theirs);
+    let mut conflicts: BTreeSet<[i64; 3]> = BTreeSet::new();
+    for (position, mine) in &ours_changes {
+        if let Some(other) = theirs_changes.get(position) {
+            let same = match (mine.after, other.after) {
+                (None, None) => true,
+                (Some(a), Some(b)) => same_block(&a, &ours.palette, &b, &theirs.palette),
+                _ => false,
+            };
+            if !same {
+                conflicts.insert(*position);
+            }
+        }
+    }
+
+    let mut world = World::from_objects(ours.chunk_size(), ours.palette.clone(), ours.objects().map(|(_, object)| object))?;
+    //Compare objects with their colours in the merged palette
+    let in_merged = |world: &mut World, from: &Palette, object: Brick| -> Result<Brick> {
+        Ok(Brick { color: color_into(from, object.color, &mut world.palette)?, ..object })
+    };
+    let mut base_objects = BTreeMap::new();
+    for (_, object) in base.objects() {
+        let object = in_merged(&mut world, &base.palette, object)?;
+        base_objects.insert(object_key(&object), object);
+    }
+    let mut theirs_objects = BTreeMap::new();
+    for (_, object) in theirs.objects() {
+        let object = in_merged(&mut world, &theirs.palette, object)?;
+        theirs_objects.insert(object_key(&object), object);
+    }
+
+    let touches_conflict = |object: &Brick, conflicts: &BTreeSet<[i64; 3]>| object.cells().any(|cell| conflicts.contains(&cell));
+    let mut applied = 0;
+    for (key, object) in &base_objects {
+        if theirs_objects.contains_key(key) || touches_conflict(object, &conflicts) {
+            continue;
+        }
+        //Only if we still have it as it was
+        let [x, y, z] = object.origin;
+        if world.object_at(x, y, z).as_ref().map(object_key) == Some(*key) {
+            world.remove_at(x, y, z);
+            applied += 1;
+        }
+    }
+    for (key, object) in &theirs_objects {
+        if base_objects.contains_key(key) || touches_conflict(object, &conflicts) {
+            continue;
+        }
+        let [x, y, z] = object.origin;
+        if world.object_at(x, y, z).as_ref().map(object_key) == Some(*key) {
+            //We made the same edit
+            continue;
+        }
+        if world.place_brick(*object).is_ok() {
+            applied += 1;
+        } else {
+            conflicts.extend(object.cells().filter(|cell| world.get_block(cell[0], cell[1], cell[2]).is_some()));
+        }
+    }
+    Ok(Merge {
+        world,
+        conflicts: conflicts.into_iter().collect(),
+        applied,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::BlockType;
+
+    fn unit(world: &mut World, x: i64, color: ColorId) {
+        let block = Block { blocktype: BlockType::NORMAL, color, orientation: Default::default(), brick: None };
+        world.set_block(x, 0, -20, Some(block));
+    }
+
+    fn brick(origin: [i64; 3], width: u8, depth: u8, color: ColorId) -> Brick {
+        Brick {
+            shape: BrickShape::new(BlockType::BRICK, width, depth),
+            origin,
+            orientation: Default::default(),
+            color,
+        }
+    }
+
+    fn base() -> World {
+        let mut world = World::default();
+        for x in -20..-10 {
+            unit(&mut world, x, 1);
+        }
+        world.place_brick(brick([0, 0, 0], 2, 4, 4)).unwrap();
+        world
+    }
+
+    //Theirs adds a colour, recolours a unit block, places a brick and removes the 2x4
+    fn theirs() -> World {
+        let mut world = base();
+        let orange = world.palette.add("Orange", [1.0, 0.5, 0.0]).unwrap();
+        unit(&mut world, -20, orange);
+        unit(&mut world, -19, 3);
+        world.place_brick(brick([5, 1, 5], 1, 2, orange)).unwrap();
+        let id = world.brick_at(0, 0, 0).unwrap().0;
+        world.remove_brick(id);
+        world
+    }
+
+    #[test]
+    fn diff_counts_changes() {
+        let (base, theirs) = (base(), theirs());
+        assert!(WorldDiff::between(&base, &self::base()).is_empty());
+        let diff = WorldDiff::between(&base, &theirs);
+        assert_eq!(diff.count(ChangeKind::Removed), 8);
+        assert_eq!(diff.count(ChangeKind::Added), 2);
+        assert_eq!(diff.count(ChangeKind::Recolored), 2);
+        assert_eq!(diff.count(ChangeKind::Replaced), 0);
+
+        let (overlay, shades) = diff.overlay(&base, &theirs).unwrap();
+        assert_eq!(shades.get(&[0, 0, 0]), Some(&ChangeKind::Removed));
+        assert_eq!(shades.get(&[5, 1, 5]), Some(&ChangeKind::Added));
+        assert_eq!(overlay.iter_blocks().count(), theirs.iter_blocks().count() + 8);
+    }
+
+    #[test]
+    fn diff_compares_colours_not_palette_slots() {
+        let base = base();
+        let mut reordered = self::base();
+        let white = base.palette.get(1).unwrap();
+        let copy = reordered.palette.add(&white.name, white.rgb).unwrap();
+        unit(&mut reordered, -20, copy);
+        assert!(WorldDiff::between(&base, &reordered).is_empty());
+    }
+
+    #[test]
+    fn merge_applies_theirs_and_keeps_ours_on_conflict() {
+        let base = base();
+        let mut ours = self::base();
+        unit(&mut ours, -19, 2);
+        let slope = Brick { shape: BrickShape::new(BlockType::SLOPE, 2, 2), ..brick([-5, 3, -5], 2, 2, 5) };
+        ours.place_brick(slope).unwrap();
+
+        let merged = merge(&base, &ours, &theirs()).unwrap();
+        assert_eq!(merged.conflicts, vec![[-19, 0, -20]]);
+        let world = &merged.world;
+        assert!(world.get_block(0, 0, 0).is_none());
+        assert!(world.get_block(5, 1, 5).is_some());
+        assert!(world.get_block(-5, 3, -5).is_some());
+        let color = world.get_block(-20, 0, -20).unwrap().color;
+        assert_eq!(world.palette.get(color).unwrap().name, "Orange");
+        assert_eq!(world.get_block(-19, 0, -20).unwrap().color, 2);
+
+        //Their edits are already ours
+        let theirs = theirs();
+        let merged = merge(&base, &theirs, &theirs).unwrap();
+        assert!(merged.conflicts.is_empty());
+        assert_eq!(merged.applied, 0);
+    }
+}
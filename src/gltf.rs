@@ -0,0 +1,397 @@
+use anyhow::*;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::bom::{self, CELL_HEIGHT_MM, CELL_WIDTH_MM};
+use crate::brick::Brick;
+use crate::mesh_export::{self, WorldMesh};
+use crate::palette::ColorId;
+use crate::shape::{self, Face};
+use crate::world::{BlockType, World};
+
+/// glTF works in metres, so real brick sizes are 8 mm by 9.6 mm.
+pub const LEGO_METRES: [f32; 3] = [CELL_WIDTH_MM / 1000.0, CELL_HEIGHT_MM / 1000.0, CELL_WIDTH_MM / 1000.0];
+
+/// How the world is laid out in the file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GltfMode {
+    //One mesh per chunk with the hidden faces left out and flat sides merged
+    Merged,
+    //One mesh per part of each shape's surface and colour, placed once per object with
+    //EXT_mesh_gpu_instancing, like the editor draws them. Small files for big worlds.
+    Instanced,
+}
+
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+
+//Collects the binary chunk and the views and accessors pointing into it
+#[derive(Default)]
+struct Buffers {
+    data: Vec<u8>,
+    views: Vec<Value>,
+    accessors: Vec<Value>,
+}
+
+impl Buffers {
+    fn view(&mut self, bytes: &[u8], target: Option<u32>) -> usize {
+        while !self.data.len().is_multiple_of(4) {
+            self.data.push(0);
+        }
+        let mut view = json!({
+            "buffer": 0,
+            "byteOffset": self.data.len(),
+            "byteLength": bytes.len(),
+        });
+        if let Some(target) = target {
+            view["target"] = json!(target);
+        }
+        self.data.extend_from_slice(bytes);
+        self.views.push(view);
+        self.views.len() - 1
+    }
+
+    //Vectors of floats. Positions need their bounds in the accessor.
+    fn floats(&mut self, values: &[[f32; 3]], with_bounds: bool) -> usize {
+        let bytes: Vec<u8> = values.iter().flatten().flat_map(|value| value.to_le_bytes().to_vec()).collect();
+        let view = self.view(&bytes, None);
+        let mut accessor = json!({
+            "bufferView": view,
+            "componentType": FLOAT,
+            "count": values.len(),
+            "type": "VEC3",
+        });
+        if with_bounds {
+            let mut min = [f32::MAX; 3];
+            let mut max = [f32::MIN; 3];
+            for value in values {
+                for axis in 0..3 {
+                    min[axis] = min[axis].min(value[axis]);
+                    max[axis] = max[axis].max(value[axis]);
+                }
+            }
+            accessor["min"] = json!(min);
+            accessor["max"] = json!(max);
+        }
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+
+    fn rotations(&mut self, values: &[[f32; 4]]) -> usize {
+        let bytes: Vec<u8> = values.iter().flatten().flat_map(|value| value.to_le_bytes().to_vec()).collect();
+        let view = self.view(&bytes, None);
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": FLOAT,
+            "count": values.len(),
+            "type": "VEC4",
+        }));
+        self.accessors.len() - 1
+    }
+
+    fn indices(&mut self, values: &[u32]) -> usize {
+        let bytes: Vec<u8> = values.iter().flat_map(|value| value.to_le_bytes().to_vec()).collect();
+        let view = self.view(&bytes, Some(ELEMENT_ARRAY_BUFFER));
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": UNSIGNED_INT,
+            "count": values.len(),
+            "type": "SCALAR",
+        }));
+        self.accessors.len() - 1
+    }
+
+    fn vertices(&mut self, positions: &[[f32; 3]]) -> usize {
+        let accessor = self.floats(positions, true);
+        let view = self.accessors[accessor]["bufferView"].as_u64().unwrap() as usize;
+        self.views[view]["target"] = json!(ARRAY_BUFFER);
+        accessor
+    }
+}
+
+//A PBR material per palette colour. Brick plastic is not metal and fairly glossy.
+fn materials(world: &World) -> Vec<Value> {
+    world
+        .palette
+        .iter()
+        .map(|(id, color)| {
+            json!({
+                "name": mesh_export::material_name(&world.palette, id),
+                "pbrMetallicRoughness": {
+                    "baseColorFactor": [color.rgb[0], color.rgb[1], color.rgb[2], 1.0],
+                    "metallicFactor": 0.0,
+                    "roughnessFactor": 0.35,
+                },
+            })
+        })
+        .collect()
+}
+
+//Primitives of a welded mesh, one per colour, each with its own vertices
+fn primitives(buffers: &mut Buffers, mesh: &WorldMesh) -> Vec<Value> {
+    let mut by_color: BTreeMap<ColorId, Vec<[u32; 3]>> = BTreeMap::new();
+    for (indices, color) in &mesh.triangles {
+        by_color.entry(*color).or_default().push(*indices);
+    }
+    let mut primitives = Vec::new();
+    for (color, triangles) in by_color {
+        let mut local: BTreeMap<u32, u32> = BTreeMap::new();
+        let mut positions = Vec::new();
+        let mut indices = Vec::new();
+        for index in triangles.iter().flatten() {
+            let next = local.len() as u32;
+            let local_index = *local.entry(*index).or_insert_with(|| {
+                positions.push(mesh.positions[*index as usize]);
+                next
+            });
+            indices.push(local_index);
+        }
+        primitives.push(json!({
+            "attributes": { "POSITION": buffers.vertices(&positions) },
+            "indices": buffers.indices(&indices),
+            "material": color,
+        }));
+    }
+    primitives
+}
+
+fn merged(world: &World, buffers: &mut Buffers, meshes: &mut Vec<Value>, nodes: &mut Vec<Value>) {
+    let mut chunks: BTreeMap<[i64; 3], Vec<Brick>> = BTreeMap::new();
+    for (_, object) in world.objects() {
+        let (chunk, _) = world.split_address(object.origin[0], object.origin[1], object.origin[2]);
+        chunks.entry(chunk).or_default().push(object);
+    }
+    for (chunk, objects) in chunks {
+        let mesh = WorldMesh::from_objects(world, objects.into_iter(), mesh_export::CELLS, true);
+        if mesh.triangles.is_empty() {
+            continue;
+        }
+        let name = format!("chunk {} {} {}", chunk[0], chunk[1], chunk[2]);
+        meshes.push(json!({ "name": name, "primitives": primitives(buffers, &mesh) }));
+        nodes.push(json!({ "name": name, "mesh": meshes.len() - 1 }));
+    }
+}
+
+fn instanced(world: &World, buffers: &mut Buffers, meshes: &mut Vec<Value>, nodes: &mut Vec<Value>) {
+    for &blocktype in BlockType::ALL.iter() {
+        let geometry = shape::geometry(blocktype);
+        for (number, group) in geometry.groups.iter().enumerate() {
+            //Same culling as Model::build_meshes, per object and part of the surface
+            let mut by_color: BTreeMap<ColorId, Vec<Brick>> = BTreeMap::new();
+            for (_, object) in world.objects() {
+                if object.shape.blocktype != blocktype {
+                    continue;
+                }
+                let hidden = group.cull.is_some_and(|local| {
+                    let face = Face::from_normal(object.orientation.rotate(local.normal())).unwrap();
+                    shape::face_hidden(world, &object, face)
+                });
+                if !hidden {
+                    by_color.entry(object.color).or_default().push(object);
+                }
+            }
+            if by_color.is_empty() {
+                continue;
+            }
+            let positions: Vec<[f32; 3]> = group.triangles.iter().flatten().copied().collect();
+            let position_accessor = buffers.vertices(&positions);
+            for (color, objects) in by_color {
+                let name = format!("{:?} {} {}", blocktype, number, mesh_export::material_name(&world.palette, color));
+                meshes.push(json!({
+                    "name": name,
+                    "primitives": [{ "attributes": { "POSITION": position_accessor }, "material": color }],
+                }));
+                let translations: Vec<[f32; 3]> = objects.iter().map(|object| object.position()).collect();
+                let rotations: Vec<[f32; 4]> = objects
+                    .iter()
+                    .map(|object| {
+                        let q = object.orientation.quaternion();
+                        [q.v.x, q.v.y, q.v.z, q.s]
+                    })
+                    .collect();
+                let scales: Vec<[f32; 3]> = objects.iter().map(|object| object.shape.size()).collect();
+                nodes.push(json!({
+                    "name": name,
+                    "mesh": meshes.len() - 1,
+                    "extensions": {
+                        "EXT_mesh_gpu_instancing": {
+                            "attributes": {
+                                "TRANSLATION": buffers.floats(&translations, false),
+                                "ROTATION": buffers.rotations(&rotations),
+                                "SCALE": buffers.floats(&scales, false),
+                            }
+                        }
+                    },
+                }));
+            }
+        }
+    }
+}
+
+/// The world as binary glTF. `scale` is the size of a cell in metres, see `LEGO_METRES`.
+pub fn to_glb(world: &World, mode: GltfMode, scale: [f32; 3]) -> Result<Vec<u8>> {
+    //A scene without meshes or buffers is not valid glTF
+    ensure!(bom::bounds(world).is_some(), "The world is empty");
+    let mut buffers = Buffers::default();
+    let mut meshes = Vec::new();
+    let mut nodes = Vec::new();
+    match mode {
+        GltfMode::Merged => merged(world, &mut buffers, &mut meshes, &mut nodes),
+        GltfMode::Instanced => instanced(world, &mut buffers, &mut meshes, &mut nodes),
+    }
+    while !buffers.data.len().is_multiple_of(4) {
+        buffers.data.push(0);
+    }
+
+    //Everything hangs off one node carrying the scale, which leaves the parts' own
+    //transforms in cells
+    let children: Vec<usize> = (1..=nodes.len()).collect();
+    let mut all_nodes = vec![json!({ "name": "world", "scale": scale, "children": children })];
+    all_nodes.extend(nodes);
+    let mut document = json!({
+        "asset": { "version": "2.0", "generator": "byggeklosser" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": all_nodes,
+        "meshes": meshes,
+        "materials": materials(world),
+        "accessors": buffers.accessors,
+        "bufferViews": buffers.views,
+        "buffers": [{ "byteLength": buffers.data.len() }],
+    });
+    if mode == GltfMode::Instanced {
+        //Without it a viewer would show every part once, at the world origin
+        document["extensionsUsed"] = json!(["EXT_mesh_gpu_instancing"]);
+        document["extensionsRequired"] = json!(["EXT_mesh_gpu_instancing"]);
+    }
+
+    let mut text = serde_json::to_vec(&document)?;
+    while !text.len().is_multiple_of(4) {
+        text.push(b' ');
+    }
+    let length = 12 + 8 + text.len() + 8 + buffers.data.len();
+    let mut glb = Vec::with_capacity(length);
+    glb.extend_from_slice(b"glTF");
+    glb.extend_from_slice(&2u32.to_le_bytes());
+    glb.extend_from_slice(&(length as u32).to_le_bytes());
+    glb.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    glb.extend_from_slice(b"JSON");
+    glb.extend_from_slice(&text);
+    glb.extend_from_slice(&(buffers.data.len() as u32).to_le_bytes());
+    glb.extend_from_slice(b"BIN\0");
+    glb.extend_from_slice(&buffers.data);
+    Ok(glb)
+}
+
+pub fn save<P: AsRef<Path>>(world: &World, path: P, mode: GltfMode, scale: [f32; 3]) -> Result<()> {
+    let path = path.as_ref();
+    std::fs::write(path, to_glb(world, mode, scale)?).with_context(|| format!("Cannot write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brick::BrickShape;
+    use crate::orientation::Orientation;
+    use crate::world::Block;
+
+    fn world() -> World {
+        let mut world = World::default();
+        for x in 0..20 {
+            for z in 0..2 {
+                let block = Block { blocktype: BlockType::NORMAL, color: 1, orientation: Default::default(), brick: None };
+                world.set_block(x, 0, z, Some(block));
+            }
+        }
+        let turned = Orientation::new(1).unwrap();
+        let shape = BrickShape::new(BlockType::BRICK, 2, 4);
+        world.place_brick(Brick { shape, origin: [0, 1, 0], orientation: turned, color: 4 }).unwrap();
+        let shape = BrickShape::new(BlockType::SLOPE, 2, 2);
+        world.place_brick(Brick { shape, origin: [4, 1, 0], orientation: Default::default(), color: 4 }).unwrap();
+        world
+    }
+
+    fn word(bytes: &[u8], at: usize) -> usize {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]) as usize
+    }
+
+    //The JSON document and the binary chunk
+    fn chunks(glb: &[u8]) -> (Value, &[u8]) {
+        assert_eq!(&glb[..4], b"glTF");
+        assert_eq!(word(glb, 4), 2);
+        assert_eq!(word(glb, 8), glb.len());
+        let text = word(glb, 12);
+        assert_eq!(&glb[16..20], b"JSON");
+        let binary = 20 + text;
+        assert_eq!(&glb[binary + 4..binary + 8], b"BIN\0");
+        assert_eq!(binary + 8 + word(glb, binary), glb.len());
+        assert!(text.is_multiple_of(4) && glb.len().is_multiple_of(4));
+        (serde_json::from_slice(&glb[20..binary]).unwrap(), &glb[binary + 8..])
+    }
+
+    #[test]
+    fn views_and_accessors_fit_the_buffer() {
+        let world = world();
+        for &mode in &[GltfMode::Merged, GltfMode::Instanced] {
+            let glb = to_glb(&world, mode, LEGO_METRES).unwrap();
+            let (document, binary) = chunks(&glb);
+            assert_eq!(document["buffers"][0]["byteLength"], binary.len());
+            let views = document["bufferViews"].as_array().unwrap();
+            for view in views {
+                let offset = view["byteOffset"].as_u64().unwrap() as usize;
+                assert!(offset.is_multiple_of(4));
+                assert!(offset + view["byteLength"].as_u64().unwrap() as usize <= binary.len());
+            }
+            for accessor in document["accessors"].as_array().unwrap() {
+                let view = &views[accessor["bufferView"].as_u64().unwrap() as usize];
+                let components = if accessor["type"] == "VEC4" { 4 } else if accessor["type"] == "VEC3" { 3 } else { 1 };
+                let length = accessor["count"].as_u64().unwrap() * components * 4;
+                assert_eq!(view["byteLength"].as_u64().unwrap(), length);
+            }
+            assert_eq!(document["materials"].as_array().unwrap().len(), world.palette.iter().count());
+            //Only the root carries the scale
+            let nodes = document["nodes"].as_array().unwrap();
+            assert_eq!(nodes[0]["scale"], json!(LEGO_METRES));
+            assert_eq!(nodes[0]["children"].as_array().unwrap().len(), nodes.len() - 1);
+            assert!(nodes[1..].iter().all(|node| node.get("scale").is_none()));
+        }
+    }
+
+    #[test]
+    fn instancing_places_every_visible_part() {
+        let world = world();
+        let glb = to_glb(&world, GltfMode::Instanced, [1.0; 3]).unwrap();
+        let (document, _) = chunks(&glb);
+        assert_eq!(document["extensionsRequired"], json!(["EXT_mesh_gpu_instancing"]));
+        let accessors = document["accessors"].as_array().unwrap();
+        let nodes = document["nodes"].as_array().unwrap();
+        let mut slopes = 0;
+        for node in &nodes[1..] {
+            let attributes = &node["extensions"]["EXT_mesh_gpu_instancing"]["attributes"];
+            let count = |name: &str| accessors[attributes[name].as_u64().unwrap() as usize]["count"].clone();
+            assert_eq!(count("TRANSLATION"), count("ROTATION"));
+            assert_eq!(count("TRANSLATION"), count("SCALE"));
+            if node["name"].as_str().unwrap().starts_with("SLOPE") {
+                slopes += 1;
+                assert_eq!(count("TRANSLATION"), 1);
+            }
+        }
+        //Parts with their own surface are never all hidden
+        assert!(slopes > 0);
+
+        let glb = to_glb(&world, GltfMode::Merged, [1.0; 3]).unwrap();
+        let (document, _) = chunks(&glb);
+        assert!(document.get("extensionsRequired").is_none());
+        let meshes = document["meshes"].as_array().unwrap();
+        assert!(meshes.iter().all(|mesh| mesh["name"].as_str().unwrap().starts_with("chunk")));
+    }
+
+    #[test]
+    fn empty_worlds_are_rejected() {
+        assert!(to_glb(&World::default(), GltfMode::Merged, LEGO_METRES).is_err());
+        assert!(to_glb(&World::default(), GltfMode::Instanced, LEGO_METRES).is_err());
+    }
+}
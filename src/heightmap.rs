@@ -0,0 +1,199 @@
+use anyhow::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::lego;
+use crate::palette::{ColorId, Palette};
+use crate::quantize::{DeltaE, Quantizer};
+use crate::world::{Block, BlockType, World};
+
+/// Highest `Heightmap::scale`, in blocks. Far above any LEGO build, and small enough
+/// that a column is built in no time.
+pub const MAX_SCALE: f32 = 4096.0;
+
+/// Where the colour of a terrain column comes from.
+#[derive(Debug, Clone)]
+pub enum TerrainColors {
+    //(fraction of the full height, colour) sorted by fraction. A column takes the
+    //colour of the first band its height is below, or the last one.
+    Bands(Vec<(f32, ColorId)>),
+    //A picture of the same area, read through the palette. Resized to the heightmap if needed.
+    Image(image::RgbImage),
+}
+
+/// Builds terrain from a grayscale picture, one column of blocks per pixel. Black is
+/// the base level, white is `scale` blocks above it.
+#[derive(Debug, Clone)]
+pub struct Heightmap {
+    pub scale: f32,
+    //Height of the lowest block of every column
+    pub base: i64,
+    pub palette: Palette,
+    pub metric: DeltaE,
+    pub colors: TerrainColors,
+}
+
+impl Default for Heightmap {
+    fn default() -> Self {
+        let palette = lego::lego_basic_palette();
+        Self {
+            scale: 16.0,
+            base: 0,
+            colors: TerrainColors::Bands(default_bands(&palette)),
+            palette,
+            metric: DeltaE::Ciede2000,
+        }
+    }
+}
+
+/// Water, sand, grass, earth, rock and snow, from the colours `palette` has.
+pub fn default_bands(palette: &Palette) -> Vec<(f32, ColorId)> {
+    //LDraw codes: blue, tan, green, reddish brown, dark bluish gray, white
+    let bands = [(0.1, 1), (0.2, 19), (0.55, 2), (0.7, 70), (0.85, 72), (1.0, 15)];
+    let bands: Vec<(f32, ColorId)> = bands
+        .iter()
+        .filter_map(|&(upto, code)| palette.find_ldraw(code).map(|color| (upto, color)))
+        .collect();
+    if bands.is_empty() {
+        vec![(1.0, 0)]
+    } else {
+        bands
+    }
+}
+
+impl Heightmap {
+    /// Colour the terrain after the picture at `path` instead of by height.
+    pub fn load_colors<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let image = image::open(path).with_context(|| format!("Cannot read image {}", path.display()))?;
+        self.colors = TerrainColors::Image(image.to_rgb8());
+        Ok(())
+    }
+
+    /// Column height in blocks for a 16 bit intensity, at least one.
+    pub fn column_height(&self, intensity: u16) -> i64 {
+        1 + (intensity as f32 / u16::MAX as f32 * self.scale).round() as i64
+    }
+
+    /// A new world with the terrain. Pixel (x, y) becomes the column at x, z = y.
+    /// Chunks are created as the columns reach into them.
+    pub fn build(&self, heights: &image::DynamicImage) -> Result<World> {
+        //Also refuses NaN and infinity, which would make every column as tall as an i64 goes
+        ensure!(
+            (0.0..=MAX_SCALE).contains(&self.scale),
+            "The height scale must be between 0 and {}, not {}",
+            MAX_SCALE,
+            self.scale
+        );
+        ensure!(!self.palette.is_empty(), "Cannot build terrain from an empty palette");
+        //16 bit, so heightmaps with more than 256 levels keep them
+        let heights = heights.to_luma16();
+        let (width, depth) = heights.dimensions();
+
+        let colors = match &self.colors {
+            TerrainColors::Image(image) if image.dimensions() != (width, depth) => TerrainColors::Image(
+                image::imageops::resize(image, width, depth, image::imageops::FilterType::Triangle),
+            ),
+            colors => colors.clone(),
+        };
+        let quantizer = Quantizer::new(&self.palette, self.metric);
+        let mut nearest: HashMap<[u8; 3], ColorId> = HashMap::new();
+        let top = self.column_height(u16::MAX) as f32;
+
+        let mut world = World::default();
+        world.palette = self.palette.clone();
+        for (x, z, intensity) in heights.enumerate_pixels() {
+            let height = self.column_height(intensity[0]);
+            let color = match &colors {
+                TerrainColors::Bands(bands) => {
+                    let fraction = height as f32 / top;
+                    bands
+                        .iter()
+                        .find(|(upto, _)| fraction <= *upto)
+                        .or_else(|| bands.last())
+                        .map(|(_, color)| *color)
+                        .unwrap_or(0)
+                }
+                TerrainColors::Image(image) => {
+                    let rgb = image.get_pixel(x, z).0;
+                    *nearest.entry(rgb).or_insert_with(|| quantizer.nearest_srgb8(rgb))
+                }
+            };
+            let column_top = self.base.checked_add(height).context("The terrain reaches above the highest block")?;
+            for y in self.base..column_top {
+                world.set_block(
+                    x as i64,
+                    y,
+                    z as i64,
+                    Some(Block {
+                        blocktype: BlockType::NORMAL,
+                        color,
+                        orientation: Default::default(),
+                        brick: None,
+                    }),
+                );
+            }
+        }
+        Ok(world)
+    }
+
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<World> {
+        let path = path.as_ref();
+        let image = image::open(path).with_context(|| format!("Cannot read image {}", path.display()))?;
+        self.build(&image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Rises from black on the left to nearly white on the right
+    fn slope(width: u32, depth: u32) -> image::DynamicImage {
+        image::DynamicImage::ImageLuma8(image::GrayImage::from_fn(width, depth, |x, _| image::Luma([(x * 6) as u8])))
+    }
+
+    fn column_top(world: &World, x: i64, z: i64) -> Option<i64> {
+        (-64..64).rev().find(|&y| world.get_block(x, y, z).is_some())
+    }
+
+    #[test]
+    fn columns_follow_the_picture() {
+        let heightmap = Heightmap { base: -3, ..Heightmap::default() };
+        let world = heightmap.build(&slope(40, 20)).unwrap();
+        assert_eq!(heightmap.column_height(0), 1);
+        assert_eq!(heightmap.column_height(u16::MAX), 17);
+        for x in 0..40 {
+            let height = heightmap.column_height(x as u16 * 6 * 257);
+            for z in 0..20 {
+                assert_eq!(column_top(&world, x, z), Some(-3 + height - 1), "column {} {}", x, z);
+                assert!(world.get_block(x, -4, z).is_none());
+            }
+        }
+        //Low ground is water, high ground is snow
+        assert_eq!(world.get_block(0, -3, 0).map(|block| block.color), heightmap.palette.find_ldraw(1));
+        let top = -3 + heightmap.column_height(234 * 257) - 1;
+        assert_eq!(world.get_block(39, top, 0).map(|block| block.color), heightmap.palette.find_ldraw(15));
+    }
+
+    #[test]
+    fn colours_can_come_from_a_picture() {
+        let mut palette = Palette::new();
+        palette.add("Red", [1.0, 0.0, 0.0]).unwrap();
+        palette.add("Blue", [0.0, 0.0, 1.0]).unwrap();
+        let colors = image::RgbImage::from_fn(2, 1, |x, _| if x == 0 { image::Rgb([250, 10, 10]) } else { image::Rgb([0, 0, 200]) });
+        let heightmap = Heightmap { palette, colors: TerrainColors::Image(colors), scale: 2.0, ..Heightmap::default() };
+        let world = heightmap.build(&slope(4, 1)).unwrap();
+        let color = |x| world.get_block(x, 0, 0).map(|block| block.color);
+        assert_eq!((color(0), color(1), color(2), color(3)), (Some(0), Some(0), Some(1), Some(1)));
+    }
+
+    #[test]
+    fn bad_scales_are_rejected() {
+        for &scale in &[-1.0, f32::NAN, f32::INFINITY, MAX_SCALE * 2.0] {
+            assert!(Heightmap { scale, ..Heightmap::default() }.build(&slope(2, 2)).is_err(), "{}", scale);
+        }
+        let high = Heightmap { base: i64::MAX, ..Heightmap::default() };
+        assert!(high.build(&slope(2, 2)).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use anyhow::*;
+use std::collections::HashSet;
+
+use crate::brick::Brick;
+use crate::palette::Palette;
+use crate::world::World;
+
+/// A named state of the world. Only the first snapshot holds every object, the others
+/// hold what was removed and added since their parent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub name: String,
+    //Seconds since the Unix epoch
+    pub time: u64,
+    //Always an earlier snapshot
+    pub parent: Option<usize>,
+    pub palette: Palette,
+    pub removed: Vec<Brick>,
+    pub added: Vec<Brick>,
+}
+
+/// Snapshots kept in a world file. They form a tree: restoring an older snapshot and
+/// taking a new one starts a branch.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct History {
+    pub snapshots: Vec<Snapshot>,
+    //What the next snapshot's parent will be: the last one taken or restored
+    pub current: Option<usize>,
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+impl History {
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// The snapshots leading to `index`, oldest first and `index` last.
+    pub fn lineage(&self, index: usize) -> Vec<usize> {
+        let mut lineage = Vec::new();
+        let mut next = Some(index);
+        while let Some(index) = next {
+            lineage.push(index);
+            next = self.snapshots.get(index).and_then(|snapshot| snapshot.parent);
+        }
+        lineage.reverse();
+        lineage
+    }
+
+    /// Palette and objects of a snapshot, by replaying the changes from the first one.
+    pub fn contents(&self, index: usize) -> Result<(Palette, Vec<Brick>)> {
+        ensure!(index < self.snapshots.len(), "There is no snapshot {}", index);
+        let mut objects: Vec<Brick> = Vec::new();
+        for step in self.lineage(index) {
+            let snapshot = &self.snapshots[step];
+            let removed: HashSet<Brick> = snapshot.removed.iter().copied().collect();
+            objects.retain(|object| !removed.contains(object));
+            objects.extend_from_slice(&snapshot.added);
+        }
+        Ok((self.snapshots[index].palette.clone(), objects))
+    }
+
+    pub fn world(&self, index: usize, chunk_size: u8) -> Result<World> {
+        let (palette, objects) = self.contents(index)?;
+        World::from_objects(chunk_size, palette, objects)
+            .with_context(|| format!("Snapshot {} ({}) is damaged", index, self.snapshots[index].name))
+    }
+
+    /// Stores the world as it is now, after the current snapshot. Returns its index.
+    pub fn snapshot(&mut self, world: &World, name: &str) -> Result<usize> {
+        let objects: Vec<Brick> = world.objects().map(|(_, object)| object).collect();
+        let (removed, added) = match self.current {
+            Some(parent) => {
+                let (_, before) = self.contents(parent)?;
+                let now: HashSet<Brick> = objects.iter().copied().collect();
+                let then: HashSet<Brick> = before.iter().copied().collect();
+                (
+                    before.into_iter().filter(|object| !now.contains(object)).collect(),
+                    objects.into_iter().filter(|object| !then.contains(object)).collect(),
+                )
+            }
+            None => (Vec::new(), objects),
+        };
+        self.snapshots.push(Snapshot {
+            name: name.to_string(),
+            time: now(),
+            parent: self.current,
+            palette: world.palette.clone(),
+            removed,
+            added,
+        });
+        self.current = Some(self.snapshots.len() - 1);
+        Ok(self.snapshots.len() - 1)
+    }
+
+    /// Whether the world has changed since the current snapshot, or has anything in it
+    /// when there is none.
+    pub fn changed(&self, world: &World) -> Result<bool> {
+        let current = match self.current {
+            Some(current) => current,
+            None => return Ok(world.objects().next().is_some()),
+        };
+        let (palette, objects) = self.contents(current)?;
+        let then: HashSet<Brick> = objects.into_iter().collect();
+        let now: HashSet<Brick> = world.objects().map(|(_, object)| object).collect();
+        Ok(palette != world.palette || then != now)
+    }
+
+    /// Takes a snapshot only if the world has changed since the current one, so that
+    /// restoring another snapshot does not lose the changes.
+    pub fn snapshot_if_changed(&mut self, world: &World, name: &str) -> Result<Option<usize>> {
+        if self.changed(world)? {
+            self.snapshot(world, name).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The world as it was at a snapshot. Snapshots taken after this branch off it.
+    pub fn restore(&mut self, index: usize, chunk_size: u8) -> Result<World> {
+        let world = self.world(index, chunk_size)?;
+        self.current = Some(index);
+        Ok(world)
+    }
+
+    /// A snapshot by number, or the latest one of that name.
+    pub fn find(&self, name: &str) -> Option<usize> {
+        if let Some(index) = name.parse::<usize>().ok().filter(|&index| index < self.snapshots.len()) {
+            return Some(index);
+        }
+        self.snapshots.iter().rposition(|snapshot| snapshot.name == name)
+    }
+
+    /// One line per snapshot with what it added and removed, the current one marked
+    /// with `*`. Snapshots follow their parent; branches are indented and say where
+    /// they start.
+    pub fn to_text(&self) -> String {
+        let children = |parent: Option<usize>| -> Vec<usize> {
+            (0..self.snapshots.len()).filter(|&index| self.snapshots[index].parent == parent).collect()
+        };
+        let mut text = String::new();
+        let mut stack: Vec<(usize, usize)> = children(None).into_iter().rev().map(|index| (index, 0)).collect();
+        while let Some((index, depth)) = stack.pop() {
+            let snapshot = &self.snapshots[index];
+            let branch = match snapshot.parent {
+                Some(parent) if children(Some(parent))[0] != index => format!(" from {}", parent),
+                _ => String::new(),
+            };
+            text += &format!(
+                "{} {}{:>3} {}{} +{} -{} {}\n",
+                if self.current == Some(index) { '*' } else { ' ' },
+                "  ".repeat(depth),
+                index,
+                snapshot.name,
+                branch,
+                snapshot.added.len(),
+                snapshot.removed.len(),
+                format_time(snapshot.time),
+            );
+            //The oldest child carries on the line, later ones are branches
+            let mut next = children(Some(index));
+            while let Some(child) = next.pop() {
+                stack.push((child, if next.is_empty() { depth } else { depth + 1 }));
+            }
+        }
+        text
+    }
+}
+
+/// Seconds since the Unix epoch as a UTC date and time.
+pub fn format_time(time: u64) -> String {
+    let days = (time / 86400) as i64;
+    let seconds = time % 86400;
+    //Civil from days, after Howard Hinnant
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, seconds / 3600, seconds % 3600 / 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brick::BrickShape;
+    use crate::world::BlockType;
+
+    fn brick(x: i64, color: u16) -> Brick {
+        Brick {
+            shape: BrickShape::new(BlockType::BRICK, 1, 2),
+            origin: [x, 0, 0],
+            orientation: Default::default(),
+            color,
+        }
+    }
+
+    fn objects(world: &World) -> HashSet<Brick> {
+        world.objects().map(|(_, object)| object).collect()
+    }
+
+    #[test]
+    fn snapshots_store_changes() {
+        let mut world = World::default();
+        let mut history = History::default();
+        world.place_brick(brick(0, 1)).unwrap();
+        history.snapshot(&world, "one").unwrap();
+        world.place_brick(brick(3, 2)).unwrap();
+        history.snapshot(&world, "two").unwrap();
+        world.remove_at(0, 0, 0);
+        world.place_brick(brick(0, 4)).unwrap();
+        history.snapshot(&world, "three").unwrap();
+        assert_eq!(history.snapshots[2].removed, vec![brick(0, 1)]);
+        assert_eq!(history.snapshots[2].added, vec![brick(0, 4)]);
+        assert_eq!(objects(&history.world(2, world.chunk_size()).unwrap()), objects(&world));
+        assert_eq!(history.find("two"), Some(1));
+        assert_eq!(history.find("2"), Some(2));
+        assert_eq!(history.find("four"), None);
+    }
+
+    #[test]
+    fn restoring_then_snapshotting_branches() {
+        let mut world = World::default();
+        let mut history = History::default();
+        world.place_brick(brick(0, 1)).unwrap();
+        history.snapshot(&world, "one").unwrap();
+        world.place_brick(brick(3, 2)).unwrap();
+        history.snapshot(&world, "two").unwrap();
+        let mut restored = history.restore(0, world.chunk_size()).unwrap();
+        assert_eq!(restored.objects().count(), 1);
+        restored.place_brick(brick(6, 3)).unwrap();
+        history.snapshot(&restored, "branch").unwrap();
+        assert_eq!(history.snapshots[2].parent, Some(0));
+        assert_eq!(history.lineage(2), vec![0, 2]);
+        assert!(history.to_text().contains("branch from 0"));
+    }
+
+    #[test]
+    fn only_changed_worlds_are_snapshotted() {
+        let mut world = World::default();
+        let mut history = History::default();
+        assert!(!history.changed(&world).unwrap());
+        world.place_brick(brick(0, 1)).unwrap();
+        assert_eq!(history.snapshot_if_changed(&world, "one").unwrap(), Some(0));
+        assert_eq!(history.snapshot_if_changed(&world, "again").unwrap(), None);
+        world.palette.set_rgb(1, [0.2, 0.2, 0.2]).unwrap();
+        assert!(history.changed(&world).unwrap());
+    }
+
+    #[test]
+    fn times_are_utc_dates() {
+        assert_eq!(format_time(0), "1970-01-01 00:00");
+        assert_eq!(format_time(951_827_696), "2000-02-29 12:34");
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::*;
+use std::collections::BTreeMap;
+
+use crate::bom::{self, BillOfMaterials};
+use crate::brick::Brick;
+use crate::camera::Camera;
+use crate::palette::{linear_to_srgb, Palette};
+use crate::world::World;
+
+/// Width and height of the step pictures unless asked otherwise.
+pub const DEFAULT_SIZE: u32 = 800;
+
+/// How the world is cut into building steps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepOrder {
+    //One step per layer, bottom up
+    Layers,
+    //This many objects per step, in the order they were placed. The optimizer places
+    //bricks layer by layer, row by row, so an optimized world builds up in that order.
+    Placement(usize),
+}
+
+/// The objects added in one step.
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub objects: Vec<Brick>,
+}
+
+impl Step {
+    /// Parts callout: what this step adds.
+    pub fn parts(&self, palette: &Palette) -> Result<BillOfMaterials> {
+        let mut world = World::default();
+        world.palette = palette.clone();
+        for object in &self.objects {
+            world.place_brick(*object)?;
+        }
+        Ok(BillOfMaterials::from_world(&world))
+    }
+}
+
+pub fn steps(world: &World, order: StepOrder) -> Vec<Step> {
+    match order {
+        StepOrder::Layers => {
+            let mut layers: BTreeMap<i64, Vec<Brick>> = BTreeMap::new();
+            for (_, object) in world.objects() {
+                layers.entry(object.origin[1]).or_default().push(object);
+            }
+            layers.into_values().map(|objects| Step { objects }).collect()
+        }
+        StepOrder::Placement(per_step) => {
+            //Bricks by id first, then the loose unit blocks bottom up
+            let mut objects: Vec<_> = world.objects().collect();
+            objects.sort_by_key(|(id, object)| (id.is_none(), *id, object.origin[1], object.origin[2], object.origin[0]));
+            objects
+                .chunks(per_step.max(1))
+                .map(|chunk| Step { objects: chunk.iter().map(|(_, object)| *object).collect() })
+                .collect()
+        }
+    }
+}
+
+/// A view of the whole world from the front left and above, with all of it in frame.
+pub fn overview_camera(world: &World) -> Camera {
+    let (min, max) = bom::bounds(world).unwrap_or(([0; 3], [0; 3]));
+    let centre = cgmath::Point3::new(
+        (min[0] + max[0] + 1) as f32 / 2.0,
+        (min[1] + max[1] + 1) as f32 / 2.0,
+        (min[2] + max[2] + 1) as f32 / 2.0,
+    );
+    let size = [max[0] - min[0] + 1, max[1] - min[1] + 1, max[2] - min[2] + 1];
+    let radius = (size.iter().map(|&side| (side * side) as f32).sum::<f32>()).sqrt() / 2.0;
+    //Far enough for the bounding sphere to fit the 45 degree field of view
+    let distance = radius / (22.5f32).to_radians().sin() * 1.1;
+    let direction = cgmath::Vector3::new(-1.0f32, 0.9, 1.4);
+    let direction = direction / (direction.x * direction.x + direction.y * direction.y + direction.z * direction.z).sqrt();
+    Camera::looking_at(centre + direction * distance.max(2.0), centre)
+}
+
+fn css_color(palette: &Palette, line: &bom::BomLine) -> String {
+    match palette.get(line.color_id) {
+        Some(color) => format!(
+            "#{:02x}{:02x}{:02x}",
+            linear_to_srgb(color.rgb[0]),
+            linear_to_srgb(color.rgb[1]),
+            linear_to_srgb(color.rgb[2]),
+        ),
+        None => "#000000".to_string(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn parts_table(palette: &Palette, parts: &BillOfMaterials) -> String {
+    let mut html = String::from("<table class=\"parts\">\n");
+    for line in &parts.lines {
+        html += &format!(
+            "<tr><td>{}x</td><td><span class=\"swatch\" style=\"background:{}\"></span></td><td>{}</td><td>{}</td></tr>\n",
+            line.quantity,
+            css_color(palette, line),
+            escape(&line.part),
+            escape(&line.color),
+        );
+    }
+    html += "</table>\n";
+    html
+}
+
+/// Picture of step `number`, counted from 0.
+pub fn step_file(number: usize) -> String {
+    format!("step_{:03}.png", number + 1)
+}
+
+/// The instructions page: every part needed, then each step with its picture from
+/// `step_file` next to the page and the parts it adds.
+pub fn index_html(world: &World, steps: &[Step], size: (u32, u32)) -> Result<String> {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Building instructions</title>\n<style>\n\
+         body { font-family: sans-serif; }\n\
+         .step { display: flex; align-items: flex-start; gap: 1em; margin-bottom: 2em; }\n\
+         .swatch { display: inline-block; width: 1em; height: 1em; border: 1px solid #444; }\n\
+         </style>\n</head>\n<body>\n<h1>Building instructions</h1>\n",
+    );
+    let all_parts = BillOfMaterials::from_world(world);
+    html += &format!("<h2>Parts ({} in total)</h2>\n", all_parts.total);
+    html += &parts_table(&world.palette, &all_parts);
+
+    for (number, step) in steps.iter().enumerate() {
+        html += &format!(
+            "<div class=\"step\">\n<div><h2>Step {}</h2>\n<img src=\"{}\" width=\"{}\" height=\"{}\"></div>\n<div>{}</div>\n</div>\n",
+            number + 1,
+            step_file(number),
+            size.0,
+            size.1,
+            parts_table(&world.palette, &step.parts(&world.palette)?),
+        );
+    }
+    html += "</body>\n</html>\n";
+    Ok(html)
+}
+
+/// Renders every step to `dir/step_NNN.png`, with the new parts highlighted and the
+/// earlier ones dimmed, and writes `dir/index.html` with a parts callout per step.
+#[cfg(feature = "render")]
+pub fn write_instructions(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    world: &World,
+    steps: &[Step],
+    dir: &std::path::Path,
+    size: (u32, u32),
+) -> Result<()> {
+    use crate::model::{Model, Shade};
+    use crate::render::Offscreen;
+
+    std::fs::create_dir_all(dir).with_context(|| format!("Cannot create {}", dir.display()))?;
+    let mut offscreen = Offscreen::new(device, size.0, size.1, &world.palette)?;
+    let camera = overview_camera(world);
+
+    let mut obj_model = Model::new()?;
+    obj_model.world = World::new(world.chunk_size());
+    obj_model.world.palette = world.palette.clone();
+    //The whole model is in view, so draw every stud
+    obj_model.stud_distance = f32::INFINITY;
+
+    for (number, step) in steps.iter().enumerate() {
+        for shade in obj_model.shades.values_mut() {
+            *shade = Shade::Dim;
+        }
+        for object in &step.objects {
+            obj_model.world.place_brick(*object)?;
+            obj_model.shades.insert(object.origin, Shade::Highlight);
+        }
+        obj_model.build_meshes(device);
+        obj_model.update_studs(device, camera.position);
+
+        let image = offscreen.render(device, queue, &obj_model, &camera, crate::render::CLEAR_COLOR)?;
+        let file = step_file(number);
+        image.save(dir.join(&file)).with_context(|| format!("Cannot write {}", file))?;
+    }
+    let index = dir.join("index.html");
+    std::fs::write(&index, index_html(world, steps, size)?).with_context(|| format!("Cannot write {}", index.display()))
+}
@@ -0,0 +1,217 @@
+use anyhow::*;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::bom::{parse_csv_line, BillOfMaterials};
+use crate::brick::{Brick, BrickShape};
+use crate::ldraw;
+use crate::wanted_list::{WantedItem, WantedList};
+use crate::world::{BlockType, World};
+
+/// LDraw part number and LDraw colour code, which Rebrickable shares.
+pub type PartKey = (String, u32);
+
+/// The real part a placed brick needs, if there is one.
+pub fn part_key(world: &World, brick: &Brick) -> Option<PartKey> {
+    let part = ldraw::part_id(&brick.shape)?;
+    let color = world.palette.get(brick.color)?.ldraw_id?;
+    Some((part.to_string(), color))
+}
+
+/// Parts on hand.
+#[derive(Debug, Clone, Default)]
+pub struct Inventory {
+    pub parts: BTreeMap<PartKey, u32>,
+}
+
+impl Inventory {
+    /// Reads a Rebrickable parts list (`Part,Color,Quantity[,Is Spare]`) or a parts list
+    /// exported by `BillOfMaterials::to_csv`, telling them apart by the header.
+    pub fn from_csv(text: &str) -> Result<Self> {
+        //Numbered before blank lines and comments are skipped, so errors point at the right line
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line))
+            .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+        let (_, header) = lines.next().context("Empty inventory")?;
+        let header: Vec<String> = parse_csv_line(header)
+            .iter()
+            .map(|name| name.trim().to_lowercase())
+            .collect();
+        let column = |name: &str| {
+            header
+                .iter()
+                .position(|column| column == name)
+                .with_context(|| format!("Inventory has no {} column", name))
+        };
+        let quantity = column("quantity")?;
+        //Our own parts lists describe the shape, Rebrickable's name the part
+        let own_format = header.iter().any(|column| column == "blocktype");
+        let (part, color) = if own_format {
+            (column("blocktype")?, column("ldraw_color")?)
+        } else {
+            (column("part")?, column("color")?)
+        };
+        let size = if own_format { Some((column("width")?, column("depth")?)) } else { None };
+
+        let mut inventory = Inventory::default();
+        for (number, line) in lines {
+            let fields = parse_csv_line(line);
+            let field = |index: usize| {
+                fields
+                    .get(index)
+                    .map(|field| field.trim())
+                    .with_context(|| format!("Inventory line {} is too short", number))
+            };
+            let color_code = field(color)?;
+            let key = match size {
+                Some((width, depth)) => {
+                    let blocktype = field(part)?;
+                    let blocktype = BlockType::from_name(blocktype)
+                        .with_context(|| format!("Unknown block type {} on inventory line {}", blocktype, number))?;
+                    let size = |index: usize| -> Result<u8> {
+                        let value = field(index)?;
+                        value.parse().with_context(|| format!("Invalid size {:?} on inventory line {}", value, number))
+                    };
+                    let shape = BrickShape::new(blocktype, size(width)?, size(depth)?);
+                    match (ldraw::part_id(&shape), color_code.parse().ok()) {
+                        (Some(part), Some(color)) => (part.to_string(), color),
+                        _ => {
+                            log::warn!("Skipping {} in colour {:?} on inventory line {}, it is not a real part", shape.name(), color_code, number);
+                            continue;
+                        }
+                    }
+                }
+                None => (
+                    field(part)?.to_string(),
+                    color_code.parse().with_context(|| format!("Invalid colour {} on inventory line {}", color_code, number))?,
+                ),
+            };
+            let count = field(quantity)?;
+            let count: u32 = count.parse().with_context(|| format!("Invalid quantity {:?} on inventory line {}", count, number))?;
+            *inventory.parts.entry(key).or_insert(0) += count;
+        }
+        Ok(inventory)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("Cannot open {}", path.display()))?;
+        Inventory::from_csv(&text).with_context(|| format!("Cannot read {}", path.display()))
+    }
+
+    pub fn count(&self, key: &PartKey) -> u32 {
+        self.parts.get(key).copied().unwrap_or(0)
+    }
+
+    /// Use up one part. False if there are none left.
+    pub fn take(&mut self, key: &PartKey) -> bool {
+        match self.parts.get_mut(key) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn put_back(&mut self, key: &PartKey) {
+        *self.parts.entry(key.clone()).or_insert(0) += 1;
+    }
+
+    /// What building `world` from this inventory would still need, as a wanted list. Parts
+    /// and colours that cannot be bought at all end up in its unmapped lines.
+    pub fn missing(&self, world: &World) -> WantedList {
+        let needed = WantedList::from_bom(&BillOfMaterials::from_world(world));
+        let items = needed
+            .items
+            .into_iter()
+            .filter_map(|item| {
+                let have = self.count(&(item.part.to_string(), item.ldraw_color));
+                if have >= item.quantity {
+                    return None;
+                }
+                Some(WantedItem { quantity: item.quantity - have, ..item })
+            })
+            .collect();
+        WantedList { items, unmapped: needed.unmapped }
+    }
+
+    /// What is left after building `world`. Parts the world uses beyond the inventory
+    /// are simply not there, see `missing` for those.
+    pub fn remaining(&self, world: &World) -> Inventory {
+        let mut remaining = self.clone();
+        for item in WantedList::from_bom(&BillOfMaterials::from_world(world)).items {
+            if let Some(count) = remaining.parts.get_mut(&(item.part.to_string(), item.ldraw_color)) {
+                *count = count.saturating_sub(item.quantity);
+            }
+        }
+        remaining
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::Block;
+
+    fn brick(width: u8, depth: u8, origin: [i64; 3]) -> Brick {
+        Brick { shape: BrickShape::new(BlockType::BRICK, width, depth), origin, orientation: Default::default(), color: 4 }
+    }
+
+    //Two 2x4 bricks, a 1x1 brick and a unit block, which is built as a 1x1 brick
+    fn world() -> World {
+        let mut world = World::default();
+        world.palette = crate::lego::lego_palette();
+        for &object in &[brick(2, 4, [0, 0, 0]), brick(2, 4, [0, 1, 0]), brick(1, 1, [5, 1, 2])] {
+            world.place_brick(object).unwrap();
+        }
+        let block = Block { blocktype: BlockType::NORMAL, color: 4, orientation: Default::default(), brick: None };
+        world.set_block(7, 0, 0, Some(block));
+        world
+    }
+
+    #[test]
+    fn rebrickable_lists_are_summed_and_compared() {
+        let world = world();
+        let [two_by_four, one_by_one] = [brick(2, 4, [0; 3]), brick(1, 1, [0; 3])].map(|brick| part_key(&world, &brick).unwrap());
+        let csv = format!(
+            "Part,Color,Quantity,Is Spare\n{0},{1},1,False\n\n# spares\n{0},{1},1,True\n{2},{3},5,False\n",
+            two_by_four.0, two_by_four.1, one_by_one.0, one_by_one.1
+        );
+        let mut inventory = Inventory::from_csv(&csv).unwrap();
+        assert_eq!((inventory.count(&two_by_four), inventory.count(&one_by_one)), (2, 5));
+        assert!(inventory.missing(&world).items.is_empty());
+        let remaining = inventory.remaining(&world);
+        assert_eq!((remaining.count(&two_by_four), remaining.count(&one_by_one)), (0, 3));
+
+        assert!(inventory.take(&two_by_four) && inventory.take(&two_by_four));
+        assert!(!inventory.take(&two_by_four));
+        let missing = inventory.missing(&world);
+        assert_eq!(missing.items.len(), 1);
+        assert_eq!((missing.items[0].part.to_string(), missing.items[0].quantity), (two_by_four.0.clone(), 2));
+        inventory.put_back(&two_by_four);
+        assert_eq!(inventory.count(&two_by_four), 1);
+    }
+
+    #[test]
+    fn own_parts_lists_read_back() {
+        let world = world();
+        let inventory = Inventory::from_csv(&BillOfMaterials::from_world(&world).to_csv()).unwrap();
+        assert!(inventory.missing(&world).items.is_empty());
+        assert_eq!(inventory.remaining(&world).parts.values().sum::<u32>(), 0);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = |csv: &str| Inventory::from_csv(csv).unwrap_err().to_string();
+        let header = "part,blocktype,width,depth,color_id,color,ldraw_color,quantity\n";
+        assert!(error(&format!("# parts\n\n{}\n3001,BRICK,2,4,0,Red,4,x\n", header)).contains("line 5"));
+        assert!(error(&format!("{}3001,GIRDER,2,4,0,Red,4,1\n", header)).contains("Unknown block type GIRDER on inventory line 2"));
+        assert!(error(&format!("{}3001,BRICK,two,4,0,Red,4,1\n", header)).contains("line 2"));
+        assert!(error("Part,Color,Quantity\n\n3001,4\n").contains("Inventory line 3 is too short"));
+        assert!(error("Part,Color,Quantity\n3001,red,1\n").contains("Invalid colour red on inventory line 2"));
+        assert!(error("Part,Quantity\n").contains("no color column"));
+    }
+}
@@ -0,0 +1,486 @@
+use anyhow::*;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use crate::bom;
+use crate::palette::{linear_to_srgb, ColorId, Palette};
+use crate::world::World;
+
+/// Side of one cell in pixels, in both the SVG and the PNG.
+pub const CELL_PIXELS: u32 = 24;
+//Room left of and above the grid for the coordinates
+const MARGIN: u32 = 40;
+const LEGEND_ROW: u32 = 28;
+//A slice becomes a picture CELL_PIXELS wide per cell, and the plans of a whole world
+//are written one after another, so refuse sizes nobody could print or wait for
+const MAX_SLICE_CELLS: u64 = 1 << 16;
+const MAX_CELLS: u64 = 1 << 31;
+
+/// The axis a world is sliced along. `Y` gives the usual layer by layer plans seen from
+/// above, `X` and `Z` give vertical slices seen from the side.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+impl Axis {
+    pub fn parse(name: &str) -> Result<Axis> {
+        match name {
+            "x" | "X" => Ok(Axis::X),
+            "y" | "Y" => Ok(Axis::Y),
+            "z" | "Z" => Ok(Axis::Z),
+            _ => bail!("Invalid axis {:?}, expected x, y or z", name),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Axis::X => "x",
+            Axis::Y => "y",
+            Axis::Z => "z",
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Axis::X => 0,
+            Axis::Y => 1,
+            Axis::Z => 2,
+        }
+    }
+
+    //World axes along the columns and rows of the drawing, and whether rows count down
+    fn plane(&self) -> (usize, usize, bool) {
+        match self {
+            //Seen from above, north up
+            Axis::Y => (0, 2, false),
+            //Seen from the side, up is up
+            Axis::X => (2, 1, true),
+            Axis::Z => (0, 1, true),
+        }
+    }
+}
+
+/// One slice of the world as a grid of palette indices.
+#[derive(Debug, Clone)]
+pub struct LayerPlan {
+    pub axis: Axis,
+    pub level: i64,
+    pub columns: usize,
+    pub rows: usize,
+    //World coordinate of every column and every row, top row first
+    pub column_coords: Vec<i64>,
+    pub row_coords: Vec<i64>,
+    //Row major, top row first
+    pub cells: Vec<Option<ColorId>>,
+}
+
+impl LayerPlan {
+    /// Every slice through the world's bounds along `axis`, empty ones included so the
+    /// numbering has no gaps. All slices share the same grid. They are made as they are
+    /// iterated, so only one is in memory at a time.
+    pub fn slices(world: &World, axis: Axis) -> Result<impl Iterator<Item = LayerPlan> + '_> {
+        //An empty world has no slices, an empty range on every axis
+        let (min, max) = bom::bounds(world).unwrap_or(([0; 3], [-1; 3]));
+        let (column_axis, row_axis, downwards) = axis.plane();
+        let length = |axis: usize| (max[axis] as i128 - min[axis] as i128 + 1) as u64;
+        let slice_cells = length(column_axis).checked_mul(length(row_axis));
+        ensure!(
+            slice_cells.is_some_and(|cells| cells <= MAX_SLICE_CELLS),
+            "The world is too big for layer plans, a slice would have {} by {} cells",
+            length(column_axis),
+            length(row_axis)
+        );
+        let cells = slice_cells.and_then(|cells| cells.checked_mul(length(axis.index())));
+        ensure!(cells.is_some_and(|cells| cells <= MAX_CELLS), "The world is too big for layer plans, it has {} slices", length(axis.index()));
+        let column_coords: Vec<i64> = (min[column_axis]..=max[column_axis]).collect();
+        let mut row_coords: Vec<i64> = (min[row_axis]..=max[row_axis]).collect();
+        if downwards {
+            row_coords.reverse();
+        }
+
+        Ok((min[axis.index()]..=max[axis.index()]).map(move |level| {
+            let mut cells = Vec::with_capacity(column_coords.len() * row_coords.len());
+            for &row in &row_coords {
+                for &column in &column_coords {
+                    let mut position = [0; 3];
+                    position[axis.index()] = level;
+                    position[column_axis] = column;
+                    position[row_axis] = row;
+                    let block = world.get_block(position[0], position[1], position[2]);
+                    cells.push(block.map(|block| block.color));
+                }
+            }
+            LayerPlan {
+                axis,
+                level,
+                columns: column_coords.len(),
+                rows: row_coords.len(),
+                column_coords: column_coords.clone(),
+                row_coords: row_coords.clone(),
+                cells,
+            }
+        }))
+    }
+
+    pub fn get(&self, column: usize, row: usize) -> Option<ColorId> {
+        self.cells[row * self.columns + column]
+    }
+
+    /// The colours used in this slice, each with how many cells it fills.
+    pub fn legend(&self) -> Vec<(ColorId, usize)> {
+        let mut counts = std::collections::BTreeMap::new();
+        for color in self.cells.iter().flatten() {
+            *counts.entry(*color).or_insert(0) += 1;
+        }
+        counts.into_iter().collect()
+    }
+
+    pub fn file_stem(&self) -> String {
+        format!("{}_{}", self.axis.name(), self.level)
+    }
+
+    fn title(&self) -> String {
+        format!("{} = {}", self.axis.name(), self.level)
+    }
+
+    fn size(&self) -> (u32, u32) {
+        let width = MARGIN + self.columns as u32 * CELL_PIXELS + CELL_PIXELS;
+        let height = MARGIN + self.rows as u32 * CELL_PIXELS + CELL_PIXELS / 2 + self.legend().len() as u32 * LEGEND_ROW;
+        (width.max(MARGIN + 10 * CELL_PIXELS), height)
+    }
+
+    pub fn to_svg(&self, palette: &Palette) -> String {
+        let (width, height) = self.size();
+        let font = CELL_PIXELS / 2;
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-family=\"sans-serif\" font-size=\"{}\">",
+            width, height, font
+        );
+        let _ = writeln!(svg, "<rect width=\"{}\" height=\"{}\" fill=\"white\"/>", width, height);
+        let _ = writeln!(svg, "<text x=\"4\" y=\"{}\" font-weight=\"bold\">{}</text>", font + 2, escape(&self.title()));
+
+        //Coordinates along the top and the left side
+        for (column, coord) in self.column_coords.iter().enumerate() {
+            let _ = writeln!(
+                svg,
+                "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+                MARGIN + column as u32 * CELL_PIXELS + CELL_PIXELS / 2,
+                MARGIN - 4,
+                coord
+            );
+        }
+        for (row, coord) in self.row_coords.iter().enumerate() {
+            let _ = writeln!(
+                svg,
+                "<text x=\"{}\" y=\"{}\" text-anchor=\"end\" dominant-baseline=\"middle\">{}</text>",
+                MARGIN - 4,
+                MARGIN + row as u32 * CELL_PIXELS + CELL_PIXELS / 2,
+                coord
+            );
+        }
+
+        for row in 0..self.rows {
+            for column in 0..self.columns {
+                let x = MARGIN + column as u32 * CELL_PIXELS;
+                let y = MARGIN + row as u32 * CELL_PIXELS;
+                match self.get(column, row) {
+                    Some(color) => {
+                        let _ = writeln!(
+                            svg,
+                            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\" stroke=\"#444\"/>",
+                            x, y, CELL_PIXELS, CELL_PIXELS, css_color(palette, color)
+                        );
+                        let _ = writeln!(
+                            svg,
+                            "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\" dominant-baseline=\"middle\" fill=\"{}\">{}</text>",
+                            x + CELL_PIXELS / 2,
+                            y + CELL_PIXELS / 2,
+                            if is_light(palette, color) { "black" } else { "white" },
+                            color
+                        );
+                    }
+                    None => {
+                        let _ = writeln!(
+                            svg,
+                            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"#ddd\"/>",
+                            x, y, CELL_PIXELS, CELL_PIXELS
+                        );
+                    }
+                }
+            }
+        }
+
+        let legend_top = MARGIN + self.rows as u32 * CELL_PIXELS + CELL_PIXELS / 2;
+        for (i, (color, count)) in self.legend().into_iter().enumerate() {
+            let y = legend_top + i as u32 * LEGEND_ROW;
+            let name = palette.get(color).map(|color| color.name.as_str()).unwrap_or("?");
+            let _ = writeln!(
+                svg,
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\" stroke=\"#444\"/>",
+                MARGIN, y, CELL_PIXELS, CELL_PIXELS, css_color(palette, color)
+            );
+            let _ = writeln!(
+                svg,
+                "<text x=\"{}\" y=\"{}\" dominant-baseline=\"middle\">{}: {} ({}x)</text>",
+                MARGIN + CELL_PIXELS + 8,
+                y + CELL_PIXELS / 2,
+                color,
+                escape(name),
+                count
+            );
+        }
+        svg += "</svg>\n";
+        svg
+    }
+
+    /// The same drawing as `to_svg`. Without a font at hand the labels are drawn with a small
+    /// built in font of digits and axis names, so the legend shows indices but not colour names.
+    pub fn to_png(&self, palette: &Palette) -> image::RgbaImage {
+        let (width, height) = self.size();
+        let mut image = image::RgbaImage::from_pixel(width, height, image::Rgba([255, 255, 255, 255]));
+        let black = image::Rgba([0, 0, 0, 255]);
+        let white = image::Rgba([255, 255, 255, 255]);
+        let grid = image::Rgba([68, 68, 68, 255]);
+        let empty = image::Rgba([221, 221, 221, 255]);
+
+        draw_text(&mut image, 4, 4, &self.title().replace(' ', ""), black);
+        for (column, coord) in self.column_coords.iter().enumerate() {
+            let text = coord.to_string();
+            let x = MARGIN + column as u32 * CELL_PIXELS + CELL_PIXELS / 2;
+            draw_text(&mut image, x.saturating_sub(text_width(&text) / 2), MARGIN - GLYPH_HEIGHT - 6, &text, black);
+        }
+        for (row, coord) in self.row_coords.iter().enumerate() {
+            let text = coord.to_string();
+            let y = MARGIN + row as u32 * CELL_PIXELS + (CELL_PIXELS - GLYPH_HEIGHT) / 2;
+            draw_text(&mut image, (MARGIN - 4).saturating_sub(text_width(&text)), y, &text, black);
+        }
+
+        for row in 0..self.rows {
+            for column in 0..self.columns {
+                let x = MARGIN + column as u32 * CELL_PIXELS;
+                let y = MARGIN + row as u32 * CELL_PIXELS;
+                match self.get(column, row) {
+                    Some(color) => {
+                        fill_rect(&mut image, x, y, CELL_PIXELS, CELL_PIXELS, rgba(palette, color));
+                        outline_rect(&mut image, x, y, CELL_PIXELS, CELL_PIXELS, grid);
+                        let text = color.to_string();
+                        let ink = if is_light(palette, color) { black } else { white };
+                        draw_text(
+                            &mut image,
+                            x + (CELL_PIXELS.saturating_sub(text_width(&text))) / 2,
+                            y + (CELL_PIXELS - GLYPH_HEIGHT) / 2,
+                            &text,
+                            ink,
+                        );
+                    }
+                    None => outline_rect(&mut image, x, y, CELL_PIXELS, CELL_PIXELS, empty),
+                }
+            }
+        }
+
+        let legend_top = MARGIN + self.rows as u32 * CELL_PIXELS + CELL_PIXELS / 2;
+        for (i, (color, _)) in self.legend().into_iter().enumerate() {
+            let y = legend_top + i as u32 * LEGEND_ROW;
+            fill_rect(&mut image, MARGIN, y, CELL_PIXELS, CELL_PIXELS, rgba(palette, color));
+            outline_rect(&mut image, MARGIN, y, CELL_PIXELS, CELL_PIXELS, grid);
+            draw_text(&mut image, MARGIN + CELL_PIXELS + 8, y + (CELL_PIXELS - GLYPH_HEIGHT) / 2, &color.to_string(), black);
+        }
+        image
+    }
+}
+
+/// Writes `<axis>_<level>.svg` and `.png` for every slice into `dir`, returning how many.
+pub fn save(world: &World, axis: Axis, dir: &Path) -> Result<usize> {
+    std::fs::create_dir_all(dir).with_context(|| format!("Cannot create {}", dir.display()))?;
+    let mut count = 0;
+    for slice in LayerPlan::slices(world, axis)? {
+        let svg = dir.join(format!("{}.svg", slice.file_stem()));
+        std::fs::write(&svg, slice.to_svg(&world.palette)).with_context(|| format!("Cannot write {}", svg.display()))?;
+        let png = dir.join(format!("{}.png", slice.file_stem()));
+        slice
+            .to_png(&world.palette)
+            .save(&png)
+            .with_context(|| format!("Cannot write {}", png.display()))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn srgb(palette: &Palette, color: ColorId) -> [u8; 3] {
+    match palette.get(color) {
+        Some(color) => [
+            linear_to_srgb(color.rgb[0]),
+            linear_to_srgb(color.rgb[1]),
+            linear_to_srgb(color.rgb[2]),
+        ],
+        None => [255, 0, 255],
+    }
+}
+
+fn rgba(palette: &Palette, color: ColorId) -> image::Rgba<u8> {
+    let [r, g, b] = srgb(palette, color);
+    image::Rgba([r, g, b, 255])
+}
+
+fn css_color(palette: &Palette, color: ColorId) -> String {
+    let [r, g, b] = srgb(palette, color);
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+//Whether dark text reads better than white on this colour
+fn is_light(palette: &Palette, color: ColorId) -> bool {
+    match palette.get(color) {
+        Some(color) => 0.2126 * color.rgb[0] + 0.7152 * color.rgb[1] + 0.0722 * color.rgb[2] > 0.18,
+        None => true,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn fill_rect(image: &mut image::RgbaImage, x: u32, y: u32, width: u32, height: u32, color: image::Rgba<u8>) {
+    for py in y..(y + height).min(image.height()) {
+        for px in x..(x + width).min(image.width()) {
+            image.put_pixel(px, py, color);
+        }
+    }
+}
+
+fn outline_rect(image: &mut image::RgbaImage, x: u32, y: u32, width: u32, height: u32, color: image::Rgba<u8>) {
+    fill_rect(image, x, y, width, 1, color);
+    fill_rect(image, x, y + height - 1, width, 1, color);
+    fill_rect(image, x, y, 1, height, color);
+    fill_rect(image, x + width - 1, y, 1, height, color);
+}
+
+//3x5 digits and axis names, one row per byte, drawn at twice the size
+const GLYPH_SCALE: u32 = 2;
+const GLYPH_WIDTH: u32 = 3 * GLYPH_SCALE;
+const GLYPH_HEIGHT: u32 = 5 * GLYPH_SCALE;
+const GLYPH_SPACING: u32 = GLYPH_SCALE;
+
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        'x' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        _ => [0; 5],
+    }
+}
+
+fn text_width(text: &str) -> u32 {
+    let count = text.chars().count() as u32;
+    (count * (GLYPH_WIDTH + GLYPH_SPACING)).saturating_sub(GLYPH_SPACING)
+}
+
+fn draw_text(image: &mut image::RgbaImage, x: u32, y: u32, text: &str, color: image::Rgba<u8>) {
+    for (i, c) in text.chars().enumerate() {
+        let left = x + i as u32 * (GLYPH_WIDTH + GLYPH_SPACING);
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) != 0 {
+                    fill_rect(
+                        image,
+                        left + column * GLYPH_SCALE,
+                        y + row as u32 * GLYPH_SCALE,
+                        GLYPH_SCALE,
+                        GLYPH_SCALE,
+                        color,
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{Block, BlockType};
+
+    fn unit(world: &mut World, x: i64, y: i64, z: i64, color: ColorId) {
+        let block = Block { blocktype: BlockType::NORMAL, color, orientation: Default::default(), brick: None };
+        world.set_block(x, y, z, Some(block));
+    }
+
+    //A row of five colours along x at z = 1, two layers high, and one block at the back
+    fn world() -> World {
+        let mut world = World::default();
+        for x in -2..3 {
+            for y in 0..2 {
+                unit(&mut world, x, y, 1, (x + 2) as ColorId);
+            }
+        }
+        unit(&mut world, 0, 0, 0, 6);
+        world
+    }
+
+    #[test]
+    fn slices_share_one_grid() {
+        let world = world();
+        let layers: Vec<LayerPlan> = LayerPlan::slices(&world, Axis::Y).unwrap().collect();
+        assert_eq!(layers.len(), 2);
+        for layer in &layers {
+            assert_eq!((layer.columns, layer.rows), (5, 2));
+            assert_eq!(layer.column_coords, vec![-2, -1, 0, 1, 2]);
+            //Seen from above, north is up
+            assert_eq!(layer.row_coords, vec![0, 1]);
+        }
+        assert_eq!((layers[0].get(0, 1), layers[0].get(4, 1), layers[0].get(2, 0)), (Some(0), Some(4), Some(6)));
+        assert_eq!(layers[1].get(2, 0), None);
+        assert_eq!(layers[0].legend(), vec![(0, 1), (1, 1), (2, 1), (3, 1), (4, 1), (6, 1)]);
+
+        //From the side the top row is the highest one
+        let sides: Vec<LayerPlan> = LayerPlan::slices(&world, Axis::Z).unwrap().collect();
+        assert_eq!(sides[0].row_coords, vec![1, 0]);
+        assert_eq!((sides[0].get(2, 1), sides[0].get(2, 0)), (Some(6), None));
+        assert_eq!(sides[1].file_stem(), "z_1");
+        assert_eq!(LayerPlan::slices(&World::default(), Axis::X).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn drawings_show_every_cell() {
+        let world = world();
+        let layer = LayerPlan::slices(&world, Axis::Y).unwrap().next().unwrap();
+        let svg = layer.to_svg(&world.palette);
+        let (width, height) = layer.size();
+        assert!(svg.starts_with(&format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\"", width, height)));
+        assert!(svg.trim_end().ends_with("</svg>") && svg.contains("y = 0"));
+        assert_eq!(svg.matches(&css_color(&world.palette, 6)).count(), 2);
+        let png = layer.to_png(&world.palette);
+        assert_eq!(png.dimensions(), layer.size());
+        //The middle of the top left cell has its colour
+        let centre = MARGIN + CELL_PIXELS / 2;
+        let [r, g, b] = srgb(&world.palette, 0);
+        assert_eq!(png.get_pixel(centre - 6, centre + CELL_PIXELS - 6).0, [r, g, b, 255]);
+    }
+
+    #[test]
+    fn save_writes_a_picture_per_slice() {
+        let dir = std::env::temp_dir().join(format!("byggeklosser-plans-{}", std::process::id()));
+        assert_eq!(save(&world(), Axis::X, &dir).unwrap(), 5);
+        for stem in &["x_-2", "x_2"] {
+            assert!(dir.join(format!("{}.svg", stem)).exists() && dir.join(format!("{}.png", stem)).exists());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(Axis::parse("w").is_err());
+    }
+}
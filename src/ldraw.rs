@@ -0,0 +1,129 @@
+use anyhow::*;
+use std::path::Path;
+
+use crate::brick::{Brick, BrickShape};
+use crate::lego;
+use crate::quantize::{DeltaE, Quantizer};
+use crate::world::{BlockType, World};
+
+//LDraw units per stud and per brick height. LDraw has -Y pointing up.
+const LDU_PER_CELL: f32 = 20.0;
+const LDU_PER_BRICK: f32 = 24.0;
+
+/// LDraw part number of a shape, if there is a real part for it.
+pub fn part_id(shape: &BrickShape) -> Option<&'static str> {
+    let (a, b) = if shape.width <= shape.depth { (shape.width, shape.depth) } else { (shape.depth, shape.width) };
+    let id = match (shape.blocktype, a, b) {
+        (BlockType::NORMAL, 1, 1) => "3005",
+        (BlockType::BRICK, 1, 1) => "3005",
+        (BlockType::BRICK, 1, 2) => "3004",
+        (BlockType::BRICK, 1, 3) => "3622",
+        (BlockType::BRICK, 1, 4) => "3010",
+        (BlockType::BRICK, 1, 6) => "3009",
+        (BlockType::BRICK, 1, 8) => "3008",
+        (BlockType::BRICK, 2, 2) => "3003",
+        (BlockType::BRICK, 2, 3) => "3002",
+        (BlockType::BRICK, 2, 4) => "3001",
+        (BlockType::BRICK, 2, 6) => "2456",
+        (BlockType::BRICK, 2, 8) => "3007",
+        (BlockType::PLATE, 1, 1) => "3024",
+        (BlockType::PLATE, 1, 2) => "3023",
+        (BlockType::PLATE, 1, 3) => "3623",
+        (BlockType::PLATE, 1, 4) => "3710",
+        (BlockType::PLATE, 1, 6) => "3666",
+        (BlockType::PLATE, 1, 8) => "3460",
+        (BlockType::PLATE, 2, 2) => "3022",
+        (BlockType::PLATE, 2, 3) => "3021",
+        (BlockType::PLATE, 2, 4) => "3020",
+        (BlockType::PLATE, 2, 6) => "3795",
+        (BlockType::PLATE, 2, 8) => "3034",
+        (BlockType::TILE, 1, 1) => "3070b",
+        (BlockType::TILE, 1, 2) => "3069b",
+        (BlockType::TILE, 1, 4) => "2431",
+        (BlockType::TILE, 2, 2) => "3068b",
+        (BlockType::TILE, 2, 4) => "87079",
+        (BlockType::SLOPE, 1, 2) => "3040b",
+        (BlockType::SLOPE, 2, 2) => "3039",
+        (BlockType::SLOPE_INVERTED, 1, 2) => "3665",
+        (BlockType::SLOPE_CORNER, 2, 2) => "3045",
+        (BlockType::ARCH, 1, 3) => "4490",
+        (BlockType::ARCH, 1, 4) => "3659",
+        (BlockType::ARCH, 1, 6) => "3455",
+        _ => return None,
+    };
+    Some(id)
+}
+
+//World cell coordinates to LDraw units
+fn to_ldu(v: [f32; 3]) -> [f32; 3] {
+    [v[0] * LDU_PER_CELL, -v[1] * LDU_PER_BRICK, v[2] * LDU_PER_CELL]
+}
+
+/// Part reference line for a brick: the part origin is the centre of its top face and
+/// its long side runs along LDraw X.
+fn part_line(brick: &Brick, color: u32, part: &str) -> String {
+    let size = brick.shape.size();
+    let top = brick.orientation.rotate_f32([size[0] / 2.0, size[1], size[2] / 2.0]);
+    let position = brick.position();
+    let [x, y, z] = to_ldu([top[0] + position[0], top[1] + position[1], top[2] + position[2]]);
+
+    //Part axes in our local frame, quarter turned when the long side is along z
+    let local = if brick.shape.width >= brick.shape.depth {
+        [[1, 0, 0], [0, 1, 0], [0, 0, 1]]
+    } else {
+        [[0, 0, 1], [0, 1, 0], [-1, 0, 0]]
+    };
+    let rotation = brick.orientation.matrix();
+    //Flip y on both sides to go between y up and y down
+    let flip = [1, -1, 1];
+    let mut m = [[0; 3]; 3];
+    for row in 0..3 {
+        for col in 0..3 {
+            let value: i32 = (0..3).map(|k| rotation[row][k] * local[k][col]).sum();
+            m[row][col] = flip[row] * value * flip[col];
+        }
+    }
+    format!(
+        "1 {} {} {} {} {} {} {} {} {} {} {} {} {} {}.dat",
+        color, x, y, z,
+        m[0][0], m[0][1], m[0][2], m[1][0], m[1][1], m[1][2], m[2][0], m[2][1], m[2][2],
+        part,
+    )
+}
+
+/// The world as an LDraw model, one STEP per layer from the bottom up. Colours without an
+/// LDraw code get the nearest LEGO colour; shapes without a part become comments.
+pub fn to_ldraw(world: &World, name: &str) -> String {
+    let lego = lego::lego_palette();
+    let quantizer = Quantizer::new(&lego, DeltaE::Ciede2000);
+    let mut objects: Vec<Brick> = world.objects().map(|(_, object)| object).collect();
+    objects.sort_by_key(|object| (object.origin[1], object.origin[2], object.origin[0]));
+
+    let mut ldr = format!("0 {}\n0 Name: {}.ldr\n0 Author: byggeklosser\n", name, name);
+    let mut layer = objects.first().map(|object| object.origin[1]);
+    for object in &objects {
+        if Some(object.origin[1]) != layer {
+            ldr += "0 STEP\n";
+            layer = Some(object.origin[1]);
+        }
+        let color = world.palette.get(object.color).map_or(0, |color| {
+            color.ldraw_id.unwrap_or_else(|| {
+                let nearest = quantizer.nearest_linear(color.rgb);
+                lego.get(nearest).and_then(|color| color.ldraw_id).unwrap_or(0)
+            })
+        });
+        match part_id(&object.shape) {
+            Some(part) => ldr += &part_line(object, color, part),
+            None => ldr += &format!("0 // no part for {} at {:?}", object.shape.name(), object.origin),
+        }
+        ldr += "\n";
+    }
+    ldr += "0 STEP\n";
+    ldr
+}
+
+pub fn save<P: AsRef<Path>>(world: &World, path: P) -> Result<()> {
+    let path = path.as_ref();
+    let name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("model");
+    std::fs::write(path, to_ldraw(world, name)).with_context(|| format!("Cannot write {}", path.display()))
+}
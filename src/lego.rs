@@ -0,0 +1,89 @@
+use crate::palette::{srgb_to_linear, Palette};
+
+/// Solid colours from the LDraw LDConfig.ldr colour table: (LDraw code, name, sRGB hex).
+/// Rebrickable colour ids follow the same numbering.
+pub const LDRAW_COLORS: &[(u32, &str, u32)] = &[
+    (0, "Black", 0x1B2A34),
+    (1, "Blue", 0x1E5AA8),
+    (2, "Green", 0x00852B),
+    (3, "Dark Turquoise", 0x069D9F),
+    (4, "Red", 0xB40000),
+    (5, "Dark Pink", 0xD3359D),
+    (6, "Brown", 0x543324),
+    (7, "Light Gray", 0x8A928D),
+    (8, "Dark Gray", 0x545955),
+    (9, "Light Blue", 0x97CBD9),
+    (10, "Bright Green", 0x58AB41),
+    (11, "Light Turquoise", 0x00AAA4),
+    (12, "Salmon", 0xF06D61),
+    (13, "Pink", 0xF6A9BB),
+    (14, "Yellow", 0xFAC80A),
+    (15, "White", 0xF4F4F4),
+    (17, "Light Green", 0xADD9A8),
+    (18, "Light Yellow", 0xFFD67F),
+    (19, "Tan", 0xD7BA8C),
+    (20, "Light Violet", 0xAFBED6),
+    (22, "Purple", 0x671F81),
+    (23, "Dark Blue Violet", 0x0E3E9A),
+    (25, "Orange", 0xD67923),
+    (26, "Magenta", 0x901F76),
+    (27, "Lime", 0xA5CA18),
+    (28, "Dark Tan", 0x897D62),
+    (29, "Bright Pink", 0xFF9ECD),
+    (30, "Medium Lavender", 0xA06EB9),
+    (31, "Lavender", 0xCDA4DE),
+    (68, "Very Light Orange", 0xFDC383),
+    (70, "Reddish Brown", 0x5F3109),
+    (71, "Light Bluish Gray", 0x969696),
+    (72, "Dark Bluish Gray", 0x646464),
+    (73, "Medium Blue", 0x7396C8),
+    (74, "Medium Green", 0x7FC475),
+    (78, "Light Nougat", 0xFFC995),
+    (84, "Medium Nougat", 0xAA7D55),
+    (85, "Dark Purple", 0x441A91),
+    (92, "Nougat", 0xBB805A),
+    (115, "Medium Lime", 0xC7D23C),
+    (191, "Bright Light Orange", 0xFCAC00),
+    (212, "Bright Light Blue", 0x9DC3F7),
+    (226, "Bright Light Yellow", 0xFFEC6C),
+    (272, "Dark Blue", 0x19325A),
+    (288, "Dark Green", 0x00451A),
+    (308, "Dark Brown", 0x352100),
+    (320, "Dark Red", 0x720012),
+    (321, "Dark Azure", 0x469BC3),
+    (322, "Medium Azure", 0x68C3E2),
+    (323, "Light Aqua", 0xD3F2EA),
+    (326, "Yellowish Green", 0xE2F99A),
+    (330, "Olive Green", 0x77774E),
+    (378, "Sand Green", 0x708E7C),
+    (379, "Sand Blue", 0x70819A),
+    (484, "Dark Orange", 0x91501C),
+];
+
+fn hex_to_linear(hex: u32) -> [f32; 3] {
+    [
+        srgb_to_linear((hex >> 16) as u8),
+        srgb_to_linear((hex >> 8) as u8),
+        srgb_to_linear(hex as u8),
+    ]
+}
+
+/// Every solid LDraw colour, tagged with its LDraw code.
+pub fn lego_palette() -> Palette {
+    let mut palette = Palette::new();
+    for &(code, name, hex) in LDRAW_COLORS {
+        palette.add_ldraw(name, hex_to_linear(hex), code).unwrap();
+    }
+    palette
+}
+
+/// A small palette of the colours that are easy to get in bulk.
+pub fn lego_basic_palette() -> Palette {
+    let mut palette = Palette::new();
+    for &(code, name, hex) in LDRAW_COLORS {
+        if [0, 1, 2, 4, 14, 15, 19, 25, 70, 71, 72].contains(&code) {
+            palette.add_ldraw(name, hex_to_linear(hex), code).unwrap();
+        }
+    }
+    palette
+}
@@ -0,0 +1,48 @@
+//! Bricks and the worlds built from them, with picking, generators and file formats.
+//! None of it needs a GPU. The renderer and the editor app are behind the `render`
+//! feature, which is on by default.
+
+pub mod chunk;
+pub mod world;
+pub mod palette;
+pub mod orientation;
+pub mod brick;
+pub mod shape;
+pub mod stud;
+pub mod lego;
+pub mod quantize;
+pub mod world_file;
+pub mod bom;
+pub mod optimizer;
+pub mod ldraw;
+pub mod wanted_list;
+pub mod inventory;
+pub mod instructions;
+pub mod layer_plan;
+pub mod mosaic;
+pub mod heightmap;
+pub mod mesh;
+pub mod voxelize;
+pub mod mesh_export;
+pub mod gltf;
+pub mod nbt;
+pub mod schematic;
+pub mod voxel_grid;
+pub mod scene;
+pub mod diff;
+pub mod history;
+pub mod recovery;
+pub mod camera;
+pub mod mouse_picker;
+pub mod cli;
+
+#[cfg(feature = "render")]
+pub mod timelapse;
+#[cfg(feature = "render")]
+pub mod model;
+#[cfg(feature = "render")]
+pub mod texture;
+#[cfg(feature = "render")]
+pub mod render;
+#[cfg(feature = "render")]
+pub mod state;
@@ -3,14 +3,19 @@ use winit::{
     event_loop::{ControlFlow, EventLoop}
 };
 
-mod model;
-mod texture;
-mod camera;
-mod mouse_picker;
-mod state;
+use byggeklosser::{cli, lego, state};
 
 fn main() {
     env_logger::init();
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(result) = cli::run(&args) {
+        if let Err(e) = result {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let event_loop = EventLoop::new();
     let title = env!("CARGO_PKG_NAME");
     let window = winit::window::WindowBuilder::new()
@@ -22,9 +27,41 @@ fn main() {
 
     // Since main can't be async, we're going to need to block
     let mut appstate = block_on(state::State::new(&window));
+    let (world_path, inventory_path, diff_path) = match cli::editor_args(&args) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let opened = match world_path {
+        Some(path) => appstate.open_world(path),
+        //Nothing to open, but the default world may have been left unsaved
+        None => {
+            appstate.check_recovery();
+            Ok(())
+        }
+    };
+    if let Err(e) = opened {
+        eprintln!("{:?}", e);
+    }
+    if let Some(path) = inventory_path {
+        if let Err(e) = appstate.load_inventory(path) {
+            eprintln!("{:?}", e);
+        }
+    }
+    if let Some(path) = diff_path {
+        if let Err(e) = appstate.show_diff(path) {
+            eprintln!("{:?}", e);
+        }
+    }
+    window.set_title(&appstate.status());
     let mut last_render_time = std::time::Instant::now();
+    let mut modifiers = ModifiersState::empty();
 
     event_loop.run(move |event, _, control_flow| {
+        //No autosave on a panic, the world may be half changed. Every finished edit is in the
+        //journal already, unbuffered, so the next session restores up to the last one.
         *control_flow = ControlFlow::Poll;
         match event {
             Event::MainEventsCleared => window.request_redraw(),
@@ -39,15 +76,172 @@ fn main() {
                 window_id,
             } if window_id == window.id() => {
                 match event {
-                    WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                    WindowEvent::CloseRequested => {
+                        appstate.autosave();
+                        *control_flow = ControlFlow::Exit;
+                    }
                     WindowEvent::KeyboardInput { input, .. } => match input {
                         KeyboardInput {
                             state: ElementState::Pressed,
                             virtual_keycode: Some(VirtualKeyCode::Escape),
                             ..
                         } => {
+                            appstate.autosave();
                             *control_flow = ControlFlow::Exit;
                         }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::R),
+                            ..
+                        } => {
+                            if modifiers.ctrl() {
+                                appstate.tip_selected();
+                            } else {
+                                appstate.rotate_selected(modifiers.shift());
+                            }
+                        }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::T),
+                            ..
+                        } => appstate.toggle_studs(),
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::Tab),
+                            ..
+                        } => appstate.select_next_shape(),
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::X),
+                            ..
+                        } => appstate.select_next_color(),
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::L),
+                            ..
+                        } => {
+                            //Snap every colour to the nearest real LEGO colour
+                            appstate.switch_palette(lego::lego_palette());
+                        }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F2),
+                            ..
+                        } => {
+                            if let Err(e) = appstate.save_world() {
+                                eprintln!("{:?}", e);
+                            }
+                        }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F3),
+                            ..
+                        } => {
+                            if let Err(e) = appstate.export_scene() {
+                                eprintln!("{:?}", e);
+                            }
+                        }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::I),
+                            ..
+                        } => appstate.toggle_constrained(),
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::O),
+                            ..
+                        } => appstate.optimize_bricks(),
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F4),
+                            ..
+                        } => {
+                            if let Err(e) = appstate.export_ldraw() {
+                                eprintln!("{:?}", e);
+                            }
+                        }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F5),
+                            ..
+                        } => {
+                            if let Err(e) = appstate.export_instructions() {
+                                eprintln!("{:?}", e);
+                            }
+                        }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F6),
+                            ..
+                        } => {
+                            if let Err(e) = appstate.export_layer_plans() {
+                                eprintln!("{:?}", e);
+                            }
+                        }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F7),
+                            ..
+                        } => {
+                            if let Err(e) = appstate.export_stl() {
+                                eprintln!("{:?}", e);
+                            }
+                        }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F8),
+                            ..
+                        } => {
+                            if let Err(e) = appstate.export_gltf() {
+                                eprintln!("{:?}", e);
+                            }
+                        }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F9),
+                            ..
+                        } => {
+                            if let Err(e) = appstate.toggle_diff() {
+                                eprintln!("{:?}", e);
+                            }
+                        }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F10),
+                            ..
+                        } => {
+                            if let Err(e) = appstate.take_snapshot() {
+                                eprintln!("{:?}", e);
+                            }
+                        }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F11),
+                            ..
+                        } => {
+                            if let Err(e) = appstate.step_history(!modifiers.shift()) {
+                                eprintln!("{:?}", e);
+                            }
+                            window.set_title(&appstate.status());
+                        }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F12),
+                            ..
+                        } => {
+                            if let Err(e) = appstate.restore_recovery() {
+                                eprintln!("{:?}", e);
+                            }
+                        }
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::B),
+                            ..
+                        } => {
+                            if let Err(e) = appstate.export_bom() {
+                                eprintln!("{:?}", e);
+                            }
+                        }
                         _ => {}
                     },
                     WindowEvent::Resized(physical_size) => {
@@ -56,19 +250,45 @@ fn main() {
                     WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
                         appstate.resize(**new_inner_size);
                     },
+                    WindowEvent::DroppedFile(path) => {
+                        //Dropped schematics and voxel grids are opened, a picture becomes a new mosaic world
+                        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+                        let result = match extension.as_str() {
+                            "schem" | "litematic" => appstate.import_schematic(path),
+                            "binvox" | "raw" => appstate.import_voxels(path),
+                            "json" | "ron" => appstate.open_world(path),
+                            _ => appstate.import_mosaic(path),
+                        };
+                        if let Err(e) = result {
+                            eprintln!("{:?}", e);
+                        }
+                        window.set_title(&appstate.status());
+                    },
+                    WindowEvent::ModifiersChanged(new_modifiers) => {
+                        modifiers = *new_modifiers;
+                    },
                     WindowEvent::CursorMoved {position, ..}=>{
                         appstate.curr_cursor_pos = *position;
+                        appstate.update_preview();
                     }, 
                     WindowEvent::MouseInput{state, button, ..}=>{
                         if *state == ElementState::Released && *button == MouseButton::Right
                         {
                             println!("state.curr_cursor_pos {:?}", appstate.curr_cursor_pos);
-                            //select block under mouse
-                            let coord_selected_block = mouse_picker::MousePicker::get_model_coordinates_for_voxel_under_mouse( &appstate.size, &appstate.curr_cursor_pos, &appstate.camera, &appstate.projection, &appstate.obj_model);
+                            //place selected brick against the block under mouse
+                            appstate.place_selected();
                         }                       
+                        if *state == ElementState::Released && *button == MouseButton::Middle
+                        {
+                            appstate.remove_under_cursor();
+                        }
                     },
                     _ => {}
                 }
+                //Keep the selection and remaining stock in the title bar
+                if let WindowEvent::KeyboardInput { .. } | WindowEvent::MouseInput { .. } = event {
+                    window.set_title(&appstate.status());
+                }
             }
             // UPDATED!
             Event::RedrawRequested(_) => {
@@ -81,7 +301,10 @@ fn main() {
                     // Recreate the swap_chain if lost
                     Err(wgpu::SwapChainError::Lost) => appstate.resize(appstate.size),
                     // The system is out of memory, we should probably quit
-                    Err(wgpu::SwapChainError::OutOfMemory) => *control_flow = ControlFlow::Exit,
+                    Err(wgpu::SwapChainError::OutOfMemory) => {
+                        appstate.autosave();
+                        *control_flow = ControlFlow::Exit;
+                    }
                     // All other errors (Outdated, Timeout) should be resolved by the next frame
                     Err(e) => eprintln!("{:?}", e),
                 }
@@ -0,0 +1,298 @@
+use anyhow::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::palette::srgb_to_linear;
+
+/// One triangle of an imported mesh, with the colour of each corner in linear rgb if the
+/// file had any.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Triangle {
+    pub vertices: [[f32; 3]; 3],
+    pub colors: Option<[[f32; 3]; 3]>,
+}
+
+/// A triangle soup read from an OBJ or STL file.
+#[derive(Debug, Clone, Default)]
+pub struct TriangleMesh {
+    pub triangles: Vec<Triangle>,
+}
+
+impl TriangleMesh {
+    /// Smallest and largest corner of the axis aligned box around all triangles.
+    pub fn bounds(&self) -> Option<([f32; 3], [f32; 3])> {
+        let mut vertices = self.triangles.iter().flat_map(|triangle| triangle.vertices.iter());
+        let first = *vertices.next()?;
+        Some(vertices.fold((first, first), |(mut min, mut max), vertex| {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(vertex[axis]);
+                max[axis] = max[axis].max(vertex[axis]);
+            }
+            (min, max)
+        }))
+    }
+
+    /// Turn a Z up mesh, as most CAD and printing tools make them, into the Y up the world uses.
+    pub fn z_up_to_y_up(&mut self) {
+        for triangle in &mut self.triangles {
+            for vertex in &mut triangle.vertices {
+                *vertex = [vertex[0], vertex[2], -vertex[1]];
+            }
+        }
+    }
+}
+
+//Rust parses "nan" and "inf", which would poison every comparison later
+fn parse_floats(fields: &[&str], what: &str, number: usize) -> Result<Vec<f32>> {
+    fields
+        .iter()
+        .map(|field| {
+            field
+                .parse::<f32>()
+                .ok()
+                .filter(|value| value.is_finite())
+                .with_context(|| format!("Invalid {} on line {}: {:?}", what, number, field))
+        })
+        .collect()
+}
+
+/// Diffuse colours by material name from an MTL file.
+pub fn read_mtl(text: &str) -> Result<HashMap<String, [f32; 3]>> {
+    let mut materials = HashMap::new();
+    let mut current = None;
+    for (number, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.first() {
+            Some(&"newmtl") => current = fields.get(1).map(|name| name.to_string()),
+            Some(&"Kd") if fields.len() >= 4 => {
+                let rgb = parse_floats(&fields[1..4], "colour", number + 1)?;
+                if let Some(name) = &current {
+                    materials.insert(name.clone(), [rgb[0], rgb[1], rgb[2]]);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(materials)
+}
+
+/// Reads the faces of an OBJ file, fan triangulating polygons. Colours come from the
+/// `Kd` of the material in use, or from vertex colours written as `v x y z r g b`.
+/// `materials` is called with every `mtllib` name.
+pub fn read_obj<F>(text: &str, mut materials: F) -> Result<TriangleMesh>
+where
+    F: FnMut(&str) -> Result<HashMap<String, [f32; 3]>>,
+{
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let mut vertex_colors: Vec<Option<[f32; 3]>> = Vec::new();
+    let mut library: HashMap<String, [f32; 3]> = HashMap::new();
+    let mut material: Option<[f32; 3]> = None;
+    let mut mesh = TriangleMesh::default();
+
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.first() {
+            Some(&"v") => {
+                ensure!(fields.len() >= 4, "Vertex with too few coordinates on line {}", number);
+                let position = parse_floats(&fields[1..4], "vertex", number)?;
+                positions.push([position[0], position[1], position[2]]);
+                //Vertex colours are written the way they look, so sRGB
+                vertex_colors.push(if fields.len() >= 7 {
+                    let rgb = parse_floats(&fields[4..7], "vertex colour", number)?;
+                    let linear = |c: f32| srgb_to_linear((c.clamp(0.0, 1.0) * 255.0).round() as u8);
+                    Some([linear(rgb[0]), linear(rgb[1]), linear(rgb[2])])
+                } else {
+                    None
+                });
+            }
+            Some(&"mtllib") => {
+                for name in &fields[1..] {
+                    library.extend(materials(name)?);
+                }
+            }
+            Some(&"usemtl") => material = fields.get(1).and_then(|name| library.get(*name)).copied(),
+            Some(&"f") => {
+                let mut corners = Vec::new();
+                for field in &fields[1..] {
+                    //v, v/vt, v//vn or v/vt/vn, counted from 1 or from the end when negative
+                    let index: i64 = field
+                        .split('/')
+                        .next()
+                        .and_then(|index| index.parse().ok())
+                        .with_context(|| format!("Invalid face on line {}: {:?}", number, field))?;
+                    let index = if index < 0 { positions.len() as i64 + index } else { index - 1 };
+                    ensure!(
+                        index >= 0 && (index as usize) < positions.len(),
+                        "Face on line {} uses missing vertex {}",
+                        number,
+                        field
+                    );
+                    corners.push(index as usize);
+                }
+                for i in 1..corners.len().saturating_sub(1) {
+                    let indices = [corners[0], corners[i], corners[i + 1]];
+                    let colors = match material {
+                        Some(rgb) => Some([rgb; 3]),
+                        None => match (vertex_colors[indices[0]], vertex_colors[indices[1]], vertex_colors[indices[2]]) {
+                            (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+                            _ => None,
+                        },
+                    };
+                    mesh.triangles.push(Triangle {
+                        vertices: [positions[indices[0]], positions[indices[1]], positions[indices[2]]],
+                        colors,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(mesh)
+}
+
+fn read_f32(bytes: &[u8], offset: usize) -> Result<f32> {
+    let value = f32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+    ensure!(value.is_finite(), "Invalid vertex coordinate {}", value);
+    Ok(value)
+}
+
+/// Reads binary or ASCII STL. Binary files may carry a colour per triangle in the
+/// attribute word, VisCAM style: 5 bits each of red, green and blue, and bit 15 set.
+pub fn read_stl(bytes: &[u8]) -> Result<TriangleMesh> {
+    //ASCII files start with "solid", but so do the headers of some binary ones, so trust the size
+    if bytes.len() >= 84 {
+        let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+        if bytes.len() == 84 + count * 50 {
+            return read_binary_stl(&bytes[84..], count);
+        }
+    }
+    ensure!(bytes.starts_with(b"solid"), "Not an STL file");
+    read_ascii_stl(std::str::from_utf8(bytes).context("ASCII STL is not valid text")?)
+}
+
+fn read_binary_stl(bytes: &[u8], count: usize) -> Result<TriangleMesh> {
+    let mut mesh = TriangleMesh::default();
+    for (number, record) in bytes.chunks(50).take(count).enumerate() {
+        //Skip the normal, the winding is enough
+        let vertex = |i: usize| -> Result<[f32; 3]> {
+            let offset = 12 + i * 12;
+            let read = |offset| read_f32(record, offset).with_context(|| format!("Triangle {} is damaged", number + 1));
+            Ok([read(offset)?, read(offset + 4)?, read(offset + 8)?])
+        };
+        let attribute = u16::from_le_bytes([record[48], record[49]]);
+        let colors = if attribute & 0x8000 != 0 {
+            let channel = |shift: u16| srgb_to_linear((((attribute >> shift) & 0x1f) as u32 * 255 / 31) as u8);
+            let rgb = [channel(10), channel(5), channel(0)];
+            Some([rgb; 3])
+        } else {
+            None
+        };
+        let vertices = [vertex(0)?, vertex(1)?, vertex(2)?];
+        mesh.triangles.push(Triangle { vertices, colors });
+    }
+    Ok(mesh)
+}
+
+fn read_ascii_stl(text: &str) -> Result<TriangleMesh> {
+    let mut mesh = TriangleMesh::default();
+    let mut corners = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.first() {
+            Some(&"vertex") => {
+                ensure!(fields.len() >= 4, "Vertex with too few coordinates on line {}", number + 1);
+                let position = parse_floats(&fields[1..4], "vertex", number + 1)?;
+                corners.push([position[0], position[1], position[2]]);
+            }
+            Some(&"endloop") => {
+                for i in 1..corners.len().saturating_sub(1) {
+                    mesh.triangles.push(Triangle {
+                        vertices: [corners[0], corners[i], corners[i + 1]],
+                        colors: None,
+                    });
+                }
+                corners.clear();
+            }
+            _ => {}
+        }
+    }
+    Ok(mesh)
+}
+
+/// Reads `.obj` (with the `.mtl` files next to it) or `.stl`, by extension.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<TriangleMesh> {
+    let path = path.as_ref();
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "obj" => {
+            let text = std::fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+            let dir = path.parent().unwrap_or_else(|| Path::new("."));
+            read_obj(&text, |name| {
+                let mtl = dir.join(name);
+                //A missing material library only costs the colours
+                std::fs::read_to_string(&mtl).map_or_else(
+                    |e| {
+                        log::warn!("Cannot read {}: {}", mtl.display(), e);
+                        Ok(HashMap::new())
+                    },
+                    |text| read_mtl(&text),
+                )
+            })
+        }
+        "stl" => read_stl(&std::fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?),
+        _ => bail!("Unknown mesh format {:?}, expected .obj or .stl", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //A unit cube as quads, with a material for the top
+    const CUBE_OBJ: &str = "mtllib cube.mtl\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 0 1\nv 1 0 1\nv 1 1 1\nv 0 1 1\n\
+        f 1 4 3 2\nf 5 6 7 8\nf 1 2 6 5\nf 1 5 8 4\nf 2 3 7 6\nusemtl red\nf 4 8 7 3\n";
+
+    #[test]
+    fn obj_faces_are_split_and_coloured() {
+        let mut requested = Vec::new();
+        let mesh = read_obj(CUBE_OBJ, |name| {
+            requested.push(name.to_string());
+            read_mtl("newmtl red\nKd 0.8 0.0 0.0\n")
+        })
+        .unwrap();
+        assert_eq!(requested, vec!["cube.mtl"]);
+        assert_eq!(mesh.triangles.len(), 12);
+        assert_eq!(mesh.bounds(), Some(([0.0; 3], [1.0; 3])));
+        assert!(mesh.triangles[..10].iter().all(|triangle| triangle.colors.is_none()));
+        assert_eq!(mesh.triangles[11].colors, Some([[0.8, 0.0, 0.0]; 3]));
+        assert!(read_obj("v 0 0 0\nf 1 2 3\n", |_| Ok(HashMap::new())).is_err());
+    }
+
+    #[test]
+    fn binary_and_ascii_stl() {
+        let mut stl = vec![0u8; 80];
+        stl.extend_from_slice(&2u32.to_le_bytes());
+        for &attribute in &[0x8000u16 | 31 << 10, 0] {
+            stl.extend_from_slice(&[0; 12]);
+            for value in &[0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+                stl.extend_from_slice(&value.to_le_bytes());
+            }
+            stl.extend_from_slice(&attribute.to_le_bytes());
+        }
+        let mesh = read_stl(&stl).unwrap();
+        assert_eq!(mesh.triangles.len(), 2);
+        assert_eq!(mesh.triangles[0].colors, Some([[1.0, 0.0, 0.0]; 3]));
+        assert_eq!(mesh.triangles[1].colors, None);
+        assert_eq!(mesh.triangles[1].vertices, [[0.0; 3], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]);
+
+        let ascii = "solid x\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\nendloop\nendfacet\nendsolid\n";
+        let mesh = read_stl(ascii.as_bytes()).unwrap();
+        assert_eq!(mesh.triangles.len(), 1);
+        assert!(read_stl(b"not a mesh").is_err());
+        //Rust parses these, but they would poison every comparison later
+        assert!(read_stl(ascii.replace("vertex 1 0 0", "vertex nan 0 0").as_bytes()).is_err());
+        stl[84 + 12..84 + 16].copy_from_slice(&f32::INFINITY.to_le_bytes());
+        assert!(read_stl(&stl).is_err());
+    }
+}
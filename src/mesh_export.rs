@@ -0,0 +1,368 @@
+use anyhow::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::path::Path;
+
+use crate::bom::{CELL_HEIGHT_MM, CELL_WIDTH_MM};
+use crate::brick::Brick;
+use crate::palette::{linear_to_srgb, ColorId, Palette};
+use crate::shape::{self, Face};
+use crate::world::World;
+
+/// One unit per cell, as the world stores it.
+pub const CELLS: [f32; 3] = [1.0, 1.0, 1.0];
+/// Millimetres for real bricks: 8 mm stud pitch, 9.6 mm brick height.
+pub const LEGO_MM: [f32; 3] = [CELL_WIDTH_MM, CELL_HEIGHT_MM, CELL_WIDTH_MM];
+
+/// The visible surface of a world as an indexed triangle mesh. Vertices are shared
+/// between triangles of the same colour, so every vertex has one colour.
+#[derive(Debug, Clone, Default)]
+pub struct WorldMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub vertex_colors: Vec<ColorId>,
+    //Counter clockwise seen from outside
+    pub triangles: Vec<([u32; 3], ColorId)>,
+    vertex_index: HashMap<([u32; 3], ColorId), u32>,
+}
+
+//Unit squares on the sides of box shaped cells, by (axis, facing positive, plane, colour)
+type Squares = BTreeMap<(usize, bool, i64, ColorId), BTreeSet<(i64, i64)>>;
+
+impl WorldMesh {
+    /// Faces hidden behind a neighbour are left out, as in the editor. With `merge`, the
+    /// sides of box shaped parts that lie in one plane and share a colour become one
+    /// rectangle. That leaves T junctions where rectangles meet, which slicers dislike,
+    /// so meshes for printing keep one square per cell side.
+    pub fn from_world(world: &World, scale: [f32; 3], merge: bool) -> WorldMesh {
+        WorldMesh::from_objects(world, world.objects().map(|(_, object)| object), scale, merge)
+    }
+
+    /// The surface of some of the world's objects. Faces are still hidden by any neighbour.
+    pub fn from_objects<I: Iterator<Item = Brick>>(world: &World, objects: I, scale: [f32; 3], merge: bool) -> WorldMesh {
+        let mut mesh = WorldMesh::default();
+        let mut squares = Squares::new();
+        for object in objects {
+            let blocktype = object.shape.blocktype;
+            if shape::full_faces(blocktype).len() == shape::FACES.len() {
+                //Box shaped, so the surface can be built cell by cell
+                for cell in object.cells() {
+                    for &face in shape::FACES.iter() {
+                        if shape::cell_face_hidden(world, cell, face) {
+                            continue;
+                        }
+                        let n = face.normal();
+                        let axis = n.iter().position(|&c| c != 0).unwrap();
+                        let positive = n[axis] > 0;
+                        let plane = if positive { cell[axis] + 1 } else { cell[axis] };
+                        squares
+                            .entry((axis, positive, plane, object.color))
+                            .or_default()
+                            .insert((cell[(axis + 1) % 3], cell[(axis + 2) % 3]));
+                    }
+                }
+                continue;
+            }
+            for group in shape::geometry(blocktype).groups {
+                let hidden = group.cull.is_some_and(|local| {
+                    let face = Face::from_normal(object.orientation.rotate(local.normal())).unwrap();
+                    shape::face_hidden(world, &object, face)
+                });
+                if hidden {
+                    continue;
+                }
+                for triangle in &group.triangles {
+                    let corners = [
+                        shape::to_world(&object, triangle[0]),
+                        shape::to_world(&object, triangle[1]),
+                        shape::to_world(&object, triangle[2]),
+                    ];
+                    mesh.add_triangle(corners, object.color, scale);
+                }
+            }
+        }
+
+        for ((axis, positive, plane, color), mut cells) in squares {
+            while let Some(&(u, v)) = cells.iter().next() {
+                //Grow along v, then along u for as long as the whole strip is there
+                let (mut u1, mut v1) = (u + 1, v + 1);
+                if merge {
+                    while cells.contains(&(u, v1)) {
+                        v1 += 1;
+                    }
+                    while (v..v1).all(|vv| cells.contains(&(u1, vv))) {
+                        u1 += 1;
+                    }
+                }
+                for uu in u..u1 {
+                    for vv in v..v1 {
+                        cells.remove(&(uu, vv));
+                    }
+                }
+                let corner = |cu: i64, cv: i64| {
+                    let mut point = [0.0; 3];
+                    point[axis] = plane as f32;
+                    point[(axis + 1) % 3] = cu as f32;
+                    point[(axis + 2) % 3] = cv as f32;
+                    point
+                };
+                let quad = [corner(u, v), corner(u1, v), corner(u1, v1), corner(u, v1)];
+                if positive {
+                    mesh.add_triangle([quad[0], quad[1], quad[2]], color, scale);
+                    mesh.add_triangle([quad[0], quad[2], quad[3]], color, scale);
+                } else {
+                    mesh.add_triangle([quad[0], quad[2], quad[1]], color, scale);
+                    mesh.add_triangle([quad[0], quad[3], quad[2]], color, scale);
+                }
+            }
+        }
+        mesh
+    }
+
+    fn vertex(&mut self, position: [f32; 3], color: ColorId) -> u32 {
+        let key = ([position[0].to_bits(), position[1].to_bits(), position[2].to_bits()], color);
+        if let Some(&index) = self.vertex_index.get(&key) {
+            return index;
+        }
+        let index = self.positions.len() as u32;
+        self.positions.push(position);
+        self.vertex_colors.push(color);
+        self.vertex_index.insert(key, index);
+        index
+    }
+
+    fn add_triangle(&mut self, corners: [[f32; 3]; 3], color: ColorId, scale: [f32; 3]) {
+        let mut indices = [0; 3];
+        for (index, corner) in indices.iter_mut().zip(corners.iter()) {
+            //Adding 0.0 turns -0.0 into 0.0, so both weld to the same vertex
+            let position = [corner[0] * scale[0] + 0.0, corner[1] * scale[1] + 0.0, corner[2] * scale[2] + 0.0];
+            *index = self.vertex(position, color);
+        }
+        self.triangles.push((indices, color));
+    }
+
+    /// Wavefront OBJ with one material per colour, defined in `mtl_name`.
+    pub fn to_obj(&self, palette: &Palette, mtl_name: &str) -> String {
+        let mut obj = String::from("# byggeklosser\n");
+        let _ = writeln!(obj, "mtllib {}", mtl_name);
+        for p in &self.positions {
+            let _ = writeln!(obj, "v {} {} {}", p[0], p[1], p[2]);
+        }
+        let mut by_color: BTreeMap<ColorId, Vec<[u32; 3]>> = BTreeMap::new();
+        for (indices, color) in &self.triangles {
+            by_color.entry(*color).or_default().push(*indices);
+        }
+        for (color, triangles) in by_color {
+            let _ = writeln!(obj, "usemtl {}", material_name(palette, color));
+            for [a, b, c] in triangles {
+                let _ = writeln!(obj, "f {} {} {}", a + 1, b + 1, c + 1);
+            }
+        }
+        obj
+    }
+
+    pub fn to_mtl(&self, palette: &Palette) -> String {
+        let used: BTreeSet<ColorId> = self.triangles.iter().map(|(_, color)| *color).collect();
+        let mut mtl = String::from("# byggeklosser\n");
+        for color in used {
+            let rgb = palette.get(color).map(|color| color.rgb).unwrap_or([1.0, 0.0, 1.0]);
+            let _ = writeln!(mtl, "newmtl {}", material_name(palette, color));
+            let _ = writeln!(mtl, "Kd {} {} {}", rgb[0], rgb[1], rgb[2]);
+            mtl += "\n";
+        }
+        mtl
+    }
+
+    /// ASCII PLY with an sRGB colour per vertex.
+    pub fn to_ply(&self, palette: &Palette) -> String {
+        let mut ply = String::from("ply\nformat ascii 1.0\ncomment byggeklosser\n");
+        let _ = writeln!(ply, "element vertex {}", self.positions.len());
+        ply += "property float x\nproperty float y\nproperty float z\n";
+        ply += "property uchar red\nproperty uchar green\nproperty uchar blue\n";
+        let _ = writeln!(ply, "element face {}", self.triangles.len());
+        ply += "property list uchar int vertex_indices\nend_header\n";
+        for (p, color) in self.positions.iter().zip(self.vertex_colors.iter()) {
+            let [r, g, b] = srgb(palette, *color);
+            let _ = writeln!(ply, "{} {} {} {} {} {}", p[0], p[1], p[2], r, g, b);
+        }
+        for ([a, b, c], _) in &self.triangles {
+            let _ = writeln!(ply, "3 {} {} {}", a, b, c);
+        }
+        ply
+    }
+
+    /// Binary STL, turned Z up for slicers. Colours go in the attribute word, VisCAM style.
+    /// Built without `merge`, the surface is closed where parts meet face to face with full
+    /// faces, every edge shared by two triangles. It is not watertight where a part touches
+    /// a partial face, like the side of a slope or a plate, where a part's side is only partly
+    /// covered, or where parts touch along an edge only. Slicers usually repair those.
+    pub fn to_stl(&self, palette: &Palette) -> Vec<u8> {
+        let mut stl = Vec::with_capacity(84 + self.triangles.len() * 50);
+        let mut header = [0u8; 80];
+        header[..12].copy_from_slice(b"byggeklosser");
+        stl.extend_from_slice(&header);
+        stl.extend_from_slice(&(self.triangles.len() as u32).to_le_bytes());
+        let z_up = |p: [f32; 3]| [p[0], -p[2], p[1]];
+        for (indices, color) in &self.triangles {
+            let [a, b, c] = [
+                z_up(self.positions[indices[0] as usize]),
+                z_up(self.positions[indices[1] as usize]),
+                z_up(self.positions[indices[2] as usize]),
+            ];
+            for value in normal(a, b, c).iter().chain(a.iter()).chain(b.iter()).chain(c.iter()) {
+                stl.extend_from_slice(&value.to_le_bytes());
+            }
+            let [r, g, b] = srgb(palette, *color);
+            let channel = |value: u8| (value as u16 * 31 + 127) / 255;
+            let attribute = 0x8000 | channel(r) << 10 | channel(g) << 5 | channel(b);
+            stl.extend_from_slice(&attribute.to_le_bytes());
+        }
+        stl
+    }
+}
+
+/// A name for the material of a palette colour, safe in OBJ and glTF files.
+pub fn material_name(palette: &Palette, color: ColorId) -> String {
+    let name = palette.get(color).map(|color| color.name.as_str()).unwrap_or("missing");
+    let name: String = name.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect();
+    format!("color_{}_{}", color, name)
+}
+
+fn srgb(palette: &Palette, color: ColorId) -> [u8; 3] {
+    match palette.get(color) {
+        Some(color) => [
+            linear_to_srgb(color.rgb[0]),
+            linear_to_srgb(color.rgb[1]),
+            linear_to_srgb(color.rgb[2]),
+        ],
+        None => [255, 0, 255],
+    }
+}
+
+fn normal(a: [f32; 3], b: [f32; 3], c: [f32; 3]) -> [f32; 3] {
+    let u = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+    let v = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+    let n = [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]];
+    let length = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+    if length > 0.0 {
+        [n[0] / length, n[1] / length, n[2] / length]
+    } else {
+        [0.0; 3]
+    }
+}
+
+/// Writes `.obj` (with a `.mtl` next to it), `.ply` or `.stl`, by extension.
+pub fn save<P: AsRef<Path>>(world: &World, path: P, scale: [f32; 3]) -> Result<()> {
+    let path = path.as_ref();
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_lowercase();
+    let write = |path: &Path, contents: &[u8]| std::fs::write(path, contents).with_context(|| format!("Cannot write {}", path.display()));
+    match extension.as_str() {
+        "obj" => {
+            let mesh = WorldMesh::from_world(world, scale, true);
+            let mtl = path.with_extension("mtl");
+            let mtl_name = mtl.file_name().and_then(|name| name.to_str()).unwrap_or("world.mtl");
+            write(&mtl, mesh.to_mtl(&world.palette).as_bytes())?;
+            write(path, mesh.to_obj(&world.palette, mtl_name).as_bytes())
+        }
+        "ply" => write(path, WorldMesh::from_world(world, scale, true).to_ply(&world.palette).as_bytes()),
+        "stl" => write(path, &WorldMesh::from_world(world, scale, false).to_stl(&world.palette)),
+        _ => bail!("Unknown mesh format {:?}, expected .obj, .ply or .stl", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brick::BrickShape;
+    use crate::world::{Block, BlockType};
+
+    fn cube(world: &mut World, x: i64, y: i64, z: i64, color: ColorId) {
+        let block = Block { blocktype: BlockType::NORMAL, color, orientation: Default::default(), brick: None };
+        world.set_block(x, y, z, Some(block));
+    }
+
+    fn place(world: &mut World, blocktype: BlockType, width: u8, depth: u8, origin: [i64; 3]) {
+        let shape = BrickShape::new(blocktype, width, depth);
+        world.place_brick(Brick { shape, origin, orientation: Default::default(), color: 4 }).unwrap();
+    }
+
+    //How often each directed edge appears, by position as a slicer sees it
+    fn edge_counts(mesh: &crate::mesh::TriangleMesh) -> HashMap<([u32; 3], [u32; 3]), usize> {
+        let key = |p: [f32; 3]| [p[0].to_bits(), p[1].to_bits(), p[2].to_bits()];
+        let mut edges = HashMap::new();
+        for triangle in &mesh.triangles {
+            for i in 0..3 {
+                let edge = (key(triangle.vertices[i]), key(triangle.vertices[(i + 1) % 3]));
+                *edges.entry(edge).or_insert(0) += 1;
+            }
+        }
+        edges
+    }
+
+    fn signed_volume(mesh: &WorldMesh) -> f32 {
+        mesh.triangles
+            .iter()
+            .map(|(t, _)| {
+                let [a, b, c] = [mesh.positions[t[0] as usize], mesh.positions[t[1] as usize], mesh.positions[t[2] as usize]];
+                (a[0] * (b[1] * c[2] - b[2] * c[1]) - a[1] * (b[0] * c[2] - b[2] * c[0]) + a[2] * (b[0] * c[1] - b[1] * c[0])) / 6.0
+            })
+            .sum()
+    }
+
+    #[test]
+    fn stl_is_closed_where_parts_meet_face_to_face() {
+        let mut world = World::default();
+        for x in 0..3 {
+            for z in 0..2 {
+                cube(&mut world, x, 0, z, (x % 2) as ColorId);
+            }
+        }
+        //Overhangs the cubes, and a slope and a plate on their own
+        place(&mut world, BlockType::BRICK, 2, 4, [0, 1, 0]);
+        place(&mut world, BlockType::SLOPE, 2, 2, [5, 0, 0]);
+        place(&mut world, BlockType::PLATE, 2, 2, [9, 0, 0]);
+        let mesh = WorldMesh::from_world(&world, LEGO_MM, false);
+        let stl = crate::mesh::read_stl(&mesh.to_stl(&world.palette)).unwrap();
+        assert_eq!(stl.triangles.len(), mesh.triangles.len());
+        //Every edge is used by exactly two triangles, once in each direction
+        let edges = edge_counts(&stl);
+        for (&(a, b), &count) in &edges {
+            assert_eq!(count, 1, "edge {:?} {:?} is used {} times", a, b, count);
+            assert_eq!(edges.get(&(b, a)), Some(&1), "open edge {:?} {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn hidden_faces_are_left_out_and_sides_merged() {
+        let mut world = World::default();
+        cube(&mut world, 0, 0, 0, 1);
+        cube(&mut world, 1, 0, 0, 1);
+        //Ten cell sides are visible, merged into six rectangles
+        assert_eq!(WorldMesh::from_world(&world, CELLS, false).triangles.len(), 20);
+        let merged = WorldMesh::from_world(&world, LEGO_MM, true);
+        assert_eq!(merged.triangles.len(), 12);
+        assert_eq!(merged.positions.len(), 8);
+        let cell = CELL_WIDTH_MM * CELL_HEIGHT_MM * CELL_WIDTH_MM;
+        assert!((signed_volume(&merged) - 2.0 * cell).abs() < 1e-2);
+        //Meshing only one object still hides the side it shares with the other
+        let one = WorldMesh::from_objects(&world, world.objects().map(|(_, object)| object).take(1), CELLS, false);
+        assert_eq!(one.triangles.len(), 10);
+    }
+
+    #[test]
+    fn formats_list_every_vertex_and_triangle() {
+        let mut world = World::default();
+        cube(&mut world, 0, 0, 0, 0);
+        cube(&mut world, 0, 1, 0, 4);
+        let mesh = WorldMesh::from_world(&world, CELLS, true);
+        let obj = mesh.to_obj(&world.palette, "world.mtl");
+        assert_eq!(obj.lines().filter(|line| line.starts_with("v ")).count(), mesh.positions.len());
+        assert_eq!(obj.lines().filter(|line| line.starts_with("f ")).count(), mesh.triangles.len());
+        assert_eq!(obj.lines().filter(|line| line.starts_with("usemtl ")).count(), 2);
+        assert_eq!(mesh.to_mtl(&world.palette).matches("newmtl").count(), 2);
+        let ply = mesh.to_ply(&world.palette);
+        assert!(ply.contains(&format!("element vertex {}\n", mesh.positions.len())));
+        assert!(ply.contains(&format!("element face {}\n", mesh.triangles.len())));
+        assert_eq!(mesh.to_stl(&world.palette).len(), 84 + 50 * mesh.triangles.len());
+        assert!(save(&world, "world.dae", CELLS).is_err());
+    }
+}
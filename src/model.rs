@@ -1,10 +1,14 @@
 use anyhow::*;
-use wgpu::util::DeviceExt;
-use crate::texture;
-use rand::Rng;
 use std::collections::HashMap;
+use wgpu::util::DeviceExt;
 use cgmath::Vector3;
-use cgmath::Vector2;
+use crate::brick::Brick;
+use crate::chunk::Chunk;
+use crate::orientation::Orientation;
+use crate::shape;
+use crate::stud;
+use crate::palette::ColorId;
+use crate::world::{Block, BlockType, World};
 
 pub trait Vertex {
     fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a>;
@@ -74,83 +78,77 @@ pub struct Mesh {
     pub num_instances: u32,
 }
 
-#[derive(Debug)]
-pub struct World{
-    pub chunks: HashMap<[i64;3], Chunk>,
-}
-
-impl World {
-    pub fn GetBlockFromGlobalAddress(&self, x : f64, y: f64, z: f64) -> Option<&Block>
-    {
-        //Find chunk
-        let chunk_x : i64 = ((x / (CHUNKSIZE as f64)) as f64).floor() as i64;
-        let chunk_y : i64 = ((y / (CHUNKSIZE as f64)) as f64).floor() as i64;
-        let chunk_z : i64 = ((z / (CHUNKSIZE as f64)) as f64).floor() as i64;
-        println!("chunk_x {:?}", chunk_x);
-        println!("chunk_y {:?}", chunk_y);
-        println!("chunk_z {:?}", chunk_z);
-
-        let chunk = self.chunks.get(&[chunk_x, chunk_y, chunk_z]);
-        if chunk.is_none(){
-            println!("chunk not found ");
-            return None
-        }
-
-        //find block
-        let block_x : u8 = (x.floor().abs() as u64 - (chunk_x.abs() as u64 * CHUNKSIZE as u64) as u64) as u8;
-        let block_y : u8 = (y.floor().abs() as u64 - (chunk_y.abs() as u64 * CHUNKSIZE as u64) as u64) as u8;
-        let block_z : u8 = (z.floor().abs() as u64 - (chunk_z.abs() as u64 * CHUNKSIZE as u64) as u64) as u8;
-
-        println!("block_x {:?}", block_x);
-        println!("block_y {:?}", block_y);
-        println!("block_z {:?}", block_z);
-
-        let block = chunk.unwrap().blocks.get(&[block_x, block_y, block_z]);
-
-        block
-    }
-}
-
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
-pub enum BlockType {
-    NORMAL,
+//Side length of the filled cube in the demo chunk
+const DEMO_SIZE: u8 = 3;
+
+/// How an instance stands out, for building instructions and world diffs.
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub enum Shade {
+    #[default]
+    Normal = 0,
+    //Parts added in the current step
+    Highlight = 1,
+    //Parts from earlier steps
+    Dim = 2,
+    //Diffs: green, red see-through ghosts and yellow
+    Added = 3,
+    Removed = 4,
+    Changed = 5,
 }
 
 #[derive(Debug)]
-pub struct Block {
-    pub blocktype : BlockType,
-    pub color: [f32; 3]
+pub struct Instance {
+    position: cgmath::Vector3<f32>,
+    //Size in cells, bricks stretch the unit cube over their footprint
+    scale: cgmath::Vector3<f32>,
+    color: ColorId,
+    rotation: cgmath::Quaternion<f32>,
+    shade: Shade,
 }
 
-#[derive(Debug)]
-pub struct Chunk {
-    pub blocks: HashMap<[u8;3], Block>,
-}
+impl Instance {
+    /// An instance filling the cells from `origin` after rotating a `size` box by `orientation`.
+    pub fn new(origin: [i64; 3], size: [f32; 3], orientation: Orientation, color: ColorId) -> Self {
+        let min = orientation.min_corner(size);
+        Instance {
+            position: cgmath::Vector3::new(
+                origin[0] as f32 - min[0],
+                origin[1] as f32 - min[1],
+                origin[2] as f32 - min[2],
+            ),
+            scale: cgmath::Vector3::from(size),
+            color,
+            rotation: orientation.quaternion(),
+            shade: Shade::Normal,
+        }
+    }
 
-const CHUNKSIZE: u8 = 3;
+    /// An unrotated, unscaled instance.
+    pub fn at(position: [f32; 3], color: ColorId) -> Self {
+        Instance {
+            position: cgmath::Vector3::from(position),
+            scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+            color,
+            rotation: Orientation::default().quaternion(),
+            shade: Shade::Normal,
+        }
+    }
 
-const CUBE_INDICES: &[u16] = &[
-    0, 1, 2, 2, 3, 0, // top
-    4, 5, 6, 6, 7, 4, // bottom
-    8, 9, 10, 10, 11, 8, // right
-    12, 13, 14, 14, 15, 12, // left
-    16, 17, 18, 18, 19, 16, // front
-    20, 21, 22, 22, 23, 20, // back
-];
+    pub fn with_shade(self, shade: Shade) -> Self {
+        Instance { shade, ..self }
+    }
 
-#[derive(Debug)]
-pub struct Instance {
-    position: cgmath::Vector3<f32>,
-    color: cgmath::Vector3<f32>,
-    //rotation: cgmath::Quaternion<f32>,
-}
+    pub fn from_brick(brick: &Brick) -> Self {
+        Instance::new(brick.origin, brick.shape.size(), brick.orientation, brick.color)
+    }
 
-impl Instance {
     pub fn to_raw(&self) -> InstanceRaw {
         InstanceRaw {
-            model: cgmath::Matrix4::from_translation(self.position).into(),
-            color: [self.color[0], self.color[1], self.color[2]]
-                //* cgmath::Matrix4::from(self.rotation),
+            model: (cgmath::Matrix4::from_translation(self.position)
+                * cgmath::Matrix4::from(self.rotation)
+                * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)).into(),
+            color: self.color as u32,
+            shade: self.shade as u32,
         }
     }
 }
@@ -159,7 +157,9 @@ impl Instance {
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct InstanceRaw {
     model: [[f32; 4]; 4],
-    color: [f32; 3]
+    //index into the palette uniform
+    color: u32,
+    shade: u32,
 }
 
 impl InstanceRaw {
@@ -200,33 +200,61 @@ impl InstanceRaw {
                 wgpu::VertexAttributeDescriptor {
                     offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
                     shader_location: 9,
-                    format: wgpu::VertexFormat::Float3,
-                },                
+                    format: wgpu::VertexFormat::Uint,
+                },
+                //shade
+                wgpu::VertexAttributeDescriptor {
+                    offset: (mem::size_of::<[f32; 16]>() + mem::size_of::<u32>()) as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Uint,
+                },
             ],
         }
     }
 }
 
+/// Studs on exposed top faces. Only the studs near the camera are uploaded.
+#[derive(Debug)]
+pub struct StudMesh {
+    pub vertex_buffer: wgpu::Buffer,
+    pub index_buffer: wgpu::Buffer,
+    pub num_indexes: u32,
+    pub instances_buffer: Option<wgpu::Buffer>,
+    pub num_instances: u32,
+    //Every exposed stud in the world
+    studs: Vec<([f32; 3], ColorId, Shade)>,
+    //Camera position the uploaded studs were picked for
+    lod_center: Option<cgmath::Point3<f32>>,
+}
+
 #[derive(Debug)]
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub world : World,
-
+    //The brick about to be placed, drawn on top of the world
+    pub preview: Option<Mesh>,
+    pub studs: Option<StudMesh>,
+    pub show_studs: bool,
+    //Studs further from the camera than this are left out
+    pub stud_distance: f32,
+    //Shading of the objects starting at these cells, everything else is drawn normally
+    pub shades: HashMap<[i64; 3], Shade>,
 }
 
 impl Model {
     fn build_random_chunk(&self)->Chunk
     {
         //Generate random chunk
-        let mut chunk = Chunk{ blocks : HashMap::new(),};
-        //chunk.blocks.insert( [1, 1, 1], Block{blocktype:BlockType::GRASS});
+        let mut chunk = Chunk::new(self.world.chunk_size());
+        //chunk.insert( [1, 1, 1], Block{blocktype:BlockType::GRASS});
         
-        let mut rng = rand::thread_rng();
-        for k in 0..CHUNKSIZE {
-            for l in 0..CHUNKSIZE {
-                for m in 0..CHUNKSIZE {
+        let fill = DEMO_SIZE.min(self.world.chunk_size());
+        let color = self.world.palette.find("Green").unwrap_or(0);
+        for k in 0..fill {
+            for l in 0..fill {
+                for m in 0..fill {
                     //Add block
-                    chunk.blocks.insert( [k, l, m], Block{blocktype:BlockType::NORMAL, color: [ 0.0, 1.0, 0.0]});
+                    chunk.insert( [k, l, m], Block{blocktype:BlockType::NORMAL, color, orientation: Orientation::default(), brick: None});
                 }
             }
         }
@@ -234,58 +262,16 @@ impl Model {
     }
 
 
-    fn create_vertices(&self, blocktype:BlockType) -> Vec<ModelVertex>{
-        //Build ModelVertex. Have to lookup u and v wich is dependent on QuadType. (this decides where to find in correct bitmap in blockatlas.jpg)
-        //TODO: move umap and vmap outside function and convert to closure
-        fn build_vertex(position:[i8;3])->ModelVertex
-        {
-            let pos = Vector3::new(position[0] as f32, position[1] as f32, position[2] as f32);
-            ModelVertex{position:pos}
-        }
-            
-        let mut vertex_data: Vec<ModelVertex>= Vec::new();
-        
-        // top (0, 0, 1)    
-        vertex_data.push(build_vertex([0, 0, 1]));
-        vertex_data.push(build_vertex([1, 0, 1]));
-        vertex_data.push(build_vertex([1, 1, 1]));
-        vertex_data.push(build_vertex([0, 1, 1]));
-    
-        // bottom (0, 0, -1)     
-        vertex_data.push(build_vertex([0, 1, 0]));
-        vertex_data.push(build_vertex([1, 1, 0]));
-        vertex_data.push(build_vertex([1, 0, 0]));
-        vertex_data.push(build_vertex([0, 0, 0]));
-    
-        // right (1, 0, 0)
-        vertex_data.push(build_vertex([1, 0, 0]));
-        vertex_data.push(build_vertex([1, 1, 0]));
-        vertex_data.push(build_vertex([1, 1, 1]));
-        vertex_data.push(build_vertex([1, 0, 1]));
-    
-        // left (-1, 0, 0)    
-        vertex_data.push(build_vertex([0, 0, 1]));
-        vertex_data.push(build_vertex([0, 1, 1]));
-        vertex_data.push(build_vertex([0, 1, 0]));
-        vertex_data.push(build_vertex([0, 0, 0]));
-    
-        // front (0, 1, 0)    
-        vertex_data.push(build_vertex([1, 1, 0]));
-        vertex_data.push(build_vertex([0, 1, 0]));
-        vertex_data.push(build_vertex([0, 1, 1]));
-        vertex_data.push(build_vertex([1, 1, 1]));
-    
-        // back (0, -1, 0)
-        vertex_data.push(build_vertex([1, 0, 1]));
-        vertex_data.push(build_vertex([0, 0, 1]));
-        vertex_data.push(build_vertex([0, 0, 0]));
-        vertex_data.push(build_vertex([1, 0, 0]));
-    
-        vertex_data
-    }
-    
     pub fn new()-> Result<Self>{
-        Ok(Self { meshes: Vec::new(), world: World{chunks:HashMap::new()} })
+        Ok(Self {
+            meshes: Vec::new(),
+            world: World::default(),
+            preview: None,
+            studs: None,
+            show_studs: true,
+            stud_distance: stud::STUD_LOD_DISTANCE,
+            shades: HashMap::new(),
+        })
     }
 
     pub fn load(
@@ -302,79 +288,171 @@ impl Model {
         //self.world.chunks.insert( [0, 1, 1], self.build_random_chunk());
         //self.world.chunks.insert( [1, 1, 1], self.build_random_chunk());
 
-        //Go through world and build meshes. One mesh for each blocktype
-        let mut create_mesh_and_addto_model = |blocktype| {
-            let create_instance = |x, y, z, r, g, b| {
-                let position = cgmath::Vector3 {
-                    x: x as f32,
-                    y: y as f32,
-                    z: z as f32,
-                };
-                let color = cgmath::Vector3 {
-                    x: r as f32,
-                    y: g as f32,
-                    z: b as f32,
-                };
-                Instance { position, color }
-            };
-
-            let mut instances=Vec::new();
-            for (chunkkey, chunk) in &self.world.chunks {
-                for (blockkey, block) in &chunk.blocks {
-                    if block.blocktype == blocktype
-                    {
-                        //transler til rett plass. Må ta hensyn til flere chunks.
-                        let x = (chunkkey[0] * CHUNKSIZE as i64) + blockkey[0] as i64;
-                        let y = (chunkkey[1] * CHUNKSIZE as i64) + blockkey[1] as i64;
-                        let z = (chunkkey[2] * CHUNKSIZE as i64) + blockkey[2] as i64;
-
-                        let r = block.color[0];
-                        let g = block.color[1];
-                        let b = block.color[2];
-
-                        instances.push(create_instance(x as f32, y as f32, z as f32, r as f32, g as f32, b as f32));
+        self.build_meshes(device);
+    }
+
+    /// Throw away the current meshes and rebuild them from `world`.
+    pub fn build_meshes(
+        &mut self,
+        device: &wgpu::Device,
+    ){
+        self.meshes.clear();
+
+        //Go through world and build meshes. One mesh for each part of each blocktype's
+        //surface, so faces hidden behind a neighbour can be left out per instance.
+        for &blocktype in BlockType::ALL.iter() {
+            let geometry = shape::geometry(blocktype);
+            let mut instances: Vec<Vec<Instance>> = geometry.groups.iter().map(|_| Vec::new()).collect();
+            for (_, object) in self.world.objects() {
+                if object.shape.blocktype != blocktype
+                {
+                    continue;
+                }
+                for (group, group_instances) in geometry.groups.iter().zip(instances.iter_mut()) {
+                    let hidden = group.cull.is_some_and(|local| {
+                        let face = shape::Face::from_normal(object.orientation.rotate(local.normal())).unwrap();
+                        shape::face_hidden(&self.world, &object, face)
+                    });
+                    if !hidden {
+                        group_instances.push(Instance::from_brick(&object).with_shade(self.shade_of(&object)));
                     }
                 }
             }
             //println!("gvtest instances: {:?}", instances);
-            let num_instances = instances.len() as u32;
-            if num_instances > 0
-            {
-                let vertices = self.create_vertices(blocktype);
-                let  vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Vertex Buffer"),
-                    contents: bytemuck::cast_slice(&vertices),
-                    usage: wgpu::BufferUsage::VERTEX,
-                });
-                let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Index Buffer"),
-                    contents: bytemuck::cast_slice(CUBE_INDICES),
-                    usage: wgpu::BufferUsage::INDEX,
-                });
-        
-                let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
-                let instances_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Instance Buffer"),
-                    contents: bytemuck::cast_slice(&instance_data),
-                    usage: wgpu::BufferUsage::VERTEX,
-                });
-                
-                self.meshes.push(Mesh{
-                    blocktype: blocktype, 
-                    vertex_buffer: vertex_buffer,
-                    index_buffer: index_buffer,
-                    num_indexes: CUBE_INDICES.len() as u32,
-                    instances: instances,
-                    instances_buffer: instances_buffer,
-                    //uniform_bind_group_instances: uniform_bind_group_instances,
-                    num_instances: num_instances,
-                });
+            for (group, group_instances) in geometry.groups.iter().zip(instances.into_iter()) {
+                if !group_instances.is_empty()
+                {
+                    let mesh = self.create_mesh(device, blocktype, &group.triangles, group_instances);
+                    self.meshes.push(mesh);
+                }
+            }
+        }
+
+        self.build_studs(device);
+    }
+
+    fn shade_of(&self, object: &Brick) -> Shade {
+        self.shades.get(&object.origin).copied().unwrap_or_default()
+    }
+
+    fn build_studs(&mut self, device: &wgpu::Device) {
+        let (positions, indices) = stud::stud_geometry();
+        let vertices = positions
+            .iter()
+            .map(|&position| ModelVertex { position: Vector3::from(position) })
+            .collect::<Vec<_>>();
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Stud Vertex Buffer"),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Stud Index Buffer"),
+            contents: bytemuck::cast_slice(&indices),
+            usage: wgpu::BufferUsage::INDEX,
+        });
+        //Studs take the shade of the object they sit on
+        let studs = stud::exposed_studs(&self.world)
+            .into_iter()
+            .map(|(position, color)| {
+                //Studs stand on the top face, so step back down into the cell below it
+                let cell = [position[0] as i64, (position[1] - 0.01).floor() as i64, position[2] as i64];
+                let shade = self
+                    .world
+                    .object_at(cell[0], cell[1], cell[2])
+                    .map_or(Shade::Normal, |object| self.shade_of(&object));
+                (position, color, shade)
+            })
+            .collect();
+        self.studs = Some(StudMesh {
+            vertex_buffer,
+            index_buffer,
+            num_indexes: indices.len() as u32,
+            instances_buffer: None,
+            num_instances: 0,
+            studs,
+            lod_center: None,
+        });
+    }
+
+    /// Upload the studs within `STUD_LOD_DISTANCE` of the camera. Cheap to call every frame,
+    /// the selection is only redone after the camera has moved a cell.
+    pub fn update_studs(&mut self, device: &wgpu::Device, eye: cgmath::Point3<f32>) {
+        use cgmath::MetricSpace;
+        let studs = match &mut self.studs {
+            Some(studs) => studs,
+            None => return,
+        };
+        if let Some(center) = studs.lod_center {
+            if center.distance2(eye) < 1.0 {
+                return;
             }
+        }
+        studs.lod_center = Some(eye);
+
+        let max_distance2 = self.stud_distance * self.stud_distance;
+        let instance_data = studs
+            .studs
+            .iter()
+            .filter(|(position, _, _)| cgmath::Point3::from(*position).distance2(eye) < max_distance2)
+            .map(|&(position, color, shade)| Instance::at(position, color).with_shade(shade).to_raw())
+            .collect::<Vec<_>>();
+        studs.num_instances = instance_data.len() as u32;
+        studs.instances_buffer = if instance_data.is_empty() {
+            None
+        } else {
+            Some(device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Stud Instance Buffer"),
+                contents: bytemuck::cast_slice(&instance_data),
+                usage: wgpu::BufferUsage::VERTEX,
+            }))
         };
+    }
 
-        create_mesh_and_addto_model(BlockType::NORMAL);
-        //create_mesh_and_addto_model(BlockType::DIRT);
-        //create_mesh_and_addto_model(BlockType::STONE);
+    /// Show `brick` as the placement preview, or hide the preview with `None`.
+    pub fn set_preview(&mut self, device: &wgpu::Device, brick: Option<&Brick>) {
+        self.preview = brick.map(|brick| {
+            let triangles: Vec<_> = shape::geometry(brick.shape.blocktype).triangles().copied().collect();
+            self.create_mesh(device, brick.shape.blocktype, &triangles, vec![Instance::from_brick(brick)])
+        });
+    }
+
+    fn create_mesh(&self, device: &wgpu::Device, blocktype: BlockType, triangles: &[[[f32; 3]; 3]], instances: Vec<Instance>) -> Mesh {
+        let num_instances = instances.len() as u32;
+        let vertices = triangles
+            .iter()
+            .flat_map(|triangle| triangle.iter())
+            .map(|&position| ModelVertex { position: Vector3::from(position) })
+            .collect::<Vec<_>>();
+        let indices = (0..vertices.len() as u16).collect::<Vec<_>>();
+        let  vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Vertex Buffer"),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Index Buffer"),
+            contents: bytemuck::cast_slice(&indices),
+            usage: wgpu::BufferUsage::INDEX,
+        });
+
+        let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+        let instances_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Instance Buffer"),
+            contents: bytemuck::cast_slice(&instance_data),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+        
+        Mesh{
+            blocktype,
+            vertex_buffer,
+            index_buffer,
+            num_indexes: indices.len() as u32,
+            instances,
+            instances_buffer,
+            //uniform_bind_group_instances: uniform_bind_group_instances,
+            num_instances,
+        }
     }
 }
 
@@ -436,7 +514,7 @@ where
         self.set_index_buffer(mesh.index_buffer.slice(..));
         //self.set_bind_group(0, &material.bind_group, &[]);
         //self.set_bind_group(1, &uniforms, &[]);
-        self.set_bind_group(0, &uniforms, &[]);
+        self.set_bind_group(0, uniforms, &[]);
 
         //self.set_bind_group(2, &light, &[]);
         //self.draw_indexed(0..mesh.num_elements, 0, instances);
@@ -464,5 +542,17 @@ where
             
             self.draw_mesh_instanced(mesh, /*, instances.clone()*/uniforms/*, light*/);
         }
+        if let Some(preview) = &model.preview {
+            self.draw_mesh_instanced(preview, uniforms);
+        }
+        if let (true, Some(studs)) = (model.show_studs, &model.studs) {
+            if let Some(instances_buffer) = &studs.instances_buffer {
+                self.set_vertex_buffer(0, studs.vertex_buffer.slice(..));
+                self.set_vertex_buffer(1, instances_buffer.slice(..));
+                self.set_index_buffer(studs.index_buffer.slice(..));
+                self.set_bind_group(0, uniforms, &[]);
+                self.draw_indexed(0..studs.num_indexes, 0, 0..studs.num_instances);
+            }
+        }
     }
 }
@@ -0,0 +1,208 @@
+use anyhow::*;
+use image::GenericImageView;
+use std::path::Path;
+
+use crate::bom::{CELL_HEIGHT_MM, CELL_WIDTH_MM};
+use crate::brick::{Brick, BrickShape};
+use crate::lego;
+use crate::palette::{srgb_to_linear, ColorId, Palette};
+use crate::quantize::{DeltaE, Quantizer};
+use crate::world::{BlockType, World};
+
+//Refuse mosaics with more parts than anyone would build, the picture is resized to this many pixels
+const MAX_CELLS: usize = 1 << 24;
+
+/// How the picture stands in the world.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    //Lying flat, 1x1 tiles on a baseplate seen from above, top of the picture towards -z
+    Baseplate,
+    //Standing upright in the xy plane, a wall of 1x1 bricks
+    Wall,
+}
+
+/// Turns a picture into a one part thick mosaic.
+#[derive(Debug, Clone)]
+pub struct Mosaic {
+    //Width of the mosaic in studs. The height follows from the picture.
+    pub width: u32,
+    pub palette: Palette,
+    pub metric: DeltaE,
+    //Floyd-Steinberg error diffusion, which trades blocky areas for grain
+    pub dither: bool,
+    pub layout: Layout,
+}
+
+impl Default for Mosaic {
+    fn default() -> Self {
+        Self {
+            width: 48,
+            palette: lego::lego_basic_palette(),
+            metric: DeltaE::Ciede2000,
+            dither: true,
+            layout: Layout::Baseplate,
+        }
+    }
+}
+
+impl Mosaic {
+    /// Height in parts for a picture of the given size. Bricks are taller than wide, so
+    /// a wall needs fewer rows than a baseplate for the same picture.
+    pub fn height_for(&self, image_width: u32, image_height: u32) -> u32 {
+        let rows = image_height as f32 * self.width as f32 / image_width.max(1) as f32;
+        let rows = match self.layout {
+            Layout::Baseplate => rows,
+            Layout::Wall => rows * CELL_WIDTH_MM / CELL_HEIGHT_MM,
+        };
+        (rows.round() as u32).max(1)
+    }
+
+    /// The picture resized and reduced to the palette, row major with the top row first.
+    /// Mostly transparent pixels stay empty.
+    pub fn quantize(&self, image: &image::DynamicImage) -> Result<(u32, u32, Vec<Option<ColorId>>)> {
+        ensure!(self.width > 0, "A mosaic needs to be at least one stud wide");
+        ensure!(!self.palette.is_empty(), "Cannot build a mosaic from an empty palette");
+        let (width, height) = (self.width, self.height_for(image.width(), image.height()));
+        let count = match (width as usize).checked_mul(height as usize) {
+            Some(count) if count <= MAX_CELLS => count,
+            _ => bail!("A {}x{} mosaic is too big, it may have at most {} parts", width, height, MAX_CELLS),
+        };
+        let resized = image::imageops::resize(&image.to_rgba8(), width, height, image::imageops::FilterType::Triangle);
+        let quantizer = Quantizer::new(&self.palette, self.metric);
+
+        //Linear rgb, so the diffused error adds up like light does
+        let mut pixels: Vec<[f32; 3]> = resized
+            .pixels()
+            .map(|pixel| [srgb_to_linear(pixel[0]), srgb_to_linear(pixel[1]), srgb_to_linear(pixel[2])])
+            .collect();
+        let opaque: Vec<bool> = resized.pixels().map(|pixel| pixel[3] >= 128).collect();
+
+        let mut cells = vec![None; count];
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                let i = y * width as usize + x;
+                if !opaque[i] {
+                    continue;
+                }
+                let wanted = pixels[i];
+                let clamped = [wanted[0].clamp(0.0, 1.0), wanted[1].clamp(0.0, 1.0), wanted[2].clamp(0.0, 1.0)];
+                let color = quantizer.nearest_linear(clamped);
+                cells[i] = Some(color);
+                if !self.dither {
+                    continue;
+                }
+                let got = self.palette.get(color).unwrap().rgb;
+                let error = [clamped[0] - got[0], clamped[1] - got[1], clamped[2] - got[2]];
+                let mut spread = |dx: isize, dy: usize, weight: f32| {
+                    let nx = x as isize + dx;
+                    let ny = y + dy;
+                    if nx < 0 || nx >= width as isize || ny >= height as usize {
+                        return;
+                    }
+                    let pixel = &mut pixels[ny * width as usize + nx as usize];
+                    for (channel, error) in pixel.iter_mut().zip(error.iter()) {
+                        *channel += error * weight;
+                    }
+                };
+                spread(1, 0, 7.0 / 16.0);
+                spread(-1, 1, 3.0 / 16.0);
+                spread(0, 1, 5.0 / 16.0);
+                spread(1, 1, 1.0 / 16.0);
+            }
+        }
+        Ok((width, height, cells))
+    }
+
+    /// A new world holding only the mosaic, with the mosaic palette. The picture's top
+    /// left corner is at the origin.
+    pub fn build(&self, image: &image::DynamicImage) -> Result<World> {
+        let (width, height, cells) = self.quantize(image)?;
+        let mut world = World::default();
+        world.palette = self.palette.clone();
+        let shape = match self.layout {
+            Layout::Baseplate => BrickShape::new(BlockType::TILE, 1, 1),
+            Layout::Wall => BrickShape::new(BlockType::BRICK, 1, 1),
+        };
+        for row in 0..height {
+            for column in 0..width {
+                let color = match cells[row as usize * width as usize + column as usize] {
+                    Some(color) => color,
+                    None => continue,
+                };
+                let origin = match self.layout {
+                    Layout::Baseplate => [column as i64, 0, row as i64],
+                    Layout::Wall => [column as i64, (height - 1 - row) as i64, 0],
+                };
+                world.place_brick(Brick {
+                    shape,
+                    origin,
+                    orientation: Default::default(),
+                    color,
+                })?;
+            }
+        }
+        Ok(world)
+    }
+
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<World> {
+        let path = path.as_ref();
+        let image = image::open(path).with_context(|| format!("Cannot read image {}", path.display()))?;
+        self.build(&image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::linear_to_srgb;
+
+    //A gradient with the left quarter transparent
+    fn picture(width: u32, height: u32) -> image::DynamicImage {
+        image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(width, height, |x, y| {
+            let alpha = if x < width / 4 { 0 } else { 255 };
+            image::Rgba([(x * 255 / width) as u8, (y * 255 / height) as u8, 128, alpha])
+        }))
+    }
+
+    #[test]
+    fn transparent_pixels_stay_empty() {
+        let mosaic = Mosaic { width: 16, ..Mosaic::default() };
+        let (width, height, cells) = mosaic.quantize(&picture(64, 32)).unwrap();
+        assert_eq!((width, height), (16, 8));
+        for (i, cell) in cells.iter().enumerate() {
+            assert_eq!(cell.is_none(), i % 16 < 4, "cell {}", i);
+        }
+        let world = mosaic.build(&picture(64, 32)).unwrap();
+        assert_eq!(world.objects().count(), 12 * 8);
+        assert!(world.objects().all(|(_, object)| object.shape.blocktype == BlockType::TILE && object.origin[1] == 0));
+    }
+
+    #[test]
+    fn walls_have_fewer_rows() {
+        let mosaic = Mosaic { width: 16, layout: Layout::Wall, dither: false, ..Mosaic::default() };
+        assert_eq!(mosaic.height_for(64, 32), 7);
+        let world = mosaic.build(&picture(64, 32)).unwrap();
+        let (min, max) = crate::bom::bounds(&world).unwrap();
+        assert_eq!((min, max), ([4, 0, 0], [15, 6, 0]));
+    }
+
+    #[test]
+    fn palette_colours_are_kept_without_dithering() {
+        let mosaic = Mosaic { width: 2, dither: false, ..Mosaic::default() };
+        let colors: Vec<ColorId> = vec![0, (mosaic.palette.len() - 1) as ColorId];
+        let rgb = |id: ColorId| {
+            let [r, g, b] = mosaic.palette.get(id).unwrap().rgb;
+            image::Rgba([linear_to_srgb(r), linear_to_srgb(g), linear_to_srgb(b), 255])
+        };
+        let image = image::RgbaImage::from_fn(2, 1, |x, _| rgb(colors[x as usize]));
+        let (_, _, cells) = mosaic.quantize(&image::DynamicImage::ImageRgba8(image)).unwrap();
+        assert_eq!(cells, vec![Some(colors[0]), Some(colors[1])]);
+    }
+
+    #[test]
+    fn oversized_mosaics_are_rejected() {
+        let mosaic = Mosaic { width: u32::MAX, ..Mosaic::default() };
+        assert!(mosaic.quantize(&picture(4, 4)).is_err());
+        assert!(Mosaic { width: 0, ..Mosaic::default() }.quantize(&picture(4, 4)).is_err());
+    }
+}
@@ -0,0 +1,327 @@
+use anyhow::*;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+/// A value in Minecraft's Named Binary Tag format.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    String(String),
+    //Element type id and elements, the type is kept so empty lists round trip
+    List(u8, Vec<Tag>),
+    Compound(BTreeMap<String, Tag>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+const END: u8 = 0;
+
+impl Tag {
+    pub fn id(&self) -> u8 {
+        match self {
+            Tag::Byte(_) => 1,
+            Tag::Short(_) => 2,
+            Tag::Int(_) => 3,
+            Tag::Long(_) => 4,
+            Tag::Float(_) => 5,
+            Tag::Double(_) => 6,
+            Tag::ByteArray(_) => 7,
+            Tag::String(_) => 8,
+            Tag::List(_, _) => 9,
+            Tag::Compound(_) => 10,
+            Tag::IntArray(_) => 11,
+            Tag::LongArray(_) => 12,
+        }
+    }
+
+    /// A list of compounds, the most common kind.
+    pub fn compound_list(tags: Vec<Tag>) -> Tag {
+        Tag::List(10, tags)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tag> {
+        match self {
+            Tag::Compound(map) => map.get(name),
+            _ => None,
+        }
+    }
+
+    /// A named child, with an error naming what is missing.
+    pub fn field(&self, name: &str) -> Result<&Tag> {
+        self.get(name).with_context(|| format!("Missing NBT tag {}", name))
+    }
+
+    /// Any integer tag widened to i64.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Tag::Byte(value) => Some(value as i64),
+            Tag::Short(value) => Some(value as i64),
+            Tag::Int(value) => Some(value as i64),
+            Tag::Long(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn int(&self, name: &str) -> Result<i64> {
+        self.field(name)?.as_i64().with_context(|| format!("NBT tag {} is not a number", name))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Tag::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_compound(&self) -> Option<&BTreeMap<String, Tag>> {
+        match self {
+            Tag::Compound(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Tag]> {
+        match self {
+            Tag::List(_, tags) => Some(tags),
+            _ => None,
+        }
+    }
+}
+
+/// Builds a compound from name and tag pairs.
+pub fn compound(fields: Vec<(&str, Tag)>) -> Tag {
+    Tag::Compound(fields.into_iter().map(|(name, tag)| (name.to_string(), tag)).collect())
+}
+
+//Lengths come from the file, so only allocate what is actually there
+fn read_exact<R: Read>(reader: &mut R, count: usize) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.by_ref().take(count as u64).read_to_end(&mut bytes)?;
+    ensure!(bytes.len() == count, "NBT data ends early");
+    Ok(bytes)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8> {
+    Ok(read_exact(reader, 1)?[0])
+}
+
+fn read_i16<R: Read>(reader: &mut R) -> Result<i16> {
+    let b = read_exact(reader, 2)?;
+    Ok(i16::from_be_bytes([b[0], b[1]]))
+}
+
+fn read_i32<R: Read>(reader: &mut R) -> Result<i32> {
+    let b = read_exact(reader, 4)?;
+    Ok(i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_i64<R: Read>(reader: &mut R) -> Result<i64> {
+    let b = read_exact(reader, 8)?;
+    Ok(i64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+}
+
+fn read_length<R: Read>(reader: &mut R) -> Result<usize> {
+    let length = read_i32(reader)?;
+    ensure!(length >= 0, "Negative NBT length {}", length);
+    Ok(length as usize)
+}
+
+//Java writes modified UTF-8, which only differs from UTF-8 for NUL and characters
+//outside the BMP. Those are rare in block names, so decode leniently.
+fn read_string<R: Read>(reader: &mut R) -> Result<String> {
+    let length = read_i16(reader)? as u16 as usize;
+    Ok(String::from_utf8_lossy(&read_exact(reader, length)?).into_owned())
+}
+
+fn read_payload<R: Read>(reader: &mut R, id: u8, depth: usize) -> Result<Tag> {
+    ensure!(depth < 512, "NBT nested too deep");
+    Ok(match id {
+        1 => Tag::Byte(read_u8(reader)? as i8),
+        2 => Tag::Short(read_i16(reader)?),
+        3 => Tag::Int(read_i32(reader)?),
+        4 => Tag::Long(read_i64(reader)?),
+        5 => Tag::Float(f32::from_bits(read_i32(reader)? as u32)),
+        6 => Tag::Double(f64::from_bits(read_i64(reader)? as u64)),
+        7 => {
+            let length = read_length(reader)?;
+            Tag::ByteArray(read_exact(reader, length)?.into_iter().map(|b| b as i8).collect())
+        }
+        8 => Tag::String(read_string(reader)?),
+        9 => {
+            let element = read_u8(reader)?;
+            let length = read_length(reader)?;
+            let mut tags = Vec::with_capacity(length.min(1 << 16));
+            for _ in 0..length {
+                tags.push(read_payload(reader, element, depth + 1)?);
+            }
+            Tag::List(element, tags)
+        }
+        10 => {
+            let mut map = BTreeMap::new();
+            loop {
+                let id = read_u8(reader)?;
+                if id == END {
+                    break;
+                }
+                let name = read_string(reader)?;
+                map.insert(name, read_payload(reader, id, depth + 1)?);
+            }
+            Tag::Compound(map)
+        }
+        11 => {
+            let length = read_length(reader)?;
+            let mut values = Vec::with_capacity(length.min(1 << 16));
+            for _ in 0..length {
+                values.push(read_i32(reader)?);
+            }
+            Tag::IntArray(values)
+        }
+        12 => {
+            let length = read_length(reader)?;
+            let mut values = Vec::with_capacity(length.min(1 << 16));
+            for _ in 0..length {
+                values.push(read_i64(reader)?);
+            }
+            Tag::LongArray(values)
+        }
+        _ => bail!("Unknown NBT tag type {}", id),
+    })
+}
+
+/// Reads an uncompressed NBT document: a named root tag, usually a compound.
+pub fn read<R: Read>(reader: &mut R) -> Result<(String, Tag)> {
+    let id = read_u8(reader)?;
+    ensure!(id != END, "Empty NBT document");
+    let name = read_string(reader)?;
+    Ok((name, read_payload(reader, id, 0)?))
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> Result<()> {
+    ensure!(value.len() <= u16::MAX as usize, "NBT string too long");
+    writer.write_all(&(value.len() as u16).to_be_bytes())?;
+    writer.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+fn write_payload<W: Write>(writer: &mut W, tag: &Tag) -> Result<()> {
+    match tag {
+        Tag::Byte(value) => writer.write_all(&[*value as u8])?,
+        Tag::Short(value) => writer.write_all(&value.to_be_bytes())?,
+        Tag::Int(value) => writer.write_all(&value.to_be_bytes())?,
+        Tag::Long(value) => writer.write_all(&value.to_be_bytes())?,
+        Tag::Float(value) => writer.write_all(&value.to_bits().to_be_bytes())?,
+        Tag::Double(value) => writer.write_all(&value.to_bits().to_be_bytes())?,
+        Tag::ByteArray(values) => {
+            writer.write_all(&(values.len() as i32).to_be_bytes())?;
+            let bytes: Vec<u8> = values.iter().map(|&b| b as u8).collect();
+            writer.write_all(&bytes)?;
+        }
+        Tag::String(value) => write_string(writer, value)?,
+        Tag::List(element, tags) => {
+            ensure!(tags.iter().all(|tag| tag.id() == *element), "NBT list with mixed types");
+            writer.write_all(&[if tags.is_empty() { *element } else { tags[0].id() }])?;
+            writer.write_all(&(tags.len() as i32).to_be_bytes())?;
+            for tag in tags {
+                write_payload(writer, tag)?;
+            }
+        }
+        Tag::Compound(map) => {
+            for (name, tag) in map {
+                writer.write_all(&[tag.id()])?;
+                write_string(writer, name)?;
+                write_payload(writer, tag)?;
+            }
+            writer.write_all(&[END])?;
+        }
+        Tag::IntArray(values) => {
+            writer.write_all(&(values.len() as i32).to_be_bytes())?;
+            for value in values {
+                writer.write_all(&value.to_be_bytes())?;
+            }
+        }
+        Tag::LongArray(values) => {
+            writer.write_all(&(values.len() as i32).to_be_bytes())?;
+            for value in values {
+                writer.write_all(&value.to_be_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn write<W: Write>(writer: &mut W, name: &str, tag: &Tag) -> Result<()> {
+    writer.write_all(&[tag.id()])?;
+    write_string(writer, name)?;
+    write_payload(writer, tag)
+}
+
+/// Reads a file that may be gzipped, as schematics are, or plain.
+pub fn read_file(bytes: &[u8]) -> Result<(String, Tag)> {
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut data = Vec::new();
+        flate2::read::GzDecoder::new(bytes).read_to_end(&mut data).context("Broken gzip data")?;
+        read(&mut &data[..])
+    } else {
+        read(&mut &bytes[..])
+    }
+}
+
+/// A gzipped NBT document, the way Minecraft tools expect files.
+pub fn write_file(name: &str, tag: &Tag) -> Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    write(&mut encoder, name, tag)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every_kind() -> Tag {
+        compound(vec![
+            ("byte", Tag::Byte(-3)),
+            ("short", Tag::Short(-300)),
+            ("int", Tag::Int(1 << 20)),
+            ("long", Tag::Long(-(1 << 40))),
+            ("float", Tag::Float(0.25)),
+            ("double", Tag::Double(-1.5)),
+            ("bytes", Tag::ByteArray(vec![-1, 0, 1])),
+            ("text", Tag::String("minecraft:stone".to_string())),
+            ("empty", Tag::List(3, Vec::new())),
+            ("list", Tag::compound_list(vec![compound(vec![("x", Tag::Int(1))])])),
+            ("ints", Tag::IntArray(vec![i32::MIN, 0, i32::MAX])),
+            ("longs", Tag::LongArray(vec![i64::MIN, 0, i64::MAX])),
+        ])
+    }
+
+    #[test]
+    fn tags_round_trip() {
+        let mut bytes = Vec::new();
+        write(&mut bytes, "Schematic", &every_kind()).unwrap();
+        assert_eq!(read(&mut &bytes[..]).unwrap(), ("Schematic".to_string(), every_kind()));
+        let gzipped = write_file("Schematic", &every_kind()).unwrap();
+        assert_eq!(read_file(&gzipped).unwrap().1, every_kind());
+        assert_eq!(read_file(&bytes).unwrap().1, every_kind());
+    }
+
+    #[test]
+    fn lengths_past_the_end_are_rejected() {
+        //A byte array claiming i32::MAX bytes, followed by three
+        let mut bytes = vec![7];
+        write_string(&mut bytes, "bytes").unwrap();
+        bytes.extend_from_slice(&i32::MAX.to_be_bytes());
+        bytes.extend_from_slice(&[1, 2, 3]);
+        assert!(read(&mut &bytes[..]).is_err());
+
+        let mut bytes = Vec::new();
+        write(&mut bytes, "", &every_kind()).unwrap();
+        bytes.pop();
+        assert!(read(&mut &bytes[..]).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::*;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::brick::{Brick, BrickShape, CATALOGUE};
+use crate::orientation::Orientation;
+use crate::palette::ColorId;
+use crate::world::{BlockType, World};
+
+//Area, in cells, a brick gives up for every cell of seam it lines up with the layer below
+const SEAM_PENALTY: i32 = 4;
+
+/// Covers runs of unit blocks with standard bricks.
+///
+/// Works one layer at a time. The lowest uncovered cell of a layer is always covered
+/// next, by the largest brick that fits in its colour region, less `SEAM_PENALTY` for
+/// every cell of seam that lines up with a seam in the layer below. Odd and even layers
+/// prefer bricks running along different axes, so walls end up in a running bond.
+pub struct Optimizer {
+    pub shapes: Vec<BrickShape>,
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Optimizer::new(CATALOGUE.iter().copied().filter(|shape| shape.blocktype == BlockType::BRICK).collect())
+    }
+}
+
+//Where a candidate brick would go in a layer
+#[derive(Clone, Copy)]
+struct Placement {
+    x: i64,
+    z: i64,
+    width: u8,
+    depth: u8,
+}
+
+impl Placement {
+    fn cells(&self) -> impl Iterator<Item = (i64, i64)> {
+        let Placement { x, z, width, depth } = *self;
+        (z..z + depth as i64).flat_map(move |z| (x..x + width as i64).map(move |x| (z, x)))
+    }
+
+    //Pairs of (inside, outside) cells across each side of the rectangle
+    fn boundary(&self) -> Vec<((i64, i64), (i64, i64))> {
+        let Placement { x, z, width, depth } = *self;
+        let (x1, z1) = (x + width as i64 - 1, z + depth as i64 - 1);
+        let mut pairs = Vec::new();
+        for zz in z..=z1 {
+            pairs.push(((zz, x), (zz, x - 1)));
+            pairs.push(((zz, x1), (zz, x1 + 1)));
+        }
+        for xx in x..=x1 {
+            pairs.push(((z, xx), (z - 1, xx)));
+            pairs.push(((z1, xx), (z1 + 1, xx)));
+        }
+        pairs
+    }
+}
+
+impl Optimizer {
+    /// Only brick shapes make sense here; each covers whole cells of one layer.
+    pub fn new(shapes: Vec<BrickShape>) -> Self {
+        Optimizer { shapes }
+    }
+
+    /// A copy of `world` with its unit blocks merged into bricks. Everything that is
+    /// already a brick or a special shape is kept as it is.
+    pub fn optimize(&self, world: &World) -> Result<World> {
+        let mut optimized = World::new(world.chunk_size());
+        optimized.palette = world.palette.clone();
+
+        //Cells to cover per layer, keyed (z, x) so the scan runs row by row
+        let mut layers: BTreeMap<i64, BTreeMap<(i64, i64), ColorId>> = BTreeMap::new();
+        for (id, object) in world.objects() {
+            if id.is_none() && object.shape.blocktype == BlockType::NORMAL {
+                let [x, y, z] = object.origin;
+                layers.entry(y).or_default().insert((z, x), object.color);
+            } else {
+                optimized.place_brick(object)?;
+            }
+        }
+
+        //Which brick covers each cell of the layer below
+        let mut below: HashMap<(i64, i64), usize> = HashMap::new();
+        let mut previous_y = None;
+        let mut brick_count = 0;
+        for (y, layer) in layers {
+            if previous_y != Some(y - 1) {
+                below.clear();
+            }
+            let mut remaining = layer.clone();
+            let mut current = HashMap::new();
+            let along_x = y.rem_euclid(2) == 0;
+
+            while let Some((&(z, x), &color)) = remaining.iter().next() {
+                let best = self.best_placement(x, z, color, along_x, &remaining, &layer, &below);
+                let (placement, blocktype) = match best {
+                    Some(placement) => (placement, BlockType::BRICK),
+                    //Nothing in the catalogue fits, keep the unit block
+                    None => (Placement { x, z, width: 1, depth: 1 }, BlockType::NORMAL),
+                };
+                for cell in placement.cells() {
+                    remaining.remove(&cell);
+                    current.insert(cell, brick_count);
+                }
+                brick_count += 1;
+                optimized.place_brick(Brick {
+                    shape: BrickShape::new(blocktype, placement.width, placement.depth),
+                    origin: [x, y, z],
+                    orientation: Orientation::default(),
+                    color,
+                })?;
+            }
+            below = current;
+            previous_y = Some(y);
+        }
+        Ok(optimized)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn best_placement(
+        &self,
+        x: i64,
+        z: i64,
+        color: ColorId,
+        along_x: bool,
+        remaining: &BTreeMap<(i64, i64), ColorId>,
+        layer: &BTreeMap<(i64, i64), ColorId>,
+        below: &HashMap<(i64, i64), usize>,
+    ) -> Option<Placement> {
+        let mut best: Option<(i32, Placement)> = None;
+        for shape in &self.shapes {
+            for &(width, depth) in &[(shape.width, shape.depth), (shape.depth, shape.width)] {
+                let placement = Placement { x, z, width, depth };
+                if !placement.cells().all(|cell| remaining.get(&cell) == Some(&color)) {
+                    continue;
+                }
+                //A seam lines up when both sides of our edge sit on different bricks below
+                let aligned = placement
+                    .boundary()
+                    .into_iter()
+                    .filter(|(inside, outside)| {
+                        layer.contains_key(outside)
+                            && match (below.get(inside), below.get(outside)) {
+                                (Some(a), Some(b)) => a != b,
+                                _ => false,
+                            }
+                    })
+                    .count() as i32;
+                let area = width as i32 * depth as i32;
+                let runs_along_x = width > depth;
+                let bonus = if width != depth && runs_along_x == along_x { 1 } else { 0 };
+                let score = 2 * (area - SEAM_PENALTY * aligned) + bonus;
+                if best.is_none_or(|(best_score, _)| score > best_score) {
+                    best = Some((score, placement));
+                }
+            }
+        }
+        best.map(|(_, placement)| placement)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::Block;
+
+    fn unit(world: &mut World, x: i64, y: i64, z: i64, color: ColorId) {
+        let block = Block { blocktype: BlockType::NORMAL, color, orientation: Orientation::default(), brick: None };
+        world.set_block(x, y, z, Some(block));
+    }
+
+    fn cells(world: &World) -> Vec<([i64; 3], ColorId)> {
+        let mut cells: Vec<_> = world.iter_blocks().map(|(position, block)| (position, block.color)).collect();
+        cells.sort_unstable();
+        cells
+    }
+
+    #[test]
+    fn every_cell_keeps_its_colour() {
+        let mut world = World::default();
+        for y in 0..4 {
+            for x in 0..12 {
+                for z in 0..2 {
+                    unit(&mut world, x, y, z, if x < 9 { 1 } else { 4 });
+                }
+            }
+        }
+        world.set_block(5, 0, 0, None);
+        let optimized = Optimizer::default().optimize(&world).unwrap();
+        assert_eq!(cells(&optimized), cells(&world));
+        assert!(optimized.objects().count() * 4 < world.objects().count());
+    }
+
+    #[test]
+    fn bricks_and_other_shapes_are_kept() {
+        let mut world = World::default();
+        let object = |blocktype, width, depth, origin, color| Brick {
+            shape: BrickShape::new(blocktype, width, depth),
+            origin,
+            orientation: Orientation::default(),
+            color,
+        };
+        let slope = object(BlockType::SLOPE, 2, 2, [0, 1, 0], 2);
+        let brick = object(BlockType::BRICK, 1, 2, [3, 0, 0], 3);
+        world.place_brick(slope).unwrap();
+        world.place_brick(brick).unwrap();
+        unit(&mut world, 0, 0, 0, 1);
+        unit(&mut world, 1, 0, 0, 1);
+        let optimized = Optimizer::default().optimize(&world).unwrap();
+        let objects: Vec<Brick> = optimized.objects().map(|(_, object)| object).collect();
+        assert!(objects.contains(&slope) && objects.contains(&brick));
+        assert_eq!(objects.len(), 3);
+        assert_eq!(cells(&optimized), cells(&world));
+    }
+
+    #[test]
+    fn seams_do_not_line_up_between_layers() {
+        let mut world = World::default();
+        for y in 0..4 {
+            for x in 0..10 {
+                unit(&mut world, x, y, 0, 1);
+            }
+        }
+        let optimized = Optimizer::default().optimize(&world).unwrap();
+        //Where a brick starts inside the wall there is a seam
+        let seams = |y: i64| -> Vec<i64> {
+            optimized
+                .objects()
+                .map(|(_, object)| object.origin)
+                .filter(|origin| origin[1] == y && origin[0] > 0)
+                .map(|origin| origin[0])
+                .collect()
+        };
+        for y in 1..4 {
+            assert!(!seams(y).is_empty());
+            assert!(seams(y).iter().all(|x| !seams(y - 1).contains(x)), "layer {} {:?} on {:?}", y, seams(y), seams(y - 1));
+        }
+    }
+}
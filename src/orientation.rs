@@ -0,0 +1,231 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// One of the 24 rotations that map the grid axes onto themselves.
+///
+/// Stored as `up * 4 + turn`: `up` says where the local +Y axis points
+/// (+Y, -Y, +X, -X, +Z, -Z) and `turn` is the number of quarter turns about local +Y
+/// applied first.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct Orientation(u8);
+
+pub const ORIENTATION_COUNT: u8 = 24;
+
+type Matrix = [[i32; 3]; 3];
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m = [[0; 3]; 3];
+    for row in 0..3 {
+        for col in 0..3 {
+            m[row][col] = (0..3).map(|k| a[row][k] * b[k][col]).sum();
+        }
+    }
+    m
+}
+
+//Quarter turns about the world axes
+const QUARTER_X: Matrix = [[1, 0, 0], [0, 0, -1], [0, 1, 0]];
+const QUARTER_Y: Matrix = [[0, 0, 1], [0, 1, 0], [-1, 0, 0]];
+const IDENTITY: Matrix = [[1, 0, 0], [0, 1, 0], [0, 0, 1]];
+
+impl Orientation {
+    pub fn new(index: u8) -> Option<Self> {
+        if index < ORIENTATION_COUNT {
+            Some(Orientation(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn index(&self) -> u8 {
+        self.0
+    }
+
+    pub fn all() -> impl Iterator<Item = Orientation> {
+        (0..ORIENTATION_COUNT).map(Orientation)
+    }
+
+    /// Rotation matrix in row major order, mapping local to world directions.
+    pub fn matrix(&self) -> Matrix {
+        let up: Matrix = match self.0 / 4 {
+            0 => IDENTITY,
+            1 => [[1, 0, 0], [0, -1, 0], [0, 0, -1]],
+            2 => [[0, 1, 0], [-1, 0, 0], [0, 0, 1]],
+            3 => [[0, -1, 0], [1, 0, 0], [0, 0, 1]],
+            4 => [[1, 0, 0], [0, 0, -1], [0, 1, 0]],
+            _ => [[1, 0, 0], [0, 0, 1], [0, -1, 0]],
+        };
+        let mut turn = IDENTITY;
+        for _ in 0..self.0 % 4 {
+            turn = multiply(&QUARTER_Y, &turn);
+        }
+        multiply(&up, &turn)
+    }
+
+    fn from_matrix(matrix: &Matrix) -> Orientation {
+        Orientation::all()
+            .find(|orientation| orientation.matrix() == *matrix)
+            .expect("not an axis aligned rotation")
+    }
+
+    /// This orientation followed by a quarter turn about the world Y axis.
+    pub fn rotated_y(&self) -> Orientation {
+        Orientation::from_matrix(&multiply(&QUARTER_Y, &self.matrix()))
+    }
+
+    /// This orientation followed by a quarter turn the other way about world Y.
+    pub fn rotated_y_back(&self) -> Orientation {
+        self.rotated_y().rotated_y().rotated_y()
+    }
+
+    /// This orientation followed by a quarter turn about the world X axis.
+    pub fn rotated_x(&self) -> Orientation {
+        Orientation::from_matrix(&multiply(&QUARTER_X, &self.matrix()))
+    }
+
+    pub fn inverse(&self) -> Orientation {
+        let m = self.matrix();
+        let mut transposed = [[0; 3]; 3];
+        for row in 0..3 {
+            for col in 0..3 {
+                transposed[row][col] = m[col][row];
+            }
+        }
+        Orientation::from_matrix(&transposed)
+    }
+
+    pub fn rotate(&self, v: [i64; 3]) -> [i64; 3] {
+        let m = self.matrix();
+        let mut out = [0; 3];
+        for row in 0..3 {
+            out[row] = (0..3).map(|k| m[row][k] as i64 * v[k]).sum();
+        }
+        out
+    }
+
+    pub fn rotate_f32(&self, v: [f32; 3]) -> [f32; 3] {
+        let m = self.matrix();
+        let mut out = [0.0; 3];
+        for row in 0..3 {
+            out[row] = (0..3).map(|k| m[row][k] as f32 * v[k]).sum();
+        }
+        out
+    }
+
+    /// World direction of the local +Y axis.
+    pub fn up(&self) -> [i64; 3] {
+        self.rotate([0, 1, 0])
+    }
+
+    pub fn quaternion(&self) -> cgmath::Quaternion<f32> {
+        let m = self.matrix();
+        //cgmath matrices are column major
+        cgmath::Quaternion::from(cgmath::Matrix3::new(
+            m[0][0] as f32, m[1][0] as f32, m[2][0] as f32,
+            m[0][1] as f32, m[1][1] as f32, m[2][1] as f32,
+            m[0][2] as f32, m[1][2] as f32, m[2][2] as f32,
+        ))
+    }
+
+    /// Where a local box `[0, size]` ends up after rotation: its lowest corner in world space.
+    pub fn min_corner(&self, size: [f32; 3]) -> [f32; 3] {
+        let mut min = [f32::MAX; 3];
+        for &x in &[0.0, size[0]] {
+            for &y in &[0.0, size[1]] {
+                for &z in &[0.0, size[2]] {
+                    let corner = self.rotate_f32([x, y, z]);
+                    for axis in 0..3 {
+                        min[axis] = min[axis].min(corner[axis]);
+                    }
+                }
+            }
+        }
+        min
+    }
+}
+
+//Text formats store the index, which must name one of the 24 rotations
+impl TryFrom<u8> for Orientation {
+    type Error = String;
+
+    fn try_from(index: u8) -> Result<Self, String> {
+        Orientation::new(index).ok_or_else(|| format!("Invalid orientation {}", index))
+    }
+}
+
+impl From<Orientation> for u8 {
+    fn from(orientation: Orientation) -> u8 {
+        orientation.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Rotation;
+    use std::collections::HashSet;
+
+    fn determinant(m: &Matrix) -> i32 {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    #[test]
+    fn matrices_are_the_24_rotations() {
+        let matrices: HashSet<Matrix> = Orientation::all().map(|orientation| orientation.matrix()).collect();
+        assert_eq!(matrices.len(), 24);
+        for matrix in &matrices {
+            assert_eq!(determinant(matrix), 1);
+            let transposed = Orientation::from_matrix(matrix).inverse().matrix();
+            assert_eq!(multiply(matrix, &transposed), IDENTITY);
+        }
+    }
+
+    #[test]
+    fn up_follows_the_index() {
+        let ups = [[0, 1, 0], [0, -1, 0], [1, 0, 0], [-1, 0, 0], [0, 0, 1], [0, 0, -1]];
+        for orientation in Orientation::all() {
+            assert_eq!(orientation.up(), ups[orientation.index() as usize / 4]);
+        }
+    }
+
+    #[test]
+    fn quarter_turns_come_back() {
+        for orientation in Orientation::all() {
+            let turned = orientation.rotated_y().rotated_y().rotated_y().rotated_y();
+            assert_eq!(turned, orientation);
+            assert_eq!(orientation.rotated_y().rotated_y_back(), orientation);
+            assert_eq!(orientation.inverse().rotate(orientation.rotate([1, 2, 3])), [1, 2, 3]);
+        }
+        //Turns about X and Y reach every orientation
+        let mut seen = HashSet::new();
+        let mut stack = vec![Orientation::default()];
+        while let Some(orientation) = stack.pop() {
+            if seen.insert(orientation) {
+                stack.push(orientation.rotated_x());
+                stack.push(orientation.rotated_y());
+            }
+        }
+        assert_eq!(seen.len(), 24);
+    }
+
+    #[test]
+    fn quaternion_matches_matrix() {
+        for orientation in Orientation::all() {
+            let rotated = orientation.quaternion().rotate_vector(cgmath::Vector3::new(0.3, 0.5, 0.7));
+            let expected = orientation.rotate_f32([0.3, 0.5, 0.7]);
+            assert!((rotated.x - expected[0]).abs() < 1e-5, "{:?}", orientation);
+            assert!((rotated.y - expected[1]).abs() < 1e-5, "{:?}", orientation);
+            assert!((rotated.z - expected[2]).abs() < 1e-5, "{:?}", orientation);
+        }
+    }
+
+    #[test]
+    fn min_corner_of_a_turned_box() {
+        let turned = Orientation::default().rotated_y();
+        assert_eq!(turned.min_corner([2.0, 1.0, 4.0]), [0.0, 0.0, -2.0]);
+        assert_eq!(Orientation::default().min_corner([2.0, 1.0, 4.0]), [0.0, 0.0, 0.0]);
+        assert!(Orientation::try_from(ORIENTATION_COUNT).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// Index of a colour in a `Palette`.
+pub type ColorId = u16;
+
+/// The shader keeps the palette in a fixed size uniform array.
+pub const MAX_PALETTE_COLORS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaletteColor {
+    pub name: String,
+    //Linear rgb, 0.0 - 1.0
+    pub rgb: [f32; 3],
+    //Colour code in the LDraw colour table, for palettes of real brick colours
+    pub ldraw_id: Option<u32>,
+}
+
+/// The named colours a world is built from. Blocks only store an index into it,
+/// so changing an entry recolours every block using it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<PaletteColor>", into = "Vec<PaletteColor>")]
+pub struct Palette {
+    colors: Vec<PaletteColor>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        let mut palette = Palette::new();
+        for (name, rgb) in &[
+            ("Green", [0.0, 1.0, 0.0]),
+            ("White", [1.0, 1.0, 1.0]),
+            ("Black", [0.05, 0.05, 0.05]),
+            ("Gray", [0.5, 0.5, 0.5]),
+            ("Red", [1.0, 0.0, 0.0]),
+            ("Blue", [0.0, 0.0, 1.0]),
+            ("Yellow", [1.0, 1.0, 0.0]),
+        ] {
+            palette.add(name, *rgb).unwrap();
+        }
+        palette
+    }
+}
+
+impl Palette {
+    pub fn new() -> Self {
+        Self { colors: Vec::new() }
+    }
+
+    pub fn add(&mut self, name: &str, rgb: [f32; 3]) -> Result<ColorId> {
+        self.push(PaletteColor { name: name.to_string(), rgb, ldraw_id: None })
+    }
+
+    pub fn add_ldraw(&mut self, name: &str, rgb: [f32; 3], ldraw_id: u32) -> Result<ColorId> {
+        self.push(PaletteColor { name: name.to_string(), rgb, ldraw_id: Some(ldraw_id) })
+    }
+
+    fn push(&mut self, color: PaletteColor) -> Result<ColorId> {
+        if self.colors.len() >= MAX_PALETTE_COLORS {
+            bail!("Palette is full ({} colours)", MAX_PALETTE_COLORS);
+        }
+        check_rgb(&color.name, color.rgb)?;
+        self.colors.push(color);
+        Ok((self.colors.len() - 1) as ColorId)
+    }
+
+    pub fn get(&self, id: ColorId) -> Option<&PaletteColor> {
+        self.colors.get(id as usize)
+    }
+
+    pub fn set_rgb(&mut self, id: ColorId, rgb: [f32; 3]) -> Result<()> {
+        let color = self
+            .colors
+            .get_mut(id as usize)
+            .with_context(|| format!("No palette colour {}", id))?;
+        check_rgb(&color.name, rgb)?;
+        color.rgb = rgb;
+        Ok(())
+    }
+
+    pub fn find_ldraw(&self, ldraw_id: u32) -> Option<ColorId> {
+        self.colors
+            .iter()
+            .position(|color| color.ldraw_id == Some(ldraw_id))
+            .map(|id| id as ColorId)
+    }
+
+    pub fn find(&self, name: &str) -> Option<ColorId> {
+        self.colors
+            .iter()
+            .position(|color| color.name.eq_ignore_ascii_case(name))
+            .map(|id| id as ColorId)
+    }
+
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ColorId, &PaletteColor)> + '_ {
+        self.colors
+            .iter()
+            .enumerate()
+            .map(|(id, color)| (id as ColorId, color))
+    }
+
+    /// The palette as the vec4 array the shader expects, padded to `MAX_PALETTE_COLORS`.
+    pub fn to_raw(&self) -> Vec<[f32; 4]> {
+        let mut raw = vec![[1.0, 0.0, 1.0, 1.0]; MAX_PALETTE_COLORS];
+        for (slot, color) in raw.iter_mut().zip(self.colors.iter()) {
+            *slot = [color.rgb[0], color.rgb[1], color.rgb[2], 1.0];
+        }
+        raw
+    }
+}
+
+//Colours are compared by distance, which means nothing for NaN or infinite channels
+fn check_rgb(name: &str, rgb: [f32; 3]) -> Result<()> {
+    ensure!(rgb.iter().all(|channel| channel.is_finite()), "Colour {} has an invalid value {:?}", name, rgb);
+    Ok(())
+}
+
+//Palettes are written as their list of colours and checked when read
+impl TryFrom<Vec<PaletteColor>> for Palette {
+    type Error = Error;
+
+    fn try_from(colors: Vec<PaletteColor>) -> Result<Self> {
+        ensure!(!colors.is_empty(), "Palette has no colours");
+        ensure!(colors.len() <= MAX_PALETTE_COLORS, "Palette has {} colours, at most {} fit", colors.len(), MAX_PALETTE_COLORS);
+        for color in &colors {
+            check_rgb(&color.name, color.rgb)?;
+        }
+        Ok(Palette { colors })
+    }
+}
+
+impl From<Palette> for Vec<PaletteColor> {
+    fn from(palette: Palette) -> Self {
+        palette.colors
+    }
+}
+
+pub fn srgb_to_linear(value: u8) -> f32 {
+    let c = value as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(value: f32) -> u8 {
+    let c = value.clamp(0.0, 1.0);
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_palettes_are_rejected() {
+        let color = |rgb| PaletteColor { name: "Test".to_string(), rgb, ldraw_id: None };
+        assert!(Palette::try_from(Vec::new()).is_err());
+        assert!(Palette::try_from(vec![color([0.5, f32::NAN, 0.5])]).is_err());
+        assert!(Palette::try_from(vec![color([0.5, 0.5, 0.5]); MAX_PALETTE_COLORS + 1]).is_err());
+        assert!(Palette::try_from(vec![color([0.5, 0.5, 0.5])]).is_ok());
+
+        let mut palette = Palette::default();
+        assert!(palette.add("Glow", [f32::INFINITY, 0.0, 0.0]).is_err());
+        assert!(palette.set_rgb(0, [0.0, f32::NAN, 0.0]).is_err());
+        assert_eq!(palette.get(0).unwrap().rgb, [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn srgb_round_trips() {
+        for value in 0..=255 {
+            assert_eq!(linear_to_srgb(srgb_to_linear(value)), value);
+        }
+        assert_eq!(linear_to_srgb(-1.0), 0);
+        assert_eq!(linear_to_srgb(2.0), 255);
+    }
+}
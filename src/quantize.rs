@@ -0,0 +1,168 @@
+use crate::palette::{srgb_to_linear, ColorId, Palette};
+use crate::world::World;
+
+/// How the distance between two CIELAB colours is measured.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum DeltaE {
+    //Plain euclidean distance in Lab
+    Cie76,
+    //Corrects Lab's poor uniformity in the blues and for saturated colours. Slower.
+    Ciede2000,
+}
+
+/// Linear rgb to CIELAB (D65 white point).
+pub fn linear_rgb_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb;
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.119192 * g + 0.9503041 * b) / 1.08883;
+
+    fn f(t: f32) -> f32 {
+        const DELTA: f32 = 6.0 / 29.0;
+        if t > DELTA * DELTA * DELTA {
+            t.cbrt()
+        } else {
+            t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+        }
+    }
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+pub fn srgb8_to_lab(rgb: [u8; 3]) -> [f32; 3] {
+    linear_rgb_to_lab([
+        srgb_to_linear(rgb[0]),
+        srgb_to_linear(rgb[1]),
+        srgb_to_linear(rgb[2]),
+    ])
+}
+
+pub fn delta_e(metric: DeltaE, lab1: [f32; 3], lab2: [f32; 3]) -> f32 {
+    match metric {
+        DeltaE::Cie76 => {
+            let dl = lab1[0] - lab2[0];
+            let da = lab1[1] - lab2[1];
+            let db = lab1[2] - lab2[2];
+            (dl * dl + da * da + db * db).sqrt()
+        }
+        DeltaE::Ciede2000 => ciede2000(lab1, lab2),
+    }
+}
+
+//Sharma, Wu, Dalal: The CIEDE2000 Color-Difference Formula (2005)
+fn ciede2000(lab1: [f32; 3], lab2: [f32; 3]) -> f32 {
+    use std::f64::consts::PI;
+    let (l1, a1, b1) = (lab1[0] as f64, lab1[1] as f64, lab1[2] as f64);
+    let (l2, a2, b2) = (lab2[0] as f64, lab2[1] as f64, lab2[2] as f64);
+
+    let c_mean = ((a1 * a1 + b1 * b1).sqrt() + (a2 * a2 + b2 * b2).sqrt()) / 2.0;
+    let c_mean7 = c_mean.powi(7);
+    let g = 0.5 * (1.0 - (c_mean7 / (c_mean7 + 25f64.powi(7))).sqrt());
+    let a1p = (1.0 + g) * a1;
+    let a2p = (1.0 + g) * a2;
+    let c1p = (a1p * a1p + b1 * b1).sqrt();
+    let c2p = (a2p * a2p + b2 * b2).sqrt();
+
+    let hue = |b: f64, a: f64| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            let h = b.atan2(a).to_degrees();
+            if h < 0.0 { h + 360.0 } else { h }
+        }
+    };
+    let h1p = hue(b1, a1p);
+    let h2p = hue(b2, a2p);
+
+    let dlp = l2 - l1;
+    let dcp = c2p - c1p;
+    let dhp = if c1p * c2p == 0.0 {
+        0.0
+    } else if (h2p - h1p).abs() <= 180.0 {
+        h2p - h1p
+    } else if h2p - h1p > 180.0 {
+        h2p - h1p - 360.0
+    } else {
+        h2p - h1p + 360.0
+    };
+    let dhp_big = 2.0 * (c1p * c2p).sqrt() * (dhp.to_radians() / 2.0).sin();
+
+    let lp_mean = (l1 + l2) / 2.0;
+    let cp_mean = (c1p + c2p) / 2.0;
+    let hp_mean = if c1p * c2p == 0.0 {
+        h1p + h2p
+    } else if (h1p - h2p).abs() <= 180.0 {
+        (h1p + h2p) / 2.0
+    } else if h1p + h2p < 360.0 {
+        (h1p + h2p + 360.0) / 2.0
+    } else {
+        (h1p + h2p - 360.0) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (hp_mean - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * hp_mean).to_radians().cos()
+        + 0.32 * (3.0 * hp_mean + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * hp_mean - 63.0).to_radians().cos();
+    let d_theta = 30.0 * (-((hp_mean - 275.0) / 25.0).powi(2)).exp();
+    let cp_mean7 = cp_mean.powi(7);
+    let rc = 2.0 * (cp_mean7 / (cp_mean7 + 25f64.powi(7))).sqrt();
+    let lp_50 = (lp_mean - 50.0).powi(2);
+    let sl = 1.0 + 0.015 * lp_50 / (20.0 + lp_50).sqrt();
+    let sc = 1.0 + 0.045 * cp_mean;
+    let sh = 1.0 + 0.015 * cp_mean * t;
+    let rt = -(2.0 * d_theta * PI / 180.0).sin() * rc;
+
+    let dl = dlp / sl;
+    let dc = dcp / sc;
+    let dh = dhp_big / sh;
+    (dl * dl + dc * dc + dh * dh + rt * dc * dh).sqrt() as f32
+}
+
+/// Maps arbitrary colours to the perceptually closest colour of a palette.
+#[derive(Debug, Clone)]
+pub struct Quantizer {
+    colors: Vec<(ColorId, [f32; 3])>,
+    metric: DeltaE,
+}
+
+impl Quantizer {
+    pub fn new(palette: &Palette, metric: DeltaE) -> Self {
+        Self {
+            colors: palette
+                .iter()
+                .map(|(id, color)| (id, linear_rgb_to_lab(color.rgb)))
+                .collect(),
+            metric,
+        }
+    }
+
+    /// Closest palette colour to a Lab colour. Panics on an empty palette.
+    pub fn nearest_lab(&self, lab: [f32; 3]) -> ColorId {
+        self.colors
+            .iter()
+            .map(|(id, candidate)| (*id, delta_e(self.metric, lab, *candidate)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .expect("cannot quantize to an empty palette")
+            .0
+    }
+
+    pub fn nearest_linear(&self, rgb: [f32; 3]) -> ColorId {
+        self.nearest_lab(linear_rgb_to_lab(rgb))
+    }
+
+    /// Closest palette colour to an sRGB pixel, as read from an image.
+    pub fn nearest_srgb8(&self, rgb: [u8; 3]) -> ColorId {
+        self.nearest_lab(srgb8_to_lab(rgb))
+    }
+}
+
+/// Switch `world` over to `target`, replacing every block colour with the nearest colour in it.
+pub fn requantize_world(world: &mut World, target: Palette, metric: DeltaE) {
+    let quantizer = Quantizer::new(&target, metric);
+    let mut mapping = Vec::new();
+    for (_, color) in world.palette.iter() {
+        mapping.push(quantizer.nearest_linear(color.rgb));
+    }
+    world.map_colors(|color| mapping[color as usize]);
+    world.palette = target;
+}
@@ -0,0 +1,217 @@
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::brick::Brick;
+use crate::history::History;
+use crate::palette::ColorId;
+use crate::world::World;
+use crate::world_file;
+
+/// How often the whole world is written to the recovery file while there are unsaved changes.
+pub const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// One edit, as appended to the journal. Edits go on top of the last autosave.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Edit {
+    Place(Brick),
+    Remove(Brick),
+    SetColor { id: ColorId, rgb: [f32; 3] },
+}
+
+impl Edit {
+    /// Redo the edit. Edits the world already has are skipped, since a crash between
+    /// writing the autosave and emptying the journal leaves them in both.
+    pub fn apply(&self, world: &mut World) -> bool {
+        match *self {
+            Edit::Place(brick) => world.place_brick(brick).is_ok(),
+            Edit::Remove(brick) => {
+                let [x, y, z] = brick.origin;
+                world.object_at(x, y, z) == Some(brick) && world.remove_at(x, y, z)
+            }
+            Edit::SetColor { id, rgb } => world.palette.set_rgb(id, rgb).is_ok(),
+        }
+    }
+}
+
+/// The world as of the last autosave, next to the world file.
+pub fn autosave_path<P: AsRef<Path>>(world_path: P) -> PathBuf {
+    world_path.as_ref().with_extension(format!("autosave.{}", world_file::EXTENSION))
+}
+
+/// Edits since the last autosave, one JSON object per line.
+pub fn journal_path<P: AsRef<Path>>(world_path: P) -> PathBuf {
+    world_path.as_ref().with_extension("journal")
+}
+
+/// When the unsaved changes to a world file were last written, if there are any.
+pub fn pending<P: AsRef<Path>>(world_path: P) -> Option<SystemTime> {
+    let autosave = std::fs::metadata(autosave_path(&world_path)).ok()?;
+    let journal = std::fs::metadata(journal_path(&world_path)).and_then(|journal| journal.modified());
+    let autosaved = autosave.modified().ok()?;
+    Some(journal.map_or(autosaved, |journal| journal.max(autosaved)))
+}
+
+/// The world with the unsaved changes left by an earlier session, and how many journal
+/// edits were replayed onto the autosave. A last line cut short by a crash is ignored.
+pub fn recover<P: AsRef<Path>>(world_path: P) -> Result<(World, History, usize)> {
+    let (mut world, history) = world_file::load_with_history(autosave_path(&world_path))?;
+    let mut replayed = 0;
+    if let std::result::Result::Ok(journal) = File::open(journal_path(&world_path)) {
+        for line in BufReader::new(journal).lines() {
+            let edit: Edit = match serde_json::from_str(&line?) {
+                std::result::Result::Ok(edit) => edit,
+                Err(_) => break,
+            };
+            if edit.apply(&mut world) {
+                replayed += 1;
+            }
+        }
+    }
+    Ok((world, history, replayed))
+}
+
+/// Keeps the unsaved work on one world file safe from crashes: every edit is appended to
+/// a journal right away, and now and then the whole world is autosaved and the journal
+/// started over. Saving the world removes both.
+pub struct Recovery {
+    world_path: PathBuf,
+    //Open while there are unsaved changes. Unbuffered, so a recorded edit survives a panic
+    journal: Option<File>,
+    last_autosave: Instant,
+}
+
+impl Recovery {
+    pub fn new<P: AsRef<Path>>(world_path: P) -> Self {
+        Recovery {
+            world_path: world_path.as_ref().to_path_buf(),
+            journal: None,
+            last_autosave: Instant::now(),
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    /// Write the whole world and start an empty journal. Saving goes through a temporary
+    /// file, so a crash while writing keeps the older autosave.
+    pub fn autosave(&mut self, world: &World, history: &History) -> Result<()> {
+        //Counted from the attempt, so a failing autosave is not retried every frame
+        self.last_autosave = Instant::now();
+        world_file::save_with_history(world, history, autosave_path(&self.world_path))?;
+        let journal = journal_path(&self.world_path);
+        self.journal = Some(File::create(&journal).with_context(|| format!("Cannot create {}", journal.display()))?);
+        Ok(())
+    }
+
+    /// Note an edit `world` already has. The first one after a save autosaves instead,
+    /// which gives the journal something to build on.
+    pub fn record(&mut self, edit: &Edit, world: &World, history: &History) -> Result<()> {
+        match &mut self.journal {
+            Some(journal) => {
+                writeln!(journal, "{}", serde_json::to_string(edit)?)?;
+                Ok(())
+            }
+            None => self.autosave(world, history),
+        }
+    }
+
+    /// Autosave if there are unsaved changes and the last autosave is old enough.
+    pub fn tick(&mut self, world: &World, history: &History) -> Result<()> {
+        if self.is_dirty() && self.last_autosave.elapsed() >= AUTOSAVE_INTERVAL {
+            self.autosave(world, history)?;
+        }
+        Ok(())
+    }
+
+    /// Everything is saved: remove the autosave and the journal.
+    pub fn clear(&mut self) -> Result<()> {
+        self.journal = None;
+        for path in [autosave_path(&self.world_path), journal_path(&self.world_path)].iter() {
+            if path.exists() {
+                std::fs::remove_file(path).with_context(|| format!("Cannot remove {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brick::BrickShape;
+    use crate::diff::WorldDiff;
+    use crate::world::BlockType;
+
+    fn brick(x: i64, color: ColorId) -> Brick {
+        Brick {
+            shape: BrickShape::new(BlockType::BRICK, 2, 1),
+            origin: [x, 0, 0],
+            orientation: Default::default(),
+            color,
+        }
+    }
+
+    //A world path of its own for every test, the files go next to it
+    fn world_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("byggeklosser-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("world.bkw")
+    }
+
+    #[test]
+    fn journal_replays_onto_the_autosave() {
+        let path = world_path("journal");
+        let mut world = World::default();
+        let history = History::default();
+        let mut recovery = Recovery::new(&path);
+        assert!(pending(&path).is_none());
+
+        //The first edit autosaves, the others go in the journal
+        let edits = [
+            Edit::Place(brick(0, 1)),
+            Edit::Place(brick(4, 2)),
+            Edit::Remove(brick(0, 1)),
+            Edit::SetColor { id: 2, rgb: [0.5, 0.5, 0.5] },
+        ];
+        for edit in &edits {
+            assert!(edit.apply(&mut world));
+            recovery.record(edit, &world, &history).unwrap();
+        }
+        assert!(recovery.is_dirty());
+        //A crash while appending leaves half a line
+        std::fs::OpenOptions::new().append(true).open(journal_path(&path)).unwrap().write_all(b"{\"place\":{\"sha").unwrap();
+
+        assert!(pending(&path).is_some());
+        let (recovered, _, replayed) = recover(&path).unwrap();
+        assert_eq!(replayed, 3);
+        assert!(WorldDiff::between(&recovered, &world).is_empty());
+        assert_eq!(recovered.palette, world.palette);
+
+        recovery.clear().unwrap();
+        assert!(pending(&path).is_none());
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn edits_in_the_autosave_are_skipped() {
+        let path = world_path("autosaved");
+        let mut world = World::default();
+        let history = History::default();
+        let mut recovery = Recovery::new(&path);
+        let edit = Edit::Place(brick(0, 1));
+        edit.apply(&mut world);
+        recovery.record(&edit, &world, &history).unwrap();
+        recovery.record(&edit, &world, &history).unwrap();
+        let (recovered, _, replayed) = recover(&path).unwrap();
+        assert_eq!(replayed, 0);
+        assert_eq!(recovered.objects().count(), 1);
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
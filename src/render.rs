@@ -0,0 +1,377 @@
+use anyhow::*;
+use cgmath::prelude::*;
+use std::iter;
+use wgpu::util::DeviceExt;
+
+use crate::camera;
+use crate::model::{self, DrawModel, Vertex};
+use crate::palette::Palette;
+use crate::texture;
+
+fn create_render_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    color_format: wgpu::TextureFormat,
+    vertex_descs: &[wgpu::VertexBufferDescriptor],
+    vs_src: wgpu::ShaderModuleSource,
+    fs_src: wgpu::ShaderModuleSource,
+) -> wgpu::RenderPipeline {
+    let vs_module = device.create_shader_module(vs_src);
+    let fs_module = device.create_shader_module(fs_src);
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Render Pipeline"),
+        layout: Some(layout),
+        vertex_stage: wgpu::ProgrammableStageDescriptor {
+            module: &vs_module,
+            entry_point: "main",
+        },
+        fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+            module: &fs_module,
+            entry_point: "main",
+        }),
+        rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: wgpu::CullMode::Back,
+            depth_bias: 0,
+            depth_bias_slope_scale: 0.0,
+            depth_bias_clamp: 0.0,
+            clamp_depth: false,
+        }),
+        primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+        color_states: &[wgpu::ColorStateDescriptor {
+            format: color_format,
+            color_blend: wgpu::BlendDescriptor::REPLACE,
+            alpha_blend: wgpu::BlendDescriptor::REPLACE,
+            write_mask: wgpu::ColorWrite::ALL,
+        }],
+        depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+            format: texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilStateDescriptor::default(),
+        }),
+        sample_count: 1,
+        sample_mask: !0,
+        alpha_to_coverage_enabled: false,
+        vertex_state: wgpu::VertexStateDescriptor {
+            //index_format: wgpu::IndexFormat::Uint32,
+            index_format: wgpu::IndexFormat::Uint16,
+            vertex_buffers: vertex_descs,
+        },
+    })
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct Uniforms {
+    view_position: [f32; 4],
+    view_proj: [[f32; 4]; 4],
+}
+
+impl Uniforms {
+    fn new() -> Self {
+        Self {
+            view_position: [0.0; 4],
+            view_proj: cgmath::Matrix4::identity().into(),
+        }
+    }
+
+    fn update_view_proj(&mut self, camera: &camera::Camera, projection: &camera::Projection) {
+        self.view_position = camera.position.to_homogeneous().into();
+        self.view_proj = (projection.calc_matrix() * camera.calc_matrix()).into()
+    }
+}
+
+/// Background behind the world.
+pub const CLEAR_COLOR: wgpu::Color = wgpu::Color {
+    r: 0.1,
+    g: 0.2,
+    b: 0.3,
+    a: 1.0,
+};
+
+/// The pipeline and the uniforms it reads. Does not care whether it draws to a window
+/// or to a texture.
+pub struct Renderer {
+    render_pipeline: wgpu::RenderPipeline,
+    uniforms: Uniforms,
+    uniform_buffer: wgpu::Buffer,
+    palette_buffer: wgpu::Buffer,
+    uniform_bind_group: wgpu::BindGroup,
+}
+
+impl Renderer {
+    pub fn new(device: &wgpu::Device, color_format: wgpu::TextureFormat, palette: &Palette) -> Self {
+        let uniforms = Uniforms::new();
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Uniform Buffer"),
+            contents: bytemuck::cast_slice(&[uniforms]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let palette_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Palette Buffer"),
+            contents: bytemuck::cast_slice(&palette.to_raw()),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let uniform_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::VERTEX,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::VERTEX,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some("uniform_bind_group_layout"),
+            });
+
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(uniform_buffer.slice(..)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Buffer(palette_buffer.slice(..)),
+                },
+            ],
+            label: Some("uniform_bind_group"),
+        });
+
+        let render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Render Pipeline Layout"),
+                bind_group_layouts: &[
+                    &uniform_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+
+        let render_pipeline = create_render_pipeline(
+            device,
+            &render_pipeline_layout,
+            color_format,
+            &[model::ModelVertex::desc(), model::InstanceRaw::desc()],
+            wgpu::include_spirv!("shader.vert.spv"),
+            wgpu::include_spirv!("shader.frag.spv"),
+        );
+
+        Self {
+            render_pipeline,
+            uniforms,
+            uniform_buffer,
+            palette_buffer,
+            uniform_bind_group,
+        }
+    }
+
+    pub fn update_camera(&mut self, queue: &wgpu::Queue, camera: &camera::Camera, projection: &camera::Projection) {
+        self.uniforms.update_view_proj(camera, projection);
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[self.uniforms]));
+    }
+
+    pub fn write_palette(&self, queue: &wgpu::Queue, palette: &Palette) {
+        queue.write_buffer(&self.palette_buffer, 0, bytemuck::cast_slice(&palette.to_raw()));
+    }
+
+    /// Record a pass drawing `obj_model` over a cleared `target`.
+    pub fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+        depth: &wgpu::TextureView,
+        obj_model: &model::Model,
+        clear: wgpu::Color,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(clear),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        render_pass.set_pipeline(&self.render_pipeline);
+        render_pass.draw_model_instanced(obj_model, &self.uniform_bind_group);
+    }
+}
+
+/// A device without a window or surface, for rendering images from the command line.
+pub fn headless_device() -> Result<(wgpu::Device, wgpu::Queue)> {
+    use futures::executor::block_on;
+    let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
+    let adapter = block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+        power_preference: wgpu::PowerPreference::Default,
+        compatible_surface: None,
+    }))
+    .context("No graphics adapter found")?;
+    let (device, queue) = block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            features: wgpu::Features::empty(),
+            limits: wgpu::Limits::default(),
+            shader_validation: true,
+        },
+        None,
+    ))?;
+    Ok((device, queue))
+}
+
+/// Largest picture side. wgpu's default limits guarantee 2D textures this wide on every backend.
+pub const MAX_TEXTURE_SIZE: u32 = 8192;
+
+/// Draws into a texture instead of a window and reads the pixels back.
+pub struct Offscreen {
+    width: u32,
+    height: u32,
+    texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    depth_texture: texture::Texture,
+    //Rows in the read back buffer are padded to what copies need
+    padded_bytes_per_row: u32,
+    buffer: wgpu::Buffer,
+    renderer: Renderer,
+}
+
+impl Offscreen {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+    pub fn new(device: &wgpu::Device, width: u32, height: u32, palette: &Palette) -> Result<Self> {
+        ensure!(
+            (1..=MAX_TEXTURE_SIZE).contains(&width) && (1..=MAX_TEXTURE_SIZE).contains(&height),
+            "Picture size {}x{} must be between 1 and {} on each side",
+            width,
+            height,
+            MAX_TEXTURE_SIZE
+        );
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = width
+            .checked_mul(4)
+            .map(|bytes| bytes.div_ceil(align) * align)
+            .context("Picture row too large")?;
+        let buffer_size = u64::from(padded_bytes_per_row)
+            .checked_mul(u64::from(height))
+            .context("Picture too large")?;
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("offscreen_texture"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::FORMAT,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let depth_texture = texture::Texture::create_depth_texture_sized(device, width, height, "offscreen_depth");
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("offscreen_buffer"),
+            size: buffer_size,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        Ok(Self {
+            width,
+            height,
+            texture,
+            view,
+            depth_texture,
+            padded_bytes_per_row,
+            buffer,
+            renderer: Renderer::new(device, Self::FORMAT, palette),
+        })
+    }
+
+    pub fn projection(&self) -> camera::Projection {
+        camera::Projection::new(self.width, self.height, cgmath::Deg(45.0), 0.1, 10000.0)
+    }
+
+    /// Render `obj_model` as seen from `camera` and return the picture. The model's palette
+    /// is uploaded first, so models with different palettes can share one `Offscreen`.
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        obj_model: &model::Model,
+        camera: &camera::Camera,
+        clear: wgpu::Color,
+    ) -> Result<image::RgbaImage> {
+        self.renderer.update_camera(queue, camera, &self.projection());
+        self.renderer.write_palette(queue, &obj_model.world.palette);
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Offscreen Encoder"),
+        });
+        self.renderer.draw(&mut encoder, &self.view, &self.depth_texture.view, obj_model, clear);
+        encoder.copy_texture_to_buffer(
+            wgpu::TextureCopyView {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::BufferCopyView {
+                buffer: &self.buffer,
+                layout: wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: self.padded_bytes_per_row,
+                    rows_per_image: self.height,
+                },
+            },
+            wgpu::Extent3d {
+                width: self.width,
+                height: self.height,
+                depth: 1,
+            },
+        );
+        queue.submit(iter::once(encoder.finish()));
+
+        let slice = self.buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        device.poll(wgpu::Maintain::Wait);
+        futures::executor::block_on(mapping)?;
+
+        let mut pixels = Vec::with_capacity(self.width as usize * self.height as usize * 4);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks(self.padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..(self.width * 4) as usize]);
+            }
+        }
+        self.buffer.unmap();
+        image::RgbaImage::from_raw(self.width, self.height, pixels).context("Offscreen image has the wrong size")
+    }
+}
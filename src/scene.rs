@@ -0,0 +1,152 @@
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::camera::Camera;
+use crate::world::World;
+
+/// A world and, when saved from the editor, the camera looking at it. Written as JSON
+/// or RON, small builds diff well and scripts can generate them.
+#[derive(Debug, Deserialize)]
+pub struct Scene {
+    pub world: World,
+    #[serde(default)]
+    pub camera: Option<Camera>,
+}
+
+//Saving borrows, so the editor does not have to give up its world
+#[derive(Serialize)]
+struct SceneRef<'a> {
+    world: &'a World,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    camera: Option<&'a Camera>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SceneFormat {
+    Json,
+    Ron,
+}
+
+impl SceneFormat {
+    /// The format a file name asks for, if it is a scene at all.
+    pub fn of<P: AsRef<Path>>(path: P) -> Option<SceneFormat> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "json" => Some(SceneFormat::Json),
+            "ron" => Some(SceneFormat::Ron),
+            _ => None,
+        }
+    }
+}
+
+pub fn to_string(world: &World, camera: Option<&Camera>, format: SceneFormat) -> Result<String> {
+    let scene = SceneRef { world, camera };
+    Ok(match format {
+        SceneFormat::Json => serde_json::to_string_pretty(&scene)?,
+        SceneFormat::Ron => ron::ser::to_string_pretty(&scene, ron::ser::PrettyConfig::default())?,
+    })
+}
+
+pub fn from_str(text: &str, format: SceneFormat) -> Result<Scene> {
+    Ok(match format {
+        SceneFormat::Json => serde_json::from_str(text)?,
+        SceneFormat::Ron => ron::de::from_str(text)?,
+    })
+}
+
+/// Writes `.json` or `.ron`, by extension.
+pub fn save<P: AsRef<Path>>(world: &World, camera: Option<&Camera>, path: P) -> Result<()> {
+    let path = path.as_ref();
+    let format = SceneFormat::of(path).with_context(|| format!("Unknown scene format {:?}, expected .json or .ron", path))?;
+    std::fs::write(path, to_string(world, camera, format)?).with_context(|| format!("Cannot write {}", path.display()))
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Scene> {
+    let path = path.as_ref();
+    let format = SceneFormat::of(path).with_context(|| format!("Unknown scene format {:?}, expected .json or .ron", path))?;
+    let text = std::fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    from_str(&text, format).with_context(|| format!("Invalid scene {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brick::{Brick, BrickShape};
+    use crate::camera::CameraView;
+    use crate::orientation::Orientation;
+    use crate::world::{Block, BlockType};
+
+    fn world() -> World {
+        let mut world = World::new(4);
+        for x in -6..3 {
+            let block = Block { blocktype: BlockType::NORMAL, color: 2, orientation: Orientation::new(5).unwrap(), brick: None };
+            world.set_block(x, -5, x, Some(block));
+        }
+        let shape = BrickShape::new(BlockType::SLOPE, 2, 3);
+        world.place_brick(Brick { shape, origin: [-9, -9, -9], orientation: Orientation::new(7).unwrap(), color: 4 }).unwrap();
+        world
+    }
+
+    fn objects(world: &World) -> Vec<Brick> {
+        world.objects().map(|(_, object)| object).collect()
+    }
+
+    #[test]
+    fn scenes_read_back_in_both_formats() {
+        let world = world();
+        let camera = Camera::from(CameraView { position: [1.0, 2.0, 3.0], yaw: -90.0, pitch: -20.0 });
+        for &format in &[SceneFormat::Json, SceneFormat::Ron] {
+            let back = from_str(&to_string(&world, Some(&camera), format).unwrap(), format).unwrap();
+            assert_eq!(back.world.chunk_size(), 4);
+            assert_eq!(back.world.palette, world.palette);
+            assert_eq!(objects(&back.world), objects(&world));
+            let view = CameraView::from(back.camera.unwrap());
+            assert_eq!(view.position, [1.0, 2.0, 3.0]);
+            assert!((view.yaw + 90.0).abs() < 1e-3 && (view.pitch + 20.0).abs() < 1e-3);
+
+            let text = to_string(&world, None, format).unwrap();
+            assert!(!text.contains("camera"));
+            assert!(from_str(&text, format).unwrap().camera.is_none());
+        }
+    }
+
+    #[test]
+    fn bad_scenes_are_rejected() {
+        let text = to_string(&world(), None, SceneFormat::Json).unwrap();
+        let scene: serde_json::Value = serde_json::from_str(&text).unwrap();
+        let broken = |change: &dyn Fn(&mut serde_json::Value)| {
+            let mut scene = scene.clone();
+            change(&mut scene);
+            from_str(&scene.to_string(), SceneFormat::Json).is_err()
+        };
+        assert!(broken(&|scene| scene["world"]["objects"][0]["orientation"] = 30.into()));
+        assert!(broken(&|scene| scene["world"]["objects"][0]["color"] = 999.into()));
+        assert!(broken(&|scene| scene["world"]["chunk_size"] = 0.into()));
+        //Two objects in the same cells
+        assert!(broken(&|scene| {
+            let first = scene["world"]["objects"][0].clone();
+            scene["world"]["objects"].as_array_mut().unwrap().push(first);
+        }));
+        assert!(from_str("{", SceneFormat::Ron).is_err());
+    }
+
+    #[test]
+    fn files_pick_the_format_by_extension() {
+        assert_eq!(SceneFormat::of("castle.JSON"), Some(SceneFormat::Json));
+        assert_eq!(SceneFormat::of("castle.ron"), Some(SceneFormat::Ron));
+        assert_eq!(SceneFormat::of("castle.bkl"), None);
+        assert_eq!(SceneFormat::of("castle"), None);
+
+        let dir = std::env::temp_dir().join(format!("byggeklosser-scene-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let world = world();
+        for name in &["castle.json", "castle.ron"] {
+            save(&world, None, dir.join(name)).unwrap();
+            assert_eq!(objects(&load(dir.join(name)).unwrap().world), objects(&world));
+        }
+        assert!(save(&world, None, dir.join("castle.txt")).is_err());
+        assert!(load(dir.join("missing.json")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
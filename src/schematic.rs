@@ -0,0 +1,802 @@
+use anyhow::*;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::Path;
+
+use crate::bom::{self, csv_field, parse_csv_line};
+use crate::brick::{Brick, BrickShape};
+use crate::nbt::{self, compound, Tag};
+use crate::orientation::Orientation;
+use crate::palette::{linear_to_srgb, srgb_to_linear, ColorId, Palette};
+use crate::quantize::{DeltaE, Quantizer};
+use crate::world::{Block, BlockType, World};
+
+/// Minecraft 1.16.5, what the files we write claim to come from.
+pub const DATA_VERSION: i32 = 2586;
+const AIR: &str = "minecraft:air";
+
+/// A Minecraft block state like `minecraft:oak_stairs[facing=east,half=bottom]`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockState {
+    pub id: String,
+    pub properties: BTreeMap<String, String>,
+}
+
+impl BlockState {
+    pub fn parse(state: &str) -> BlockState {
+        let state = state.trim();
+        let (id, properties) = match state.find('[') {
+            Some(start) => (&state[..start], state[start + 1..].trim_end_matches(']')),
+            None => (state, ""),
+        };
+        //Ids without a namespace are vanilla
+        let id = if id.contains(':') { id.to_string() } else { format!("minecraft:{}", id) };
+        let properties = properties
+            .split(',')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) => Some((key.trim().to_string(), value.trim().to_string())),
+                    _ => None,
+                }
+            })
+            .collect();
+        BlockState { id, properties }
+    }
+
+    fn with(mut self, key: &str, value: &str) -> BlockState {
+        self.properties.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    //Whether every property `pattern` names has the same value here
+    fn matches(&self, pattern: &BlockState) -> bool {
+        self.id == pattern.id && pattern.properties.iter().all(|(key, value)| self.properties.get(key) == Some(value))
+    }
+}
+
+impl fmt::Display for BlockState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        if !self.properties.is_empty() {
+            let pairs: Vec<String> = self.properties.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+            write!(f, "[{}]", pairs.join(","))?;
+        }
+        std::result::Result::Ok(())
+    }
+}
+
+//What kind of Minecraft block a block type is written as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Family {
+    Full,
+    Slab,
+    Stairs,
+}
+
+fn family(blocktype: BlockType) -> Family {
+    match blocktype {
+        BlockType::NORMAL | BlockType::BRICK | BlockType::ARCH => Family::Full,
+        BlockType::PLATE | BlockType::TILE => Family::Slab,
+        BlockType::SLOPE | BlockType::SLOPE_INVERTED | BlockType::SLOPE_CORNER | BlockType::WEDGE => Family::Stairs,
+    }
+}
+
+/// One row of the mapping table. `state` may leave properties out to match any value;
+/// facing and top or bottom halves are handled through the orientation.
+#[derive(Debug, Clone)]
+pub struct MappingEntry {
+    pub state: BlockState,
+    pub blocktype: BlockType,
+    //Palette colour name and its linear rgb
+    pub color: String,
+    pub rgb: [f32; 3],
+}
+
+/// Which Minecraft blocks correspond to which block types and colours. Reading uses the
+/// first row that matches, writing the closest colour among the rows of the right kind.
+#[derive(Debug, Clone)]
+pub struct BlockMapping {
+    pub entries: Vec<MappingEntry>,
+}
+
+//(state, block type, colour name, sRGB)
+const DEFAULT_MAPPING: &[(&str, BlockType, &str, u32)] = &[
+    ("white_concrete", BlockType::NORMAL, "White", 0xCFD5D6),
+    ("orange_concrete", BlockType::NORMAL, "Orange", 0xE06101),
+    ("magenta_concrete", BlockType::NORMAL, "Magenta", 0xA9309F),
+    ("light_blue_concrete", BlockType::NORMAL, "Light Blue", 0x2489C7),
+    ("yellow_concrete", BlockType::NORMAL, "Yellow", 0xF1AF15),
+    ("lime_concrete", BlockType::NORMAL, "Lime", 0x5EA918),
+    ("pink_concrete", BlockType::NORMAL, "Pink", 0xD5658F),
+    ("gray_concrete", BlockType::NORMAL, "Gray", 0x373A3E),
+    ("light_gray_concrete", BlockType::NORMAL, "Light Gray", 0x7D7D73),
+    ("cyan_concrete", BlockType::NORMAL, "Cyan", 0x157788),
+    ("purple_concrete", BlockType::NORMAL, "Purple", 0x64209C),
+    ("blue_concrete", BlockType::NORMAL, "Blue", 0x2D2F8F),
+    ("brown_concrete", BlockType::NORMAL, "Brown", 0x603C20),
+    ("green_concrete", BlockType::NORMAL, "Green", 0x495B24),
+    ("red_concrete", BlockType::NORMAL, "Red", 0x8E2121),
+    ("black_concrete", BlockType::NORMAL, "Black", 0x080A0F),
+    //Wool reads as the concrete of the same colour
+    ("white_wool", BlockType::NORMAL, "White", 0xCFD5D6),
+    ("orange_wool", BlockType::NORMAL, "Orange", 0xE06101),
+    ("magenta_wool", BlockType::NORMAL, "Magenta", 0xA9309F),
+    ("light_blue_wool", BlockType::NORMAL, "Light Blue", 0x2489C7),
+    ("yellow_wool", BlockType::NORMAL, "Yellow", 0xF1AF15),
+    ("lime_wool", BlockType::NORMAL, "Lime", 0x5EA918),
+    ("pink_wool", BlockType::NORMAL, "Pink", 0xD5658F),
+    ("gray_wool", BlockType::NORMAL, "Gray", 0x373A3E),
+    ("light_gray_wool", BlockType::NORMAL, "Light Gray", 0x7D7D73),
+    ("cyan_wool", BlockType::NORMAL, "Cyan", 0x157788),
+    ("purple_wool", BlockType::NORMAL, "Purple", 0x64209C),
+    ("blue_wool", BlockType::NORMAL, "Blue", 0x2D2F8F),
+    ("brown_wool", BlockType::NORMAL, "Brown", 0x603C20),
+    ("green_wool", BlockType::NORMAL, "Green", 0x495B24),
+    ("red_wool", BlockType::NORMAL, "Red", 0x8E2121),
+    ("black_wool", BlockType::NORMAL, "Black", 0x080A0F),
+    ("stone", BlockType::NORMAL, "Stone", 0x7D7D7D),
+    ("cobblestone", BlockType::NORMAL, "Cobblestone", 0x7A7A7A),
+    ("stone_bricks", BlockType::NORMAL, "Stone Bricks", 0x7A7979),
+    ("smooth_stone", BlockType::NORMAL, "Smooth Stone", 0x9E9E9E),
+    ("dirt", BlockType::NORMAL, "Dirt", 0x866043),
+    ("grass_block", BlockType::NORMAL, "Grass", 0x5D9B3A),
+    ("sand", BlockType::NORMAL, "Sand", 0xDBCFA3),
+    ("sandstone", BlockType::NORMAL, "Sandstone", 0xD8CB9B),
+    ("oak_planks", BlockType::NORMAL, "Oak", 0xA2824E),
+    ("spruce_planks", BlockType::NORMAL, "Spruce", 0x725430),
+    ("oak_log", BlockType::NORMAL, "Oak Log", 0x6D5532),
+    ("bricks", BlockType::NORMAL, "Bricks", 0x966153),
+    ("snow_block", BlockType::NORMAL, "Snow", 0xF9FEFE),
+    ("glass", BlockType::NORMAL, "Glass", 0xC0D8DC),
+    ("smooth_stone_slab", BlockType::PLATE, "Smooth Stone", 0x9E9E9E),
+    ("stone_slab", BlockType::PLATE, "Stone", 0x7D7D7D),
+    ("cobblestone_slab", BlockType::PLATE, "Cobblestone", 0x7A7A7A),
+    ("stone_brick_slab", BlockType::PLATE, "Stone Bricks", 0x7A7979),
+    ("sandstone_slab", BlockType::PLATE, "Sandstone", 0xD8CB9B),
+    ("oak_slab", BlockType::PLATE, "Oak", 0xA2824E),
+    ("spruce_slab", BlockType::PLATE, "Spruce", 0x725430),
+    ("brick_slab", BlockType::PLATE, "Bricks", 0x966153),
+    ("stone_stairs", BlockType::SLOPE, "Stone", 0x7D7D7D),
+    ("cobblestone_stairs", BlockType::SLOPE, "Cobblestone", 0x7A7A7A),
+    ("stone_brick_stairs", BlockType::SLOPE, "Stone Bricks", 0x7A7979),
+    ("sandstone_stairs", BlockType::SLOPE, "Sandstone", 0xD8CB9B),
+    ("oak_stairs", BlockType::SLOPE, "Oak", 0xA2824E),
+    ("spruce_stairs", BlockType::SLOPE, "Spruce", 0x725430),
+    ("brick_stairs", BlockType::SLOPE, "Bricks", 0x966153),
+];
+
+fn hex_to_linear(hex: u32) -> [f32; 3] {
+    [
+        srgb_to_linear((hex >> 16) as u8),
+        srgb_to_linear((hex >> 8) as u8),
+        srgb_to_linear(hex as u8),
+    ]
+}
+
+impl Default for BlockMapping {
+    /// Concrete in the 16 dye colours, common building stones and woods, with their slabs and stairs.
+    fn default() -> Self {
+        BlockMapping {
+            entries: DEFAULT_MAPPING
+                .iter()
+                .map(|&(state, blocktype, color, hex)| MappingEntry {
+                    state: BlockState::parse(state),
+                    blocktype,
+                    color: color.to_string(),
+                    rgb: hex_to_linear(hex),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl BlockMapping {
+    /// Reads a table with the columns `state,blocktype,color,rgb`, rgb as sRGB hex like `#8E2121`.
+    pub fn from_csv(text: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        let lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+        for (number, line) in lines.skip(1) {
+            let fields = parse_csv_line(line);
+            ensure!(fields.len() >= 4, "Mapping line {} needs state, blocktype, color and rgb", number + 1);
+            let blocktype = BlockType::from_name(fields[1].trim())
+                .with_context(|| format!("Unknown block type {} on mapping line {}", fields[1], number + 1))?;
+            let hex = u32::from_str_radix(fields[3].trim().trim_start_matches('#'), 16)
+                .with_context(|| format!("Invalid colour {} on mapping line {}", fields[3], number + 1))?;
+            entries.push(MappingEntry {
+                state: BlockState::parse(&fields[0]),
+                blocktype,
+                color: fields[2].trim().to_string(),
+                rgb: hex_to_linear(hex),
+            });
+        }
+        ensure!(!entries.is_empty(), "The mapping table is empty");
+        Ok(BlockMapping { entries })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+        Self::from_csv(&text)
+    }
+
+    /// The table as CSV, for editing and loading back with `from_csv`.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("state,blocktype,color,rgb\n");
+        for entry in &self.entries {
+            csv += &format!(
+                "{},{:?},{},#{:02X}{:02X}{:02X}\n",
+                csv_field(&entry.state.to_string()),
+                entry.blocktype,
+                csv_field(&entry.color),
+                linear_to_srgb(entry.rgb[0]),
+                linear_to_srgb(entry.rgb[1]),
+                linear_to_srgb(entry.rgb[2]),
+            );
+        }
+        csv
+    }
+
+    fn find(&self, state: &BlockState) -> Option<&MappingEntry> {
+        self.entries.iter().find(|entry| state.matches(&entry.state))
+    }
+}
+
+//Orientation for a block turned so its back, the local -z side, faces `facing`
+fn orientation_for(facing: Option<&str>, upside_down: bool) -> Orientation {
+    let back = match facing {
+        Some("east") => [1, 0, 0],
+        Some("south") => [0, 0, 1],
+        Some("west") => [-1, 0, 0],
+        _ => [0, 0, -1],
+    };
+    let up = if upside_down { [0, -1, 0] } else { [0, 1, 0] };
+    Orientation::all()
+        .find(|orientation| orientation.up() == up && orientation.rotate([0, 0, -1]) == back)
+        .unwrap_or_default()
+}
+
+fn facing_of(orientation: Orientation) -> &'static str {
+    match orientation.rotate([0, 0, -1]) {
+        [1, 0, 0] => "east",
+        [0, 0, 1] => "south",
+        [-1, 0, 0] => "west",
+        _ => "north",
+    }
+}
+
+/// A schematic read into a world, and the states the mapping did not know with how often they appeared.
+pub struct Imported {
+    pub world: World,
+    pub unknown: BTreeMap<String, usize>,
+}
+
+impl Imported {
+    pub fn unknown_report(&self) -> String {
+        let mut report = String::new();
+        for (state, count) in &self.unknown {
+            report += &format!("{} x{}\n", state, count);
+        }
+        report
+    }
+}
+
+//Builds a world from block states cell by cell
+struct Importer<'a> {
+    mapping: &'a BlockMapping,
+    imported: Imported,
+}
+
+impl<'a> Importer<'a> {
+    fn new(mapping: &'a BlockMapping) -> Self {
+        let mut world = World::default();
+        //Colours come from the mapping, so names like Green mean the mapped block's colour
+        world.palette = Palette::new();
+        Importer {
+            mapping,
+            imported: Imported {
+                world,
+                unknown: BTreeMap::new(),
+            },
+        }
+    }
+
+    fn finish(mut self) -> Imported {
+        if self.imported.world.palette.is_empty() {
+            self.imported.world.palette = Palette::default();
+        }
+        self.imported
+    }
+
+    fn place(&mut self, position: [i64; 3], state: &BlockState) -> Result<()> {
+        if state.id == AIR || state.id == "minecraft:cave_air" || state.id == "minecraft:void_air" {
+            return Ok(());
+        }
+        let entry = match self.mapping.find(state) {
+            Some(entry) => entry,
+            None => {
+                *self.imported.unknown.entry(state.to_string()).or_insert(0) += 1;
+                return Ok(());
+            }
+        };
+        let world = &mut self.imported.world;
+        let color = match world.palette.find(&entry.color) {
+            Some(color) => color,
+            None => world.palette.add(&entry.color, entry.rgb)?,
+        };
+        if entry.blocktype == BlockType::NORMAL {
+            world.set_block(position[0], position[1], position[2], Some(Block {
+                blocktype: BlockType::NORMAL,
+                color,
+                orientation: Default::default(),
+                brick: None,
+            }));
+            return Ok(());
+        }
+        //Slabs say type=top, stairs half=top
+        let upside_down = state.properties.get("type").map(String::as_str) == Some("top")
+            || state.properties.get("half").map(String::as_str) == Some("top");
+        let orientation = orientation_for(state.properties.get("facing").map(String::as_str), upside_down);
+        world.place_brick(Brick {
+            shape: BrickShape::new(entry.blocktype, 1, 1),
+            origin: position,
+            orientation,
+            color,
+        })?;
+        Ok(())
+    }
+}
+
+//Picks the Minecraft block for each kind of block and colour
+struct Exporter<'a> {
+    mapping: &'a BlockMapping,
+    candidates: HashMap<Family, (Vec<&'a MappingEntry>, Quantizer)>,
+    chosen: HashMap<(Family, ColorId), Option<&'a MappingEntry>>,
+    //Blocks written as something else than they are, by what they became
+    approximated: BTreeMap<String, usize>,
+}
+
+impl<'a> Exporter<'a> {
+    fn new(mapping: &'a BlockMapping) -> Self {
+        let mut candidates = HashMap::new();
+        for &family in &[Family::Full, Family::Slab, Family::Stairs] {
+            let entries: Vec<&MappingEntry> = mapping.entries.iter().filter(|entry| self::family(entry.blocktype) == family).collect();
+            let mut palette = Palette::new();
+            for entry in &entries {
+                //Names can repeat, the palette only needs the colours in order
+                let _ = palette.add(&entry.color, entry.rgb);
+            }
+            if !entries.is_empty() && palette.len() == entries.len() {
+                candidates.insert(family, (entries, Quantizer::new(&palette, DeltaE::Ciede2000)));
+            }
+        }
+        Exporter {
+            mapping,
+            candidates,
+            chosen: HashMap::new(),
+            approximated: BTreeMap::new(),
+        }
+    }
+
+    fn entry(&mut self, family: Family, color: ColorId, palette: &Palette) -> Option<&'a MappingEntry> {
+        if let Some(entry) = self.chosen.get(&(family, color)) {
+            return *entry;
+        }
+        let wanted = palette.get(color);
+        let entry = self.candidates.get(&family).and_then(|(entries, quantizer)| {
+            //Same colour name first, then the nearest colour
+            let named = wanted.and_then(|wanted| entries.iter().find(|entry| entry.color == wanted.name));
+            named.copied().or_else(|| wanted.map(|wanted| entries[quantizer.nearest_linear(wanted.rgb) as usize]))
+        });
+        self.chosen.insert((family, color), entry);
+        entry
+    }
+
+    fn state(&mut self, block: &Block, palette: &Palette) -> BlockState {
+        let wanted = family(block.blocktype);
+        let (family, entry) = match self.entry(wanted, block.color, palette) {
+            Some(entry) => (wanted, Some(entry)),
+            None => (Family::Full, self.entry(Family::Full, block.color, palette)),
+        };
+        let entry = match entry {
+            Some(entry) => entry,
+            None => return BlockState::parse(&self.mapping.entries[0].state.id),
+        };
+        if family != wanted {
+            *self.approximated.entry(format!("{:?} as {}", block.blocktype, entry.state)).or_insert(0) += 1;
+        }
+        let upside_down = block.orientation.up() == [0, -1, 0];
+        let state = entry.state.clone();
+        match family {
+            Family::Full => state,
+            Family::Slab => state.with("type", if upside_down { "top" } else { "bottom" }).with("waterlogged", "false"),
+            Family::Stairs => state
+                .with("facing", facing_of(block.orientation))
+                .with("half", if upside_down || block.blocktype == BlockType::SLOPE_INVERTED { "top" } else { "bottom" })
+                .with("shape", "straight")
+                .with("waterlogged", "false"),
+        }
+    }
+}
+
+/// States for every cell in the world's bounds, x fastest, then z, then y, as both formats
+/// store them, and the palette of distinct states with air first.
+fn world_states(world: &World, mapping: &BlockMapping) -> Result<Cells> {
+    let (min, max) = bom::bounds(world).context("The world is empty")?;
+    let size = [max[0] - min[0] + 1, max[1] - min[1] + 1, max[2] - min[2] + 1];
+    ensure!(size.iter().all(|&side| side <= u16::MAX as i64), "The world is too big for a schematic");
+    ensure!(!mapping.entries.is_empty(), "The mapping table is empty");
+    let mut exporter = Exporter::new(mapping);
+    let mut palette = vec![BlockState::parse(AIR)];
+    let mut index: HashMap<BlockState, u32> = HashMap::new();
+    index.insert(palette[0].clone(), 0);
+    let mut cells = Vec::with_capacity((size[0] * size[1] * size[2]) as usize);
+    for y in min[1]..=max[1] {
+        for z in min[2]..=max[2] {
+            for x in min[0]..=max[0] {
+                let state = match world.get_block(x, y, z) {
+                    Some(block) => exporter.state(block, &world.palette),
+                    None => {
+                        cells.push(0);
+                        continue;
+                    }
+                };
+                let next = palette.len() as u32;
+                let id = *index.entry(state.clone()).or_insert_with(|| {
+                    palette.push(state);
+                    next
+                });
+                cells.push(id);
+            }
+        }
+    }
+    Ok(Cells {
+        min,
+        size,
+        palette,
+        cells,
+        approximated: exporter.approximated,
+    })
+}
+
+struct Cells {
+    min: [i64; 3],
+    size: [i64; 3],
+    palette: Vec<BlockState>,
+    cells: Vec<u32>,
+    approximated: BTreeMap<String, usize>,
+}
+
+/// Sponge schematic version 2, which WorldEdit and most tools read.
+fn write_schem(cells: &Cells) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(cells.cells.len());
+    for &id in &cells.cells {
+        //Varint, seven bits at a time
+        let mut value = id;
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                data.push(byte as i8);
+                break;
+            }
+            data.push((byte | 0x80) as i8);
+        }
+    }
+    let palette: BTreeMap<String, Tag> =
+        cells.palette.iter().enumerate().map(|(id, state)| (state.to_string(), Tag::Int(id as i32))).collect();
+    let schematic = compound(vec![
+        ("Version", Tag::Int(2)),
+        ("DataVersion", Tag::Int(DATA_VERSION)),
+        ("Width", Tag::Short(cells.size[0] as u16 as i16)),
+        ("Height", Tag::Short(cells.size[1] as u16 as i16)),
+        ("Length", Tag::Short(cells.size[2] as u16 as i16)),
+        ("Offset", Tag::IntArray(vec![cells.min[0] as i32, cells.min[1] as i32, cells.min[2] as i32])),
+        ("PaletteMax", Tag::Int(cells.palette.len() as i32)),
+        ("Palette", Tag::Compound(palette)),
+        ("BlockData", Tag::ByteArray(data)),
+        ("BlockEntities", Tag::compound_list(Vec::new())),
+    ]);
+    nbt::write_file("Schematic", &schematic)
+}
+
+fn read_schem(root: &Tag, mapping: &BlockMapping) -> Result<Imported> {
+    //Version 3 wraps everything in a Schematic compound and the blocks in another
+    let schematic = root.get("Schematic").unwrap_or(root);
+    let version = schematic.int("Version")?;
+    let blocks = if version >= 3 { schematic.field("Blocks")? } else { schematic };
+    let (palette, data) = if version >= 3 {
+        (blocks.field("Palette")?, blocks.field("Data")?)
+    } else {
+        (schematic.field("Palette")?, schematic.field("BlockData")?)
+    };
+    let size = [
+        schematic.int("Width")? as u16 as i64,
+        schematic.int("Height")? as u16 as i64,
+        schematic.int("Length")? as u16 as i64,
+    ];
+    let offset = match schematic.get("Offset") {
+        Some(Tag::IntArray(offset)) if offset.len() == 3 => [offset[0] as i64, offset[1] as i64, offset[2] as i64],
+        _ => [0; 3],
+    };
+
+    let mut states: HashMap<i64, BlockState> = HashMap::new();
+    for (state, id) in palette.as_compound().context("Schematic palette is not a compound")? {
+        states.insert(id.as_i64().context("Schematic palette id is not a number")?, BlockState::parse(state));
+    }
+    let data = match data {
+        Tag::ByteArray(data) => data,
+        _ => bail!("Schematic block data is not a byte array"),
+    };
+
+    let mut importer = Importer::new(mapping);
+    let mut bytes = data.iter().map(|&b| b as u8);
+    for index in 0..size[0] * size[1] * size[2] {
+        let mut id = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = bytes.next().context("Schematic block data ends early")?;
+            id |= ((byte & 0x7f) as i64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+            ensure!(shift < 35, "Broken varint in schematic block data");
+        }
+        let state = states.get(&id).with_context(|| format!("Schematic uses state {} that is not in its palette", id))?;
+        let x = index % size[0];
+        let z = (index / size[0]) % size[2];
+        let y = index / (size[0] * size[2]);
+        importer.place([offset[0] + x, offset[1] + y, offset[2] + z], state)?;
+    }
+    Ok(importer.finish())
+}
+
+//Bits per entry in a Litematica block state array
+fn litematic_bits(palette_len: usize) -> u32 {
+    let mut bits = 2;
+    while (1usize << bits) < palette_len {
+        bits += 1;
+    }
+    bits
+}
+
+fn state_tag(state: &BlockState) -> Tag {
+    let mut fields = vec![("Name", Tag::String(state.id.clone()))];
+    if !state.properties.is_empty() {
+        let properties = state.properties.iter().map(|(key, value)| (key.clone(), Tag::String(value.clone()))).collect();
+        fields.push(("Properties", Tag::Compound(properties)));
+    }
+    compound(fields)
+}
+
+fn vector(x: i64, y: i64, z: i64) -> Tag {
+    compound(vec![("x", Tag::Int(x as i32)), ("y", Tag::Int(y as i32)), ("z", Tag::Int(z as i32))])
+}
+
+/// Litematica schematic with one region. Entries are packed across long boundaries.
+fn write_litematic(cells: &Cells, name: &str) -> Result<Vec<u8>> {
+    let bits = litematic_bits(cells.palette.len());
+    let mask = (1u64 << bits) - 1;
+    let mut longs = vec![0u64; (cells.cells.len() * bits as usize).div_ceil(64)];
+    for (index, &id) in cells.cells.iter().enumerate() {
+        let start = index * bits as usize;
+        let (word, offset) = (start / 64, (start % 64) as u32);
+        longs[word] |= (id as u64 & mask) << offset;
+        if offset + bits > 64 {
+            longs[word + 1] |= (id as u64 & mask) >> (64 - offset);
+        }
+    }
+    let volume = cells.size[0] * cells.size[1] * cells.size[2];
+    let blocks = cells.cells.iter().filter(|&&id| id != 0).count();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|time| time.as_millis() as i64)
+        .unwrap_or(0);
+    let region = compound(vec![
+        ("Position", vector(cells.min[0], cells.min[1], cells.min[2])),
+        ("Size", vector(cells.size[0], cells.size[1], cells.size[2])),
+        ("BlockStatePalette", Tag::compound_list(cells.palette.iter().map(state_tag).collect())),
+        ("BlockStates", Tag::LongArray(longs.into_iter().map(|long| long as i64).collect())),
+        ("Entities", Tag::compound_list(Vec::new())),
+        ("TileEntities", Tag::compound_list(Vec::new())),
+        ("PendingBlockTicks", Tag::compound_list(Vec::new())),
+        ("PendingFluidTicks", Tag::compound_list(Vec::new())),
+    ]);
+    let metadata = compound(vec![
+        ("Name", Tag::String(name.to_string())),
+        ("Author", Tag::String(env!("CARGO_PKG_NAME").to_string())),
+        ("Description", Tag::String(String::new())),
+        ("RegionCount", Tag::Int(1)),
+        ("TotalBlocks", Tag::Int(blocks as i32)),
+        ("TotalVolume", Tag::Int(volume as i32)),
+        ("EnclosingSize", vector(cells.size[0], cells.size[1], cells.size[2])),
+        ("TimeCreated", Tag::Long(now)),
+        ("TimeModified", Tag::Long(now)),
+    ]);
+    let root = compound(vec![
+        ("Version", Tag::Int(5)),
+        ("MinecraftDataVersion", Tag::Int(DATA_VERSION)),
+        ("Metadata", metadata),
+        ("Regions", compound(vec![(name, region)])),
+    ]);
+    nbt::write_file("", &root)
+}
+
+fn read_vector(tag: &Tag, name: &str) -> Result<[i64; 3]> {
+    let vector = tag.field(name)?;
+    Ok([vector.int("x")?, vector.int("y")?, vector.int("z")?])
+}
+
+fn read_litematic(root: &Tag, mapping: &BlockMapping) -> Result<Imported> {
+    let mut importer = Importer::new(mapping);
+    let regions = root.field("Regions")?.as_compound().context("Litematic regions are not a compound")?;
+    for region in regions.values() {
+        let position = read_vector(region, "Position")?;
+        let size = read_vector(region, "Size")?;
+        //Litematica writes ints, so anything wider is damage and would overflow below
+        let in_range = |value: &i64| i32::MIN as i64 <= *value && *value <= i32::MAX as i64;
+        ensure!(position.iter().chain(size.iter()).all(in_range), "Litematic region is out of range");
+        //Sizes are negative when the region was selected towards the negative axis
+        let mut min = [0; 3];
+        let mut extent = [0; 3];
+        for axis in 0..3 {
+            extent[axis] = size[axis].abs();
+            min[axis] = if size[axis] < 0 { position[axis] + size[axis] + 1 } else { position[axis] };
+        }
+        let mut palette = Vec::new();
+        for state in region.field("BlockStatePalette")?.as_list().context("Litematic palette is not a list")? {
+            let mut parsed = BlockState::parse(state.field("Name")?.as_str().context("Litematic block name is not text")?);
+            if let Some(properties) = state.get("Properties").and_then(Tag::as_compound) {
+                for (key, value) in properties {
+                    if let Some(value) = value.as_str() {
+                        parsed.properties.insert(key.clone(), value.to_string());
+                    }
+                }
+            }
+            palette.push(parsed);
+        }
+        let longs = match region.field("BlockStates")? {
+            Tag::LongArray(longs) => longs,
+            _ => bail!("Litematic block states are not a long array"),
+        };
+        let bits = litematic_bits(palette.len());
+        let mask = (1u64 << bits) - 1;
+        let volume = extent[0]
+            .checked_mul(extent[1])
+            .and_then(|area| area.checked_mul(extent[2]))
+            .and_then(|volume| usize::try_from(volume).ok())
+            .context("Litematic region is too big")?;
+        let needed = volume.checked_mul(bits as usize).context("Litematic region is too big")?;
+        ensure!(longs.len().saturating_mul(64) >= needed, "Litematic block states are too short");
+        for index in 0..volume {
+            let start = index * bits as usize;
+            let (word, offset) = (start / 64, (start % 64) as u32);
+            let mut id = (longs[word] as u64 >> offset) & mask;
+            if offset + bits > 64 {
+                id |= (longs[word + 1] as u64) << (64 - offset) & mask;
+            }
+            let state = palette.get(id as usize).with_context(|| format!("Litematic uses state {} that is not in its palette", id))?;
+            let index = index as i64;
+            let x = index % extent[0];
+            let z = (index / extent[0]) % extent[2];
+            let y = index / (extent[0] * extent[2]);
+            importer.place([min[0] + x, min[1] + y, min[2] + z], state)?;
+        }
+    }
+    Ok(importer.finish())
+}
+
+fn is_litematic(path: &Path) -> bool {
+    path.extension().and_then(|extension| extension.to_str()).map(|extension| extension.eq_ignore_ascii_case("litematic")) == Some(true)
+}
+
+/// Reads a `.schem` (Sponge versions 1 to 3) or `.litematic` file.
+pub fn load<P: AsRef<Path>>(path: P, mapping: &BlockMapping) -> Result<Imported> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    let (_, root) = nbt::read_file(&bytes).with_context(|| format!("{} is not an NBT file", path.display()))?;
+    if is_litematic(path) {
+        read_litematic(&root, mapping)
+    } else {
+        read_schem(&root, mapping)
+    }
+}
+
+/// Writes a `.schem` or `.litematic` file, by extension. Returns the blocks that had to be
+/// written as a different kind of block, for reporting.
+pub fn save<P: AsRef<Path>>(world: &World, path: P, mapping: &BlockMapping) -> Result<BTreeMap<String, usize>> {
+    let path = path.as_ref();
+    let cells = world_states(world, mapping)?;
+    let bytes = if is_litematic(path) {
+        let name = path.file_stem().and_then(|name| name.to_str()).unwrap_or("world");
+        write_litematic(&cells, name)?
+    } else {
+        write_schem(&cells)?
+    };
+    std::fs::write(path, bytes).with_context(|| format!("Cannot write {}", path.display()))?;
+    Ok(cells.approximated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Enough colours that litematic states straddle their longs
+    fn world() -> World {
+        let mut world = World::default();
+        for x in 0..40 {
+            for z in 0..3 {
+                let block = Block { blocktype: BlockType::NORMAL, color: (x % 3) as ColorId, orientation: Default::default(), brick: None };
+                world.set_block(x - 5, -2, z, Some(block));
+            }
+        }
+        let red = world.palette.find("Red").unwrap();
+        for (i, orientation) in Orientation::all().filter(|orientation| orientation.up() == [0, 1, 0]).enumerate() {
+            let slope = Brick { shape: BrickShape::new(BlockType::SLOPE, 1, 1), origin: [i as i64 * 2, -1, 0], orientation, color: red };
+            world.place_brick(slope).unwrap();
+        }
+        world
+    }
+
+    //Colours come back as the mapping's, so compare the states both worlds export to
+    fn assert_same(imported: &Imported, world: &World) {
+        assert!(imported.unknown.is_empty(), "{}", imported.unknown_report());
+        assert_eq!(imported.world.iter_blocks().count(), world.iter_blocks().count());
+        for (position, block) in world.iter_blocks() {
+            let read = imported.world.get_block(position[0], position[1], position[2]).expect("missing block");
+            assert_eq!(read.blocktype, block.blocktype);
+            assert_eq!(read.orientation, block.orientation);
+        }
+        let mapping = BlockMapping::default();
+        let (before, after) = (world_states(world, &mapping).unwrap(), world_states(&imported.world, &mapping).unwrap());
+        assert_eq!((before.min, before.size), (after.min, after.size));
+        assert_eq!(before.palette, after.palette);
+        assert_eq!(before.cells, after.cells);
+    }
+
+    #[test]
+    fn schem_round_trip() {
+        let (world, mapping) = (world(), BlockMapping::default());
+        let cells = world_states(&world, &mapping).unwrap();
+        assert!(cells.approximated.is_empty());
+        let (_, root) = nbt::read_file(&write_schem(&cells).unwrap()).unwrap();
+        assert_same(&read_schem(&root, &mapping).unwrap(), &world);
+    }
+
+    #[test]
+    fn litematic_round_trip() {
+        let (world, mapping) = (world(), BlockMapping::default());
+        let cells = world_states(&world, &mapping).unwrap();
+        assert_eq!(litematic_bits(cells.palette.len()), 3);
+        let (_, root) = nbt::read_file(&write_litematic(&cells, "test").unwrap()).unwrap();
+        assert_same(&read_litematic(&root, &mapping).unwrap(), &world);
+    }
+
+    #[test]
+    fn oversized_litematic_regions_are_rejected() {
+        let region = |size: i64| {
+            compound(vec![
+                ("Position", vector(0, 0, 0)),
+                ("Size", compound(vec![("x", Tag::Long(size)), ("y", Tag::Long(size)), ("z", Tag::Long(size))])),
+                ("BlockStatePalette", Tag::compound_list(vec![compound(vec![("Name", Tag::String(AIR.to_string()))])])),
+                ("BlockStates", Tag::LongArray(vec![0; 4])),
+            ])
+        };
+        let root = |size| compound(vec![("Regions", compound(vec![("region", region(size))]))]);
+        let mapping = BlockMapping::default();
+        assert!(read_litematic(&root(i32::MAX as i64), &mapping).is_err());
+        assert!(read_litematic(&root(i64::MIN), &mapping).is_err());
+        assert!(read_litematic(&root(5), &mapping).is_ok());
+    }
+
+    #[test]
+    fn mapping_and_states_round_trip() {
+        let mapping = BlockMapping::default();
+        let csv = mapping.to_csv();
+        assert_eq!(BlockMapping::from_csv(&csv).unwrap().to_csv(), csv);
+        let state = BlockState::parse("oak_stairs[half=bottom,facing=east]");
+        assert_eq!(state.to_string(), "minecraft:oak_stairs[facing=east,half=bottom]");
+    }
+}
@@ -2,14 +2,36 @@
 
 layout(location=0) in vec3 v_color;
 layout(location=1) in vec3 v_position;
+layout(location=2) flat in uint v_shade;
 
 layout(location=0) out vec4 f_color;
 
 void main() {
+    vec3 color = v_color;
+    vec3 edge = vec3(0.0, 0.0, 0.0);
+    if (v_shade == 1) {
+        edge = vec3(1.0, 0.6, 0.0);
+    } else if (v_shade == 2) {
+        color = mix(color, vec3(0.85, 0.85, 0.85), 0.7);
+        edge = vec3(0.6, 0.6, 0.6);
+    } else if (v_shade == 3) {
+        color = mix(color, vec3(0.1, 0.8, 0.1), 0.7);
+        edge = vec3(0.0, 0.4, 0.0);
+    } else if (v_shade == 4) {
+        // Ghosts skip every other pixel, so what is behind them shows through
+        if (((int(gl_FragCoord.x) + int(gl_FragCoord.y)) & 1) == 0) {
+            discard;
+        }
+        color = vec3(0.9, 0.1, 0.1);
+        edge = vec3(0.5, 0.0, 0.0);
+    } else if (v_shade == 5) {
+        color = mix(color, vec3(1.0, 0.85, 0.0), 0.7);
+        edge = vec3(0.6, 0.5, 0.0);
+    }
     vec3 vRel = fract(v_position);
     if (any(lessThan(vec4(vRel, 1.0 - vRel), vec4(0.02)))) {
-        f_color = vec4(0.0, 0.0, 0.0, 1.0);
+        f_color = vec4(edge, 1.0);
     } else {
-        f_color = vec4(v_color, 1.0);
+        f_color = vec4(color, 1.0);
     }
-}
\ No newline at end of file
+}
@@ -5,18 +5,28 @@ layout(location=1) in vec2 a_tex_coords;
 
 layout(location=0) out vec3 v_color;
 layout(location=1) out vec3 v_position;
+layout(location=2) flat out uint v_shade;
 
-layout(set=1, binding=0) 
+layout(set=0, binding=0) 
 uniform Uniforms {
     vec3 u_view_position; 
     mat4 u_view_proj;
 };
 
+// Colours are looked up here so editing the palette recolours every block at once
+layout(set=0, binding=1)
+uniform Palette {
+    vec4 u_palette[256];
+};
+
 layout(location=5) in mat4 model_matrix;
-layout(location=9) in vec3 a_color;
+layout(location=9) in uint a_color;
+// 0 normal, 1 highlighted, 2 dimmed, 3 added, 4 removed, 5 changed
+layout(location=10) in uint a_shade;
 
 void main() {
-    v_color = a_color;
+    v_color = u_palette[a_color].rgb;
     v_position = a_position;
+    v_shade = a_shade;
     gl_Position = u_view_proj * model_matrix * vec4(a_position, 1.0);
-}
\ No newline at end of file
+}
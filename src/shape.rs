@@ -0,0 +1,391 @@
+use crate::brick::Brick;
+use crate::world::{BlockType, World};
+
+/// The six sides of a cell.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub enum Face {
+    PosX,
+    NegX,
+    PosY,
+    NegY,
+    PosZ,
+    NegZ,
+}
+
+pub const FACES: [Face; 6] = [Face::PosX, Face::NegX, Face::PosY, Face::NegY, Face::PosZ, Face::NegZ];
+
+impl Face {
+    pub fn normal(&self) -> [i64; 3] {
+        match self {
+            Face::PosX => [1, 0, 0],
+            Face::NegX => [-1, 0, 0],
+            Face::PosY => [0, 1, 0],
+            Face::NegY => [0, -1, 0],
+            Face::PosZ => [0, 0, 1],
+            Face::NegZ => [0, 0, -1],
+        }
+    }
+
+    pub fn from_normal(normal: [i64; 3]) -> Option<Face> {
+        FACES.iter().copied().find(|face| face.normal() == normal)
+    }
+
+    pub fn opposite(&self) -> Face {
+        let n = self.normal();
+        Face::from_normal([-n[0], -n[1], -n[2]]).unwrap()
+    }
+}
+
+/// Part of a shape's surface. Groups lying on a side of the unit box have `cull` set and
+/// can be hidden by a neighbour there; `full` says they cover that whole side.
+#[derive(Debug, Clone)]
+pub struct FaceGroup {
+    pub cull: Option<Face>,
+    pub full: bool,
+    //Counter clockwise seen from outside
+    pub triangles: Vec<[[f32; 3]; 3]>,
+}
+
+/// The surface of a shape inside the unit box, before scaling to its footprint and rotating.
+#[derive(Debug, Clone)]
+pub struct ShapeGeometry {
+    pub groups: Vec<FaceGroup>,
+}
+
+impl ShapeGeometry {
+    pub fn is_full(&self, face: Face) -> bool {
+        self.groups.iter().any(|group| group.cull == Some(face) && group.full)
+    }
+
+    pub fn triangles(&self) -> impl Iterator<Item = &[[f32; 3]; 3]> + '_ {
+        self.groups.iter().flat_map(|group| group.triangles.iter())
+    }
+
+    /// Box shapes fill every cell they cover, so picking and culling can skip the triangles.
+    pub fn is_box(&self) -> bool {
+        FACES.iter().all(|&face| self.is_full(face))
+    }
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+//Collects triangles, flipping them as needed so they face `normal`
+struct Builder {
+    groups: Vec<FaceGroup>,
+}
+
+impl Builder {
+    fn new() -> Self {
+        Builder { groups: Vec::new() }
+    }
+
+    fn group(&mut self, cull: Option<Face>, full: bool) -> &mut FaceGroup {
+        if let Some(index) = self.groups.iter().position(|group| group.cull == cull && group.full == full) {
+            return &mut self.groups[index];
+        }
+        self.groups.push(FaceGroup { cull, full, triangles: Vec::new() });
+        self.groups.last_mut().unwrap()
+    }
+
+    fn triangle(&mut self, cull: Option<Face>, full: bool, normal: [f32; 3], a: [f32; 3], b: [f32; 3], c: [f32; 3]) {
+        let triangle = if dot(cross(sub(b, a), sub(c, a)), normal) < 0.0 { [a, c, b] } else { [a, b, c] };
+        self.group(cull, full).triangles.push(triangle);
+    }
+
+    //Convex polygon, fanned from its first corner
+    fn polygon(&mut self, cull: Option<Face>, full: bool, normal: [f32; 3], corners: &[[f32; 3]]) {
+        for i in 1..corners.len() - 1 {
+            self.triangle(cull, full, normal, corners[0], corners[i], corners[i + 1]);
+        }
+    }
+
+    //A face lying on a side of the unit box
+    fn side(&mut self, face: Face, full: bool, corners: &[[f32; 3]]) {
+        let n = face.normal();
+        self.polygon(Some(face), full, [n[0] as f32, n[1] as f32, n[2] as f32], corners);
+    }
+
+    fn build(self) -> ShapeGeometry {
+        ShapeGeometry { groups: self.groups }
+    }
+}
+
+//The unit box. Plates and tiles use it too, the transform scales it to their height, but
+//then only the bottom is a full face.
+fn box_geometry(full: bool) -> ShapeGeometry {
+    let h = 1.0;
+    let mut b = Builder::new();
+    b.side(Face::NegY, true, &[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 0.0, 1.0], [0.0, 0.0, 1.0]]);
+    let top = [[0.0, h, 0.0], [1.0, h, 0.0], [1.0, h, 1.0], [0.0, h, 1.0]];
+    if full {
+        b.side(Face::PosY, true, &top);
+    } else {
+        b.polygon(None, false, [0.0, 1.0, 0.0], &top);
+    }
+    b.side(Face::NegX, full, &[[0.0, 0.0, 0.0], [0.0, h, 0.0], [0.0, h, 1.0], [0.0, 0.0, 1.0]]);
+    b.side(Face::PosX, full, &[[1.0, 0.0, 0.0], [1.0, h, 0.0], [1.0, h, 1.0], [1.0, 0.0, 1.0]]);
+    b.side(Face::NegZ, full, &[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, h, 0.0], [0.0, h, 0.0]]);
+    b.side(Face::PosZ, full, &[[0.0, 0.0, 1.0], [1.0, 0.0, 1.0], [1.0, h, 1.0], [0.0, h, 1.0]]);
+    b.build()
+}
+
+//High and vertical at the back (z = 0), falling to the front edge (z = 1)
+fn slope_geometry() -> ShapeGeometry {
+    let mut b = Builder::new();
+    b.side(Face::NegY, true, &[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 0.0, 1.0], [0.0, 0.0, 1.0]]);
+    b.side(Face::NegZ, true, &[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]]);
+    b.polygon(None, false, [0.0, 1.0, 1.0], &[[0.0, 1.0, 0.0], [1.0, 1.0, 0.0], [1.0, 0.0, 1.0], [0.0, 0.0, 1.0]]);
+    b.side(Face::NegX, false, &[[0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+    b.side(Face::PosX, false, &[[1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [1.0, 0.0, 1.0]]);
+    b.build()
+}
+
+//The slope upside down: flat top, sloping underside
+fn inverted_slope_geometry() -> ShapeGeometry {
+    let mut b = Builder::new();
+    b.side(Face::PosY, true, &[[0.0, 1.0, 0.0], [1.0, 1.0, 0.0], [1.0, 1.0, 1.0], [0.0, 1.0, 1.0]]);
+    b.side(Face::NegZ, true, &[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]]);
+    b.polygon(None, false, [0.0, -1.0, 1.0], &[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 1.0], [0.0, 1.0, 1.0]]);
+    b.side(Face::NegX, false, &[[0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 1.0, 1.0]]);
+    b.side(Face::PosX, false, &[[1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [1.0, 1.0, 1.0]]);
+    b.build()
+}
+
+//Outside corner: a pyramid with its peak over the back left corner
+fn corner_slope_geometry() -> ShapeGeometry {
+    let peak = [0.0, 1.0, 0.0];
+    let mut b = Builder::new();
+    b.side(Face::NegY, true, &[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 0.0, 1.0], [0.0, 0.0, 1.0]]);
+    b.side(Face::NegX, false, &[[0.0, 0.0, 0.0], peak, [0.0, 0.0, 1.0]]);
+    b.side(Face::NegZ, false, &[[0.0, 0.0, 0.0], peak, [1.0, 0.0, 0.0]]);
+    b.triangle(None, false, [0.0, 1.0, 1.0], peak, [0.0, 0.0, 1.0], [1.0, 0.0, 1.0]);
+    b.triangle(None, false, [1.0, 1.0, 0.0], peak, [1.0, 0.0, 1.0], [1.0, 0.0, 0.0]);
+    b.build()
+}
+
+//Triangular from above, with square back and left sides
+fn wedge_geometry() -> ShapeGeometry {
+    let mut b = Builder::new();
+    b.side(Face::NegY, false, &[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]]);
+    b.side(Face::PosY, false, &[[0.0, 1.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 1.0]]);
+    b.side(Face::NegZ, true, &[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]]);
+    b.side(Face::NegX, true, &[[0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 1.0, 1.0], [0.0, 0.0, 1.0]]);
+    b.polygon(None, false, [1.0, 0.0, 1.0], &[[1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 1.0], [0.0, 0.0, 1.0]]);
+    b.build()
+}
+
+//A box with a round topped opening through it along z, in the middle third along x
+fn arch_geometry() -> ShapeGeometry {
+    const LEFT: f32 = 1.0 / 3.0;
+    const RIGHT: f32 = 2.0 / 3.0;
+    const SPRING: f32 = 0.5;
+    const RISE: f32 = 0.3;
+    const SEGMENTS: usize = 8;
+    //Opening outline in x/y: up the left side, over the arc, down the right side
+    let arc: Vec<[f32; 2]> = (0..=SEGMENTS)
+        .map(|i| {
+            let angle = std::f32::consts::PI * (1.0 - i as f32 / SEGMENTS as f32);
+            [0.5 + (RIGHT - 0.5) * angle.cos(), SPRING + RISE * angle.sin()]
+        })
+        .collect();
+
+    let mut b = Builder::new();
+    b.side(Face::PosY, true, &[[0.0, 1.0, 0.0], [1.0, 1.0, 0.0], [1.0, 1.0, 1.0], [0.0, 1.0, 1.0]]);
+    b.side(Face::NegX, true, &[[0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 1.0, 1.0], [0.0, 0.0, 1.0]]);
+    b.side(Face::PosX, true, &[[1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [1.0, 1.0, 1.0], [1.0, 0.0, 1.0]]);
+    for &(x0, x1) in &[(0.0, LEFT), (RIGHT, 1.0)] {
+        b.side(Face::NegY, false, &[[x0, 0.0, 0.0], [x1, 0.0, 0.0], [x1, 0.0, 1.0], [x0, 0.0, 1.0]]);
+    }
+    for &(face, z) in &[(Face::NegZ, 0.0), (Face::PosZ, 1.0)] {
+        //Pillars, then the strip between the arc and the top edge
+        b.side(face, false, &[[0.0, 0.0, z], [LEFT, 0.0, z], [LEFT, 1.0, z], [0.0, 1.0, z]]);
+        b.side(face, false, &[[RIGHT, 0.0, z], [1.0, 0.0, z], [1.0, 1.0, z], [RIGHT, 1.0, z]]);
+        for pair in arc.windows(2) {
+            let (a, c) = (pair[0], pair[1]);
+            b.side(face, false, &[[a[0], a[1], z], [c[0], c[1], z], [c[0], 1.0, z], [a[0], 1.0, z]]);
+        }
+    }
+    //Inside of the opening
+    b.polygon(None, false, [1.0, 0.0, 0.0], &[[LEFT, 0.0, 0.0], [LEFT, SPRING, 0.0], [LEFT, SPRING, 1.0], [LEFT, 0.0, 1.0]]);
+    b.polygon(None, false, [-1.0, 0.0, 0.0], &[[RIGHT, 0.0, 0.0], [RIGHT, SPRING, 0.0], [RIGHT, SPRING, 1.0], [RIGHT, 0.0, 1.0]]);
+    for pair in arc.windows(2) {
+        let (a, c) = (pair[0], pair[1]);
+        let middle = [(a[0] + c[0]) / 2.0, (a[1] + c[1]) / 2.0];
+        let inwards = [0.5 - middle[0], SPRING - middle[1], 0.0];
+        b.polygon(None, false, inwards, &[[a[0], a[1], 0.0], [c[0], c[1], 0.0], [c[0], c[1], 1.0], [a[0], a[1], 1.0]]);
+    }
+    b.build()
+}
+
+/// The sides of a shape's unit box it covers completely. Matches `geometry` but is cheap,
+/// for culling tests that run for every face of every object.
+pub fn full_faces(blocktype: BlockType) -> &'static [Face] {
+    match blocktype {
+        BlockType::NORMAL | BlockType::BRICK => &FACES,
+        BlockType::PLATE | BlockType::TILE | BlockType::SLOPE_CORNER => &[Face::NegY],
+        BlockType::SLOPE => &[Face::NegY, Face::NegZ],
+        BlockType::SLOPE_INVERTED => &[Face::PosY, Face::NegZ],
+        BlockType::WEDGE => &[Face::NegZ, Face::NegX],
+        BlockType::ARCH => &[Face::PosY, Face::NegX, Face::PosX],
+    }
+}
+
+/// The surface of a shape in its unit box. `to_world` scales it to the object's size.
+pub fn geometry(blocktype: BlockType) -> ShapeGeometry {
+    match blocktype {
+        BlockType::NORMAL | BlockType::BRICK | BlockType::PLATE | BlockType::TILE => box_geometry(blocktype.height() >= 1.0),
+        BlockType::SLOPE => slope_geometry(),
+        BlockType::SLOPE_INVERTED => inverted_slope_geometry(),
+        BlockType::SLOPE_CORNER => corner_slope_geometry(),
+        BlockType::WEDGE => wedge_geometry(),
+        BlockType::ARCH => arch_geometry(),
+    }
+}
+
+/// Volume of the shape as a fraction of a cell, from the divergence theorem.
+pub fn volume(blocktype: BlockType) -> f32 {
+    let unit: f32 = geometry(blocktype)
+        .triangles()
+        .map(|&[a, b, c]| dot(a, cross(b, c)) / 6.0)
+        .sum();
+    unit * blocktype.height()
+}
+
+/// Whether the side of `object` facing world direction `face` is completely covered by
+/// full faces of its neighbours, so it never needs drawing.
+pub fn face_hidden(world: &World, object: &Brick, face: Face) -> bool {
+    let n = face.normal();
+    let cells: Vec<[i64; 3]> = object.cells().collect();
+    for cell in &cells {
+        let neighbour = [cell[0] + n[0], cell[1] + n[1], cell[2] + n[2]];
+        if cells.contains(&neighbour) {
+            continue;
+        }
+        if !cell_face_hidden(world, *cell, face) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Whether the neighbour of `cell` towards `face` covers that whole side with a full face.
+pub fn cell_face_hidden(world: &World, cell: [i64; 3], face: Face) -> bool {
+    let n = face.normal();
+    let block = match world.get_block(cell[0] + n[0], cell[1] + n[1], cell[2] + n[2]) {
+        Some(block) => block,
+        None => return false,
+    };
+    //Which side of the neighbour's own shape is turned towards us
+    let back = face.opposite().normal();
+    let local = Face::from_normal(block.orientation.inverse().rotate(back)).unwrap();
+    full_faces(block.blocktype).contains(&local)
+}
+
+/// A point of the unit box moved to where `object` puts it, using the same transform as the renderer.
+pub fn to_world(object: &Brick, v: [f32; 3]) -> [f32; 3] {
+    let size = object.shape.size();
+    let position = object.position();
+    let r = object.orientation.rotate_f32([v[0] * size[0], v[1] * size[1], v[2] * size[2]]);
+    [r[0] + position[0], r[1] + position[1], r[2] + position[2]]
+}
+
+/// World space triangles of a placed object.
+pub fn world_triangles(object: &Brick) -> Vec<[[f32; 3]; 3]> {
+    let transform = |v: [f32; 3]| to_world(object, v);
+    geometry(object.shape.blocktype)
+        .triangles()
+        .map(|t| [transform(t[0]), transform(t[1]), transform(t[2])])
+        .collect()
+}
+
+/// Möller-Trumbore. Distance along `direction` to the nearest hit, if any.
+pub fn ray_triangles(origin: [f32; 3], direction: [f32; 3], triangles: &[[[f32; 3]; 3]]) -> Option<f32> {
+    let mut nearest: Option<f32> = None;
+    for [a, b, c] in triangles.iter().copied() {
+        let edge1 = sub(b, a);
+        let edge2 = sub(c, a);
+        let p = cross(direction, edge2);
+        let det = dot(edge1, p);
+        if det.abs() < 1e-7 {
+            continue;
+        }
+        let to_origin = sub(origin, a);
+        let u = dot(to_origin, p) / det;
+        if !(0.0..=1.0).contains(&u) {
+            continue;
+        }
+        let q = cross(to_origin, edge1);
+        let v = dot(direction, q) / det;
+        if v < 0.0 || u + v > 1.0 {
+            continue;
+        }
+        let t = dot(edge2, q) / det;
+        if t > 0.0 && nearest.is_none_or(|nearest| t < nearest) {
+            nearest = Some(t);
+        }
+    }
+    nearest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brick::BrickShape;
+    use crate::orientation::Orientation;
+
+    fn vertical_extent(object: &Brick) -> (f32, f32) {
+        let ys = world_triangles(object).into_iter().flat_map(|triangle| triangle.iter().map(|corner| corner[1]).collect::<Vec<_>>());
+        ys.fold((f32::MAX, f32::MIN), |(low, high), y| (low.min(y), high.max(y)))
+    }
+
+    fn object(blocktype: BlockType, orientation: Orientation) -> Brick {
+        Brick { shape: BrickShape::new(blocktype, 2, 2), origin: [3, 5, -2], orientation, color: 0 }
+    }
+
+    #[test]
+    fn plates_are_a_third_of_a_cell_tall() {
+        for &blocktype in &[BlockType::PLATE, BlockType::TILE] {
+            let (low, high) = vertical_extent(&object(blocktype, Orientation::default()));
+            assert!((low - 5.0).abs() < 1e-5 && (high - (5.0 + 1.0 / 3.0)).abs() < 1e-5, "{:?} spans {} to {}", blocktype, low, high);
+        }
+        let (low, high) = vertical_extent(&object(BlockType::BRICK, Orientation::default()));
+        assert_eq!((low, high), (5.0, 6.0));
+        //On its side a plate is two cells tall and a third of a cell thick
+        let (low, high) = vertical_extent(&object(BlockType::PLATE, Orientation::default().rotated_x()));
+        assert!((low - 5.0).abs() < 1e-5 && (high - 7.0).abs() < 1e-5, "{} to {}", low, high);
+    }
+
+    #[test]
+    fn studs_sit_on_the_top_face() {
+        let mut world = World::default();
+        let plate = object(BlockType::PLATE, Orientation::default());
+        world.place_brick(plate).unwrap();
+        let (_, high) = vertical_extent(&plate);
+        let studs = crate::stud::exposed_studs(&world);
+        assert_eq!(studs.len(), 4);
+        assert!(studs.iter().all(|(position, _)| (position[1] - high).abs() < 1e-5));
+    }
+
+    #[test]
+    fn shapes_are_closed_and_match_full_faces() {
+        for &blocktype in BlockType::ALL.iter() {
+            let geometry = geometry(blocktype);
+            for &face in FACES.iter() {
+                assert_eq!(geometry.is_full(face), full_faces(blocktype).contains(&face), "{:?} {:?}", blocktype, face);
+            }
+            assert!(volume(blocktype) > 0.0 && volume(blocktype) <= 1.0, "{:?}", blocktype);
+        }
+        assert!((volume(BlockType::PLATE) - 1.0 / 3.0).abs() < 1e-5);
+        assert!((volume(BlockType::SLOPE) - 0.5).abs() < 1e-5);
+    }
+}
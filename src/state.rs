@@ -1,96 +1,41 @@
 
 use crate::model;
+use crate::render;
 use crate::camera;
 use crate::texture;
+use crate::mouse_picker;
+use crate::brick;
+use crate::orientation::Orientation;
+use crate::palette::{ColorId, Palette};
+use crate::quantize;
+use crate::recovery::{self, Edit, Recovery};
+use crate::bom;
+use crate::ldraw;
+use crate::gltf;
+use crate::mesh_export;
+use crate::mosaic::Mosaic;
+use crate::diff::{ChangeKind, WorldDiff};
+use crate::history::{self, History};
+use crate::optimizer::Optimizer;
+use crate::scene::{self, SceneFormat};
+use crate::schematic::{self, BlockMapping};
+use crate::voxel_grid;
+use crate::wanted_list::WantedList;
+use crate::inventory::{self, Inventory};
+use crate::instructions;
+use crate::layer_plan;
+use crate::world::World;
+use crate::world_file;
 
 use std::iter;
-use crate::model::Vertex;
-use crate::model::DrawModel;
+use std::path::{Path, PathBuf};
 
-use cgmath::prelude::*;
-use wgpu::util::DeviceExt;
 use winit::{
     event::*,
-    event_loop::{ControlFlow, EventLoop},
-    window::{Window, WindowBuilder},
+    window::Window,
     dpi::PhysicalPosition
 };
 
-fn create_render_pipeline(
-    device: &wgpu::Device,
-    layout: &wgpu::PipelineLayout,
-    color_format: wgpu::TextureFormat,
-    vertex_descs: &[wgpu::VertexBufferDescriptor],
-    vs_src: wgpu::ShaderModuleSource,
-    fs_src: wgpu::ShaderModuleSource,
-) -> wgpu::RenderPipeline {
-    let vs_module = device.create_shader_module(vs_src);
-    let fs_module = device.create_shader_module(fs_src);
-
-    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-        label: Some("Render Pipeline"),
-        layout: Some(&layout),
-        vertex_stage: wgpu::ProgrammableStageDescriptor {
-            module: &vs_module,
-            entry_point: "main",
-        },
-        fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
-            module: &fs_module,
-            entry_point: "main",
-        }),
-        rasterization_state: Some(wgpu::RasterizationStateDescriptor {
-            front_face: wgpu::FrontFace::Ccw,
-            cull_mode: wgpu::CullMode::Back,
-            depth_bias: 0,
-            depth_bias_slope_scale: 0.0,
-            depth_bias_clamp: 0.0,
-            clamp_depth: false,
-        }),
-        primitive_topology: wgpu::PrimitiveTopology::TriangleList,
-        color_states: &[wgpu::ColorStateDescriptor {
-            format: color_format,
-            color_blend: wgpu::BlendDescriptor::REPLACE,
-            alpha_blend: wgpu::BlendDescriptor::REPLACE,
-            write_mask: wgpu::ColorWrite::ALL,
-        }],
-        depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
-            format: texture::Texture::DEPTH_FORMAT,
-            depth_write_enabled: true,
-            depth_compare: wgpu::CompareFunction::Less,
-            stencil: wgpu::StencilStateDescriptor::default(),
-        }),        
-        sample_count: 1,
-        sample_mask: !0,
-        alpha_to_coverage_enabled: false,
-        vertex_state: wgpu::VertexStateDescriptor {
-            //index_format: wgpu::IndexFormat::Uint32,
-            index_format: wgpu::IndexFormat::Uint16,
-            vertex_buffers: vertex_descs,
-        },
-    })
-}
-
-#[repr(C)]
-#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-struct Uniforms {
-    view_position: [f32; 4],
-    view_proj: [[f32; 4]; 4],
-}
-
-impl Uniforms {
-    fn new() -> Self {
-        Self {
-            view_position: [0.0; 4],
-            view_proj: cgmath::Matrix4::identity().into(),
-        }
-    }
-
-    fn update_view_proj(&mut self, camera: &camera::Camera, projection: &camera::Projection) {
-        self.view_position = camera.position.to_homogeneous().into();
-        self.view_proj = (projection.calc_matrix() * camera.calc_matrix()).into()
-    }
-}
-
 pub struct State {
     surface: wgpu::Surface,
     device: wgpu::Device,
@@ -98,21 +43,36 @@ pub struct State {
     sc_desc: wgpu::SwapChainDescriptor,
     swap_chain: wgpu::SwapChain,
     pub size: winit::dpi::PhysicalSize<u32>,
-    render_pipeline: wgpu::RenderPipeline,
+    renderer: render::Renderer,
     pub obj_model: model::Model,
     #[allow(dead_code)]
     pub camera: camera::Camera,                     
     pub projection: camera::Projection,          
     camera_controller: camera::CameraController, 
-    uniforms: Uniforms,
-    uniform_buffer: wgpu::Buffer,
-    uniform_bind_group: wgpu::BindGroup,
 
     #[allow(dead_code)]
     mouse_pressed: bool,
     depth_texture: texture::Texture,
 
     pub curr_cursor_pos: winit::dpi::PhysicalPosition<f64>,
+    //What right click places: index into brick::CATALOGUE, and a palette colour
+    pub selected_shape: usize,
+    pub selected_color: ColorId,
+    pub selected_orientation: Orientation,
+    //Where F2 saves to
+    pub world_path: PathBuf,
+    //Parts owned, and in constrained mode what is left of them after the current world
+    pub inventory: Option<Inventory>,
+    pub stock: Option<Inventory>,
+    //World to compare against, and while the diff is shown the real world it replaces
+    pub diff_base: Option<PathBuf>,
+    hidden_world: Option<World>,
+    //Snapshots saved with the world
+    pub history: History,
+    //Autosave and edit journal of the unsaved changes to world_path
+    recovery: Recovery,
+    //Whether an earlier session left unsaved changes that are not overwritten yet
+    recovery_found: bool,
     //inv_view_proj: cgmath::Matrix4<f32>,
 }
 
@@ -159,39 +119,6 @@ impl State {
             camera::Projection::new(sc_desc.width, sc_desc.height, cgmath::Deg(45.0), 0.1, 100.0);
         let camera_controller = camera::CameraController::new(4.0, 0.4);
 
-        let mut uniforms = Uniforms::new();
-        uniforms.update_view_proj(&camera, &projection);
-        //let inv_view_proj = cgmath::Matrix4::from(uniforms.view_proj).invert().unwrap();
-
-        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Uniform Buffer"),
-            contents: bytemuck::cast_slice(&[uniforms]),
-            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
-        });
-
-        let uniform_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStage::VERTEX,
-                    ty: wgpu::BindingType::UniformBuffer {
-                        dynamic: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
-                label: Some("uniform_bind_group_layout"),
-            });
-
-        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &uniform_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::Buffer(uniform_buffer.slice(..)),
-            }],
-            label: Some("uniform_bind_group"),
-        });
-
         let now = std::time::Instant::now(); 
         let mut obj_model = model::Model::new().unwrap();
         
@@ -199,28 +126,13 @@ impl State {
             &device,
         );
 
+        let mut renderer = render::Renderer::new(&device, sc_desc.format, &obj_model.world.palette);
+        renderer.update_camera(&queue, &camera, &projection);
+
         let depth_texture =
         texture::Texture::create_depth_texture(&device, &sc_desc, "depth_texture");
 
         println!("Elapsed (Original): {:?}", std::time::Instant::now());        
-        let render_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[
-                    &uniform_bind_group_layout,
-                ],
-                push_constant_ranges: &[],
-            });
-
-
-        let render_pipeline = create_render_pipeline(
-            &device,
-            &render_pipeline_layout,
-            sc_desc.format,
-            &[model::ModelVertex::desc(), model::InstanceRaw::desc()],
-            wgpu::include_spirv!("shader.vert.spv"),
-            wgpu::include_spirv!("shader.frag.spv"),
-        );
    
         let curr_cursor_pos:PhysicalPosition<f64> = PhysicalPosition{x: 0.0, y: 0.0};
 
@@ -230,18 +142,26 @@ impl State {
             queue,
             sc_desc,
             swap_chain,
-            render_pipeline,
+            renderer,
             obj_model,
             camera,
             projection,
             camera_controller,
-            uniform_buffer,
-            uniform_bind_group,
-            uniforms,
             size,
             mouse_pressed: false,
             depth_texture,
             curr_cursor_pos,
+            selected_shape: 0,
+            selected_color: 0,
+            selected_orientation: Orientation::default(),
+            world_path: PathBuf::from(format!("world.{}", world_file::EXTENSION)),
+            recovery: Recovery::new(format!("world.{}", world_file::EXTENSION)),
+            recovery_found: false,
+            inventory: None,
+            stock: None,
+            diff_base: None,
+            hidden_world: None,
+            history: History::default(),
             //inv_view_proj
         }
     }
@@ -286,16 +206,562 @@ impl State {
         }
     }
 
+    /// Change a palette entry. Every block using it changes colour on the next frame.
+    /// Not while a diff is shown, its overlay has a palette of its own.
+    pub fn set_palette_color(&mut self, id: ColorId, rgb: [f32; 3]) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.viewing_diff(), "Hide the diff before changing colours");
+        self.obj_model.world.palette.set_rgb(id, rgb)?;
+        self.write_palette();
+        self.record(Edit::SetColor { id, rgb });
+        Ok(())
+    }
+
+    //Journal an edit made to the world. If that fails only crash safety is lost, so it
+    //is reported rather than undoing the edit.
+    fn record(&mut self, edit: Edit) {
+        //The first edit replaces whatever an earlier session left
+        self.recovery_found = false;
+        let world = self.hidden_world.as_ref().unwrap_or(&self.obj_model.world);
+        if let Err(e) = self.recovery.record(&edit, world, &self.history) {
+            eprintln!("Cannot journal the edit: {:?}", e);
+        }
+    }
+
+    //Autosave right away, after changes too big to journal
+    fn checkpoint(&mut self) {
+        self.recovery_found = false;
+        let world = self.hidden_world.as_ref().unwrap_or(&self.obj_model.world);
+        if let Err(e) = self.recovery.autosave(world, &self.history) {
+            eprintln!("Cannot autosave: {:?}", e);
+        }
+    }
+
+    fn write_palette(&mut self) {
+        self.renderer.write_palette(&self.queue, &self.obj_model.world.palette);
+    }
+
+    /// Requantize the world to another palette, e.g. real LEGO colours.
+    pub fn switch_palette(&mut self, palette: Palette) {
+        self.hide_diff();
+        quantize::requantize_world(&mut self.obj_model.world, palette, quantize::DeltaE::Ciede2000);
+        self.recount_stock();
+        self.rebuild_model();
+        self.checkpoint();
+    }
+
+    /// Upload the world again after it has been edited.
+    pub fn rebuild_model(&mut self) {
+        self.obj_model.build_meshes(&self.device);
+        self.write_palette();
+        self.update_preview();
+    }
+
+    fn pick(&self) -> Option<mouse_picker::Pick> {
+        mouse_picker::MousePicker::pick(
+            (self.size.width, self.size.height),
+            (self.curr_cursor_pos.x, self.curr_cursor_pos.y),
+            &self.camera,
+            &self.projection,
+            &self.obj_model.world,
+        )
+    }
+
+    /// Global address of the block under the mouse cursor, if any.
+    pub fn block_under_cursor(&self) -> Option<[i64; 3]> {
+        self.pick()
+            .map(|pick| [pick.block.x as i64, pick.block.y as i64, pick.block.z as i64])
+    }
+
+    pub fn select_next_shape(&mut self) {
+        self.selected_shape = (self.selected_shape + 1) % brick::CATALOGUE.len();
+        println!("Selected {}", brick::CATALOGUE[self.selected_shape].name());
+        self.update_preview();
+    }
+
+    pub fn select_next_color(&mut self) {
+        let palette = &self.obj_model.world.palette;
+        self.selected_color = ((self.selected_color as usize + 1) % palette.len()) as ColorId;
+        println!("Selected colour {}", palette.get(self.selected_color).unwrap().name);
+        self.update_preview();
+    }
+
+    pub fn toggle_studs(&mut self) {
+        self.obj_model.show_studs = !self.obj_model.show_studs;
+    }
+
+    /// Turn the selected shape a quarter turn about the vertical axis.
+    pub fn rotate_selected(&mut self, backwards: bool) {
+        self.selected_orientation = if backwards {
+            self.selected_orientation.rotated_y_back()
+        } else {
+            self.selected_orientation.rotated_y()
+        };
+        self.update_preview();
+    }
+
+    /// Tip the selected shape over about the x axis. Together with `rotate_selected`
+    /// this reaches all 24 orientations.
+    pub fn tip_selected(&mut self) {
+        self.selected_orientation = self.selected_orientation.rotated_x();
+        self.update_preview();
+    }
+
+    //The brick that a right click would place right now
+    fn selected_brick(&self) -> Option<brick::Brick> {
+        let pick = self.pick()?;
+        Some(brick::Brick {
+            shape: brick::CATALOGUE[self.selected_shape],
+            origin: [pick.previous.x as i64, pick.previous.y as i64, pick.previous.z as i64],
+            orientation: self.selected_orientation,
+            color: self.selected_color,
+        })
+    }
+
+    /// Move the placement preview to follow the mouse cursor.
+    pub fn update_preview(&mut self) {
+        let brick = self.selected_brick();
+        self.obj_model.set_preview(&self.device, brick.as_ref());
+    }
+
+    /// Place the selected shape against the face under the mouse cursor.
+    pub fn place_selected(&mut self) {
+        if self.viewing_diff() {
+            return;
+        }
+        let brick = match self.selected_brick() {
+            Some(brick) => brick,
+            None => return,
+        };
+        //In constrained mode every placement uses up a part from the inventory
+        let key = inventory::part_key(&self.obj_model.world, &brick);
+        if let Some(stock) = &self.stock {
+            match &key {
+                Some(key) if stock.count(key) > 0 => {}
+                Some((part, color)) => {
+                    println!("No {} left in colour {}", part, color);
+                    return;
+                }
+                None => {
+                    println!("{} in this colour is not a real part", brick.shape.name());
+                    return;
+                }
+            }
+        }
+        match self.obj_model.world.place_brick(brick) {
+            Ok(_) => {
+                if let (Some(stock), Some(key)) = (&mut self.stock, &key) {
+                    stock.take(key);
+                    if stock.count(key) == 0 {
+                        println!("That was the last {} in colour {}", key.0, key.1);
+                    }
+                }
+                self.rebuild_model();
+                self.record(Edit::Place(brick));
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    /// Remove the block or whole brick under the mouse cursor.
+    pub fn remove_under_cursor(&mut self) {
+        if self.viewing_diff() {
+            return;
+        }
+        if let Some([x, y, z]) = self.block_under_cursor() {
+            let object = self.obj_model.world.object_at(x, y, z);
+            if self.obj_model.world.remove_at(x, y, z) {
+                let key = object.and_then(|object| inventory::part_key(&self.obj_model.world, &object));
+                if let (Some(stock), Some(key)) = (&mut self.stock, &key) {
+                    stock.put_back(key);
+                }
+                self.rebuild_model();
+                if let Some(object) = object {
+                    self.record(Edit::Remove(object));
+                }
+            }
+        }
+    }
+
+    /// Load the parts on hand and report what the current world would still need.
+    pub fn load_inventory<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        let inventory = Inventory::load(path)?;
+        self.report_missing(&inventory);
+        self.inventory = Some(inventory);
+        self.stock = None;
+        Ok(())
+    }
+
+    fn report_missing(&self, inventory: &Inventory) {
+        let missing = inventory.missing(self.edited_world());
+        if missing.items.is_empty() {
+            println!("The inventory has every part this world needs");
+        } else {
+            print!("Missing parts:\n{}", missing.to_rebrickable_csv());
+        }
+        if !missing.unmapped.is_empty() {
+            print!("Not real parts:\n{}", missing.unmapped_report());
+        }
+    }
+
+    /// Switch constrained building on or off. Turning it on starts from whatever the
+    /// inventory has left after the parts already in the world.
+    pub fn toggle_constrained(&mut self) {
+        if self.stock.take().is_some() {
+            println!("Constrained building off");
+            return;
+        }
+        match &self.inventory {
+            Some(inventory) => {
+                self.report_missing(inventory);
+                self.stock = Some(inventory.remaining(self.edited_world()));
+                println!("Constrained building on");
+            }
+            None => println!("Load an inventory first, with --inventory"),
+        }
+    }
+
+    //In constrained mode, recount what the inventory has left after a world replaced the
+    //one the stock was counted for
+    fn recount_stock(&mut self) {
+        if self.stock.is_some() {
+            self.stock = self.inventory.as_ref().map(|inventory| inventory.remaining(self.edited_world()));
+        }
+    }
+
+    /// Selected shape and colour, in constrained mode how many of them are left, and
+    /// whether there are unsaved changes to restore.
+    pub fn status(&self) -> String {
+        let shape = brick::CATALOGUE[self.selected_shape].name();
+        let palette = &self.obj_model.world.palette;
+        let color = palette.get(self.selected_color).map_or("", |color| color.name.as_str());
+        let mut status = format!("{} - {} {}", env!("CARGO_PKG_NAME"), shape, color);
+        if let Some(stock) = &self.stock {
+            let brick = brick::Brick {
+                shape: brick::CATALOGUE[self.selected_shape],
+                origin: [0; 3],
+                orientation: self.selected_orientation,
+                color: self.selected_color,
+            };
+            match inventory::part_key(&self.obj_model.world, &brick) {
+                Some(key) => status += &format!(": {} left", stock.count(&key)),
+                None => status += ": not a real part",
+            }
+        }
+        if self.recovery_found {
+            status += " - unsaved changes found, F12 restores them";
+        }
+        status
+    }
+
+    /// Replace the world with one saved earlier. Later saves go back to the same file,
+    /// except for JSON and RON scenes, which are saved as a world file next to them.
+    /// Unsaved changes an earlier session left to it can be restored with F12.
+    pub fn open_world<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        self.hide_diff();
+        if SceneFormat::of(&path).is_some() {
+            let scene = scene::load(&path)?;
+            self.obj_model.world = scene.world;
+            if let Some(camera) = scene.camera {
+                self.camera = camera;
+            }
+            self.world_path = path.as_ref().with_extension(world_file::EXTENSION);
+            self.history = History::default();
+        } else {
+            let (world, history) = world_file::load_with_history(&path)?;
+            self.obj_model.world = world;
+            self.history = history;
+            self.world_path = path.as_ref().to_path_buf();
+        }
+        self.recovery = Recovery::new(&self.world_path);
+        self.recount_stock();
+        self.rebuild_model();
+        self.check_recovery();
+        Ok(())
+    }
+
+    /// Replace the world with a mosaic of the picture at `path`. Saving writes it next to the picture.
+    pub fn import_mosaic<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        self.hide_diff();
+        self.obj_model.world = Mosaic::default().load(&path)?;
+        self.world_path = path.as_ref().with_extension(world_file::EXTENSION);
+        self.history = History::default();
+        self.recovery = Recovery::new(&self.world_path);
+        self.recount_stock();
+        self.rebuild_model();
+        self.checkpoint();
+        Ok(())
+    }
+
+    /// Opens a Sponge or Litematica schematic with the built in block mapping.
+    pub fn import_schematic<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        self.hide_diff();
+        let imported = schematic::load(&path, &BlockMapping::default())?;
+        if !imported.unknown.is_empty() {
+            println!("{} block states are not in the mapping and were left out:", imported.unknown.len());
+            print!("{}", imported.unknown_report());
+        }
+        self.obj_model.world = imported.world;
+        self.world_path = path.as_ref().with_extension(world_file::EXTENSION);
+        self.history = History::default();
+        self.recovery = Recovery::new(&self.world_path);
+        self.recount_stock();
+        self.rebuild_model();
+        self.checkpoint();
+        Ok(())
+    }
+
+    /// Opens a binvox file or a raw grid with its sidecar.
+    pub fn import_voxels<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        self.hide_diff();
+        self.obj_model.world = voxel_grid::load(&path)?;
+        self.world_path = path.as_ref().with_extension(world_file::EXTENSION);
+        self.history = History::default();
+        self.recovery = Recovery::new(&self.world_path);
+        self.recount_stock();
+        self.rebuild_model();
+        self.checkpoint();
+        Ok(())
+    }
+
+    /// Show what changed since the world at `path`: added blocks in green, removed ones as
+    /// red ghosts and changed ones in yellow. Editing waits until the diff is hidden again.
+    pub fn show_diff<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        self.hide_diff();
+        let before = world_file::load(&path)?;
+        let diff = WorldDiff::between(&before, &self.obj_model.world);
+        println!("Changes since {}: {}", path.as_ref().display(), diff.summary());
+        let (overlay, kinds) = diff.overlay(&before, &self.obj_model.world)?;
+        self.diff_base = Some(path.as_ref().to_path_buf());
+        self.hidden_world = Some(std::mem::replace(&mut self.obj_model.world, overlay));
+        self.obj_model.shades = kinds
+            .into_iter()
+            .map(|(origin, kind)| {
+                let shade = match kind {
+                    ChangeKind::Added => model::Shade::Added,
+                    ChangeKind::Removed => model::Shade::Removed,
+                    ChangeKind::Recolored | ChangeKind::Replaced => model::Shade::Changed,
+                };
+                (origin, shade)
+            })
+            .collect();
+        self.rebuild_model();
+        Ok(())
+    }
+
+    /// Go back to the world being edited.
+    pub fn hide_diff(&mut self) {
+        if let Some(world) = self.hidden_world.take() {
+            self.obj_model.world = world;
+            self.obj_model.shades.clear();
+            self.rebuild_model();
+        }
+    }
+
+    pub fn viewing_diff(&self) -> bool {
+        self.hidden_world.is_some()
+    }
+
+    /// Switch between the diff against `diff_base` and the world itself.
+    pub fn toggle_diff(&mut self) -> anyhow::Result<()> {
+        if self.viewing_diff() {
+            self.hide_diff();
+            return Ok(());
+        }
+        match self.diff_base.clone() {
+            Some(path) => self.show_diff(path),
+            None => {
+                println!("No world to compare with, start with --diff before.bkw");
+                Ok(())
+            }
+        }
+    }
+
+    //The world being edited, even while a diff is shown instead
+    fn edited_world(&self) -> &World {
+        self.hidden_world.as_ref().unwrap_or(&self.obj_model.world)
+    }
+
+    pub fn save_world(&mut self) -> anyhow::Result<()> {
+        world_file::save_with_history(self.edited_world(), &self.history, &self.world_path)?;
+        println!("Saved {}", self.world_path.display());
+        self.recovery.clear()
+    }
+
+    /// Write the unsaved changes, if any, to the recovery file now rather than at the next
+    /// autosave. For when the editor has to quit.
+    pub fn autosave(&mut self) {
+        if self.recovery.is_dirty() {
+            self.checkpoint();
+            println!("Unsaved changes are kept in {}", recovery::autosave_path(&self.world_path).display());
+        }
+    }
+
+    /// Look for unsaved changes an earlier session left to the world file. If there are,
+    /// the status says so until they are restored with F12 or replaced by an edit.
+    pub fn check_recovery(&mut self) {
+        self.recovery_found = match recovery::pending(&self.world_path) {
+            Some(time) => {
+                let seconds = time.duration_since(std::time::UNIX_EPOCH).map_or(0, |time| time.as_secs());
+                println!(
+                    "Found unsaved changes to {} from {} UTC, press F12 to restore them",
+                    self.world_path.display(),
+                    history::format_time(seconds)
+                );
+                true
+            }
+            None => false,
+        };
+    }
+
+    /// Replace the world with the autosave and journal left by an earlier session.
+    pub fn restore_recovery(&mut self) -> anyhow::Result<()> {
+        if !self.recovery_found {
+            println!("No unsaved changes from an earlier session to restore");
+            return Ok(());
+        }
+        self.hide_diff();
+        let (world, history, replayed) = recovery::recover(&self.world_path)?;
+        self.obj_model.world = world;
+        self.history = history;
+        self.recount_stock();
+        self.rebuild_model();
+        //Still unsaved, so start a fresh autosave and journal from here
+        self.checkpoint();
+        println!("Restored unsaved changes, {} edits replayed from the journal", replayed);
+        Ok(())
+    }
+
+    /// Keep the world as it is now in its history. It is written with the next save.
+    pub fn take_snapshot(&mut self) -> anyhow::Result<()> {
+        let name = format!("snapshot {}", self.history.snapshots.len() + 1);
+        let index = self.history.snapshot(self.edited_world(), &name)?;
+        println!("Took {}, save to keep it", self.history.snapshots[index].name);
+        self.checkpoint();
+        Ok(())
+    }
+
+    /// Go to the snapshot taken before (or after) the current one. Snapshots taken from
+    /// there branch off. Changes since the current snapshot are kept as a snapshot first.
+    pub fn step_history(&mut self, backwards: bool) -> anyhow::Result<()> {
+        self.hide_diff();
+        let index = match (self.history.current, backwards) {
+            (Some(current), true) if current > 0 => current - 1,
+            (Some(current), false) if current + 1 < self.history.snapshots.len() => current + 1,
+            (None, _) if !self.history.is_empty() => self.history.snapshots.len() - 1,
+            _ => {
+                println!("No more snapshots");
+                return Ok(());
+            }
+        };
+        if let Some(kept) = self.history.snapshot_if_changed(&self.obj_model.world, "before restoring")? {
+            println!("Kept the changes since the last snapshot as snapshot {}", kept);
+        }
+        self.obj_model.world = self.history.restore(index, self.obj_model.world.chunk_size())?;
+        self.recount_stock();
+        self.rebuild_model();
+        self.checkpoint();
+        println!("Restored {} {}", index, self.history.snapshots[index].name);
+        Ok(())
+    }
+
+    /// Write the parts list next to the world file, as CSV and JSON, together with
+    /// BrickLink and Rebrickable wanted lists.
+    pub fn export_bom(&self) -> anyhow::Result<()> {
+        let bom = bom::BillOfMaterials::from_world(self.edited_world());
+        let csv = self.world_path.with_extension("bom.csv");
+        let json = self.world_path.with_extension("bom.json");
+        bom.save_csv(&csv)?;
+        bom.save_json(&json)?;
+        println!("{} parts, written to {} and {}", bom.total, csv.display(), json.display());
+
+        let wanted = WantedList::from_bom(&bom);
+        wanted.save(&self.world_path)?;
+        if !wanted.is_complete() {
+            print!("Some parts could not be mapped:\n{}", wanted.unmapped_report());
+        }
+        Ok(())
+    }
+
+    /// Merge unit blocks into standard bricks, in place.
+    pub fn optimize_bricks(&mut self) {
+        self.hide_diff();
+        let before = self.obj_model.world.objects().count();
+        match Optimizer::default().optimize(&self.obj_model.world) {
+            Ok(world) => {
+                println!("{} objects merged into {}", before, world.objects().count());
+                self.obj_model.world = world;
+                self.recount_stock();
+                self.rebuild_model();
+                self.checkpoint();
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    pub fn export_ldraw(&self) -> anyhow::Result<()> {
+        let path = self.world_path.with_extension("ldr");
+        ldraw::save(self.edited_world(), &path)?;
+        println!("Exported {}", path.display());
+        Ok(())
+    }
+
+    /// Write the world's surface as STL in millimetres, ready for a slicer.
+    pub fn export_stl(&self) -> anyhow::Result<()> {
+        let path = self.world_path.with_extension("stl");
+        mesh_export::save(self.edited_world(), &path, mesh_export::LEGO_MM)?;
+        println!("Exported {}", path.display());
+        Ok(())
+    }
+
+    /// Write the world and camera as a RON scene next to the world file.
+    pub fn export_scene(&self) -> anyhow::Result<()> {
+        let path = self.world_path.with_extension("ron");
+        scene::save(self.edited_world(), Some(&self.camera), &path)?;
+        println!("Exported {}", path.display());
+        Ok(())
+    }
+
+    /// Write the world as binary glTF at real brick size, for Blender and web viewers.
+    pub fn export_gltf(&self) -> anyhow::Result<()> {
+        let path = self.world_path.with_extension("glb");
+        gltf::save(self.edited_world(), &path, gltf::GltfMode::Merged, gltf::LEGO_METRES)?;
+        println!("Exported {}", path.display());
+        Ok(())
+    }
+
+    /// Render layer by layer building instructions into a folder next to the world file.
+    pub fn export_instructions(&self) -> anyhow::Result<()> {
+        let dir = self.world_path.with_extension("instructions");
+        let steps = instructions::steps(self.edited_world(), instructions::StepOrder::Layers);
+        instructions::write_instructions(
+            &self.device,
+            &self.queue,
+            self.edited_world(),
+            &steps,
+            &dir,
+            (instructions::DEFAULT_SIZE, instructions::DEFAULT_SIZE),
+        )?;
+        println!("{} steps written to {}", steps.len(), dir.display());
+        Ok(())
+    }
+
+    /// Draw every layer as a plan seen from above, into a folder next to the world file.
+    pub fn export_layer_plans(&self) -> anyhow::Result<()> {
+        let dir = self.world_path.with_extension("plans");
+        let count = layer_plan::save(self.edited_world(), layer_plan::Axis::Y, &dir)?;
+        println!("{} layer plans written to {}", count, dir.display());
+        Ok(())
+    }
+
     pub fn update(&mut self, dt: std::time::Duration) {
         self.camera_controller.update_camera(&mut self.camera, dt);
-        self.uniforms
-            .update_view_proj(&self.camera, &self.projection);
+        self.obj_model.update_studs(&self.device, self.camera.position);
+        self.renderer.update_camera(&self.queue, &self.camera, &self.projection);
+        let world = self.hidden_world.as_ref().unwrap_or(&self.obj_model.world);
+        if let Err(e) = self.recovery.tick(world, &self.history) {
+            eprintln!("Cannot autosave: {:?}", e);
+        }
         //self.inv_view_proj = cgmath::Matrix4::from(self.uniforms.view_proj).invert().unwrap();
-        self.queue.write_buffer(
-            &self.uniform_buffer,
-            0,
-            bytemuck::cast_slice(&[self.uniforms]),
-        );
     }
 
     pub fn render(&mut self) -> Result<(), wgpu::SwapChainError> {
@@ -307,39 +773,13 @@ impl State {
                 label: Some("Render Encoder"),
             });
 
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                    attachment: &frame.view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
-                        }),
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                    attachment: &self.depth_texture.view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true,
-                    }),
-                    stencil_ops: None,
-                }),
-            });
-
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.draw_model_instanced(
-                &self.obj_model,
-                //0..self.instances.len() as u32,
-                &self.uniform_bind_group,
-                //&self.light_bind_group,
-            );
-        }
+        self.renderer.draw(
+            &mut encoder,
+            &frame.view,
+            &self.depth_texture.view,
+            &self.obj_model,
+            render::CLEAR_COLOR,
+        );
 
         self.queue.submit(iter::once(encoder.finish()));
 
@@ -0,0 +1,51 @@
+use crate::palette::ColorId;
+use crate::world::World;
+
+//Real studs are 4.8 mm wide and 1.7 mm tall on an 8 mm x 9.6 mm brick
+pub const STUD_RADIUS: f32 = 0.3;
+pub const STUD_HEIGHT: f32 = 0.18;
+const STUD_SEGMENTS: u16 = 12;
+
+/// Studs further away from the camera than this are not drawn.
+pub const STUD_LOD_DISTANCE: f32 = 40.0;
+
+/// Where studs go: the base of every top face that faces up and has nothing on it.
+pub fn exposed_studs(world: &World) -> Vec<([f32; 3], ColorId)> {
+    let mut studs = Vec::new();
+    for ([x, y, z], block) in world.iter_blocks() {
+        if !block.blocktype.has_studs() || block.orientation.up() != [0, 1, 0] {
+            continue;
+        }
+        if world.get_block(x, y + 1, z).is_some() {
+            continue;
+        }
+        studs.push(([x as f32, y as f32 + block.blocktype.height(), z as f32], block.color));
+    }
+    studs
+}
+
+/// A low poly open bottomed cylinder standing in the middle of a unit cell, from y = 0 up.
+pub fn stud_geometry() -> (Vec<[f32; 3]>, Vec<u16>) {
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    for i in 0..STUD_SEGMENTS {
+        let angle = i as f32 / STUD_SEGMENTS as f32 * std::f32::consts::PI * 2.0;
+        let x = 0.5 + STUD_RADIUS * angle.cos();
+        let z = 0.5 + STUD_RADIUS * angle.sin();
+        vertices.push([x, 0.0, z]);
+        vertices.push([x, STUD_HEIGHT, z]);
+    }
+    let centre = vertices.len() as u16;
+    vertices.push([0.5, STUD_HEIGHT, 0.5]);
+
+    for i in 0..STUD_SEGMENTS {
+        let bottom = i * 2;
+        let top = bottom + 1;
+        let next_bottom = (i + 1) % STUD_SEGMENTS * 2;
+        let next_top = next_bottom + 1;
+        //Counter clockwise seen from outside
+        indices.extend_from_slice(&[bottom, top, next_top, bottom, next_top, next_bottom]);
+        indices.extend_from_slice(&[centre, next_top, top]);
+    }
+    (vertices, indices)
+}
@@ -15,10 +15,19 @@ impl Texture {
         device: &wgpu::Device,
         sc_desc: &wgpu::SwapChainDescriptor,
         label: &str,
+    ) -> Self {
+        Self::create_depth_texture_sized(device, sc_desc.width, sc_desc.height, label)
+    }
+
+    pub fn create_depth_texture_sized(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        label: &str,
     ) -> Self {
         let size = wgpu::Extent3d {
-            width: sc_desc.width,
-            height: sc_desc.height,
+            width,
+            height,
             depth: 1,
         };
         let desc = wgpu::TextureDescriptor {
@@ -0,0 +1,41 @@
+use anyhow::*;
+use std::path::Path;
+
+use crate::history::History;
+use crate::instructions;
+use crate::model::Model;
+use crate::render::Offscreen;
+use crate::world::World;
+
+/// Render how the build grew: one picture per snapshot leading to `last`, oldest first,
+/// all from the same camera. Returns how many frames were written.
+pub fn write_timelapse(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    history: &History,
+    last: usize,
+    chunk_size: u8,
+    dir: &Path,
+    size: (u32, u32),
+) -> Result<usize> {
+    std::fs::create_dir_all(dir).with_context(|| format!("Cannot create {}", dir.display()))?;
+    let lineage = history.lineage(last);
+    let worlds: Vec<World> = lineage.iter().map(|&index| history.world(index, chunk_size)).collect::<Result<_>>()?;
+    //Builds mostly grow, so the last frame shows the most of it
+    let camera = instructions::overview_camera(worlds.last().context("No snapshots to replay")?);
+    let mut offscreen = Offscreen::new(device, size.0, size.1, &worlds[0].palette)?;
+
+    let mut obj_model = Model::new()?;
+    //The whole model is in view, so draw every stud
+    obj_model.stud_distance = f32::INFINITY;
+    for (number, world) in worlds.into_iter().enumerate() {
+        //Snapshots keep their own palette, render uploads this frame's one
+        obj_model.world = world;
+        obj_model.build_meshes(device);
+        obj_model.update_studs(device, camera.position);
+        let image = offscreen.render(device, queue, &obj_model, &camera, crate::render::CLEAR_COLOR)?;
+        let file = format!("frame_{:03}.png", number + 1);
+        image.save(dir.join(&file)).with_context(|| format!("Cannot write {}", file))?;
+    }
+    Ok(lineage.len())
+}
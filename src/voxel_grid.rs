@@ -0,0 +1,370 @@
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::bom;
+use crate::palette::{linear_to_srgb, srgb_to_linear, ColorId, Palette};
+use crate::world::{Block, BlockType, World};
+
+//Refuse grids that would not fit in memory comfortably
+const MAX_CELLS: usize = 1 << 31;
+
+//Cells in a grid of this size, refusing empty and oversized grids
+fn checked_volume(size: [usize; 3], what: &str) -> Result<usize> {
+    ensure!(size.iter().all(|&length| length > 0), "The {} has no cells ({:?})", what, size);
+    let volume = size[0].checked_mul(size[1]).and_then(|area| area.checked_mul(size[2]));
+    match volume {
+        Some(volume) if volume <= MAX_CELLS => Ok(volume),
+        _ => bail!("The {} is too big ({:?} cells)", what, size),
+    }
+}
+
+/// The world's cells as a dense box, each cell holding a colour or nothing. Shapes are
+/// lost: every occupied cell counts as full.
+#[derive(Debug, Clone)]
+pub struct VoxelGrid {
+    //World position of the grid's first cell
+    pub origin: [i64; 3],
+    pub size: [usize; 3],
+    //x fastest, then y, then z
+    pub cells: Vec<Option<ColorId>>,
+}
+
+impl VoxelGrid {
+    pub fn from_world(world: &World) -> Result<VoxelGrid> {
+        let (min, max) = bom::bounds(world).context("The world is empty")?;
+        let size = [(max[0] - min[0] + 1) as usize, (max[1] - min[1] + 1) as usize, (max[2] - min[2] + 1) as usize];
+        let volume = checked_volume(size, "world as a dense grid")?;
+        let mut grid = VoxelGrid {
+            origin: min,
+            size,
+            cells: vec![None; volume],
+        };
+        for (position, block) in world.iter_blocks() {
+            let index = grid.index([
+                (position[0] - min[0]) as usize,
+                (position[1] - min[1]) as usize,
+                (position[2] - min[2]) as usize,
+            ]);
+            grid.cells[index] = Some(block.color);
+        }
+        Ok(grid)
+    }
+
+    /// A world of unit blocks, one per occupied cell, with the given palette.
+    pub fn to_world(&self, palette: Palette) -> World {
+        let mut world = World::default();
+        world.palette = palette;
+        for x in 0..self.size[0] {
+            for y in 0..self.size[1] {
+                for z in 0..self.size[2] {
+                    if let Some(color) = self.cells[self.index([x, y, z])] {
+                        world.set_block(
+                            self.origin[0] + x as i64,
+                            self.origin[1] + y as i64,
+                            self.origin[2] + z as i64,
+                            Some(Block {
+                                blocktype: BlockType::NORMAL,
+                                color,
+                                orientation: Default::default(),
+                                brick: None,
+                            }),
+                        );
+                    }
+                }
+            }
+        }
+        world
+    }
+
+    fn index(&self, cell: [usize; 3]) -> usize {
+        cell[0] + self.size[0] * (cell[1] + self.size[1] * cell[2])
+    }
+
+    fn volume(&self) -> usize {
+        self.size[0] * self.size[1] * self.size[2]
+    }
+
+    /// Binvox, run length encoded occupancy. Binvox runs y fastest, then z, then x, and
+    /// gives the sizes in that reverse order. The grid's origin goes in `translate`, with
+    /// `scale` set so that one voxel stays one cell.
+    pub fn to_binvox(&self) -> Vec<u8> {
+        let longest = self.size.iter().max().copied().unwrap_or(1);
+        let mut binvox = format!(
+            "#binvox 1\ndim {} {} {}\ntranslate {} {} {}\nscale {}\ndata\n",
+            self.size[0], self.size[2], self.size[1], self.origin[0], self.origin[1], self.origin[2], longest
+        )
+        .into_bytes();
+        let mut run: Option<(u8, u8)> = None;
+        for x in 0..self.size[0] {
+            for z in 0..self.size[2] {
+                for y in 0..self.size[1] {
+                    let value = self.cells[self.index([x, y, z])].is_some() as u8;
+                    run = match run {
+                        Some((current, count)) if current == value && count < u8::MAX => Some((current, count + 1)),
+                        Some((current, count)) => {
+                            binvox.extend_from_slice(&[current, count]);
+                            Some((value, 1))
+                        }
+                        None => Some((value, 1)),
+                    };
+                }
+            }
+        }
+        if let Some((value, count)) = run {
+            binvox.extend_from_slice(&[value, count]);
+        }
+        binvox
+    }
+
+    /// Reads binvox, every voxel becoming `color`. Files written by other tools usually
+    /// have no meaningful origin; theirs is rounded to whole cells.
+    pub fn from_binvox(bytes: &[u8], color: ColorId) -> Result<VoxelGrid> {
+        let mut dims: Option<[usize; 3]> = None;
+        let mut translate = [0.0f64; 3];
+        let mut rest = bytes;
+        loop {
+            let end = rest.iter().position(|&b| b == b'\n').context("Binvox header ends early")?;
+            let line = String::from_utf8_lossy(&rest[..end]).trim().to_string();
+            rest = &rest[end + 1..];
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("#binvox") | None => {}
+                Some("dim") => {
+                    let values: Vec<usize> = words.filter_map(|word| word.parse().ok()).collect();
+                    ensure!(values.len() == 3, "Invalid binvox dimensions {:?}", line);
+                    //Stored as x, z, y
+                    dims = Some([values[0], values[2], values[1]]);
+                }
+                Some("translate") => {
+                    let values: Vec<f64> = words.filter_map(|word| word.parse().ok()).collect();
+                    ensure!(values.len() == 3, "Invalid binvox translation {:?}", line);
+                    translate = [values[0], values[1], values[2]];
+                }
+                Some("data") => break,
+                _ => {}
+            }
+        }
+        let size = dims.context("Binvox file without dimensions")?;
+        let volume = checked_volume(size, "binvox grid")?;
+        let mut grid = VoxelGrid {
+            origin: [translate[0].round() as i64, translate[1].round() as i64, translate[2].round() as i64],
+            size,
+            cells: vec![None; volume],
+        };
+
+        let mut filled = 0;
+        for pair in rest.chunks(2) {
+            if let [value, count] = *pair {
+                let count = count as usize;
+                ensure!(filled + count <= grid.volume(), "Binvox data is longer than the grid");
+                if value != 0 {
+                    for i in filled..filled + count {
+                        //i runs y fastest, then z, then x
+                        let y = i % size[1];
+                        let z = (i / size[1]) % size[2];
+                        let x = i / (size[1] * size[2]);
+                        let index = grid.index([x, y, z]);
+                        grid.cells[index] = Some(color);
+                    }
+                }
+                filled += count;
+            }
+        }
+        ensure!(filled == grid.volume(), "Binvox data covers {} of {} voxels", filled, grid.volume());
+        Ok(grid)
+    }
+
+    /// One byte per cell, 0 for empty and the colour id plus one otherwise, x fastest, then
+    /// y, then z. What the bytes mean goes in the sidecar.
+    pub fn to_raw(&self, palette: &Palette) -> Result<(Vec<u8>, RawSidecar)> {
+        let mut raw = Vec::with_capacity(self.volume());
+        for cell in &self.cells {
+            raw.push(match cell {
+                Some(color) => {
+                    ensure!(*color < u8::MAX as ColorId, "Colour {} does not fit in a byte", color);
+                    *color as u8 + 1
+                }
+                None => 0,
+            });
+        }
+        let colors = palette
+            .iter()
+            .filter(|(id, _)| *id < u8::MAX as ColorId)
+            .map(|(id, color)| RawColor {
+                value: id as u8 + 1,
+                name: color.name.clone(),
+                srgb: [linear_to_srgb(color.rgb[0]), linear_to_srgb(color.rgb[1]), linear_to_srgb(color.rgb[2])],
+            })
+            .collect();
+        let sidecar = RawSidecar {
+            size: self.size,
+            origin: self.origin,
+            order: RAW_ORDER.to_string(),
+            colors,
+        };
+        Ok((raw, sidecar))
+    }
+
+    /// The grid and palette back from bytes written by `to_raw`.
+    pub fn from_raw(raw: &[u8], sidecar: &RawSidecar) -> Result<(VoxelGrid, Palette)> {
+        ensure!(sidecar.order == RAW_ORDER, "Unsupported raw grid order {:?}, expected {:?}", sidecar.order, RAW_ORDER);
+        let volume = checked_volume(sidecar.size, "raw grid")?;
+        ensure!(raw.len() == volume, "Raw grid has {} bytes, the sidecar says {}", raw.len(), volume);
+
+        //Values without a colour in the sidecar get one of their own
+        let mut palette = Palette::new();
+        let mut colors: [Option<ColorId>; 256] = [None; 256];
+        for color in &sidecar.colors {
+            let rgb = [srgb_to_linear(color.srgb[0]), srgb_to_linear(color.srgb[1]), srgb_to_linear(color.srgb[2])];
+            colors[color.value as usize] = Some(palette.add(&color.name, rgb)?);
+        }
+        let mut cells = Vec::with_capacity(raw.len());
+        for &value in raw {
+            if value == 0 {
+                cells.push(None);
+                continue;
+            }
+            let color = match colors[value as usize] {
+                Some(color) => color,
+                None => {
+                    let color = palette.add(&format!("Value {}", value), [0.5, 0.5, 0.5])?;
+                    colors[value as usize] = Some(color);
+                    color
+                }
+            };
+            cells.push(Some(color));
+        }
+        if palette.is_empty() {
+            palette = Palette::default();
+        }
+        Ok((
+            VoxelGrid {
+                origin: sidecar.origin,
+                size: sidecar.size,
+                cells,
+            },
+            palette,
+        ))
+    }
+}
+
+const RAW_ORDER: &str = "x fastest, then y, then z";
+
+/// The JSON file next to a raw grid.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RawSidecar {
+    pub size: [usize; 3],
+    //World position of the first cell, y is up
+    pub origin: [i64; 3],
+    pub order: String,
+    pub colors: Vec<RawColor>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RawColor {
+    pub value: u8,
+    pub name: String,
+    pub srgb: [u8; 3],
+}
+
+fn extension(path: &Path) -> String {
+    path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_lowercase()
+}
+
+/// Writes `.binvox`, or `.raw` with a `.json` sidecar, by extension.
+pub fn save<P: AsRef<Path>>(world: &World, path: P) -> Result<()> {
+    let path = path.as_ref();
+    let grid = VoxelGrid::from_world(world)?;
+    let write = |path: &Path, contents: &[u8]| std::fs::write(path, contents).with_context(|| format!("Cannot write {}", path.display()));
+    match extension(path).as_str() {
+        "binvox" => write(path, &grid.to_binvox()),
+        "raw" => {
+            let (raw, sidecar) = grid.to_raw(&world.palette)?;
+            write(&path.with_extension("json"), &serde_json::to_vec_pretty(&sidecar)?)?;
+            write(path, &raw)
+        }
+        _ => bail!("Unknown voxel format {:?}, expected .binvox or .raw", path),
+    }
+}
+
+/// Reads `.binvox`, in gray, or `.raw` with its `.json` sidecar.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<World> {
+    let path = path.as_ref();
+    let read = |path: &Path| std::fs::read(path).with_context(|| format!("Cannot read {}", path.display()));
+    match extension(path).as_str() {
+        "binvox" => {
+            let palette = Palette::default();
+            let gray = palette.find("Gray").unwrap_or(0);
+            Ok(VoxelGrid::from_binvox(&read(path)?, gray)?.to_world(palette))
+        }
+        "raw" => {
+            let sidecar_path = path.with_extension("json");
+            let sidecar: RawSidecar = serde_json::from_slice(&read(&sidecar_path)?)
+                .with_context(|| format!("Invalid sidecar {}", sidecar_path.display()))?;
+            let (grid, palette) = VoxelGrid::from_raw(&read(path)?, &sidecar)?;
+            Ok(grid.to_world(palette))
+        }
+        _ => bail!("Unknown voxel format {:?}, expected .binvox or .raw", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //A sparse, oddly sized world so runs cross rows and colours repeat
+    fn world() -> World {
+        let mut world = World::default();
+        for x in -9..7i64 {
+            for y in -4..3i64 {
+                for z in -33..-30i64 {
+                    if (x * x + y * 3 + z).rem_euclid(3) == 0 {
+                        let color = (x + y).rem_euclid(7) as ColorId;
+                        let block = Block { blocktype: BlockType::NORMAL, color, orientation: Default::default(), brick: None };
+                        world.set_block(x, y, z, Some(block));
+                    }
+                }
+            }
+        }
+        world
+    }
+
+    fn cells(world: &World) -> Vec<([i64; 3], ColorId)> {
+        let mut cells: Vec<_> = world.iter_blocks().map(|(position, block)| (position, block.color)).collect();
+        cells.sort();
+        cells
+    }
+
+    #[test]
+    fn binvox_round_trip() {
+        let world = world();
+        let grid = VoxelGrid::from_world(&world).unwrap();
+        let read = VoxelGrid::from_binvox(&grid.to_binvox(), 3).unwrap();
+        assert_eq!((read.origin, read.size), (grid.origin, grid.size));
+        let positions = |world: &World| cells(world).into_iter().map(|(position, _)| position).collect::<Vec<_>>();
+        assert_eq!(positions(&read.to_world(Palette::default())), positions(&world));
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let world = world();
+        let (raw, sidecar) = VoxelGrid::from_world(&world).unwrap().to_raw(&world.palette).unwrap();
+        let (grid, palette) = VoxelGrid::from_raw(&raw, &sidecar).unwrap();
+        let read = grid.to_world(palette);
+        assert_eq!(cells(&read), cells(&world));
+        for (id, color) in world.palette.iter() {
+            assert_eq!(read.palette.get(id).unwrap().name, color.name);
+        }
+    }
+
+    #[test]
+    fn impossible_sizes_are_rejected() {
+        let binvox = |dim: &str| format!("#binvox 1\ndim {}\ndata\n", dim).into_bytes();
+        assert!(VoxelGrid::from_binvox(&binvox("18446744073709551615 1 1"), 0).is_err());
+        assert!(VoxelGrid::from_binvox(&binvox("4294967296 4294967296 1"), 0).is_err());
+        assert!(VoxelGrid::from_binvox(&binvox("0 1 1"), 0).is_err());
+        let sidecar = RawSidecar { size: [usize::MAX, 2, 1], origin: [0; 3], order: RAW_ORDER.to_string(), colors: Vec::new() };
+        assert!(VoxelGrid::from_raw(&[0, 0], &sidecar).is_err());
+    }
+}
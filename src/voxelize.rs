@@ -0,0 +1,264 @@
+use anyhow::*;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::bom::{CELL_HEIGHT_MM, CELL_WIDTH_MM};
+use crate::lego;
+use crate::mesh::TriangleMesh;
+use crate::palette::{ColorId, Palette};
+use crate::quantize::{DeltaE, Quantizer};
+use crate::world::{Block, BlockType, World};
+
+/// Which cells of a mesh become blocks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fill {
+    //Only the cells the surface passes through, a hollow shell
+    Surface,
+    //The shell and everything inside it. Needs a closed mesh.
+    Solid,
+}
+
+/// Turns triangle meshes into blocks.
+#[derive(Debug, Clone)]
+pub struct Voxelizer {
+    //Cells along the longest side of the mesh, as measured in studs
+    pub size: u32,
+    pub fill: Fill,
+    pub palette: Palette,
+    pub metric: DeltaE,
+    //For parts of the mesh without a colour
+    pub default_color: ColorId,
+}
+
+impl Default for Voxelizer {
+    fn default() -> Self {
+        let palette = lego::lego_basic_palette();
+        Self {
+            size: 32,
+            fill: Fill::Solid,
+            //Light bluish gray
+            default_color: palette.find_ldraw(71).unwrap_or(0),
+            palette,
+            metric: DeltaE::Ciede2000,
+        }
+    }
+}
+
+//Running sum of the colours that landed in a cell
+#[derive(Debug, Clone, Copy, Default)]
+struct ColorSum {
+    rgb: [f32; 3],
+    count: u32,
+}
+
+impl ColorSum {
+    fn add(&mut self, rgb: Option<[f32; 3]>) {
+        if let Some(rgb) = rgb {
+            for (sum, channel) in self.rgb.iter_mut().zip(rgb.iter()) {
+                *sum += channel;
+            }
+            self.count += 1;
+        }
+    }
+
+    fn mean(&self) -> Option<[f32; 3]> {
+        if self.count == 0 {
+            return None;
+        }
+        let n = self.count as f32;
+        Some([self.rgb[0] / n, self.rgb[1] / n, self.rgb[2] / n])
+    }
+}
+
+//Height and colour where a ray crosses the surface
+type Hit = (f32, Option<[f32; 3]>);
+
+fn interpolate(corners: &[[f32; 3]; 3], weights: [f32; 3]) -> [f32; 3] {
+    let mut point = [0.0; 3];
+    for (corner, weight) in corners.iter().zip(weights.iter()) {
+        for axis in 0..3 {
+            point[axis] += corner[axis] * weight;
+        }
+    }
+    point
+}
+
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+impl Voxelizer {
+    /// The mesh in cell units, its lowest corner at the origin. Cells are a stud wide and
+    /// a brick high, so the mesh is squashed vertically to keep its proportions once built.
+    fn to_cells(&self, mesh: &TriangleMesh) -> Result<TriangleMesh> {
+        let (min, max) = mesh.bounds().context("The mesh has no triangles")?;
+        let squash = CELL_WIDTH_MM / CELL_HEIGHT_MM;
+        let longest = (max[0] - min[0]).max((max[1] - min[1]) * squash).max(max[2] - min[2]);
+        ensure!(longest > 0.0, "The mesh is flat in every direction");
+        //Just short of size, so the far side does not start a cell of its own
+        let scale = (self.size as f32 - 1e-3) / longest;
+        let mut cells = mesh.clone();
+        for triangle in &mut cells.triangles {
+            for vertex in &mut triangle.vertices {
+                *vertex = [
+                    (vertex[0] - min[0]) * scale,
+                    (vertex[1] - min[1]) * scale * squash,
+                    (vertex[2] - min[2]) * scale,
+                ];
+            }
+        }
+        Ok(cells)
+    }
+
+    //Samples every triangle at less than half a cell apart and marks the cells hit
+    fn surface(&self, mesh: &TriangleMesh) -> HashMap<[i64; 3], ColorSum> {
+        let mut cells: HashMap<[i64; 3], ColorSum> = HashMap::new();
+        for triangle in &mesh.triangles {
+            let [a, b, c] = triangle.vertices;
+            let longest = distance(a, b).max(distance(b, c)).max(distance(c, a));
+            let steps = (longest * 2.0).ceil().max(1.0) as usize;
+            for i in 0..=steps {
+                for j in 0..=steps - i {
+                    let u = i as f32 / steps as f32;
+                    let v = j as f32 / steps as f32;
+                    let weights = [1.0 - u - v, u, v];
+                    let point = interpolate(&triangle.vertices, weights);
+                    let cell = [point[0].floor() as i64, point[1].floor() as i64, point[2].floor() as i64];
+                    let color = triangle.colors.map(|colors| interpolate(&colors, weights));
+                    cells.entry(cell).or_default().add(color);
+                }
+            }
+        }
+        cells
+    }
+
+    //Casts a ray up through the centre of every column and fills between the points
+    //where it enters and leaves the mesh. Interior cells take the colour of the entry.
+    fn interior(&self, mesh: &TriangleMesh) -> HashMap<[i64; 3], ColorSum> {
+        let mut columns: BTreeMap<(i64, i64), Vec<Hit>> = BTreeMap::new();
+        for triangle in &mesh.triangles {
+            let [a, b, c] = triangle.vertices;
+            let area = (b[0] - a[0]) * (c[2] - a[2]) - (c[0] - a[0]) * (b[2] - a[2]);
+            if area.abs() < 1e-9 {
+                //Seen edge on from below, the neighbours catch the ray
+                continue;
+            }
+            let x_range = a[0].min(b[0]).min(c[0]).floor() as i64..=a[0].max(b[0]).max(c[0]).floor() as i64;
+            for x in x_range {
+                let z_range = a[2].min(b[2]).min(c[2]).floor() as i64..=a[2].max(b[2]).max(c[2]).floor() as i64;
+                for z in z_range {
+                    //Nudged off the centre so rays do not run exactly through shared edges
+                    let px = x as f32 + 0.5 + 1e-4;
+                    let pz = z as f32 + 0.5 + 2e-4;
+                    let u = ((px - a[0]) * (c[2] - a[2]) - (c[0] - a[0]) * (pz - a[2])) / area;
+                    let v = ((b[0] - a[0]) * (pz - a[2]) - (px - a[0]) * (b[2] - a[2])) / area;
+                    if u < 0.0 || v < 0.0 || u + v > 1.0 {
+                        continue;
+                    }
+                    let weights = [1.0 - u - v, u, v];
+                    let y = interpolate(&triangle.vertices, weights)[1];
+                    let color = triangle.colors.map(|colors| interpolate(&colors, weights));
+                    columns.entry((x, z)).or_default().push((y, color));
+                }
+            }
+        }
+
+        let mut cells: HashMap<[i64; 3], ColorSum> = HashMap::new();
+        for ((x, z), mut hits) in columns {
+            hits.sort_by(|a, b| a.0.total_cmp(&b.0));
+            for pair in hits.chunks(2) {
+                if let [(enter, color), (leave, _)] = pair {
+                    let mut y = (enter - 0.5).ceil() as i64;
+                    while (y as f32 + 0.5) <= *leave {
+                        cells.entry([x, y, z]).or_default().add(*color);
+                        y += 1;
+                    }
+                }
+            }
+        }
+        cells
+    }
+
+    /// A new world with the mesh as unit blocks in the voxelizer's palette.
+    pub fn voxelize(&self, mesh: &TriangleMesh) -> Result<World> {
+        ensure!(self.size > 0, "Cannot voxelize to zero cells");
+        ensure!(!self.palette.is_empty(), "Cannot voxelize to an empty palette");
+        let mesh = self.to_cells(mesh)?;
+        let mut cells = self.surface(&mesh);
+        if self.fill == Fill::Solid {
+            for (cell, sum) in self.interior(&mesh) {
+                cells.entry(cell).or_insert(sum);
+            }
+        }
+
+        let quantizer = Quantizer::new(&self.palette, self.metric);
+        let mut world = World::default();
+        world.palette = self.palette.clone();
+        for ([x, y, z], sum) in cells {
+            let color = match sum.mean() {
+                Some(rgb) => quantizer.nearest_linear(rgb),
+                None => self.default_color,
+            };
+            world.set_block(
+                x,
+                y,
+                z,
+                Some(Block {
+                    blocktype: BlockType::NORMAL,
+                    color,
+                    orientation: Default::default(),
+                    brick: None,
+                }),
+            );
+        }
+        Ok(world)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::{read_mtl, read_obj, Triangle};
+
+    //A unit cube, all of it in one colour
+    fn cube(rgb: [f32; 3]) -> TriangleMesh {
+        let obj = "mtllib paint.mtl\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 0 1\nv 1 0 1\nv 1 1 1\nv 0 1 1\nusemtl paint\n\
+            f 1 4 3 2\nf 5 6 7 8\nf 1 2 6 5\nf 4 8 7 3\nf 1 5 8 4\nf 2 3 7 6\n";
+        read_obj(obj, |_| read_mtl(&format!("newmtl paint\nKd {} {} {}\n", rgb[0], rgb[1], rgb[2]))).unwrap()
+    }
+
+    #[test]
+    fn solid_and_surface_fills() {
+        let mut voxelizer = Voxelizer { size: 10, ..Voxelizer::default() };
+        let red = voxelizer.palette.find_ldraw(4).unwrap();
+        let cube = cube(voxelizer.palette.get(red).unwrap().rgb);
+        let world = voxelizer.voxelize(&cube).unwrap();
+        //Bricks are taller than wide, so the cube is 9 bricks high
+        assert_eq!(crate::bom::bounds(&world), Some(([0, 0, 0], [9, 8, 9])));
+        assert_eq!(world.iter_blocks().count(), 10 * 10 * 9);
+        assert!(world.iter_blocks().all(|(_, block)| block.color == red));
+
+        voxelizer.fill = Fill::Surface;
+        let shell = voxelizer.voxelize(&cube).unwrap();
+        assert_eq!(shell.iter_blocks().count(), 10 * 10 * 9 - 8 * 8 * 7);
+        assert!(shell.get_block(5, 4, 5).is_none());
+    }
+
+    #[test]
+    fn uncoloured_meshes_get_the_default_colour() {
+        let mut mesh = cube([0.5; 3]);
+        for triangle in &mut mesh.triangles {
+            triangle.colors = None;
+        }
+        let voxelizer = Voxelizer { size: 4, ..Voxelizer::default() };
+        let world = voxelizer.voxelize(&mesh).unwrap();
+        assert!(world.iter_blocks().all(|(_, block)| block.color == voxelizer.default_color));
+    }
+
+    #[test]
+    fn bad_input_is_rejected() {
+        assert!(Voxelizer { size: 0, ..Voxelizer::default() }.voxelize(&cube([0.5; 3])).is_err());
+        assert!(Voxelizer::default().voxelize(&TriangleMesh::default()).is_err());
+        let point = Triangle { vertices: [[1.0; 3]; 3], colors: None };
+        assert!(Voxelizer::default().voxelize(&TriangleMesh { triangles: vec![point] }).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+use anyhow::*;
+use std::path::Path;
+
+use crate::bom::{csv_field, BillOfMaterials, BomLine};
+use crate::ldraw;
+
+/// BrickLink colour id for an LDraw colour code. Rebrickable uses the LDraw codes as they are.
+pub fn bricklink_color(ldraw_id: u32) -> Option<u32> {
+    let id = match ldraw_id {
+        0 => 11,
+        1 => 7,
+        2 => 6,
+        3 => 39,
+        4 => 5,
+        5 => 47,
+        6 => 8,
+        7 => 9,
+        8 => 10,
+        9 => 62,
+        10 => 36,
+        11 => 40,
+        12 => 25,
+        13 => 23,
+        14 => 3,
+        15 => 1,
+        17 => 38,
+        18 => 33,
+        19 => 2,
+        20 => 44,
+        22 => 24,
+        23 => 109,
+        25 => 4,
+        26 => 71,
+        27 => 34,
+        28 => 69,
+        29 => 104,
+        30 => 157,
+        31 => 154,
+        68 => 96,
+        70 => 88,
+        71 => 86,
+        72 => 85,
+        73 => 42,
+        74 => 37,
+        78 => 90,
+        84 => 150,
+        85 => 89,
+        92 => 28,
+        115 => 76,
+        191 => 110,
+        212 => 105,
+        226 => 103,
+        272 => 63,
+        288 => 80,
+        308 => 120,
+        320 => 59,
+        321 => 153,
+        322 => 156,
+        323 => 152,
+        326 => 158,
+        330 => 155,
+        378 => 48,
+        379 => 55,
+        484 => 68,
+        _ => return None,
+    };
+    Some(id)
+}
+
+/// BrickLink item number for an LDraw part number. Mostly the same.
+pub fn bricklink_part(ldraw_part: &str) -> &str {
+    match ldraw_part {
+        "3040b" => "3040",
+        part => part,
+    }
+}
+
+/// A part and colour that can be ordered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WantedItem {
+    pub part: &'static str,
+    pub ldraw_color: u32,
+    pub bricklink_color: Option<u32>,
+    pub quantity: u32,
+}
+
+/// The bill of materials split into orderable items and lines that need a human.
+#[derive(Debug, Clone)]
+pub struct WantedList {
+    pub items: Vec<WantedItem>,
+    pub unmapped: Vec<(BomLine, String)>,
+}
+
+impl WantedList {
+    pub fn from_bom(bom: &BillOfMaterials) -> Self {
+        let mut items: Vec<WantedItem> = Vec::new();
+        let mut unmapped = Vec::new();
+        for line in &bom.lines {
+            let part = match ldraw::part_id(&line.shape) {
+                Some(part) => part,
+                None => {
+                    unmapped.push((line.clone(), format!("no catalogue part for {}", line.part)));
+                    continue;
+                }
+            };
+            let ldraw_color = match line.ldraw_color {
+                Some(color) => color,
+                None => {
+                    unmapped.push((line.clone(), format!("{} is not a LEGO colour", line.color)));
+                    continue;
+                }
+            };
+            //A unit block and a 1x1 brick are the same part
+            match items.iter_mut().find(|item| item.part == part && item.ldraw_color == ldraw_color) {
+                Some(item) => item.quantity += line.quantity,
+                None => items.push(WantedItem {
+                    part,
+                    ldraw_color,
+                    bricklink_color: bricklink_color(ldraw_color),
+                    quantity: line.quantity,
+                }),
+            }
+        }
+        WantedList { items, unmapped }
+    }
+
+    /// BrickLink wanted list upload format. Items without a BrickLink colour are left out
+    /// here and listed in the report instead.
+    pub fn to_bricklink_xml(&self) -> String {
+        let mut xml = String::from("<INVENTORY>\n");
+        for item in &self.items {
+            if let Some(color) = item.bricklink_color {
+                xml += &format!(
+                    "  <ITEM>\n    <ITEMTYPE>P</ITEMTYPE>\n    <ITEMID>{}</ITEMID>\n    <COLOR>{}</COLOR>\n    <MINQTY>{}</MINQTY>\n  </ITEM>\n",
+                    bricklink_part(item.part),
+                    color,
+                    item.quantity,
+                );
+            }
+        }
+        xml += "</INVENTORY>\n";
+        xml
+    }
+
+    /// Rebrickable parts list import format.
+    pub fn to_rebrickable_csv(&self) -> String {
+        let mut csv = String::from("Part,Color,Quantity\n");
+        for item in &self.items {
+            csv += &format!("{},{},{}\n", item.part, item.ldraw_color, item.quantity);
+        }
+        csv
+    }
+
+    /// Everything that did not make it into both lists, and why.
+    pub fn unmapped_report(&self) -> String {
+        let mut csv = String::from("part,color,quantity,reason\n");
+        for (line, reason) in &self.unmapped {
+            csv += &format!("{},{},{},{}\n", csv_field(&line.part), csv_field(&line.color), line.quantity, csv_field(reason));
+        }
+        for item in self.items.iter().filter(|item| item.bricklink_color.is_none()) {
+            csv += &format!(
+                "{},{},{},no BrickLink colour for this LDraw colour\n",
+                item.part, item.ldraw_color, item.quantity,
+            );
+        }
+        csv
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.unmapped.is_empty() && self.items.iter().all(|item| item.bricklink_color.is_some())
+    }
+
+    /// Writes `<stem>.bricklink.xml`, `<stem>.rebrickable.csv` and, if anything could not
+    /// be mapped, `<stem>.unmapped.csv` next to `path`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let write = |extension: &str, contents: String| -> Result<()> {
+            let path = path.with_extension(extension);
+            std::fs::write(&path, contents).with_context(|| format!("Cannot write {}", path.display()))
+        };
+        write("bricklink.xml", self.to_bricklink_xml())?;
+        write("rebrickable.csv", self.to_rebrickable_csv())?;
+        if !self.is_complete() {
+            write("unmapped.csv", self.unmapped_report())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brick::{Brick, BrickShape};
+    use crate::world::{Block, BlockType, World};
+
+    //Two red 2x4 bricks, a red 1x1 brick next to a red unit block, a red 3x5 brick,
+    //which is not a real part, and a 1x2 brick in a colour LEGO does not make
+    fn world() -> World {
+        let mut world = World::default();
+        world.palette = crate::lego::lego_palette();
+        let red = world.palette.find_ldraw(4).unwrap();
+        let custom = world.palette.add("Salmon", [1.0, 0.5, 0.4]).unwrap();
+        let mut place = |blocktype, width, depth, origin, color| {
+            let shape = BrickShape::new(blocktype, width, depth);
+            world.place_brick(Brick { shape, origin, orientation: Default::default(), color }).unwrap();
+        };
+        place(BlockType::BRICK, 2, 4, [0, 0, 0], red);
+        place(BlockType::BRICK, 2, 4, [0, 1, 0], red);
+        place(BlockType::BRICK, 1, 1, [5, 0, 0], red);
+        place(BlockType::BRICK, 3, 5, [10, 0, 0], red);
+        place(BlockType::BRICK, 1, 2, [20, 0, 0], custom);
+        let block = Block { blocktype: BlockType::NORMAL, color: red, orientation: Default::default(), brick: None };
+        world.set_block(6, 0, 0, Some(block));
+        world
+    }
+
+    #[test]
+    fn parts_are_mapped_and_merged() {
+        let wanted = WantedList::from_bom(&BillOfMaterials::from_world(&world()));
+        let quantity = |part: &str| wanted.items.iter().find(|item| item.part == part).map(|item| item.quantity);
+        assert_eq!(wanted.items.len(), 2);
+        assert_eq!((quantity("3001"), quantity("3005")), (Some(2), Some(2)));
+        assert!(wanted.items.iter().all(|item| item.ldraw_color == 4 && item.bricklink_color == Some(5)));
+        assert_eq!(wanted.unmapped.len(), 2);
+        assert!(!wanted.is_complete());
+
+        let csv = wanted.to_rebrickable_csv();
+        assert!(csv.starts_with("Part,Color,Quantity\n") && csv.contains("3001,4,2\n") && csv.contains("3005,4,2\n"));
+        let xml = wanted.to_bricklink_xml();
+        assert_eq!(xml.matches("<ITEM>").count(), 2);
+        assert!(xml.contains("<ITEMID>3001</ITEMID>\n    <COLOR>5</COLOR>\n    <MINQTY>2</MINQTY>"));
+        let report = wanted.unmapped_report();
+        assert!(report.contains("no catalogue part") && report.contains("Salmon is not a LEGO colour"), "{}", report);
+    }
+
+    #[test]
+    fn items_without_a_bricklink_colour_are_reported() {
+        let mut wanted = WantedList::from_bom(&BillOfMaterials::from_world(&world()));
+        wanted.unmapped.clear();
+        assert!(wanted.is_complete());
+        wanted.items[0].bricklink_color = None;
+        assert!(!wanted.is_complete());
+        assert_eq!(wanted.to_bricklink_xml().matches("<ITEM>").count(), 1);
+        assert!(wanted.unmapped_report().contains("no BrickLink colour"));
+        assert_eq!(bricklink_part("3040b"), "3040");
+        assert_eq!(bricklink_part("3001"), "3001");
+    }
+
+    #[test]
+    fn save_writes_the_unmapped_list_only_when_needed() {
+        let dir = std::env::temp_dir().join(format!("byggeklosser-wanted-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("world.bkw");
+        let mut wanted = WantedList::from_bom(&BillOfMaterials::from_world(&world()));
+        wanted.save(&path).unwrap();
+        for extension in &["bricklink.xml", "rebrickable.csv", "unmapped.csv"] {
+            assert!(path.with_extension(extension).exists(), "{}", extension);
+        }
+        std::fs::remove_file(path.with_extension("unmapped.csv")).unwrap();
+        wanted.unmapped.clear();
+        wanted.save(&path).unwrap();
+        assert!(!path.with_extension("unmapped.csv").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
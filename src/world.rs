@@ -0,0 +1,334 @@
+use std::collections::BTreeMap;
+
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+
+use crate::brick::{Brick, BrickId, BrickShape};
+use crate::chunk::Chunk;
+use crate::orientation::Orientation;
+use crate::palette::{ColorId, Palette};
+
+pub const DEFAULT_CHUNKSIZE: u8 = 16;
+
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum BlockType {
+    NORMAL,
+    BRICK,
+    PLATE,
+    TILE,
+    SLOPE,
+    SLOPE_INVERTED,
+    SLOPE_CORNER,
+    WEDGE,
+    ARCH,
+}
+
+impl BlockType {
+    pub const ALL: [BlockType; 9] = [
+        BlockType::NORMAL,
+        BlockType::BRICK,
+        BlockType::PLATE,
+        BlockType::TILE,
+        BlockType::SLOPE,
+        BlockType::SLOPE_INVERTED,
+        BlockType::SLOPE_CORNER,
+        BlockType::WEDGE,
+        BlockType::ARCH,
+    ];
+
+    /// Stable number for the block type, used by the file formats.
+    pub fn index(&self) -> u8 {
+        BlockType::ALL.iter().position(|blocktype| blocktype == self).unwrap() as u8
+    }
+
+    pub fn from_index(index: u8) -> Option<BlockType> {
+        BlockType::ALL.get(index as usize).copied()
+    }
+
+    /// The block type spelled as in its `Debug` output, which the text formats use.
+    pub fn from_name(name: &str) -> Option<BlockType> {
+        BlockType::ALL.iter().copied().find(|blocktype| format!("{:?}", blocktype) == name)
+    }
+
+    /// Whether the top face carries studs. Tiles are smooth and slopes have no flat top.
+    pub fn has_studs(&self) -> bool {
+        matches!(self, BlockType::NORMAL | BlockType::BRICK | BlockType::PLATE)
+    }
+
+    /// Drawn height in cells.
+    pub fn height(&self) -> f32 {
+        match self {
+            BlockType::PLATE | BlockType::TILE => 1.0 / 3.0,
+            _ => 1.0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
+pub struct Block {
+    pub blocktype : BlockType,
+    pub color: ColorId,
+    pub orientation: Orientation,
+    //The brick covering this cell. Unit blocks have none.
+    pub brick: Option<BrickId>,
+}
+
+/// Chunks are kept in a BTreeMap so meshing and saving always visit them in the same order.
+#[derive(Debug)]
+pub struct World{
+    pub chunks: BTreeMap<[i64;3], Chunk>,
+    pub palette: Palette,
+    pub bricks: BTreeMap<BrickId, Brick>,
+    next_brick_id: BrickId,
+    chunk_size: u8,
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHUNKSIZE)
+    }
+}
+
+impl World {
+    /// Panics on a chunk size of 0, chunks must hold at least one cell.
+    pub fn new(chunk_size: u8) -> Self {
+        assert!(chunk_size > 0, "chunk size must be at least 1");
+        Self {
+            chunks: BTreeMap::new(),
+            palette: Palette::default(),
+            bricks: BTreeMap::new(),
+            next_brick_id: 1,
+            chunk_size,
+        }
+    }
+
+    pub fn chunk_size(&self) -> u8 {
+        self.chunk_size
+    }
+
+    /// Split a global block address into chunk key and position inside that chunk.
+    pub fn split_address(&self, x: i64, y: i64, z: i64) -> ([i64;3], [u8;3]) {
+        let size = self.chunk_size as i64;
+        (
+            [x.div_euclid(size), y.div_euclid(size), z.div_euclid(size)],
+            [x.rem_euclid(size) as u8, y.rem_euclid(size) as u8, z.rem_euclid(size) as u8],
+        )
+    }
+
+    pub fn get_block(&self, x: i64, y: i64, z: i64) -> Option<&Block> {
+        let (chunkkey, blockkey) = self.split_address(x, y, z);
+        self.chunks.get(&chunkkey)?.get(blockkey)
+    }
+
+    /// Store a block (or air for `None`), creating or dropping chunks as needed.
+    /// Returns the block that was there before.
+    pub fn set_block(&mut self, x: i64, y: i64, z: i64, block: Option<Block>) -> Option<Block> {
+        let (chunkkey, blockkey) = self.split_address(x, y, z);
+        let chunk_size = self.chunk_size;
+        let old = match block {
+            Some(_) => self.chunks
+                .entry(chunkkey)
+                .or_insert_with(|| Chunk::new(chunk_size))
+                .set(blockkey, block),
+            None => self.chunks.get_mut(&chunkkey)?.set(blockkey, None),
+        };
+        if self.chunks.get(&chunkkey).is_some_and(|chunk| chunk.is_empty()) {
+            self.chunks.remove(&chunkkey);
+        }
+        old
+    }
+
+    /// Place a brick, marking every cell it covers. Fails if any of them is taken.
+    /// Unit blocks (`BlockType::NORMAL`) are stored as plain cells instead.
+    pub fn place_brick(&mut self, brick: Brick) -> Result<Option<BrickId>> {
+        if let Some(cell) = brick.cells().find(|&[x, y, z]| self.get_block(x, y, z).is_some()) {
+            bail!("Cannot place {} at {:?}, {:?} is taken", brick.shape.name(), brick.origin, cell);
+        }
+        if brick.shape.blocktype == BlockType::NORMAL {
+            for [x, y, z] in brick.cells() {
+                self.set_block(x, y, z, Some(Block {
+                    blocktype: BlockType::NORMAL,
+                    color: brick.color,
+                    orientation: brick.orientation,
+                    brick: None,
+                }));
+            }
+            return Ok(None);
+        }
+
+        let id = self.next_brick_id;
+        self.next_brick_id += 1;
+        let block = Block {
+            blocktype: brick.shape.blocktype,
+            color: brick.color,
+            orientation: brick.orientation,
+            brick: Some(id),
+        };
+        for [x, y, z] in brick.cells() {
+            self.set_block(x, y, z, Some(block));
+        }
+        self.bricks.insert(id, brick);
+        Ok(Some(id))
+    }
+
+    pub fn remove_brick(&mut self, id: BrickId) -> Option<Brick> {
+        let brick = self.bricks.remove(&id)?;
+        for [x, y, z] in brick.cells() {
+            self.set_block(x, y, z, None);
+        }
+        Some(brick)
+    }
+
+    pub fn brick_at(&self, x: i64, y: i64, z: i64) -> Option<(BrickId, &Brick)> {
+        let id = self.get_block(x, y, z)?.brick?;
+        self.bricks.get(&id).map(|brick| (id, brick))
+    }
+
+    /// The object covering a cell, as a brick value like `objects` returns.
+    pub fn object_at(&self, x: i64, y: i64, z: i64) -> Option<Brick> {
+        let block = self.get_block(x, y, z)?;
+        match block.brick {
+            Some(id) => self.bricks.get(&id).copied(),
+            None => Some(Brick {
+                shape: BrickShape::new(block.blocktype, 1, 1),
+                origin: [x, y, z],
+                orientation: block.orientation,
+                color: block.color,
+            }),
+        }
+    }
+
+    /// Remove whatever occupies the cell: the whole brick covering it, or the unit block.
+    pub fn remove_at(&mut self, x: i64, y: i64, z: i64) -> bool {
+        match self.get_block(x, y, z).map(|block| block.brick) {
+            Some(Some(id)) => self.remove_brick(id).is_some(),
+            Some(None) => self.set_block(x, y, z, None).is_some(),
+            None => false,
+        }
+    }
+
+    /// Rewrite the colour of every block and brick.
+    pub fn map_colors<F: Fn(ColorId) -> ColorId>(&mut self, f: F) {
+        let blocks: Vec<_> = self
+            .iter_blocks()
+            .map(|(address, block)| (address, *block))
+            .collect();
+        for ([x, y, z], mut block) in blocks {
+            block.color = f(block.color);
+            self.set_block(x, y, z, Some(block));
+        }
+        for brick in self.bricks.values_mut() {
+            brick.color = f(brick.color);
+        }
+    }
+
+    /// Everything placed in the world as brick values: the bricks with their ids, and
+    /// the unit blocks as 1x1 shapes without one.
+    pub fn objects(&self) -> impl Iterator<Item = (Option<BrickId>, Brick)> + '_ {
+        let blocks = self
+            .iter_blocks()
+            .filter(|(_, block)| block.brick.is_none())
+            .map(|(address, block)| {
+                (None, Brick {
+                    shape: BrickShape::new(block.blocktype, 1, 1),
+                    origin: address,
+                    orientation: block.orientation,
+                    color: block.color,
+                })
+            });
+        let bricks = self.bricks.iter().map(|(id, brick)| (Some(*id), *brick));
+        blocks.chain(bricks)
+    }
+
+    /// All blocks with their global address, in a deterministic order.
+    pub fn iter_blocks(&self) -> impl Iterator<Item = ([i64;3], &Block)> + '_ {
+        let size = self.chunk_size as i64;
+        self.chunks.iter().flat_map(move |(chunkkey, chunk)| {
+            chunk.iter().map(move |(blockkey, block)| {
+                (
+                    [
+                        chunkkey[0] * size + blockkey[0] as i64,
+                        chunkkey[1] * size + blockkey[1] as i64,
+                        chunkkey[2] * size + blockkey[2] as i64,
+                    ],
+                    block,
+                )
+            })
+        })
+    }
+
+    #[allow(non_snake_case)]
+    pub fn GetBlockFromGlobalAddress(&self, x : f64, y: f64, z: f64) -> Option<&Block>
+    {
+        self.get_block(x.floor() as i64, y.floor() as i64, z.floor() as i64)
+    }
+
+    /// A world holding `objects`, as returned by `objects`, placed in order. Brick ids
+    /// are handed out afresh.
+    pub fn from_objects<I: IntoIterator<Item = Brick>>(chunk_size: u8, palette: Palette, objects: I) -> Result<World> {
+        ensure!(chunk_size > 0, "Invalid chunk size 0");
+        let mut world = World::new(chunk_size);
+        world.palette = palette;
+        for object in objects {
+            ensure!(
+                (object.color as usize) < world.palette.len(),
+                "Colour {} at {:?} is not in the palette",
+                object.color,
+                object.origin
+            );
+            world.place_brick(object)?;
+        }
+        Ok(world)
+    }
+}
+
+//Text formats get the palette and the objects, like the world file, so small builds
+//read and diff well however the chunks happen to pack their cells
+#[derive(Serialize, Deserialize)]
+struct WorldScene {
+    chunk_size: u8,
+    palette: Palette,
+    objects: Vec<Brick>,
+}
+
+impl Serialize for World {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        WorldScene {
+            chunk_size: self.chunk_size,
+            palette: self.palette.clone(),
+            objects: self.objects().map(|(_, object)| object).collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for World {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let scene = WorldScene::deserialize(deserializer)?;
+        World::from_objects(scene.chunk_size, scene.palette, scene.objects).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "chunk size must be at least 1")]
+    fn chunk_size_0_is_rejected() {
+        World::new(0);
+    }
+
+    #[test]
+    fn blocks_land_in_their_chunks() {
+        let mut world = World::new(4);
+        let block = Block { blocktype: BlockType::NORMAL, color: 1, orientation: Default::default(), brick: None };
+        world.set_block(-1, 4, 3, Some(block));
+        assert_eq!(world.split_address(-1, 4, 3), ([-1, 1, 0], [3, 0, 3]));
+        assert_eq!(world.get_block(-1, 4, 3), Some(&block));
+        assert_eq!(world.set_block(-1, 4, 3, None), Some(block));
+        assert!(world.chunks.is_empty());
+        assert!(World::from_objects(0, Palette::default(), Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,396 @@
+use anyhow::*;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::brick::{Brick, BrickShape};
+use crate::history::{History, Snapshot};
+use crate::orientation::Orientation;
+use crate::palette::Palette;
+use crate::world::{BlockType, World};
+
+/// Extension of saved worlds.
+pub const EXTENSION: &str = "bkw";
+
+const MAGIC: &[u8; 4] = b"BYGG";
+//Version 1 files have no history, and are still written when there is none
+const VERSION: u32 = 2;
+
+//Marks a snapshot without a parent
+const NO_SNAPSHOT: u32 = u32::MAX;
+
+/// Save format, little endian:
+///
+/// ```text
+/// "BYGG" version:u32 chunk_size:u8
+/// palette: count:u16 { name_len:u16 name:utf8 rgb:3*f32 has_ldraw:u8 ldraw:u32 }
+/// objects: count:u64 { blocktype:u8 width:u8 depth:u8 orientation:u8 color:u16 origin:3*i64 }
+/// history (version 2): count:u32 { name_len:u16 name:utf8 time:u64 parent:u32
+///     palette removed:objects added:objects } current:u32
+/// ```
+///
+/// Objects are unit blocks and bricks as returned by `World::objects`, so loading
+/// rebuilds the chunks and brick ids from scratch. A parent or current of `u32::MAX`
+/// means none.
+pub fn write_world<W: Write>(writer: &mut W, world: &World) -> Result<()> {
+    write_world_with_history(writer, world, &History::default())
+}
+
+pub fn write_world_with_history<W: Write>(writer: &mut W, world: &World, history: &History) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    let version = if history.is_empty() { 1 } else { VERSION };
+    writer.write_all(&version.to_le_bytes())?;
+    writer.write_all(&[world.chunk_size()])?;
+    write_palette(writer, &world.palette)?;
+    let objects: Vec<_> = world.objects().map(|(_, object)| object).collect();
+    write_objects(writer, &objects)?;
+
+    if history.is_empty() {
+        return Ok(());
+    }
+    writer.write_all(&(history.snapshots.len() as u32).to_le_bytes())?;
+    for snapshot in &history.snapshots {
+        write_string(writer, &snapshot.name)?;
+        writer.write_all(&snapshot.time.to_le_bytes())?;
+        writer.write_all(&snapshot.parent.map_or(NO_SNAPSHOT, |parent| parent as u32).to_le_bytes())?;
+        write_palette(writer, &snapshot.palette)?;
+        write_objects(writer, &snapshot.removed)?;
+        write_objects(writer, &snapshot.added)?;
+    }
+    writer.write_all(&history.current.map_or(NO_SNAPSHOT, |current| current as u32).to_le_bytes())?;
+    Ok(())
+}
+
+fn write_string<W: Write>(writer: &mut W, text: &str) -> Result<()> {
+    ensure!(text.len() <= u16::MAX as usize, "Name too long to save: {:.40}...", text);
+    writer.write_all(&(text.len() as u16).to_le_bytes())?;
+    writer.write_all(text.as_bytes())?;
+    Ok(())
+}
+
+fn write_palette<W: Write>(writer: &mut W, palette: &Palette) -> Result<()> {
+    writer.write_all(&(palette.len() as u16).to_le_bytes())?;
+    for (_, color) in palette.iter() {
+        write_string(writer, &color.name)?;
+        for channel in &color.rgb {
+            writer.write_all(&channel.to_le_bytes())?;
+        }
+        writer.write_all(&[color.ldraw_id.is_some() as u8])?;
+        writer.write_all(&color.ldraw_id.unwrap_or(0).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_objects<W: Write>(writer: &mut W, objects: &[Brick]) -> Result<()> {
+    writer.write_all(&(objects.len() as u64).to_le_bytes())?;
+    for object in objects {
+        writer.write_all(&[
+            object.shape.blocktype.index(),
+            object.shape.width,
+            object.shape.depth,
+            object.orientation.index(),
+        ])?;
+        writer.write_all(&object.color.to_le_bytes())?;
+        for coordinate in &object.origin {
+            writer.write_all(&coordinate.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String> {
+    let mut text = vec![0; read_u16(reader)? as usize];
+    reader.read_exact(&mut text)?;
+    String::from_utf8(text).context("Text is not utf-8")
+}
+
+fn read_palette<R: Read>(reader: &mut R) -> Result<Palette> {
+    let mut palette = Palette::new();
+    for _ in 0..read_u16(reader)? {
+        let name = read_string(reader).context("Invalid palette colour name")?;
+        let mut rgb = [0.0; 3];
+        for channel in rgb.iter_mut() {
+            *channel = f32::from_bits(read_u32(reader)?);
+        }
+        let has_ldraw = read_u8(reader)?;
+        let ldraw_id = read_u32(reader)?;
+        if has_ldraw != 0 {
+            palette.add_ldraw(&name, rgb, ldraw_id)?;
+        } else {
+            palette.add(&name, rgb)?;
+        }
+    }
+    ensure!(!palette.is_empty(), "Palette has no colours");
+    Ok(palette)
+}
+
+fn read_objects<R: Read>(reader: &mut R) -> Result<Vec<Brick>> {
+    let mut objects = Vec::new();
+    for _ in 0..read_u64(reader)? {
+        let blocktype = read_u8(reader)?;
+        let width = read_u8(reader)?;
+        let depth = read_u8(reader)?;
+        let orientation = read_u8(reader)?;
+        let color = read_u16(reader)?;
+        ensure!(width > 0 && depth > 0, "Invalid size {}x{}", width, depth);
+        let mut origin = [0; 3];
+        for coordinate in origin.iter_mut() {
+            *coordinate = read_u64(reader)? as i64;
+        }
+        objects.push(Brick {
+            shape: BrickShape::new(
+                BlockType::from_index(blocktype).with_context(|| format!("Unknown block type {}", blocktype))?,
+                width,
+                depth,
+            ),
+            origin,
+            orientation: Orientation::new(orientation)
+                .with_context(|| format!("Invalid orientation {}", orientation))?,
+            color,
+        });
+    }
+    Ok(objects)
+}
+
+fn read_history<R: Read>(reader: &mut R) -> Result<History> {
+    let mut history = History::default();
+    let count = read_u32(reader)?;
+    for index in 0..count {
+        let name = read_string(reader).context("Invalid snapshot name")?;
+        let time = read_u64(reader)?;
+        let parent = match read_u32(reader)? {
+            NO_SNAPSHOT => None,
+            parent if parent < index => Some(parent as usize),
+            parent => bail!("Snapshot {} has invalid parent {}", index, parent),
+        };
+        history.snapshots.push(Snapshot {
+            name,
+            time,
+            parent,
+            palette: read_palette(reader)?,
+            removed: read_objects(reader)?,
+            added: read_objects(reader)?,
+        });
+    }
+    history.current = match read_u32(reader)? {
+        NO_SNAPSHOT => None,
+        current if current < count => Some(current as usize),
+        current => bail!("Invalid current snapshot {}", current),
+    };
+    Ok(history)
+}
+
+pub fn read_world<R: Read>(reader: &mut R) -> Result<World> {
+    Ok(read_world_with_history(reader)?.0)
+}
+
+pub fn read_world_with_history<R: Read>(reader: &mut R) -> Result<(World, History)> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("Not a byggeklosser world file");
+    }
+    let version = read_u32(reader)?;
+    if version == 0 || version > VERSION {
+        bail!("Unsupported world file version {}", version);
+    }
+    let chunk_size = read_u8(reader)?;
+    if chunk_size == 0 {
+        bail!("Invalid chunk size 0");
+    }
+    let palette = read_palette(reader)?;
+    let objects = read_objects(reader)?;
+    let world = World::from_objects(chunk_size, palette, objects)?;
+    let history = if version >= 2 { read_history(reader).context("Invalid history")? } else { History::default() };
+    Ok((world, history))
+}
+
+pub fn save<P: AsRef<Path>>(world: &World, path: P) -> Result<()> {
+    save_with_history(world, &History::default(), path)
+}
+
+/// Writes a temporary file next to `path` first and renames it over `path` when done,
+/// so a crash while saving keeps the old file.
+pub fn save_with_history<P: AsRef<Path>>(world: &World, history: &History, path: P) -> Result<()> {
+    let path = path.as_ref();
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let saved = write_synced(&temporary, world, history)
+        .and_then(|()| std::fs::rename(&temporary, path).with_context(|| format!("Cannot write {}", path.display())));
+    if saved.is_err() {
+        //Best effort, the error that got us here is the one worth reporting
+        let _ = std::fs::remove_file(&temporary);
+    }
+    saved?;
+    sync_directory(path)
+}
+
+//Write the file and wait until it is on disk, so the rename never points at a file the
+//disk has not got yet
+fn write_synced(path: &Path, world: &World, history: &History) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path).with_context(|| format!("Cannot create {}", path.display()))?);
+    write_world_with_history(&mut writer, world, history)?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all().with_context(|| format!("Cannot write {}", path.display()))
+}
+
+//The rename is only safe once the directory holding it is on disk too. Only Unix lets a
+//directory be opened and synced like a file.
+#[cfg(unix)]
+fn sync_directory(path: &Path) -> Result<()> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(directory)
+        .and_then(|directory| directory.sync_all())
+        .with_context(|| format!("Cannot sync {}", directory.display()))
+}
+
+#[cfg(not(unix))]
+fn sync_directory(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<World> {
+    Ok(load_with_history(path)?.0)
+}
+
+pub fn load_with_history<P: AsRef<Path>>(path: P) -> Result<(World, History)> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Cannot open {}", path.display()))?,
+    );
+    read_world_with_history(&mut reader).with_context(|| format!("Cannot read {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brick(x: i64, color: u16) -> Brick {
+        Brick {
+            shape: BrickShape::new(BlockType::BRICK, 2, 4),
+            origin: [x, 0, -3],
+            orientation: Orientation::new(1).unwrap(),
+            color,
+        }
+    }
+
+    fn objects(world: &World) -> Vec<Brick> {
+        world.objects().map(|(_, object)| object).collect()
+    }
+
+    #[test]
+    fn world_round_trip() {
+        let mut world = World::new(4);
+        world.palette.add_ldraw("Dark Red", [0.3, 0.0, 0.0], 320).unwrap();
+        world.place_brick(brick(-7, 1)).unwrap();
+        world.place_brick(brick(3, 7)).unwrap();
+        let mut bytes = Vec::new();
+        write_world(&mut bytes, &world).unwrap();
+        let read = read_world(&mut &bytes[..]).unwrap();
+        assert_eq!(read.chunk_size(), 4);
+        assert_eq!(read.palette, world.palette);
+        assert_eq!(objects(&read), objects(&world));
+    }
+
+    #[test]
+    fn history_round_trip() {
+        let mut world = World::default();
+        let mut history = History::default();
+        world.place_brick(brick(0, 1)).unwrap();
+        history.snapshot(&world, "first").unwrap();
+        world.place_brick(brick(5, 2)).unwrap();
+        history.snapshot(&world, "second").unwrap();
+        let mut bytes = Vec::new();
+        write_world_with_history(&mut bytes, &world, &history).unwrap();
+        assert_eq!(bytes[4..8], VERSION.to_le_bytes());
+        let (read, read_history) = read_world_with_history(&mut &bytes[..]).unwrap();
+        assert_eq!(objects(&read), objects(&world));
+        assert_eq!(read_history, history);
+    }
+
+    #[test]
+    fn version_1_reads_without_history() {
+        let mut world = World::default();
+        world.place_brick(brick(0, 1)).unwrap();
+        let mut bytes = Vec::new();
+        write_world(&mut bytes, &world).unwrap();
+        assert_eq!(bytes[4..8], 1u32.to_le_bytes());
+        let (read, history) = read_world_with_history(&mut &bytes[..]).unwrap();
+        assert_eq!(objects(&read), objects(&world));
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn long_names_are_rejected() {
+        let mut world = World::default();
+        world.palette.add(&"x".repeat(u16::MAX as usize + 1), [0.5, 0.5, 0.5]).unwrap();
+        assert!(write_world(&mut Vec::new(), &world).is_err());
+    }
+
+    #[test]
+    fn zero_sizes_are_rejected() {
+        let mut flat = brick(0, 1);
+        flat.shape.depth = 0;
+        let mut bytes = Vec::new();
+        write_objects(&mut bytes, &[brick(4, 1), flat]).unwrap();
+        let error = read_objects(&mut &bytes[..]).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid size 2x0");
+    }
+
+    #[test]
+    fn failed_saves_leave_no_temporary_file() {
+        let dir = std::env::temp_dir().join(format!("byggeklosser-world-file-{}", std::process::id()));
+        //Renaming over a directory fails once the file is written
+        let path = dir.join("world.bkw");
+        std::fs::create_dir_all(&path).unwrap();
+        let mut world = World::default();
+        world.place_brick(brick(0, 1)).unwrap();
+        assert!(save(&world, &path).is_err());
+        assert!(!dir.join("world.bkw.tmp").exists());
+        std::fs::remove_dir(&path).unwrap();
+        save(&world, &path).unwrap();
+        assert_eq!(objects(&load(&path).unwrap()), objects(&world));
+        assert!(!dir.join("world.bkw.tmp").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty_palettes_are_rejected() {
+        let mut bytes = Vec::new();
+        write_world(&mut bytes, &World::default()).unwrap();
+        //Palette count follows the magic, version and chunk size
+        bytes[9..11].copy_from_slice(&0u16.to_le_bytes());
+        bytes.truncate(11);
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        let error = read_world(&mut &bytes[..]).unwrap_err();
+        assert!(format!("{:#}", error).contains("no colours"), "{:#}", error);
+    }
+}